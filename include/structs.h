@@ -0,0 +1,25 @@
+/* C bindings for the structs client library, libstructs_sh. */
+#ifndef STRUCTS_H
+#define STRUCTS_H
+
+typedef struct structs_client structs_client;
+
+/* The last error on the calling thread, or NULL if the last call succeeded. */
+const char *structs_last_error(void);
+
+/* Connect to a service; NULL on failure. */
+structs_client *structs_client_connect(const char *path);
+
+/* The value of a key as JSON, to be released with structs_string_free; NULL if missing or on failure. */
+char *structs_client_get(const structs_client *client, const char *key);
+
+/* Store a JSON value; 0 on success, -1 on failure. */
+int structs_client_set(const structs_client *client, const char *key, const char *json);
+
+/* Delete a key; 1 if deleted, 0 if missing, -1 on failure. */
+int structs_client_delete(const structs_client *client, const char *key);
+
+void structs_client_free(structs_client *client);
+void structs_string_free(char *s);
+
+#endif
@@ -0,0 +1,282 @@
+use std::io;
+use std::thread;
+use std::net::Shutdown;
+use std::path::{Path, PathBuf};
+use std::os::unix::net::UnixStream;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, OnceLock, Weak};
+use std::collections::HashMap;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::error;
+use crate::rpc;
+
+/// The connections shared by clients, by the path of their socket.
+static CONNECTIONS: OnceLock<Mutex<HashMap<PathBuf, Weak<Connection>>>> = OnceLock::new();
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  match mutex.lock() {
+    Ok(guard) => guard,
+    Err(err)  => err.into_inner(),
+  }
+}
+
+/// The requests which have been written to a connection and are waiting
+/// for a response, by request id.
+struct Pending {
+  replies: HashMap<u64, mpsc::Sender<rpc::Operation>>,
+  closed: bool,
+}
+
+struct Writer {
+  stream: UnixStream,
+  next: u64,
+}
+
+/// A connection shared by any number of callers. The service answers the
+/// requests on a connection in the order they were made, so each request is
+/// given the next id as it is written, and a reader thread hands the nth
+/// response to the caller waiting on request n.
+struct Connection {
+  writer: Mutex<Writer>,
+  pending: Arc<Mutex<Pending>>,
+}
+
+impl Connection {
+  fn open(path: &Path) -> Result<Self, error::Error> {
+    let stream = UnixStream::connect(path)?;
+    let reader = io::BufReader::new(stream.try_clone()?);
+    let pending = Arc::new(Mutex::new(Pending{
+      replies: HashMap::new(),
+      closed: false,
+    }));
+    let shared = pending.clone();
+    thread::spawn(move || { read_responses(reader, shared) });
+    Ok(Self{
+      writer: Mutex::new(Writer{ stream, next: 0 }),
+      pending,
+    })
+  }
+
+  fn is_closed(&self) -> bool {
+    lock(&self.pending).closed
+  }
+
+  fn call(&self, op: &rpc::Operation) -> Result<rpc::Operation, error::Error> {
+    let (tx, rx) = mpsc::channel();
+    {
+      // ids must be assigned in the order requests are written
+      let mut writer = lock(&self.writer);
+      let id = writer.next;
+      {
+        let mut pending = lock(&self.pending);
+        if pending.closed {
+          return Err(error::Error::SendError);
+        }
+        pending.replies.insert(id, tx);
+      }
+      writer.next += 1;
+      if let Err(err) = rpc::write_operation(&mut writer.stream, op, false) {
+        let mut pending = lock(&self.pending);
+        pending.closed = true; // a partial write leaves the connection unusable
+        pending.replies.clear();
+        return Err(err);
+      }
+    }
+    Ok(rx.recv()?)
+  }
+}
+
+impl Drop for Connection {
+  fn drop(&mut self) {
+    let _ = lock(&self.writer).stream.shutdown(Shutdown::Both); // stops the reader
+  }
+}
+
+/// Read responses from a connection and hand each one to the request it
+/// answers. Once the connection closes, every caller still waiting is
+/// released with an error.
+fn read_responses(mut reader: io::BufReader<UnixStream>, pending: Arc<Mutex<Pending>>) {
+  let mut id: u64 = 0;
+  while let Ok(Some(rsp)) = rpc::read_operation(&mut reader, false) {
+    if let Some(reply) = lock(&pending).replies.remove(&id) {
+      let _ = reply.send(rsp);
+    }
+    id += 1;
+  }
+  let mut pending = lock(&pending);
+  pending.closed = true;
+  pending.replies.clear();
+}
+
+/// A client for a structs service. Clients of the same socket share a
+/// single connection, on which any number of threads may make requests
+/// concurrently; a connection which is lost is reopened by the next
+/// request.
+pub struct Client {
+  path: PathBuf,
+  conn: Mutex<Arc<Connection>>,
+}
+
+impl Client {
+  pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self, error::Error> {
+    let path = path.as_ref().to_path_buf();
+    let conn = shared(&path)?;
+    Ok(Self{
+      path,
+      conn: Mutex::new(conn),
+    })
+  }
+
+  fn connection(&self) -> Result<Arc<Connection>, error::Error> {
+    let mut conn = lock(&self.conn);
+    if conn.is_closed() {
+      *conn = shared(&self.path)?;
+    }
+    Ok(conn.clone())
+  }
+
+  /// Make a request and produce its response. Responses which turn the
+  /// request away, like busy, are produced as errors. Watches, which take
+  /// over the connection they are made on, cannot be made this way.
+  pub fn call(&self, op: &rpc::Operation) -> Result<rpc::Operation, error::Error> {
+    if op.name() == rpc::CMD_WATCH {
+      return Err(error::Error::Malformed);
+    }
+    let rsp = self.connection()?.call(op)?;
+    rpc::expect_response(Some(rsp), &[rpc::CMD_OK, rpc::CMD_FOUND, rpc::CMD_NONE, rpc::CMD_ERROR])
+  }
+
+  /// Fetch the value of a key or path, if there is one.
+  pub fn get(&self, key: &str) -> Result<Option<Value>, error::Error> {
+    let rsp = self.call(&rpc::Operation::new_get(key))?;
+    match rsp.name() {
+      rpc::CMD_FOUND => match rsp.data() {
+        Some(data) => Ok(Some(serde_json::from_str(data)?)),
+        None       => Err(error::Error::Malformed),
+      },
+      rpc::CMD_NONE  => Ok(None),
+      rpc::CMD_ERROR => Err(error::Error::RemoteError(rsp.data().clone())),
+      _              => Err(error::Error::Unexpected),
+    }
+  }
+
+  /// Store a value under a key or path.
+  pub fn set(&self, key: &str, value: &Value) -> Result<(), error::Error> {
+    let rsp = self.call(&rpc::Operation::new_set(key, &value.to_string()))?;
+    match rsp.name() {
+      rpc::CMD_OK    => Ok(()),
+      rpc::CMD_NONE  => Err(error::Error::NotFound),
+      rpc::CMD_ERROR => Err(error::Error::RemoteError(rsp.data().clone())),
+      _              => Err(error::Error::Unexpected),
+    }
+  }
+
+  /// Fetch the value of a key or path as any type it deserializes into.
+  pub fn get_as<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, error::Error> {
+    match self.get(key)? {
+      Some(value) => Ok(Some(serde_json::from_value(value)?)),
+      None        => Ok(None),
+    }
+  }
+
+  /// Store any value which serializes to JSON under a key or path.
+  pub fn set_from<T: Serialize>(&self, key: &str, value: &T) -> Result<(), error::Error> {
+    self.set(key, &serde_json::to_value(value)?)
+  }
+
+  /// Fetch the value of a key or path and delete it in one operation, if
+  /// there is one. Of any number of clients taking the same value, only one
+  /// receives it.
+  pub fn take(&self, key: &str) -> Result<Option<Value>, error::Error> {
+    let rsp = self.call(&rpc::Operation::new_take(key))?;
+    match rsp.name() {
+      rpc::CMD_FOUND => match rsp.data() {
+        Some(data) => Ok(Some(serde_json::from_str(data)?)),
+        None       => Err(error::Error::Malformed),
+      },
+      rpc::CMD_NONE  => Ok(None),
+      rpc::CMD_ERROR => Err(error::Error::RemoteError(rsp.data().clone())),
+      _              => Err(error::Error::Unexpected),
+    }
+  }
+
+  /// Delete a key or path, producing whether it existed.
+  pub fn delete(&self, key: &str) -> Result<bool, error::Error> {
+    let rsp = self.call(&rpc::Operation::new_delete(&[key]))?;
+    match rsp.name() {
+      rpc::CMD_OK    => Ok(!rsp.args().is_empty()),
+      rpc::CMD_ERROR => Err(error::Error::RemoteError(rsp.data().clone())),
+      _              => Err(error::Error::Unexpected),
+    }
+  }
+}
+
+/// The open connection to a socket, which is opened if there is none.
+fn shared(path: &Path) -> Result<Arc<Connection>, error::Error> {
+  let mut conns = lock(CONNECTIONS.get_or_init(|| { Mutex::new(HashMap::new()) }));
+  if let Some(conn) = conns.get(path).and_then(Weak::upgrade) {
+    if !conn.is_closed() {
+      return Ok(conn);
+    }
+  }
+  let conn = Arc::new(Connection::open(path)?);
+  conns.insert(path.to_path_buf(), Arc::downgrade(&conn));
+  Ok(conn)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::os::unix::net::UnixListener;
+
+  #[test]
+  fn multiplex_requests() {
+    let path = std::env::temp_dir().join(format!("structs-api-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let accepts = Arc::new(Mutex::new(0));
+    let counted = accepts.clone();
+    thread::spawn(move || {
+      // answer every get with the key it asked for, in order
+      for stream in listener.incoming() {
+        *lock(&counted) += 1;
+        let stream = stream.unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = io::BufReader::new(stream);
+        thread::spawn(move || {
+          while let Ok(Some(req)) = rpc::read_operation(&mut reader, false) {
+            let key = &req.args()[0];
+            let rsp = match key.as_str() {
+              "missing" => rpc::Operation::new_none(key),
+              _         => rpc::Operation::new_found(key, &Value::String(key.to_string()).to_string()),
+            };
+            rpc::write_operation(&mut writer, &rsp, false).unwrap();
+          }
+        });
+      }
+    });
+
+    let client = Arc::new(Client::connect(&path).unwrap());
+    let other = Client::connect(&path).unwrap();
+    let threads: Vec<_> = (0..8).map(|n| {
+      let client = client.clone();
+      thread::spawn(move || {
+        for i in 0..50 {
+          let key = format!("key-{}-{}", n, i);
+          assert_eq!(Some(Value::String(key.clone())), client.get(&key).unwrap());
+        }
+      })
+    }).collect();
+    for thread in threads {
+      thread.join().unwrap();
+    }
+    assert_eq!(None, other.get("missing").unwrap());
+    assert_eq!(Some("typed".to_string()), other.get_as::<String>("typed").unwrap());
+    assert!(other.get_as::<u64>("typed").is_err());
+    assert_eq!(1, *lock(&accepts)); // every request shared one connection
+    let _ = std::fs::remove_file(&path);
+  }
+}
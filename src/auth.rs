@@ -0,0 +1,169 @@
+use std::ffi;
+use std::mem;
+use std::ptr;
+use std::os::unix::io::AsRawFd;
+
+
+use crate::error;
+
+/// The credentials of the process on the other end of a connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Peer {
+  pub uid: u32,
+  pub gid: u32,
+  pub pid: Option<i32>,
+}
+
+impl Peer {
+  #[cfg(target_os = "linux")]
+  pub fn of<S: AsRawFd>(stream: &S) -> Result<Self, error::Error> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+      libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len)
+    };
+    if res != 0 {
+      return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(Self{uid: cred.uid, gid: cred.gid, pid: Some(cred.pid)})
+  }
+
+  #[cfg(not(target_os = "linux"))]
+  pub fn of<S: AsRawFd>(stream: &S) -> Result<Self, error::Error> {
+    let mut uid: libc::uid_t = 0;
+    let mut gid: libc::gid_t = 0;
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+      return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(Self{uid: uid, gid: gid, pid: None}) // the process isn't reported here
+  }
+}
+
+/// A group which connecting peers may belong to, either as their primary
+/// group or as a listed member.
+#[derive(Debug, Clone)]
+struct Group {
+  gid: u32,
+  members: Vec<String>,
+}
+
+/// A policy describing which peers may connect to the service. A policy
+/// which allows no users or groups explicitly is open to any peer which can
+/// reach the socket. The user running the service is always allowed.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+  uids: Vec<u32>,
+  groups: Vec<Group>,
+}
+
+impl Policy {
+  /// Create a policy from user and group names or numeric ids.
+  pub fn new(users: &[String], groups: &[String]) -> Result<Self, error::Error> {
+    let mut uids: Vec<u32> = Vec::new();
+    for user in users {
+      uids.push(lookup_user(user)?);
+    }
+    let mut gids: Vec<Group> = Vec::new();
+    for group in groups {
+      gids.push(lookup_group(group)?);
+    }
+    Ok(Self{
+      uids,
+      groups: gids,
+    })
+  }
+
+  pub fn is_open(&self) -> bool {
+    self.uids.is_empty() && self.groups.is_empty()
+  }
+
+  pub fn allows(&self, peer: &Peer) -> bool {
+    if self.is_open() || peer.uid == unsafe { libc::geteuid() } {
+      return true;
+    }
+    if self.uids.contains(&peer.uid) {
+      return true;
+    }
+    if self.groups.iter().any(|e| { e.gid == peer.gid }) {
+      return true;
+    }
+    match user_name(peer.uid) {
+      Some(name) => self.groups.iter().any(|e| { e.members.contains(&name) }),
+      None       => false,
+    }
+  }
+}
+
+fn lookup_user(user: &str) -> Result<u32, error::Error> {
+  if let Ok(uid) = user.parse::<u32>() {
+    return Ok(uid);
+  }
+  let name = cstring(user)?;
+  let pw = unsafe { libc::getpwnam(name.as_ptr()) };
+  if pw.is_null() {
+    return Err(error::Error::AuthError(format!("No such user: {}", user)));
+  }
+  Ok(unsafe { (*pw).pw_uid })
+}
+
+fn lookup_group(group: &str) -> Result<Group, error::Error> {
+  let gr = match group.parse::<u32>() {
+    Ok(gid) => unsafe { libc::getgrgid(gid) },
+    Err(_)  => unsafe { libc::getgrnam(cstring(group)?.as_ptr()) },
+  };
+  if gr.is_null() {
+    return match group.parse::<u32>() {
+      Ok(gid) => Ok(Group{gid, members: Vec::new()}), // a numeric id need not be named
+      Err(_)  => Err(error::Error::AuthError(format!("No such group: {}", group))),
+    };
+  }
+  let mut members: Vec<String> = Vec::new();
+  let mut mem = unsafe { (*gr).gr_mem };
+  while !mem.is_null() && !unsafe { *mem }.is_null() {
+    members.push(unsafe { ffi::CStr::from_ptr(*mem) }.to_string_lossy().into_owned());
+    mem = unsafe { mem.add(1) };
+  }
+  Ok(Group{
+    gid: unsafe { (*gr).gr_gid },
+    members,
+  })
+}
+
+fn user_name(uid: u32) -> Option<String> {
+  let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+  let mut res: *mut libc::passwd = ptr::null_mut();
+  let mut buf = vec![0 as libc::c_char; 4096];
+  let err = unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut res) };
+  if err != 0 || res.is_null() {
+    return None;
+  }
+  Some(unsafe { ffi::CStr::from_ptr(pwd.pw_name) }.to_string_lossy().into_owned())
+}
+
+fn cstring(text: &str) -> Result<ffi::CString, error::Error> {
+  match ffi::CString::new(text) {
+    Ok(text) => Ok(text),
+    Err(_)   => Err(error::Error::AuthError(format!("Invalid name: {}", text))),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::os::unix::net::UnixStream;
+
+  #[test]
+  fn peer_policy() {
+    let (a, _b) = UnixStream::pair().unwrap();
+    let peer = Peer::of(&a).unwrap();
+    assert_eq!(unsafe { libc::geteuid() }, peer.uid);
+    assert!(Policy::default().is_open());
+    assert!(Policy::default().allows(&Peer{uid: 12345, gid: 12345, pid: None}));
+    let policy = Policy::new(&["12345".to_string()], &["0".to_string()]).unwrap();
+    assert!(policy.allows(&peer)); // our own user
+    assert!(policy.allows(&Peer{uid: 12345, gid: 54321, pid: None}));
+    assert!(policy.allows(&Peer{uid: 54321, gid: 0, pid: None}));
+    assert!(!policy.allows(&Peer{uid: 54321, gid: 54321, pid: None}));
+    assert!(Policy::new(&["no-such-user-here".to_string()], &[]).is_err());
+  }
+}
@@ -0,0 +1,39 @@
+use std::collections::BTreeMap;
+
+use sha2::{Digest, Sha256};
+
+use crate::crypto;
+
+/// The content address of a value: the hex-encoded SHA-256 of its canonical
+/// encoding. Values are canonicalized by encoding them compactly with the
+/// members of objects in sorted order, so equal values share an address
+/// however they were formatted.
+pub fn address(value: &serde_json::Value) -> String {
+  // objects are ordered maps, so the default encoding is already canonical
+  crypto::encode_hex(&Sha256::digest(value.to_string().as_bytes()))
+}
+
+/// The checksum of an entire store: the address of the object formed by its
+/// keys and their values. Stores holding the same data share a checksum,
+/// whichever order it was written in.
+pub fn checksum(keys: &BTreeMap<String, serde_json::Value>) -> String {
+  address(&serde_json::Value::Object(keys.iter().map(|(k, v)| { (k.to_owned(), v.clone()) }).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn addresses() {
+    let a: serde_json::Value = serde_json::from_str(r#"{"b": 1, "a": [true, null]}"#).unwrap();
+    let b: serde_json::Value = serde_json::from_str(r#"{"a":[true,null],"b":1}"#).unwrap();
+    assert_eq!(address(&a), address(&b));
+    assert_eq!(64, address(&a).len());
+    assert_ne!(address(&a), address(&serde_json::json!({"a": [true, null], "b": 2})));
+    let keys = BTreeMap::from([("b".to_string(), b.clone()), ("a".to_string(), a.clone())]);
+    assert_eq!(address(&serde_json::json!({"a": a, "b": b})), checksum(&keys));
+    assert_ne!(checksum(&keys), checksum(&BTreeMap::new()));
+    assert_eq!("12ae32cb1ec02d01eda3581b127c1fee3b0dc53572ed6baf239721a03d82e126", address(&serde_json::json!("")));
+  }
+}
@@ -1,38 +1,347 @@
-use std::os::unix::net::UnixStream;
 use std::sync::mpsc;
 
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::sync::mpsc as async_mpsc;
+
 use colored::Colorize;
 
 use crate::Options;
 use crate::error;
 use crate::rpc;
+use crate::auth;
+use crate::jsonrpc;
+use crate::ratelimit;
+use crate::clients;
 use crate::log;
 
-pub fn run(opts: Options, stream: UnixStream, tx: mpsc::Sender<rpc::Request>) {
-  match handle(&opts, stream, tx) {
+/// How the service talks to its clients.
+#[derive(Clone)]
+pub struct Config {
+  pub policy: auth::Policy,
+  pub max_line: usize,
+  pub protocol: rpc::Protocol,
+  pub limiter: Option<ratelimit::Limiter>,
+  pub registry: clients::Registry,
+}
+
+pub async fn run(opts: Options, id: usize, stream: UnixStream, config: Config, tx: mpsc::Sender<rpc::Request>, inflight: rpc::Inflight) {
+  match handle(&opts, id, stream, &config, tx, inflight).await {
+    Ok(_)    => {},
+    Err(err) => log::logln!("{}", &format!("* * * {}", err).yellow().bold()),
+  };
+  if let Some(limiter) = &config.limiter {
+    limiter.release(ratelimit::Subject::Connection(id));
+  }
+}
+
+/// Serve a single client speaking over our own stdin and stdout.
+pub async fn run_stdio(opts: Options, config: Config, tx: mpsc::Sender<rpc::Request>, inflight: rpc::Inflight) {
+  let registration = config.registry.register(1, None, None);
+  match serve(&opts, Origin{ id: 1, uid: None, registration: &registration }, tokio::io::stdin(), &mut tokio::io::stdout(), &config, tx, inflight).await {
     Ok(_)    => {},
     Err(err) => log::logln!("{}", &format!("* * * {}", err).yellow().bold()),
   };
 }
 
-fn handle(opts: &Options, stream: UnixStream, tx: mpsc::Sender<rpc::Request>) -> Result<(), error::Error> {
-  let mut rpc = rpc::RPC::new(stream, rpc::Options{debug: opts.debug})?;
+/// Turn away a connection because the service is at capacity.
+pub async fn reject(opts: Options, stream: UnixStream, protocol: rpc::Protocol) {
+  let (_, mut writer) = stream.into_split();
+  let res = match protocol {
+    rpc::Protocol::Line    => write_cmd(&opts, &mut writer, &rpc::Operation::new_busy()).await,
+    rpc::Protocol::JsonRpc => write_json(&mut writer, &jsonrpc::respond(serde_json::Value::Null, &rpc::Operation::new_busy())).await,
+  };
+  if let Err(err) = res {
+    log::logln!("{}", &format!("* * * {}", err).yellow().bold());
+  }
+}
+
+async fn handle(opts: &Options, id: usize, stream: UnixStream, config: &Config, tx: mpsc::Sender<rpc::Request>, inflight: rpc::Inflight) -> Result<(), error::Error> {
+  let peer = auth::Peer::of(&stream)?;
+  let (reader, mut writer) = stream.into_split();
+  if !config.policy.allows(&peer) {
+    let err = error::Error::AuthError(format!("uid={} gid={}", peer.uid, peer.gid));
+    match config.protocol {
+      rpc::Protocol::Line    => write_cmd(opts, &mut writer, &rpc::Operation::new_error(&err.to_string())).await?,
+      rpc::Protocol::JsonRpc => write_json(&mut writer, &jsonrpc::error(serde_json::Value::Null, jsonrpc::SERVER_ERROR, &err.to_string())).await?,
+    }
+    return Err(err);
+  }
+  let registration = config.registry.register(id, Some(peer.uid), peer.pid);
+  serve(opts, Origin{ id, uid: Some(peer.uid), registration: &registration }, reader, &mut writer, config, tx, inflight).await
+}
+
+/// Who is on the other end of a connection: its id, the user it runs as,
+/// when that is known, and its entry in the clients registry.
+#[derive(Clone, Copy)]
+struct Origin<'a> {
+  id: usize,
+  uid: Option<u32>,
+  registration: &'a clients::Registration,
+}
+
+impl Origin<'_> {
+  /// Who rate limits apply to: the user, if we know it, so that they are
+  /// shared by all of a user's connections.
+  fn subject(&self) -> ratelimit::Subject {
+    match self.uid {
+      Some(uid) => ratelimit::Subject::Uid(uid),
+      None      => ratelimit::Subject::Connection(self.id),
+    }
+  }
+}
+
+/// Admit a request from a client, or produce how long it must wait before
+/// it will be, if it has exceeded its rate limit.
+fn admit(opts: &Options, origin: Origin<'_>, config: &Config) -> Result<(), std::time::Duration> {
+  let res = match &config.limiter {
+    Some(limiter) => limiter.take(origin.subject()),
+    None          => Ok(()),
+  };
+  if let Err(retry) = &res {
+    if opts.debug {
+      log::logln!(">>> Rate limited client {}; retry in {:?}", origin.id, retry);
+    }
+  }
+  res
+}
+
+/// Answer the requests which concern the client connections themselves,
+/// rather than the store, which the service never sees. This includes the
+/// sequence token the connection's requests are tagged with.
+fn answer(origin: Origin<'_>, config: &Config, sequence: &mut Option<String>, cmd: &rpc::Operation) -> Result<Option<rpc::Operation>, error::Error> {
+  match cmd.name() {
+    rpc::CMD_HELLO => match cmd.args().first() {
+      Some(name) => {
+        config.registry.name(origin.id, name);
+        Ok(Some(rpc::Operation::new_ok()))
+      },
+      None => Err(error::Error::Malformed),
+    },
+    rpc::CMD_SEQUENCE => {
+      *sequence = cmd.args().first().cloned();
+      Ok(Some(rpc::Operation::new_ok()))
+    },
+    rpc::CMD_CLIENTS => Ok(Some(rpc::Operation::new_found(rpc::CMD_CLIENTS, &serde_json::to_string(&config.registry.list())?))),
+    rpc::CMD_KILLCLIENT => match cmd.args().first().map(|e| { e.parse::<usize>() }) {
+      Some(Ok(id)) => match config.registry.kill(id, origin.uid)? {
+        true  => Ok(Some(rpc::Operation::new_ok())),
+        false => Ok(Some(rpc::Operation::new_none(&id.to_string()))),
+      },
+      _ => Err(error::Error::Malformed),
+    },
+    _ => Ok(None),
+  }
+}
+
+/// Handle requests read from a client until it has no more.
+async fn serve<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(opts: &Options, origin: Origin<'_>, reader: R, writer: &mut W, config: &Config, tx: mpsc::Sender<rpc::Request>, inflight: rpc::Inflight) -> Result<(), error::Error> {
+  if config.protocol == rpc::Protocol::JsonRpc {
+    return serve_json(opts, origin, reader, writer, config, tx, inflight).await;
+  }
+  let max_line = config.max_line;
+  let mut reader = BufReader::new(reader);
+  let mut sequence = None;
+  loop {
+    let next = tokio::select! {
+      cmd = read_cmd(opts, &mut reader, max_line) => cmd,
+      _   = origin.registration.killed()                  => Err(error::Error::Disconnected(origin.id)), // only between requests
+    };
+    let cmd = match next {
+      Ok(Some(cmd)) => cmd,
+      Ok(None)      => break,
+      Err(err @ error::Error::LineTooLong(_)) => {
+        write_cmd(opts, writer, &rpc::Operation::new_error(&err.to_string())).await?;
+        return Err(err); // we can't find the next command, give up on this connection
+      },
+      Err(err @ error::Error::Disconnected(_)) => {
+        write_cmd(opts, writer, &rpc::Operation::new_error(&err.to_string())).await?;
+        return Err(err);
+      },
+      Err(err) => return Err(err),
+    };
+    config.registry.touch(origin.id);
+    if let Err(retry) = admit(opts, origin, config) {
+      write_cmd(opts, writer, &rpc::Operation::new_limited(retry)).await?;
+      continue;
+    }
+    match answer(origin, config, &mut sequence, &cmd) {
+      Ok(Some(rsp)) => {
+        write_cmd(opts, writer, &rsp).await?;
+        continue;
+      },
+      Ok(None) => {},
+      Err(err) => {
+        write_cmd(opts, writer, &rpc::Operation::new_error(&err.to_string())).await?;
+        continue;
+      },
+    }
+    let watch = cmd.name() == rpc::CMD_WATCH;
+    let (rsp_tx, mut rsp_rx) = async_mpsc::unbounded_channel();
+    let req = rpc::Request::new(origin.id, cmd, rsp_tx).with_uid(origin.uid).with_sequence(sequence.clone());
+    inflight.begin();
+    match tx.send(req) {
+      Ok(_)  => {},
+      Err(_) => {
+        inflight.end();
+        return Err(error::Error::SendError);
+      },
+    };
+    let res = respond(opts, writer, &mut rsp_rx).await;
+    inflight.end();
+    if watch && res?.name() == rpc::CMD_OK {
+      return relay(opts, origin, &mut reader, writer, rsp_rx).await; // this connection is now a subscription
+    }
+  }
+  Ok(())
+}
+
+/// Relay the events a client is watching for until it goes away. Anything
+/// the client sends in the meantime is ignored.
+async fn relay<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(opts: &Options, origin: Origin<'_>, reader: &mut R, writer: &mut W, mut rsp_rx: async_mpsc::UnboundedReceiver<rpc::Operation>) -> Result<(), error::Error> {
+  let mut buf = [0u8; 512];
+  loop {
+    tokio::select! {
+      event = rsp_rx.recv() => match event {
+        Some(event) => write_cmd(opts, writer, &event).await?,
+        None        => return Ok(()),
+      },
+      n = reader.read(&mut buf) => match n {
+        Ok(0)    => return Ok(()), // the client hung up
+        Ok(_)    => {},
+        Err(err) => return Err(err.into()),
+      },
+      _ = origin.registration.killed() => {
+        let err = error::Error::Disconnected(origin.id);
+        write_cmd(opts, writer, &rpc::Operation::new_error(&err.to_string())).await?;
+        return Err(err);
+      },
+    }
+  }
+}
+
+/// Handle JSON-RPC requests, one per line, by translating them to and from
+/// the operations they map to.
+async fn serve_json<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(opts: &Options, origin: Origin<'_>, reader: R, writer: &mut W, config: &Config, tx: mpsc::Sender<rpc::Request>, inflight: rpc::Inflight) -> Result<(), error::Error> {
+  let mut reader = BufReader::new(reader);
   loop {
-    let cmd = match rpc.read_cmd()? {
-      Some(cmd) => cmd,
-      None      => break,
+    let mut line = String::new();
+    let next = tokio::select! {
+      n = read_line(&mut reader, &mut line, config.max_line) => n,
+      _ = origin.registration.killed()                               => Err(error::Error::Disconnected(origin.id)),
+    };
+    match next {
+      Ok(0)    => break,
+      Ok(_)    => {},
+      Err(err @ error::Error::LineTooLong(_)) => {
+        write_json(writer, &jsonrpc::error(serde_json::Value::Null, jsonrpc::INVALID_REQUEST, &err.to_string())).await?;
+        return Err(err);
+      },
+      Err(err @ error::Error::Disconnected(_)) => {
+        write_json(writer, &jsonrpc::error(serde_json::Value::Null, jsonrpc::SERVER_ERROR, &err.to_string())).await?;
+        return Err(err);
+      },
+      Err(err) => return Err(err),
+    };
+    if line.trim().is_empty() {
+      continue;
+    }
+    if opts.debug {
+      log::logln!("<<< jsonrpc: {}", line.trim());
+    }
+    let call = match jsonrpc::parse(&line) {
+      Ok(call) => call,
+      Err(rsp) => {
+        write_json(writer, &rsp).await?;
+        continue;
+      },
     };
-    let (rsp_tx, rsp_rx) = mpsc::channel();
-    let req = rpc::Request::new(cmd, rsp_tx);
+    config.registry.touch(origin.id);
+    if let Err(retry) = admit(opts, origin, config) {
+      if let Some(cid) = call.id {
+        write_json(writer, &jsonrpc::respond(cid, &rpc::Operation::new_limited(retry))).await?;
+      }
+      continue;
+    }
+    let (rsp_tx, mut rsp_rx) = async_mpsc::unbounded_channel();
+    let req = rpc::Request::new(origin.id, call.op, rsp_tx).with_uid(origin.uid);
+    inflight.begin();
     match tx.send(req) {
       Ok(_)  => {},
-      Err(_) => return Err(error::Error::SendError),
+      Err(_) => {
+        inflight.end();
+        return Err(error::Error::SendError);
+      },
+    };
+    let res = match rsp_rx.recv().await {
+      Some(rsp) => match call.id {
+        Some(cid) => write_json(writer, &jsonrpc::respond(cid, &rsp)).await,
+        None      => Ok(()), // a notification; nobody is waiting for a response
+      },
+      None => Err(error::Error::RecvError(mpsc::RecvError)),
+    };
+    inflight.end();
+    res?;
+  }
+  Ok(())
+}
+
+/// Write the response to a request, producing it.
+async fn respond<W: AsyncWrite + Unpin>(opts: &Options, writer: &mut W, rsp_rx: &mut async_mpsc::UnboundedReceiver<rpc::Operation>) -> Result<rpc::Operation, error::Error> {
+  loop {
+    let rsp = match rsp_rx.recv().await {
+      Some(rsp) => rsp,
+      None      => return Err(error::Error::RecvError(mpsc::RecvError)),
     };
-    let rsp = rsp_rx.recv()?;
     if opts.debug {
       log::logln!("<<< {:?}", &rsp);
     }
-    rpc.write_cmd(&rsp)?;
+    write_cmd(opts, writer, &rsp).await?;
+    if rsp.name() != rpc::CMD_MORE { // a streamed response continues until something else
+      return Ok(rsp);
+    }
   }
+}
+
+/// Read an operation from a connection; see `rpc::read_operation`. Lines
+/// longer than the maximum are not read in full.
+async fn read_cmd<R: AsyncBufRead + Unpin>(opts: &Options, reader: &mut R, max_line: usize) -> Result<Option<rpc::Operation>, error::Error> {
+  let mut line = String::new();
+  if read_line(reader, &mut line, max_line).await? == 0 {
+    return Ok(None);
+  }
+  let data = if rpc::expects_data(&line) {
+    let mut data = String::new();
+    if read_line(reader, &mut data, max_line).await? == 0 {
+      return Err(error::Error::Malformed);
+    }
+    Some(data)
+  } else {
+    None
+  };
+  Ok(Some(rpc::parse_operation(&line, data.as_deref(), opts.debug)?))
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R, line: &mut String, max_line: usize) -> Result<usize, error::Error> {
+  let n = (&mut *reader).take(max_line as u64 + 1).read_line(line).await?;
+  if n > 0 && !line.ends_with('\n') && n > max_line {
+    return Err(error::Error::LineTooLong(max_line));
+  }
+  Ok(n)
+}
+
+async fn write_json<W: AsyncWrite + Unpin>(writer: &mut W, rsp: &serde_json::Value) -> Result<(), error::Error> {
+  let mut buf = serde_json::to_vec(rsp)?;
+  buf.push(b'\n');
+  writer.write_all(&buf).await?;
+  writer.flush().await?;
+  Ok(())
+}
+
+async fn write_cmd<W: AsyncWrite + Unpin>(opts: &Options, writer: &mut W, cmd: &rpc::Operation) -> Result<(), error::Error> {
+  let mut buf: Vec<u8> = Vec::new();
+  rpc::write_operation(&mut buf, cmd, opts.debug)?;
+  writer.write_all(&buf).await?;
+  writer.flush().await?; // some writers, like stdout, buffer
   Ok(())
 }
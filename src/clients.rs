@@ -0,0 +1,184 @@
+use std::time;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::collections::BTreeMap;
+
+use serde::{Serialize, Deserialize};
+use tokio::sync::Notify;
+
+use crate::error;
+
+/// What is known of a connected client.
+struct Client {
+  name: Option<String>,
+  pid: Option<i32>,
+  uid: Option<u32>,
+  ops: u64,
+  last: time::Instant,
+  kill: Arc<Notify>,
+}
+
+/// A description of a connected client, as listed by `clients`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Info {
+  pub id: usize,
+  pub name: Option<String>,
+  pub pid: Option<i32>,
+  pub uid: Option<u32>,
+  pub ops: u64,
+  pub idle_ms: u128,
+}
+
+/// The clients connected to a service, by connection id, so operators of a
+/// shared service can see who is connected.
+#[derive(Clone)]
+pub struct Registry {
+  clients: Arc<Mutex<BTreeMap<usize, Client>>>,
+}
+
+impl Registry {
+  pub fn new() -> Self {
+    Self{
+      clients: Arc::new(Mutex::new(BTreeMap::new())),
+    }
+  }
+
+  fn clients(&self) -> MutexGuard<'_, BTreeMap<usize, Client>> {
+    match self.clients.lock() {
+      Ok(clients)  => clients,
+      Err(clients) => clients.into_inner(),
+    }
+  }
+
+  /// Note a client which has connected. It is forgotten when the guard is
+  /// dropped.
+  pub fn register(&self, id: usize, uid: Option<u32>, pid: Option<i32>) -> Registration {
+    let kill = Arc::new(Notify::new());
+    self.clients().insert(id, Client{
+      name: None,
+      pid,
+      uid,
+      ops: 0,
+      last: time::Instant::now(),
+      kill: kill.clone(),
+    });
+    Registration{
+      registry: self.clone(),
+      id,
+      kill,
+    }
+  }
+
+  /// Note the name a client gave itself.
+  pub fn name(&self, id: usize, name: &str) {
+    if let Some(client) = self.clients().get_mut(&id) {
+      client.name = Some(name.to_string());
+    }
+  }
+
+  /// Note a request made by a client.
+  pub fn touch(&self, id: usize) {
+    if let Some(client) = self.clients().get_mut(&id) {
+      client.ops += 1;
+      client.last = time::Instant::now();
+    }
+  }
+
+  /// Have a client disconnected on behalf of the user `by`, producing
+  /// whether it is connected. It is disconnected once it has the response
+  /// to any request in progress. Users may only disconnect their own
+  /// clients, unless they are the user the service runs as; a client we
+  /// don't know the user of, over stdio, started the service.
+  pub fn kill(&self, id: usize, by: Option<u32>) -> Result<bool, error::Error> {
+    let clients = self.clients();
+    let client = match clients.get(&id) {
+      Some(client) => client,
+      None         => return Ok(false),
+    };
+    if let Some(uid) = by {
+      if client.uid != Some(uid) && uid != unsafe { libc::geteuid() } {
+        return Err(error::Error::AuthError(format!("uid={} cannot disconnect client {} of another user", uid, id)));
+      }
+    }
+    client.kill.notify_one(); // remembered if the client isn't waiting yet
+    Ok(true)
+  }
+
+  /// Describe every connected client, in the order they connected.
+  pub fn list(&self) -> Vec<Info> {
+    self.clients().iter().map(|(id, client)| {
+      Info{
+        id: *id,
+        name: client.name.clone(),
+        pid: client.pid,
+        uid: client.uid,
+        ops: client.ops,
+        idle_ms: client.last.elapsed().as_millis(),
+      }
+    }).collect()
+  }
+}
+
+pub struct Registration {
+  registry: Registry,
+  id: usize,
+  kill: Arc<Notify>,
+}
+
+impl Registration {
+  /// Wait until the client is to be disconnected.
+  pub async fn killed(&self) {
+    self.kill.notified().await
+  }
+}
+
+impl Drop for Registration {
+  fn drop(&mut self) {
+    self.registry.clients().remove(&self.id);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn track_clients() {
+    let registry = Registry::new();
+    let a = registry.register(1, Some(1000), Some(42));
+    let _b = registry.register(2, None, None);
+    registry.name(1, "deploy");
+    registry.touch(1);
+    registry.touch(1);
+    let list = registry.list();
+    assert_eq!(vec![1, 2], list.iter().map(|e| { e.id }).collect::<Vec<usize>>());
+    assert_eq!((Some("deploy".to_string()), Some(42), 2), (list[0].name.clone(), list[0].pid, list[0].ops));
+    assert_eq!((None, 0), (list[1].name.clone(), list[1].ops));
+    drop(a);
+    assert_eq!(vec![2], registry.list().iter().map(|e| { e.id }).collect::<Vec<usize>>());
+    assert!(!registry.kill(1, None).unwrap());
+  }
+
+  #[tokio::test]
+  async fn kill_clients() {
+    let registry = Registry::new();
+    let a = registry.register(1, None, None);
+    assert!(registry.kill(1, None).unwrap());
+    a.killed().await; // the kill is not lost when nobody is waiting for it yet
+  }
+
+  #[test]
+  fn kill_only_own_clients() {
+    let owner = unsafe { libc::geteuid() };
+    let (user, other) = (owner + 1000, owner + 2000);
+    let registry = Registry::new();
+    let _a = registry.register(1, Some(user), None);
+    let _b = registry.register(2, Some(other), None);
+    let _c = registry.register(3, None, None);
+    assert!(registry.kill(1, Some(user)).unwrap());
+    assert!(registry.kill(2, Some(user)).is_err());
+    assert!(registry.kill(3, Some(user)).is_err());
+    assert!(registry.kill(2, Some(owner)).unwrap());
+    assert!(registry.kill(3, None).unwrap());
+    assert!(!registry.kill(4, Some(user)).unwrap()); // nothing to protect
+  }
+}
@@ -0,0 +1,113 @@
+use std::fs;
+use std::env;
+use std::path;
+
+use chacha20poly1305::{XChaCha20Poly1305, XNonce, Key};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+
+use crate::error;
+
+/// The environment variable from which key material is read when no key
+/// file is provided.
+pub const KEY_ENV: &str = "STRUCTS_ENCRYPT_KEY";
+
+const KEY_LEN:   usize = 32;
+const NONCE_LEN: usize = 24;
+
+/// A cipher used to encrypt state persisted by the service. Data is sealed
+/// with XChaCha20-Poly1305 under a random nonce, which is prepended to the
+/// ciphertext.
+#[derive(Clone)]
+pub struct Cipher {
+  aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+  pub fn new(key: &[u8]) -> Result<Self, error::Error> {
+    if key.len() != KEY_LEN {
+      return Err(error::Error::CryptoError(format!("Encryption keys must be {} bytes; got {}", KEY_LEN, key.len())));
+    }
+    Ok(Self{
+      aead: XChaCha20Poly1305::new(Key::from_slice(key)),
+    })
+  }
+
+  /// Load a cipher from the hex-encoded key in the provided file or, if no
+  /// file is provided, in the environment. If neither is present no cipher
+  /// is produced and state is persisted in the clear.
+  pub fn load(path: Option<&path::Path>) -> Result<Option<Self>, error::Error> {
+    let text = match path {
+      Some(path) => fs::read_to_string(path)?,
+      None       => match env::var(KEY_ENV) {
+        Ok(text) => text,
+        Err(_)   => return Ok(None),
+      },
+    };
+    match decode_hex(text.trim()) {
+      Some(key) => Ok(Some(Self::new(&key)?)),
+      None      => Err(error::Error::CryptoError("Encryption key is not valid hex".to_string())),
+    }
+  }
+
+  pub fn seal(&self, data: &[u8]) -> Result<Vec<u8>, error::Error> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let mut sealed = nonce.to_vec();
+    match self.aead.encrypt(&nonce, data) {
+      Ok(data) => sealed.extend(data),
+      Err(_)   => return Err(error::Error::CryptoError("Could not encrypt".to_string())),
+    }
+    Ok(sealed)
+  }
+
+  pub fn open(&self, data: &[u8]) -> Result<Vec<u8>, error::Error> {
+    if data.len() < NONCE_LEN {
+      return Err(error::Error::CryptoError("Encrypted data is truncated".to_string()));
+    }
+    let (nonce, data) = data.split_at(NONCE_LEN);
+    match self.aead.decrypt(XNonce::from_slice(nonce), data) {
+      Ok(data) => Ok(data),
+      Err(_)   => Err(error::Error::CryptoError("Could not decrypt; the key is wrong or the data is corrupt".to_string())),
+    }
+  }
+}
+
+pub fn encode_hex(data: &[u8]) -> String {
+  data.iter().map(|b| { format!("{:02x}", b) }).collect()
+}
+
+pub fn decode_hex(text: &str) -> Option<Vec<u8>> {
+  if !text.len().is_multiple_of(2) || !text.is_ascii() {
+    return None;
+  }
+  let mut data: Vec<u8> = Vec::new();
+  for i in (0..text.len()).step_by(2) {
+    data.push(u8::from_str_radix(&text[i..i+2], 16).ok()?);
+  }
+  Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn seal_open() {
+    let c = Cipher::new(&[7u8; KEY_LEN]).unwrap();
+    let s = c.seal(b"secret").unwrap();
+    assert_eq!(NONCE_LEN + 6 + 16, s.len());
+    assert_eq!(b"secret".to_vec(), c.open(&s).unwrap());
+    assert_ne!(s, c.seal(b"secret").unwrap()); // fresh nonce each time
+    let w = Cipher::new(&[8u8; KEY_LEN]).unwrap();
+    assert!(w.open(&s).is_err());
+    assert!(c.open(&s[..10]).is_err());
+    assert!(Cipher::new(&[7u8; 16]).is_err());
+  }
+
+  #[test]
+  fn hex() {
+    assert_eq!("00ff10", encode_hex(&[0, 255, 16]));
+    assert_eq!(Some(vec![0, 255, 16]), decode_hex("00FF10"));
+    assert_eq!(None, decode_hex("0"));
+    assert_eq!(None, decode_hex("zz"));
+  }
+}
@@ -0,0 +1,88 @@
+use std::fmt;
+
+use serde::{Serialize, Deserialize};
+use serde_json::value::Value;
+
+use crate::jsonpath;
+
+/// A single structural change between two values.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Change {
+  Add{path: String, value: Value},
+  Remove{path: String, value: Value},
+  Replace{path: String, from: Value, value: Value},
+}
+
+impl fmt::Display for Change {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Add{path, value}           => write!(f, "+ {}: {}", path, value),
+      Self::Remove{path, value}        => write!(f, "- {}: {}", path, value),
+      Self::Replace{path, from, value} => write!(f, "~ {}: {} -> {}", path, from, value),
+    }
+  }
+}
+
+/// Compute the changes required to turn one value into another. Objects and
+/// arrays are compared member-by-member so that changes are reported at the
+/// deepest path which differs; either value may be absent. Paths are
+/// prefixed with the provided prefix.
+pub fn diff(prefix: &str, old: Option<&Value>, new: Option<&Value>) -> Vec<Change> {
+  let mut changes: Vec<Change> = Vec::new();
+  diff_prefix(prefix, old, new, &mut changes);
+  changes
+}
+
+fn diff_prefix(prefix: &str, old: Option<&Value>, new: Option<&Value>, changes: &mut Vec<Change>) {
+  match (old, new) {
+    (None, None)    => {},
+    (None, Some(n)) => changes.push(Change::Add{path: prefix.to_string(), value: n.clone()}),
+    (Some(o), None) => changes.push(Change::Remove{path: prefix.to_string(), value: o.clone()}),
+    (Some(Value::Object(o)), Some(Value::Object(n))) => {
+      for (k, v) in o {
+        diff_prefix(&jsonpath::join(prefix, k), Some(v), n.get(k), changes);
+      }
+      for (k, v) in n {
+        if !o.contains_key(k) {
+          diff_prefix(&jsonpath::join(prefix, k), None, Some(v), changes);
+        }
+      }
+    },
+    (Some(Value::Array(o)), Some(Value::Array(n))) => {
+      for i in 0..o.len().max(n.len()) {
+        diff_prefix(&jsonpath::join(prefix, &i.to_string()), o.get(i), n.get(i), changes);
+      }
+    },
+    (Some(o), Some(n)) => if o != n {
+      changes.push(Change::Replace{path: prefix.to_string(), from: o.clone(), value: n.clone()});
+    },
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_diff() {
+    let o: Value = serde_json::from_str(r#"{"a": 1, "b": {"c": 2, "d": 3}, "e": [1, 2]}"#).unwrap();
+    let n: Value = serde_json::from_str(r#"{"a": 1, "b": {"c": 4, "f": 5}, "e": [1]}"#).unwrap();
+    assert_eq!(vec![
+      Change::Replace{path: "k.b.c".to_string(), from: Value::Number(2.into()), value: Value::Number(4.into())},
+      Change::Remove{path: "k.b.d".to_string(), value: Value::Number(3.into())},
+      Change::Add{path: "k.b.f".to_string(), value: Value::Number(5.into())},
+      Change::Remove{path: "k.e.1".to_string(), value: Value::Number(2.into())},
+    ], diff("k", Some(&o), Some(&n)));
+    assert_eq!(Vec::<Change>::new(), diff("k", Some(&o), Some(&o)));
+    assert_eq!(vec![Change::Add{path: "k".to_string(), value: o.clone()}], diff("k", None, Some(&o)));
+    assert_eq!(vec![Change::Replace{path: "k".to_string(), from: o.clone(), value: Value::Null}], diff("k", Some(&o), Some(&Value::Null)));
+  }
+
+  #[test]
+  fn test_change_json() {
+    let c = Change::Replace{path: "a".to_string(), from: Value::Number(1.into()), value: Value::Number(2.into())};
+    assert_eq!(r#"{"op":"replace","path":"a","from":1,"value":2}"#, serde_json::to_string(&c).unwrap());
+    assert_eq!("~ a: 1 -> 2", c.to_string());
+  }
+}
@@ -0,0 +1,201 @@
+use std::io;
+use std::fs;
+use std::path;
+use std::time;
+use std::io::Read;
+use std::ffi::CString;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixStream;
+
+use colored::Colorize;
+
+use crate::rpc;
+
+/// Clocks reading earlier than this (2020-01-01) are surely wrong.
+const EARLIEST: time::Duration = time::Duration::from_secs(1577836800);
+/// How far in the future a file may have been modified before we suspect
+/// the clock.
+const MAX_SKEW: time::Duration = time::Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+  Ok,
+  Warning,
+  Problem,
+}
+
+/// The outcome of a check and, when it is not ok, how to fix it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+  pub status: Status,
+  pub check: &'static str,
+  pub detail: String,
+  pub fix: Option<String>,
+}
+
+impl Finding {
+  fn ok(check: &'static str, detail: String) -> Self {
+    Self{ status: Status::Ok, check, detail, fix: None }
+  }
+
+  fn warning(check: &'static str, detail: String, fix: String) -> Self {
+    Self{ status: Status::Warning, check, detail, fix: Some(fix) }
+  }
+
+  fn problem(check: &'static str, detail: String, fix: String) -> Self {
+    Self{ status: Status::Problem, check, detail, fix: Some(fix) }
+  }
+
+  pub fn print(&self) {
+    let status = match self.status {
+      Status::Ok      => "ok     ".green(),
+      Status::Warning => "warning".yellow().bold(),
+      Status::Problem => "problem".red().bold(),
+    };
+    println!("{} {}: {}", status, self.check, self.detail);
+    if let Some(fix) = &self.fix {
+      println!("        fix: {}", fix);
+    }
+  }
+}
+
+/// Determine if we may write to a path.
+fn writable(path: &path::Path) -> bool {
+  match CString::new(path.as_os_str().as_bytes()) {
+    Ok(path) => unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 },
+    Err(_)   => false,
+  }
+}
+
+/// The directory a path is in, which is the current one for bare names.
+fn parent(path: &path::Path) -> &path::Path {
+  match path.parent() {
+    Some(dir) if !dir.as_os_str().is_empty() => dir,
+    _ => path::Path::new("."),
+  }
+}
+
+/// Check that a service is listening on a socket, or that one can be
+/// started there. Produces a connection to the service, if there is one.
+pub fn check_socket(path: &path::Path) -> (Finding, Option<UnixStream>) {
+  const CHECK: &str = "socket";
+  let meta = match fs::metadata(path) {
+    Ok(meta) => meta,
+    Err(err) if err.kind() == io::ErrorKind::NotFound => {
+      let dir = parent(path);
+      return match writable(dir) {
+        true  => (Finding::ok(CHECK, format!("No service is running on {}; one will be started when it is needed", path.display())), None),
+        false => (Finding::problem(CHECK, format!("No service is running on {} and one cannot be started, since {} is not writable", path.display(), dir.display()), "use a socket in a writable directory with --socket or $STRUCTS_SOCKET".to_string()), None),
+      };
+    },
+    Err(err) => return (Finding::problem(CHECK, format!("Cannot inspect {}: {}", path.display(), err), format!("check the permissions of {}", parent(path).display())), None),
+  };
+  if !meta.file_type().is_socket() {
+    return (Finding::problem(CHECK, format!("{} exists but is not a socket", path.display()), "remove it, or use another socket with --socket".to_string()), None);
+  }
+  match UnixStream::connect(path) {
+    Ok(stream) => (Finding::ok(CHECK, format!("A service is listening on {}", path.display())), Some(stream)),
+    Err(err) => match err.kind() {
+      io::ErrorKind::ConnectionRefused => (Finding::problem(CHECK, format!("{} was left behind by a service which did not exit cleanly", path.display()), format!("structs cleanup --socket {}", path.display())), None),
+      io::ErrorKind::PermissionDenied  => (Finding::problem(CHECK, format!("Permission denied connecting to {}, which belongs to uid {} (mode {:o})", path.display(), meta.uid(), meta.mode() & 0o777), format!("ask its owner to restart the service with --allow-uid {}, or use your own socket with --socket", unsafe { libc::geteuid() })), None),
+      _ => (Finding::problem(CHECK, format!("Cannot connect to {}: {}", path.display(), err), format!("structs stop --socket {}, then try again", path.display())), None),
+    },
+  }
+}
+
+/// Check that a service responds, and that it speaks our protocol.
+pub fn check_version(stream: UnixStream, timeout: time::Duration, version: &str) -> Finding {
+  const CHECK: &str = "version";
+  let res = stream.set_read_timeout(Some(timeout)).and_then(|_| { stream.set_write_timeout(Some(timeout)) });
+  if let Err(err) = res {
+    return Finding::problem(CHECK, format!("Cannot talk to the service: {}", err), "structs stop, then try again".to_string());
+  }
+  let rsp = rpc::RPC::new(stream, rpc::Options{debug: false, sequence: None, name: None}).and_then(|mut rpc| {
+    rpc.write_cmd(&rpc::Operation::new_version())?;
+    rpc.read_cmd()
+  });
+  let rsp = match rsp {
+    Ok(Some(rsp)) => rsp,
+    Ok(None) => return Finding::problem(CHECK, "The service hung up without responding".to_string(), "structs stop, then try again".to_string()),
+    Err(err) => return Finding::problem(CHECK, format!("The service did not respond within {:?}: {}", timeout, err), "structs stop; if it does not exit, kill it and run structs cleanup".to_string()),
+  };
+  match (rsp.name(), rsp.args()) {
+    (rpc::CMD_OK, [service, protocol]) if protocol.parse::<usize>().ok() != Some(rpc::PROTOCOL) => Finding::problem(CHECK, format!("The service ({}, protocol {}) does not speak this client's protocol ({})", service, protocol, rpc::PROTOCOL), "structs stop; a compatible service is started when it is next needed".to_string()),
+    (rpc::CMD_OK, [service, _]) if service != version => Finding::warning(CHECK, format!("The service ({}) is a different version than this client ({})", service, version), "structs stop to restart it with this version".to_string()),
+    (rpc::CMD_OK, [service, _]) => Finding::ok(CHECK, format!("The service and this client are both {}", service)),
+    (rpc::CMD_ERROR, _) => Finding::problem(CHECK, format!("The service refused this client: {}", rsp.data().as_deref().unwrap_or("unknown error")), format!("ask its owner to restart the service with --allow-uid {}", unsafe { libc::geteuid() })),
+    _ => Finding::problem(CHECK, "The service predates this client and does not report its version".to_string(), "structs stop; a compatible service is started when it is next needed".to_string()),
+  }
+}
+
+/// Check that a file the service persists to can be read, or that it can
+/// be created.
+pub fn check_file(check: &'static str, path: &path::Path) -> Finding {
+  let mut file = match fs::File::open(path) {
+    Ok(file) => file,
+    Err(err) if err.kind() == io::ErrorKind::NotFound => {
+      let dir = parent(path);
+      return match writable(dir) {
+        true  => Finding::ok(check, format!("{} does not exist yet and will be created", path.display())),
+        false => Finding::problem(check, format!("{} does not exist and cannot be created, since {} is not writable", path.display(), dir.display()), "use a path in a writable directory".to_string()),
+      };
+    },
+    Err(err) => return Finding::problem(check, format!("Cannot open {}: {}", path.display(), err), format!("check its permissions, e.g.: chmod u+rw {}", path.display())),
+  };
+  let mut buf = [0u8; 1];
+  if let Err(err) = file.read(&mut buf) {
+    return Finding::problem(check, format!("Cannot read {}: {}", path.display(), err), "check that it is a regular file and that its disk is healthy".to_string());
+  }
+  match writable(path) {
+    true  => Finding::ok(check, format!("{} is readable and writable", path.display())),
+    false => Finding::problem(check, format!("{} is not writable", path.display()), format!("check its permissions, e.g.: chmod u+w {}", path.display())),
+  }
+}
+
+/// Check that the clock is plausible: that it does not read a time long
+/// past, and that none of the provided files were modified in its future.
+/// Expiration and idle collection depend on it.
+pub fn check_clock(now: time::SystemTime, paths: &[&path::Path]) -> Finding {
+  const CHECK: &str = "clock";
+  const FIX: &str = "synchronize the system clock, e.g., with NTP";
+  match now.duration_since(time::UNIX_EPOCH) {
+    Ok(since) if since >= EARLIEST => {},
+    _ => return Finding::problem(CHECK, format!("The system clock reads {:?} since the epoch, which is before 2020", now.duration_since(time::UNIX_EPOCH).unwrap_or_default()), FIX.to_string()),
+  }
+  for path in paths {
+    let modified = match fs::metadata(path).and_then(|e| { e.modified() }) {
+      Ok(modified) => modified,
+      Err(_)       => continue,
+    };
+    if let Ok(ahead) = modified.duration_since(now) {
+      if ahead > MAX_SKEW {
+        return Finding::warning(CHECK, format!("{} was modified {:?} in the future; the clock may have gone backward", path.display(), ahead), FIX.to_string());
+      }
+    }
+  }
+  Finding::ok(CHECK, "The system clock looks plausible".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn check_files() {
+    let dir = std::env::temp_dir().join(format!("structs-doctor-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("data");
+    assert_eq!(Status::Ok, check_file("data", &file).status); // not yet created
+    fs::write(&file, "{}").unwrap();
+    assert_eq!(Status::Ok, check_file("data", &file).status);
+    assert_eq!(Status::Problem, check_file("data", &dir.join("missing/data")).status);
+    assert_eq!(Status::Problem, check_socket(&file).0.status); // not a socket
+
+    let now = time::SystemTime::now();
+    assert_eq!(Status::Ok, check_clock(now, &[&file]).status);
+    assert_eq!(Status::Warning, check_clock(now - time::Duration::from_secs(3600), &[&file]).status);
+    assert_eq!(Status::Problem, check_clock(time::UNIX_EPOCH, &[]).status);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,118 @@
+use std::time;
+use std::collections::BTreeMap;
+
+use serde::{Serialize, Deserialize};
+
+use crate::error;
+use crate::crypto;
+
+/// The version of the dump format produced by this version; dumps in other
+/// formats are refused rather than misread.
+pub const FORMAT: usize = 1;
+
+/// A single key serialized for transfer to another service: its value and
+/// the metadata which goes with it. Dumps are exchanged as an opaque,
+/// single-line blob.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Dump {
+  pub format: usize,
+  pub value: serde_json::Value,
+  pub ttl: Option<u64>, // milliseconds remaining when dumped
+  #[serde(default)]
+  pub created: Option<u128>,
+  #[serde(default)]
+  pub modified: Option<u128>,
+}
+
+impl Dump {
+  pub fn new(value: serde_json::Value, ttl: Option<time::Duration>) -> Self {
+    Self{
+      format: FORMAT,
+      value,
+      ttl: ttl.map(|e| { e.as_millis() as u64 }),
+      created: None,
+      modified: None,
+    }
+  }
+
+  /// Note when the key was created and last modified, in milliseconds since
+  /// the epoch, if known.
+  pub fn with_times(mut self, created: Option<u128>, modified: Option<u128>) -> Self {
+    self.created = created;
+    self.modified = modified;
+    self
+  }
+
+  pub fn ttl(&self) -> Option<time::Duration> {
+    self.ttl.map(time::Duration::from_millis)
+  }
+
+  pub fn encode(&self) -> Result<String, error::Error> {
+    Ok(crypto::encode_hex(&serde_json::to_vec(self)?))
+  }
+
+  pub fn decode(blob: &str) -> Result<Self, error::Error> {
+    let data = match crypto::decode_hex(blob.trim()) {
+      Some(data) => data,
+      None       => return Err(error::Error::Malformed),
+    };
+    let dump: Self = serde_json::from_slice(&data)?;
+    if dump.format != FORMAT {
+      return Err(error::Error::Malformed);
+    }
+    Ok(dump)
+  }
+}
+
+/// Every key in a service, and the aliases which refer to them, serialized
+/// like a single dump.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+  pub format: usize,
+  pub keys: BTreeMap<String, Dump>,
+  pub aliases: BTreeMap<String, String>,
+}
+
+impl Snapshot {
+  pub fn new(keys: BTreeMap<String, Dump>, aliases: BTreeMap<String, String>) -> Self {
+    Self{
+      format: FORMAT,
+      keys,
+      aliases,
+    }
+  }
+
+  pub fn encode(&self) -> Result<String, error::Error> {
+    Ok(crypto::encode_hex(&serde_json::to_vec(self)?))
+  }
+
+  pub fn decode(blob: &str) -> Result<Self, error::Error> {
+    let data = match crypto::decode_hex(blob.trim()) {
+      Some(data) => data,
+      None       => return Err(error::Error::Malformed),
+    };
+    let snapshot: Self = serde_json::from_slice(&data)?;
+    if snapshot.format != FORMAT {
+      return Err(error::Error::Malformed);
+    }
+    Ok(snapshot)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn encode_decode() {
+    let dump = Dump::new(serde_json::json!({"a": [1, 2]}), Some(time::Duration::from_secs(5))).with_times(Some(1), Some(2));
+    let blob = dump.encode().unwrap();
+    assert!(!blob.contains('\n'));
+    assert_eq!(dump, Dump::decode(&blob).unwrap());
+    assert_eq!(Some(time::Duration::from_secs(5)), Dump::decode(&blob).unwrap().ttl());
+    assert!(Dump::decode("not a dump").is_err());
+
+    let snapshot = Snapshot::new(BTreeMap::from([("k".to_string(), dump)]), BTreeMap::from([("a".to_string(), "k".to_string())]));
+    assert_eq!(snapshot, Snapshot::decode(&snapshot.encode().unwrap()).unwrap());
+  }
+}
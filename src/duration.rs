@@ -36,6 +36,42 @@ impl fmt::Display for Error {
   }
 }
 
+pub const FORMAT_SECS:   &str = "secs";
+pub const FORMAT_MILLIS: &str = "millis";
+pub const FORMAT_ISO:    &str = "iso";
+
+/// How a duration is written out: as a number of seconds or milliseconds,
+/// or in ISO 8601, like `PT1H30M`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+  Secs,
+  Millis,
+  Iso,
+}
+
+impl FromStr for Format {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      FORMAT_SECS   => Ok(Self::Secs),
+      FORMAT_MILLIS => Ok(Self::Millis),
+      FORMAT_ISO    => Ok(Self::Iso),
+      _             => Err(format!("Unknown duration format, expected secs, millis, or iso: {}", s)),
+    }
+  }
+}
+
+impl fmt::Display for Format {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Secs   => write!(f, "{}", FORMAT_SECS),
+      Self::Millis => write!(f, "{}", FORMAT_MILLIS),
+      Self::Iso    => write!(f, "{}", FORMAT_ISO),
+    }
+  }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Duration(time::Duration);
 
@@ -43,6 +79,38 @@ impl Duration {
   pub fn duration(&self) -> time::Duration {
     self.0
   }
+
+  /// Write this duration out in a format.
+  pub fn format(&self, format: Format) -> String {
+    match format {
+      Format::Secs   => self.0.as_secs().to_string(),
+      Format::Millis => self.0.as_millis().to_string(),
+      Format::Iso    => iso8601(self.0),
+    }
+  }
+}
+
+/// Write a duration in ISO 8601, with days, like the parser's, taken to be
+/// 24 hours.
+fn iso8601(duration: time::Duration) -> String {
+  let secs = duration.as_secs();
+  let (days, hours, mins, secs) = (secs / 86400, secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+  let mut out = "P".to_string();
+  if days > 0 {
+    out.push_str(&format!("{}D", days));
+  }
+  if hours > 0 || mins > 0 || secs > 0 || days == 0 {
+    out.push('T');
+  }
+  for (n, unit) in [(hours, 'H'), (mins, 'M'), (secs, 'S')] {
+    if n > 0 {
+      out.push_str(&format!("{}{}", n, unit));
+    }
+  }
+  if out == "PT" {
+    out.push_str("0S");
+  }
+  out
 }
 
 impl FromStr for Duration {
@@ -53,11 +121,11 @@ impl FromStr for Duration {
 }
 
 fn is_digit(c: char) -> bool {
-  c.is_digit(10)
+  c.is_ascii_digit()
 }
 
 fn parse_value(input: &str) -> Result<u64, Error> {
-  Ok(u64::from_str_radix(input, 10)?)
+  Ok(input.parse::<u64>()?)
 }
 
 fn value_primary(input: &str) -> nom::IResult<&str, u64> {
@@ -68,10 +136,7 @@ fn value_primary(input: &str) -> nom::IResult<&str, u64> {
 }
 
 fn is_unit(c: char) -> bool {
-  match c {
-    'd' | 'h' | 'm' | 's' => true,
-    _ => false,
-  }
+  matches!(c, 'd' | 'h' | 'm' | 's')
 }
 
 fn parse_unit(input: &str) -> Result<u64, Error> {
@@ -98,14 +163,14 @@ fn unit_value(input: &str) -> nom::IResult<&str, u64> {
 
 pub fn parse(input: &str) -> Result<time::Duration, Error> {
   let mut input = input.trim();
-  if input.len() == 0 { // empty input is an error
+  if input.is_empty() { // empty input is an error
     return Err(Error::ParseDurationError)
   }
   
   let mut result: u64 = 0;
-  while input != "" {
+  while !input.is_empty() {
     let (remainder, duration) = unit_value(input)?;
-    result = result + duration;
+    result += duration;
     input = remainder;
   }
   
@@ -129,6 +194,19 @@ mod tests {
     assert_eq!(Err(Error::ParseSyntaxError), parse("1"));
     assert_eq!(Err(Error::ParseSyntaxError), parse("s"));
   }
+
+  #[test]
+  fn format_durations() {
+    let d: Duration = "1h30m".parse().unwrap();
+    assert_eq!("5400", d.format(Format::Secs));
+    assert_eq!("5400000", d.format(Format::Millis));
+    assert_eq!("PT1H30M", d.format(Format::Iso));
+    assert_eq!("P1DT5S", "1d5s".parse::<Duration>().unwrap().format(Format::Iso));
+    assert_eq!("P2D", "2d".parse::<Duration>().unwrap().format(Format::Iso));
+    assert_eq!("PT0S", "0s".parse::<Duration>().unwrap().format(Format::Iso));
+    assert_eq!(Ok(Format::Iso), "iso".parse());
+    assert!("hours".parse::<Format>().is_err());
+  }
   
 }
 
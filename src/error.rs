@@ -6,8 +6,10 @@ use std::string;
 use std::sync::mpsc;
 
 use serde_json;
+use rusqlite;
 
 use crate::duration;
+use crate::predicate;
 
 #[derive(Debug)]
 pub enum Error {
@@ -15,8 +17,10 @@ pub enum Error {
   Utf8Error(str::Utf8Error),
   FromUtf8Error(string::FromUtf8Error),
   SerdeError(serde_json::Error),
+  SqliteError(rusqlite::Error),
   SystemTimeError(time::SystemTimeError),
   DurationError(duration::Error),
+  PredicateError(predicate::Error),
   SendError,
   RecvError(mpsc::RecvError),
   Malformed,
@@ -25,6 +29,22 @@ pub enum Error {
   _InvalidIndex,
   ServiceError,
   RemoteError(Option<String>),
+  UnknownBackend(String),
+  BackendError(String),
+  CryptoError(String),
+  AuthError(String),
+  Busy,
+  RateLimited(time::Duration),
+  Protected(String),
+  LineTooLong(usize),
+  CommandFailed(usize),
+  Diverged(usize),
+  Unhealthy(usize),
+  InvalidValue(String),
+  Mismatch(String),
+  Exists(String),
+  Disconnected(usize),
+  DidYouMean(Box<Error>, Vec<String>),
 }
 
 impl From<str::Utf8Error> for Error {
@@ -51,6 +71,12 @@ impl From<serde_json::Error> for Error {
   }
 }
 
+impl From<rusqlite::Error> for Error {
+  fn from(err: rusqlite::Error) -> Self {
+    Self::SqliteError(err)
+  }
+}
+
 impl From<time::SystemTimeError> for Error {
   fn from(err: time::SystemTimeError) -> Self {
     Self::SystemTimeError(err)
@@ -69,6 +95,12 @@ impl From<duration::Error> for Error {
   }
 }
 
+impl From<predicate::Error> for Error {
+  fn from(err: predicate::Error) -> Self {
+    Self::PredicateError(err)
+  }
+}
+
 impl fmt::Display for Error {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     match self {
@@ -76,8 +108,10 @@ impl fmt::Display for Error {
       Self::Utf8Error(err) => err.fmt(f),
       Self::FromUtf8Error(err) => err.fmt(f),
       Self::SerdeError(err) => err.fmt(f),
+      Self::SqliteError(err) => err.fmt(f),
       Self::SystemTimeError(err) => err.fmt(f),
       Self::DurationError(err) => err.fmt(f),
+      Self::PredicateError(err) => err.fmt(f),
       Self::SendError => write!(f, "Could not send"),
       Self::RecvError(err) => err.fmt(f),
       Self::Malformed => write!(f, "Malformed"),
@@ -89,6 +123,22 @@ impl fmt::Display for Error {
         Some(msg) => write!(f, "Remote error: {}", msg),
         None      => write!(f, "Remote error"),
       },
+      Self::UnknownBackend(name) => write!(f, "Unknown storage backend: {}", name),
+      Self::BackendError(msg) => write!(f, "Storage backend error: {}", msg),
+      Self::CryptoError(msg) => write!(f, "Encryption error: {}", msg),
+      Self::AuthError(msg) => write!(f, "Not authorized: {}", msg),
+      Self::Busy => write!(f, "Service is busy; retry later"),
+      Self::RateLimited(retry) => write!(f, "Rate limit exceeded; retry in {:?}", retry),
+      Self::Protected(name) => write!(f, "Protected: {}", name),
+      Self::LineTooLong(max) => write!(f, "Line exceeds the maximum length of {} bytes", max),
+      Self::CommandFailed(n) => write!(f, "{} command(s) failed", n),
+      Self::Diverged(n) => write!(f, "Diverged; {} difference(s) found", n),
+      Self::Unhealthy(n) => write!(f, "{} problem(s) found", n),
+      Self::InvalidValue(msg) => write!(f, "Invalid value: {}", msg),
+      Self::Mismatch(name) => write!(f, "The value of {} is not the one expected", name),
+      Self::Exists(name) => write!(f, "Already exists: {}", name),
+      Self::Disconnected(id) => write!(f, "Client {} was disconnected by an administrator", id),
+      Self::DidYouMean(err, names) => write!(f, "{}; did you mean {}?", err, names.join(" or ")),
     }
   }
 }
@@ -0,0 +1,67 @@
+use serde_json::{json, Value};
+
+use crate::error;
+
+/// The number of entries a log keeps when no cap is given.
+pub const DEFAULT_CAP: usize = 1000;
+
+/// Append an entry to a log, a list of entries each noting its sequence
+/// number, the time it was appended in milliseconds since the epoch, and its
+/// value. The oldest entries are dropped to keep no more than the cap. A
+/// missing log is created. Produces the updated log and the sequence number
+/// of the new entry.
+pub fn append(log: Option<Value>, value: Value, time: u128, cap: usize) -> Result<(Value, u64), error::Error> {
+  let mut entries = match log {
+    Some(Value::Array(entries)) => entries,
+    Some(_)                     => return Err(error::Error::Malformed), // not a log
+    None                        => Vec::new(),
+  };
+  let seq = entries.last().map(seq).unwrap_or(0) + 1;
+  entries.push(json!({"seq": seq, "time": time as u64, "value": value}));
+  if entries.len() > cap {
+    entries.drain(..entries.len() - cap);
+  }
+  Ok((Value::Array(entries), seq))
+}
+
+/// The sequence number of an entry.
+pub fn seq(entry: &Value) -> u64 {
+  entry.get("seq").and_then(Value::as_u64).unwrap_or(0)
+}
+
+/// The last n entries in a log.
+pub fn tail(log: &[Value], n: usize) -> &[Value] {
+  &log[log.len().saturating_sub(n)..]
+}
+
+/// The entries in a log which were appended after the one with the provided
+/// sequence number.
+pub fn after(log: &[Value], after: u64) -> &[Value] {
+  match log.iter().position(|e| { seq(e) > after }) {
+    Some(i) => &log[i..],
+    None    => &[],
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn append_entries() {
+    let (log, n) = append(None, json!("a"), 10, 2).unwrap();
+    assert_eq!((json!([{"seq": 1, "time": 10, "value": "a"}]), 1), (log.clone(), n));
+    let (log, _) = append(Some(log), json!("b"), 11, 2).unwrap();
+    let (log, n) = append(Some(log), json!({"c": 1}), 12, 2).unwrap();
+    assert_eq!(3, n);
+    assert_eq!(json!([{"seq": 2, "time": 11, "value": "b"}, {"seq": 3, "time": 12, "value": {"c": 1}}]), log);
+    assert!(append(Some(json!({"not": "a log"})), json!(1), 13, 2).is_err());
+
+    let log = log.as_array().unwrap();
+    assert_eq!(&log[1..], tail(log, 1));
+    assert_eq!(&log[..], tail(log, 50));
+    assert_eq!(&log[1..], after(log, 2));
+    assert_eq!(&log[..], after(log, 0));
+    assert!(after(log, 3).is_empty());
+  }
+}
@@ -0,0 +1,168 @@
+use std::ptr;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+
+use crate::api::Client;
+use crate::error;
+
+thread_local! {
+  /// The error produced by the last call on this thread which failed.
+  static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(err: Option<&error::Error>) {
+  let err = err.map(|e| { CString::new(e.to_string().replace('\0', " ")).unwrap_or_default() });
+  LAST_ERROR.with(|last| { *last.borrow_mut() = err });
+}
+
+/// Borrow a string from the caller, or produce an error if it is not UTF-8.
+unsafe fn borrow<'a>(s: *const c_char) -> Result<&'a str, error::Error> {
+  if s.is_null() {
+    return Err(error::Error::Malformed);
+  }
+  Ok(CStr::from_ptr(s).to_str()?)
+}
+
+/// The message describing the last error on the calling thread, or null if
+/// the last call succeeded. The message belongs to the library and is valid
+/// until the next call on the same thread.
+#[no_mangle]
+pub extern "C" fn structs_last_error() -> *const c_char {
+  LAST_ERROR.with(|last| {
+    match &*last.borrow() {
+      Some(err) => err.as_ptr(),
+      None      => ptr::null(),
+    }
+  })
+}
+
+/// Connect to the service listening on a socket. Produces null on failure.
+///
+/// # Safety
+/// The path must be a valid, NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn structs_client_connect(path: *const c_char) -> *mut Client {
+  match borrow(path).and_then(Client::connect) {
+    Ok(client) => {
+      set_error(None);
+      Box::into_raw(Box::new(client))
+    },
+    Err(err) => {
+      set_error(Some(&err));
+      ptr::null_mut()
+    },
+  }
+}
+
+/// Fetch the value of a key or path as JSON, which the caller releases with
+/// `structs_string_free`. Produces null if there is no value, or on failure,
+/// which `structs_last_error` distinguishes.
+///
+/// # Safety
+/// The client must have been produced by `structs_client_connect` and not yet
+/// freed; the key must be a valid, NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn structs_client_get(client: *const Client, key: *const c_char) -> *mut c_char {
+  let res = match client.as_ref() {
+    Some(client) => borrow(key).and_then(|key| { client.get(key) }),
+    None         => Err(error::Error::Malformed),
+  };
+  match res {
+    Ok(Some(value)) => {
+      set_error(None);
+      CString::new(value.to_string()).map(CString::into_raw).unwrap_or(ptr::null_mut())
+    },
+    Ok(None) => {
+      set_error(None);
+      ptr::null_mut()
+    },
+    Err(err) => {
+      set_error(Some(&err));
+      ptr::null_mut()
+    },
+  }
+}
+
+/// Store a JSON value under a key or path. Produces 0 on success and -1 on
+/// failure.
+///
+/// # Safety
+/// The client must have been produced by `structs_client_connect` and not yet
+/// freed; the key and value must be valid, NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn structs_client_set(client: *const Client, key: *const c_char, json: *const c_char) -> c_int {
+  let res = match client.as_ref() {
+    Some(client) => borrow(key).and_then(|key| {
+      let value = serde_json::from_str(borrow(json)?)?;
+      client.set(key, &value)
+    }),
+    None => Err(error::Error::Malformed),
+  };
+  match res {
+    Ok(_)    => { set_error(None); 0 },
+    Err(err) => { set_error(Some(&err)); -1 },
+  }
+}
+
+/// Delete a key or path. Produces 1 if it was deleted, 0 if it did not
+/// exist, and -1 on failure.
+///
+/// # Safety
+/// The client must have been produced by `structs_client_connect` and not yet
+/// freed; the key must be a valid, NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn structs_client_delete(client: *const Client, key: *const c_char) -> c_int {
+  let res = match client.as_ref() {
+    Some(client) => borrow(key).and_then(|key| { client.delete(key) }),
+    None         => Err(error::Error::Malformed),
+  };
+  match res {
+    Ok(deleted) => { set_error(None); deleted as c_int },
+    Err(err)    => { set_error(Some(&err)); -1 },
+  }
+}
+
+/// Release a client.
+///
+/// # Safety
+/// The client must have been produced by `structs_client_connect`, or be
+/// null, and must not be used afterward.
+#[no_mangle]
+pub unsafe extern "C" fn structs_client_free(client: *mut Client) {
+  if !client.is_null() {
+    drop(Box::from_raw(client));
+  }
+}
+
+/// Release a string produced by the library.
+///
+/// # Safety
+/// The string must have been produced by the library, or be null, and must
+/// not be used afterward.
+#[no_mangle]
+pub unsafe extern "C" fn structs_string_free(s: *mut c_char) {
+  if !s.is_null() {
+    drop(CString::from_raw(s));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn report_errors() {
+    unsafe {
+      let path = CString::new("/nonexistent/structs.sock").unwrap();
+      assert!(structs_client_connect(path.as_ptr()).is_null());
+      assert!(!structs_last_error().is_null());
+      let key = CString::new("a").unwrap();
+      assert!(structs_client_get(ptr::null(), key.as_ptr()).is_null());
+      assert_eq!("Malformed", CStr::from_ptr(structs_last_error()).to_str().unwrap());
+      assert_eq!(-1, structs_client_delete(ptr::null(), key.as_ptr()));
+      structs_client_free(ptr::null_mut());
+      structs_string_free(ptr::null_mut());
+    }
+  }
+}
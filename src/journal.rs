@@ -0,0 +1,72 @@
+use std::fs;
+use std::path;
+use std::time;
+use std::io::Write;
+
+use crate::error;
+use crate::rpc;
+use crate::crypto;
+
+/// A journal records every operation accepted by the service, in the wire
+/// format, so that the operations can be replayed against another service.
+/// Each operation is preceded by a comment line noting when it was accepted
+/// and which client connection it was received from. When a cipher is
+/// provided each entry is encrypted and written as a single sealed line.
+pub struct Journal {
+  file: fs::File,
+  cipher: Option<crypto::Cipher>,
+}
+
+impl Journal {
+  pub fn open<P: AsRef<path::Path>>(path: P, cipher: Option<crypto::Cipher>) -> Result<Self, error::Error> {
+    Ok(Self{
+      file: fs::OpenOptions::new().create(true).append(true).open(path)?,
+      cipher,
+    })
+  }
+
+  pub fn record(&mut self, client: usize, op: &rpc::Operation) -> Result<(), error::Error> {
+    let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH)?;
+    let mut buf: Vec<u8> = Vec::new();
+    writeln!(&mut buf, "{} time={} client={}", COMMENT, now.as_millis(), client)?;
+    rpc::write_operation(&mut buf, op, false)?;
+    if let Some(cipher) = &self.cipher {
+      buf = format!("{} {}\n", SEALED, crypto::encode_hex(&cipher.seal(&buf)?)).into_bytes();
+    }
+    self.file.write_all(&buf)?; // write the entry in one go so it is not interleaved
+    Ok(())
+  }
+}
+
+pub const COMMENT: &str = "#";
+pub const SEALED:  &str = "!";
+
+/// Read the operations recorded in a journal, skipping comments. Sealed
+/// entries are decrypted with the provided cipher.
+pub fn read<P: AsRef<path::Path>>(path: P, cipher: Option<&crypto::Cipher>) -> Result<Vec<rpc::Operation>, error::Error> {
+  let mut data = String::new();
+  for line in fs::read_to_string(path)?.lines() {
+    match line.strip_prefix(SEALED) {
+      Some(sealed) => data += &unseal(sealed.trim(), cipher)?,
+      None         => data += &format!("{}\n", line),
+    }
+  }
+  let data: String = data.lines().filter(|e| { !e.starts_with(COMMENT) }).map(|e| { format!("{}\n", e) }).collect();
+  let mut reader = data.as_bytes();
+  let mut ops: Vec<rpc::Operation> = Vec::new();
+  while let Some(op) = rpc::read_operation(&mut reader, false)? {
+    ops.push(op);
+  }
+  Ok(ops)
+}
+
+fn unseal(text: &str, cipher: Option<&crypto::Cipher>) -> Result<String, error::Error> {
+  let cipher = match cipher {
+    Some(cipher) => cipher,
+    None         => return Err(error::Error::CryptoError("Journal is encrypted but no key was provided".to_string())),
+  };
+  match crypto::decode_hex(text) {
+    Some(data) => Ok(String::from_utf8(cipher.open(&data)?)?),
+    None       => Err(error::Error::Malformed),
+  }
+}
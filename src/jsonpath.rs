@@ -5,7 +5,8 @@ use serde_json::value::Value;
 
 use crate::error;
 
-const SEP: &str = ".";
+pub const SEP: &str = ".";
+pub const WILDCARD: &str = "*";
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Path(String);
@@ -15,7 +16,7 @@ impl Path {
     Self(path.to_string())
   }
 
-  pub fn _path<'a>(&'a self) -> &'a str {
+  pub fn _path(&self) -> &str {
     &self.0
   }
 
@@ -27,29 +28,40 @@ impl Path {
   }
 
   pub fn set_value<'a>(&self, current: &'a Value, update: &'a Value) -> Result<Value, error::Error> {
-    self.set_value_cmp(current, update)
+    self.set_value_cmp(current, update, false, false)
   }
 
-  fn set_value_cmp<'a>(&self, current: &'a Value, update: &'a Value) -> Result<Value, error::Error> {
+  /// Set a value as with set_value, but permit the final component of the
+  /// path to name a new object member and, when parents is set, create any
+  /// missing intermediate objects along the way.
+  pub fn graft_value<'a>(&self, current: &'a Value, update: &'a Value, parents: bool) -> Result<Value, error::Error> {
+    self.set_value_cmp(current, update, true, parents)
+  }
+
+  fn set_value_cmp<'a>(&self, current: &'a Value, update: &'a Value, graft: bool, parents: bool) -> Result<Value, error::Error> {
     let (left, path) = self.first();
     let left = match left {
       Some(left) => Path::new(left),
       None       => return Err(error::Error::NotFound),
     };
-    let update = match left.value(current) {
-      Some(lval) => match &path {
-        Some(path) => &path.set_value_cmp(lval, update)?, // recurse
-        None       => update,                    // update in our container
-      },
+    let empty = Value::Object(serde_json::Map::new());
+    let lval = match left.value(current) {
+      Some(lval) => lval,
+      None if current.is_object() && path.is_some() && parents => &empty, // create the intermediate
+      None if current.is_object() && path.is_none() && graft   => &empty, // new member; replaced below
       None => return Err(error::Error::NotFound),
     };
+    let update = match &path {
+      Some(path) => path.set_value_cmp(lval, update, graft, parents)?, // recurse
+      None       => update.clone(),                                    // update in our container
+    };
     let mut current = current.clone();
     match &mut current {
       serde_json::Value::Object(v) => {
-        v.insert(left.to_string(), update.clone());
+        v.insert(left.to_string(), update);
       },
       serde_json::Value::Array(v) => match index_array(v, &left.to_string()) {
-        Some(i) => v[i] = update.clone(),
+        Some(i) => v[i] = update,
         None    => return Err(error::Error::NotFound),
       },
       _ => { // other types cannot be updated
@@ -59,6 +71,47 @@ impl Path {
     Ok(current)
   }
 
+  /// Remove the value at this path, producing the updated value and the
+  /// value which was removed.
+  pub fn delete_value(&self, current: &Value) -> Result<(Value, Value), error::Error> {
+    let (left, path) = self.first();
+    let left = match left {
+      Some(left) => left,
+      None       => return Err(error::Error::NotFound),
+    };
+    let mut current = current.clone();
+    let removed = match path {
+      Some(path) => {
+        let lval = match json_deref(left, &current) {
+          Some(lval) => lval,
+          None       => return Err(error::Error::NotFound),
+        };
+        let (update, removed) = path.delete_value(lval)?; // recurse
+        match &mut current {
+          Value::Object(v) => { v.insert(left.to_string(), update); },
+          Value::Array(v)  => match index_array(v, left) {
+            Some(i) => v[i] = update,
+            None    => return Err(error::Error::NotFound),
+          },
+          _ => return Err(error::Error::Malformed),
+        }
+        removed
+      },
+      None => match &mut current {
+        Value::Object(v) => match v.remove(left) {
+          Some(removed) => removed,
+          None          => return Err(error::Error::NotFound),
+        },
+        Value::Array(v) => match index_array(v, left) {
+          Some(i) => v.remove(i),
+          None    => return Err(error::Error::NotFound),
+        },
+        _ => return Err(error::Error::NotFound),
+      },
+    };
+    Ok((current, removed))
+  }
+
   pub fn find<'a>(&self, value: &'a Value) -> (Option<&'a Value>, Option<Path>) {
     let (v, p) = self.deref(value);
     match v {
@@ -73,16 +126,76 @@ impl Path {
     }
   }
 
+  /// Find the value at this path as with value, but match object members
+  /// case-insensitively wherever there is no exact match. When several
+  /// members match, the first in order is used.
+  pub fn value_ci<'a>(&self, value: &'a Value) -> Option<&'a Value> {
+    let mut value = value;
+    for name in self.0.split(SEP) {
+      value = match value {
+        Value::Object(v) => match v.get(name) {
+          Some(v) => v,
+          None    => v.iter().find(|(k, _)| { eq_ci(k, name) }).map(|(_, v)| { v })?,
+        },
+        _ => json_deref(name, value)?,
+      };
+    }
+    Some(value)
+  }
+
+  /// Describe why this path does not resolve in a value, which is found at
+  /// base: the first component which could not be resolved, and why.
+  /// Produces None if the path resolves.
+  pub fn explain(&self, base: &str, value: &Value) -> Option<String> {
+    let mut value = value;
+    let mut prefix = base.to_string();
+    for name in self.0.split(SEP) {
+      value = match value {
+        Value::Object(v) => match v.get(name) {
+          Some(v) => v,
+          None    => return Some(format!("No member '{}' in the object at {}", name, prefix)),
+        },
+        Value::Array(v) => match name.parse::<usize>() {
+          Ok(i) if i < v.len() => &v[i],
+          Ok(i)  => return Some(format!("Index {} is out of bounds for the array of length {} at {}", i, v.len(), prefix)),
+          Err(_) => return Some(format!("'{}' is not an index into the array at {}", name, prefix)),
+        },
+        _ => return Some(format!("Cannot look up '{}' in the {} at {}", name, type_name(value), prefix)),
+      };
+      prefix = join(&prefix, name);
+    }
+    None
+  }
+
+  /// Find where this path stops resolving in a value, which is found at
+  /// base: the name of the deepest value it reaches, that value, and the
+  /// component which could not be looked up in it. Produces None if the
+  /// path resolves.
+  pub fn unresolved<'a>(&self, base: &str, value: &'a Value) -> Option<(String, &'a Value, String)> {
+    let mut value = value;
+    let mut prefix = base.to_string();
+    for name in self.0.split(SEP) {
+      let next = match value {
+        Value::Object(v) => v.get(name),
+        Value::Array(v)  => name.parse::<usize>().ok().and_then(|i| { v.get(i) }),
+        _                => None,
+      };
+      value = match next {
+        Some(next) => next,
+        None       => return Some((prefix, value, name.to_string())),
+      };
+      prefix = join(&prefix, name);
+    }
+    None
+  }
+
   pub fn deref<'a>(&self, value: &'a Value) -> (Option<&'a Value>, Option<Path>) {
     let (n, r) = self.next();
     let v = match n {
       Some(n) => json_deref(n, value),
       None    => None,
     };
-    let r = match r {
-      Some(r) => Some(Path::new(r)),
-      None    => None,
-    };
+    let r = r.map(Path::new);
     match v {
       Some(v) => (Some(v), r),
       None    => (None, Some(self.clone())),
@@ -90,20 +203,17 @@ impl Path {
   }
 
   pub fn _has_next(&self) -> bool {
-    match self.0.find(SEP) {
-      Some(_) => true,
-      None    => false,
-    }
+    self.0.find(SEP).is_some()
   }
 
-  pub fn next<'a>(&'a self) -> (Option<&'a str>, Option<&'a str>) {
+  pub fn next(&self) -> (Option<&str>, Option<&str>) {
     match self.0.find(SEP) {
       Some(x) => (Some(&self.0[..x]), if self.0.len() > x {
         Some(&self.0[x+1..])
       } else {
         None
       }),
-      None => (if self.0.len() > 0 {
+      None => (if !self.0.is_empty() {
         Some(&self.0)
       } else {
         None
@@ -111,9 +221,9 @@ impl Path {
     }
   }
 
-  pub fn first<'a>(&'a self) -> (Option<&'a str>, Option<Path>) {
-    let p: &str = &self.0.trim();
-    if p == "" {
+  pub fn first(&self) -> (Option<&str>, Option<Path>) {
+    let p: &str = self.0.trim();
+    if p.is_empty() {
       return (None, None); // empty string has no components
     }
     match p.find(SEP) {
@@ -122,9 +232,9 @@ impl Path {
     }
   }
 
-  pub fn last<'a>(&'a self) -> (Option<Path>, Option<&'a str>) {
-    let p: &str = &self.0.trim();
-    if p == "" {
+  pub fn _last(&self) -> (Option<Path>, Option<&str>) {
+    let p: &str = self.0.trim();
+    if p.is_empty() {
       return (None, None); // empty string has no components
     }
     match p.rfind(SEP) {
@@ -133,7 +243,7 @@ impl Path {
     }
   }
 
-  pub fn _trim<'a>(&'a self, c: usize) -> (Option<Path>, Option<&'a str>) {
+  pub fn _trim(&self, c: usize) -> (Option<Path>, Option<&str>) {
     let mut p: &str = &self.0;
     let mut n: Option<&str> = None;
     for _ in 0..c {
@@ -155,17 +265,329 @@ impl fmt::Display for Path {
   }
 }
 
-pub fn print_raw<'a>(value: &'a Value) -> String {
+pub fn print_raw(value: &Value) -> String {
   match value {
     Value::Null      => "null".to_string(),
     Value::Bool(v)   => format!("{}", v),
     Value::Number(v) => format!("{}", v),
-    Value::String(v) => format!("{}", v),
+    Value::String(v) => v.to_string(),
     Value::Array(_)  => value.to_string(),
     Value::Object(_) => value.to_string(),
   }
 }
 
+/// The name of the type of a value, for use in messages.
+pub fn type_name(value: &Value) -> &'static str {
+  match value {
+    Value::Null      => "null",
+    Value::Bool(_)   => "boolean",
+    Value::Number(_) => "number",
+    Value::String(_) => "string",
+    Value::Array(_)  => "array",
+    Value::Object(_) => "object",
+  }
+}
+
+/// Compare names case-insensitively, folding case by Unicode rules rather
+/// than only ASCII so that, e.g., "Straße" matches "STRASSE".
+pub fn eq_ci(a: &str, b: &str) -> bool {
+  a == b || a.to_lowercase() == b.to_lowercase() || a.to_uppercase() == b.to_uppercase()
+}
+
+/// Join a path component onto a prefix; either may be empty.
+pub fn join(prefix: &str, name: &str) -> String {
+  if prefix.is_empty() {
+    name.to_string()
+  } else if name.is_empty() {
+    prefix.to_string()
+  } else {
+    format!("{}{}{}", prefix, SEP, name)
+  }
+}
+
+/// Visit every node in a value, depth-first, including the value itself. The
+/// visitor is provided the path of each node relative to the root value, the
+/// root itself having an empty path.
+pub fn walk<'a, F: FnMut(&str, &'a Value)>(value: &'a Value, visit: &mut F) {
+  walk_prefix("", value, visit)
+}
+
+fn walk_prefix<'a, F: FnMut(&str, &'a Value)>(prefix: &str, value: &'a Value, visit: &mut F) {
+  visit(prefix, value);
+  match value {
+    Value::Array(v)  => v.iter().enumerate().for_each(|(i, e)| { walk_prefix(&join(prefix, &i.to_string()), e, visit) }),
+    Value::Object(v) => v.iter().for_each(|(k, e)| { walk_prefix(&join(prefix, k), e, visit) }),
+    _                => {},
+  }
+}
+
+/// Flatten a value into the paths and values of each of its leaves. Empty
+/// arrays and objects are considered leaves.
+pub fn flatten<'a>(value: &'a Value) -> Vec<(String, &'a Value)> {
+  let mut leaves: Vec<(String, &'a Value)> = Vec::new();
+  walk(value, &mut |path, value| {
+    if is_leaf(value) {
+      leaves.push((path.to_string(), value));
+    }
+  });
+  leaves
+}
+
+/// Deep-merge one value over another. Objects are merged member-by-member;
+/// any other value in the overlay replaces the corresponding base value.
+pub fn merge(base: &Value, over: &Value) -> Value {
+  match (base, over) {
+    (Value::Object(b), Value::Object(o)) => {
+      let mut res = b.clone();
+      for (k, v) in o {
+        let merged = match b.get(k) {
+          Some(e) => merge(e, v),
+          None    => v.clone(),
+        };
+        res.insert(k.to_owned(), merged);
+      }
+      Value::Object(res)
+    },
+    _ => over.clone(),
+  }
+}
+
+/// Add a delta to a number, where a missing value counts as zero. Integers
+/// stay integers, unless they overflow, which is an error; otherwise the
+/// sum is a float.
+pub fn add(value: Option<&Value>, delta: &serde_json::Number) -> Result<Value, String> {
+  let value = match value {
+    Some(Value::Number(value)) => value.clone(),
+    Some(value)                => return Err(format!("Cannot add to {}, which is not a number", type_name(value))),
+    None                       => serde_json::Number::from(0),
+  };
+  if let (Some(a), Some(b)) = (value.as_i64(), delta.as_i64()) {
+    return match a.checked_add(b) {
+      Some(sum) => Ok(Value::from(sum)),
+      None      => Err(format!("Adding {} to {} overflows", b, a)),
+    };
+  }
+  let sum = value.as_f64().unwrap_or_default() + delta.as_f64().unwrap_or_default();
+  match serde_json::Number::from_f64(sum) {
+    Some(sum) => Ok(Value::Number(sum)),
+    None      => Err(format!("Adding {} to {} is not a finite number", delta, value)),
+  }
+}
+
+/// Apply a JSON merge patch (RFC 7386) to a value. Objects in the patch are
+/// merged member-by-member, null members remove those in the value, and
+/// anything else in the patch replaces the corresponding value.
+pub fn merge_patch(target: &Value, patch: &Value) -> Value {
+  let patch = match patch {
+    Value::Object(patch) => patch,
+    _                    => return patch.clone(),
+  };
+  let mut res = match target {
+    Value::Object(target) => target.clone(),
+    _                     => serde_json::Map::new(),
+  };
+  for (k, v) in patch {
+    if v.is_null() {
+      res.remove(k);
+    } else {
+      let merged = merge_patch(res.get(k).unwrap_or(&Value::Null), v);
+      res.insert(k.to_owned(), merged);
+    }
+  }
+  Value::Object(res)
+}
+
+/// Produce the path of every leaf in a value. When a depth is provided,
+/// containers at that depth are reported as though they were leaves.
+pub fn paths(value: &Value, depth: Option<usize>) -> Vec<String> {
+  let mut paths: Vec<String> = Vec::new();
+  paths_prefix("", value, depth, &mut paths);
+  paths
+}
+
+fn paths_prefix(prefix: &str, value: &Value, depth: Option<usize>, paths: &mut Vec<String>) {
+  if is_leaf(value) || depth == Some(0) {
+    paths.push(prefix.to_string());
+    return;
+  }
+  let depth = depth.map(|e| { e - 1 });
+  match value {
+    Value::Array(v)  => v.iter().enumerate().for_each(|(i, e)| { paths_prefix(&join(prefix, &i.to_string()), e, depth, paths) }),
+    Value::Object(v) => v.iter().for_each(|(k, e)| { paths_prefix(&join(prefix, k), e, depth, paths) }),
+    _                => {},
+  }
+}
+
+/// Reconstruct a value from the paths and values of its leaves, the inverse
+/// of flatten. Path components which are indexes produce arrays; all others
+/// produce objects.
+pub fn unflatten(leaves: &[(String, Value)]) -> Result<Value, error::Error> {
+  let mut root = Value::Null;
+  for (path, value) in leaves {
+    let mut dst = &mut root;
+    if !path.is_empty() {
+      for name in path.split(SEP) {
+        dst = container_member(dst, name)?;
+      }
+    }
+    *dst = value.clone();
+  }
+  Ok(root)
+}
+
+/// Print the leaves of a value as `path=value` lines, with each value as
+/// JSON, so strings are quoted and escaped and `parse_flat` reads them back
+/// exactly.
+pub fn print_flat(value: &Value) -> String {
+  flatten(value).iter().map(|(path, value)| { format!("{}={}\n", path, value) }).collect()
+}
+
+/// Reconstruct a value from `path=value` lines, as printed by `print_flat`.
+/// A path may itself contain `=`, so each line is split at the first `=`
+/// which is followed by JSON. Where none is, the value is taken to be a
+/// string, as older versions printed them unquoted.
+pub fn parse_flat(data: &str) -> Result<Value, error::Error> {
+  let mut leaves: Vec<(String, Value)> = Vec::new();
+  for line in data.lines() {
+    if line.trim().is_empty() {
+      continue;
+    }
+    let splits = || { line.match_indices('=').map(|(i, _)| { (&line[..i], &line[i + 1..]) }) };
+    let leaf = match splits().find_map(|(path, value)| { serde_json::from_str(value).ok().map(|value| { (path, value) }) }) {
+      Some(leaf) => leaf,
+      None       => match splits().next() {
+        Some((path, value)) => (path, Value::String(value.to_string())),
+        None                => return Err(error::Error::Malformed),
+      },
+    };
+    leaves.push((leaf.0.trim().to_string(), leaf.1));
+  }
+  unflatten(&leaves)
+}
+
+fn container_member<'a>(value: &'a mut Value, name: &str) -> Result<&'a mut Value, error::Error> {
+  if value.is_null() {
+    *value = match name.parse::<usize>() {
+      Ok(_)  => Value::Array(Vec::new()),
+      Err(_) => Value::Object(serde_json::Map::new()),
+    };
+  }
+  match value {
+    Value::Object(v) => Ok(v.entry(name).or_insert(Value::Null)),
+    Value::Array(v)  => match name.parse::<usize>() {
+      Ok(i) => {
+        if v.len() <= i {
+          v.resize(i + 1, Value::Null);
+        }
+        Ok(&mut v[i])
+      },
+      Err(_) => Err(error::Error::Malformed),
+    },
+    _ => Err(error::Error::Malformed), // cannot descend into a primitive
+  }
+}
+
+/// Produce the value at a path in a document which is not in the service,
+/// or the document itself for an empty path. Paths mean the same thing here
+/// as they do to the service, less the key.
+pub fn get<'a>(doc: &'a Value, path: &str) -> Option<&'a Value> {
+  match path.trim() {
+    "" => Some(doc),
+    _  => Path::new(path).value(doc),
+  }
+}
+
+/// Produce the values at a path in a document, as with get, except that a
+/// `*` component stands for every element of an array or member of an
+/// object, so one path may select any number of values.
+pub fn select<'a>(doc: &'a Value, path: &str) -> Vec<&'a Value> {
+  let mut values = vec![doc];
+  if path.trim().is_empty() {
+    return values;
+  }
+  for name in path.trim().split(SEP) {
+    values = values.into_iter().flat_map(|value| -> Vec<&'a Value> {
+      match (name, value) {
+        (WILDCARD, Value::Array(v))  => v.iter().collect(),
+        (WILDCARD, Value::Object(v)) => v.values().collect(),
+        _                            => json_deref(name, value).into_iter().collect(),
+      }
+    }).collect();
+  }
+  values
+}
+
+/// Determine if a path has a `*` component, and so may select any number of
+/// values.
+pub fn is_wildcard(path: &str) -> bool {
+  path.trim().split(SEP).any(|e| { e == WILDCARD })
+}
+
+/// A syntax error in a path, at a column counted in characters from 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError {
+  pub column: usize,
+  pub message: String,
+}
+
+impl fmt::Display for SyntaxError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "column {}: {}", self.column, self.message)
+  }
+}
+
+/// Check that a path is well formed: that it is empty, for the whole value,
+/// or made of non-empty components separated by dots. Indexes are written
+/// as components, so brackets, which other tools use for them, are refused
+/// with a suggestion.
+pub fn check(path: &str) -> Result<(), SyntaxError> {
+  if path.is_empty() {
+    return Ok(());
+  }
+  let mut column = 1;
+  for name in path.split(SEP) {
+    if name.is_empty() {
+      let message = match column {
+        1 => "The path begins with a separator".to_string(),
+        c if c > path.chars().count() => "The path ends with a separator".to_string(),
+        _ => "Empty component between two separators".to_string(),
+      };
+      return Err(SyntaxError{ column, message });
+    }
+    if let Some(at) = name.find(|c| { c == '[' || c == ']' }) {
+      let suggest = path.replace("[", SEP).replace("]", "").replace("..", SEP);
+      return Err(SyntaxError{ column: column + name[..at].chars().count(), message: format!("Brackets are not supported; write indexes as components, like {}", suggest) });
+    }
+    column += name.chars().count() + 1;
+  }
+  Ok(())
+}
+
+/// Replace the value at a path in a document, producing the updated
+/// document. As with the service, the path must already exist unless parents
+/// is set, in which case any missing objects along it are created.
+pub fn set(doc: &Value, path: &str, value: &Value, parents: bool) -> Result<Value, error::Error> {
+  match (path.trim(), parents) {
+    ("", _)    => Ok(value.clone()),
+    (_, true)  => Path::new(path).graft_value(doc, value, true),
+    (_, false) => Path::new(path).set_value(doc, value),
+  }
+}
+
+/// Remove the value at a path in a document, producing the updated
+/// document.
+pub fn delete(doc: &Value, path: &str) -> Result<Value, error::Error> {
+  Ok(Path::new(path).delete_value(doc)?.0)
+}
+
+fn is_leaf(value: &Value) -> bool {
+  match value {
+    Value::Array(v)  => v.is_empty(),
+    Value::Object(v) => v.is_empty(),
+    _                => true,
+  }
+}
+
 fn json_deref<'a>(name: &str, value: &'a Value) -> Option<&'a Value> {
   match value {
     Value::Null      => None,
@@ -192,7 +614,7 @@ pub fn _index(value: &Value, name: &str) -> Option<usize> {
   }
 }
 
-pub fn index_array(value: &Vec<Value>, name: &str) -> Option<usize> {
+pub fn index_array(value: &[Value], name: &str) -> Option<usize> {
   let i = match name.parse::<usize>() {
     Ok(i)  => i,
     Err(_) => return None,
@@ -239,13 +661,13 @@ mod tests {
   #[test]
   fn last_path() {
     let p = Path::new("a");
-    assert_eq!((None, Some("a")), p.last());
+    assert_eq!((None, Some("a")), p._last());
     let p = Path::new("a.b");
-    assert_eq!((Some(Path::new("a")), Some("b")), p.last());
+    assert_eq!((Some(Path::new("a")), Some("b")), p._last());
     let p = Path::new("a.b.c");
-    assert_eq!((Some(Path::new("a.b")), Some("c")), p.last());
+    assert_eq!((Some(Path::new("a.b")), Some("c")), p._last());
     let p = Path::new("");
-    assert_eq!((None, None), p.last());
+    assert_eq!((None, None), p._last());
   }
 
   #[test]
@@ -298,4 +720,180 @@ mod tests {
     let p = Path::new("sub2.B.two.invalid.nonsense");
     assert_eq!((None, Some(Path::new("invalid.nonsense"))), p.find(&v));
   }
+
+  #[test]
+  fn set_path() {
+    let v: Value = serde_json::from_str(r#"{"a": {"b": 1}, "c": [1, 2]}"#).unwrap();
+    let u = Value::Bool(true);
+    assert_eq!(serde_json::from_str::<Value>(r#"{"a": {"b": true}, "c": [1, 2]}"#).unwrap(), Path::new("a.b").set_value(&v, &u).unwrap());
+    assert_eq!(serde_json::from_str::<Value>(r#"{"a": {"b": 1}, "c": [1, true]}"#).unwrap(), Path::new("c.1").set_value(&v, &u).unwrap());
+    assert!(Path::new("a.x").set_value(&v, &u).is_err());
+    assert!(Path::new("c.2").set_value(&v, &u).is_err());
+
+    assert_eq!(serde_json::from_str::<Value>(r#"{"a": {"b": 1, "x": true}, "c": [1, 2]}"#).unwrap(), Path::new("a.x").graft_value(&v, &u, false).unwrap());
+    assert!(Path::new("a.x.y").graft_value(&v, &u, false).is_err());
+    assert_eq!(serde_json::from_str::<Value>(r#"{"a": {"b": 1, "x": {"y": true}}, "c": [1, 2]}"#).unwrap(), Path::new("a.x.y").graft_value(&v, &u, true).unwrap());
+    assert!(Path::new("c.2").graft_value(&v, &u, true).is_err());
+    assert!(Path::new("a.b.x").graft_value(&v, &u, true).is_err());
+  }
+
+  #[test]
+  fn delete_path() {
+    let v: Value = serde_json::from_str(r#"{"a": {"b": 1, "c": 2}, "d": [1, 2, 3]}"#).unwrap();
+    assert_eq!((serde_json::from_str::<Value>(r#"{"a": {"c": 2}, "d": [1, 2, 3]}"#).unwrap(), Value::Number(1.into())), Path::new("a.b").delete_value(&v).unwrap());
+    assert_eq!((serde_json::from_str::<Value>(r#"{"a": {"b": 1, "c": 2}, "d": [1, 3]}"#).unwrap(), Value::Number(2.into())), Path::new("d.1").delete_value(&v).unwrap());
+    assert_eq!((serde_json::from_str::<Value>(r#"{"d": [1, 2, 3]}"#).unwrap(), serde_json::from_str::<Value>(r#"{"b": 1, "c": 2}"#).unwrap()), Path::new("a").delete_value(&v).unwrap());
+    assert!(Path::new("a.x").delete_value(&v).is_err());
+    assert!(Path::new("d.3").delete_value(&v).is_err());
+    assert!(Path::new("a.b.c").delete_value(&v).is_err());
+  }
+
+  #[test]
+  fn walk_value() {
+    let v: Value = serde_json::from_str(r#"{"a": [1, {"b": 2}], "c": "d"}"#).unwrap();
+    let mut paths: Vec<String> = Vec::new();
+    walk(&v, &mut |p, _| { paths.push(p.to_string()) });
+    assert_eq!(vec!["", "a", "a.0", "a.1", "a.1.b", "c"], paths);
+  }
+
+  #[test]
+  fn merge_values() {
+    let b: Value = serde_json::from_str(r#"{"a": 1, "b": {"c": 2, "d": [1, 2]}, "e": {"f": 3}}"#).unwrap();
+    let o: Value = serde_json::from_str(r#"{"a": 10, "b": {"d": [3], "g": 4}, "e": "x"}"#).unwrap();
+    let m: Value = serde_json::from_str(r#"{"a": 10, "b": {"c": 2, "d": [3], "g": 4}, "e": "x"}"#).unwrap();
+    assert_eq!(m, merge(&b, &o));
+    assert_eq!(o, merge(&Value::Null, &o));
+  }
+
+  #[test]
+  fn merge_patches() {
+    let t: Value = serde_json::from_str(r#"{"a": "b", "c": {"d": "e", "f": "g"}, "h": [1]}"#).unwrap();
+    let p: Value = serde_json::from_str(r#"{"a": "z", "c": {"f": null}, "h": [2], "i": {"j": null, "k": 1}}"#).unwrap();
+    let m: Value = serde_json::from_str(r#"{"a": "z", "c": {"d": "e"}, "h": [2], "i": {"k": 1}}"#).unwrap();
+    assert_eq!(m, merge_patch(&t, &p));
+    assert_eq!(Value::from(3), merge_patch(&t, &Value::from(3)));
+    assert_eq!(serde_json::json!({"a": 1}), merge_patch(&Value::from("x"), &serde_json::json!({"a": 1, "b": null})));
+  }
+
+  #[test]
+  fn add_numbers() {
+    let n = |s: &str| -> serde_json::Number { s.parse().unwrap() };
+    assert_eq!(Ok(Value::from(5)), add(None, &n("5")));
+    assert_eq!(Ok(Value::from(-1)), add(Some(&Value::from(2)), &n("-3")));
+    assert_eq!(Ok(Value::from(2.5)), add(Some(&Value::from(2)), &n("0.5")));
+    assert!(add(Some(&Value::from(i64::MAX)), &n("1")).is_err());
+    assert!(add(Some(&Value::from("2")), &n("1")).is_err());
+  }
+
+  #[test]
+  fn select_values() {
+    let v: Value = serde_json::from_str(r#"{"hosts": [{"name": "a", "port": 1}, {"name": "b"}], "tags": {"x": 1, "y": 2}}"#).unwrap();
+    assert_eq!(vec![&v], select(&v, ""));
+    assert_eq!(vec!["a", "b"], select(&v, "hosts.*.name").into_iter().map(|e| { e.as_str().unwrap() }).collect::<Vec<_>>());
+    assert_eq!(vec![&Value::from(1)], select(&v, "hosts.*.port"));
+    assert_eq!(vec![&Value::from(1), &Value::from(2)], select(&v, "tags.*"));
+    assert_eq!(vec![&Value::from("b")], select(&v, "hosts.1.name"));
+    assert!(select(&v, "missing.*").is_empty());
+    assert!(is_wildcard("hosts.*.name") && !is_wildcard("hosts.0.name"));
+  }
+
+  #[test]
+  fn check_paths() {
+    assert_eq!(Ok(()), check(""));
+    assert_eq!(Ok(()), check("a.b.3.c"));
+    assert_eq!(Ok(()), check("hosts.*.name"));
+    assert_eq!(Err(SyntaxError{ column: 4, message: "Brackets are not supported; write indexes as components, like a.b.3.c".to_string() }), check("a.b[3].c"));
+    assert_eq!(1, check(".a").unwrap_err().column);
+    assert_eq!(3, check("a..b").unwrap_err().column);
+    assert_eq!(3, check("a.").unwrap_err().column);
+    assert_eq!("column 3: The path ends with a separator", check("a.").unwrap_err().to_string());
+  }
+
+  #[test]
+  fn leaf_paths() {
+    let v: Value = serde_json::from_str(r#"{"a": [1, {"b": 2}], "c": "d", "e": {}}"#).unwrap();
+    assert_eq!(vec!["a.0", "a.1.b", "c", "e"], paths(&v, None));
+    assert_eq!(vec!["a.0", "a.1", "c", "e"], paths(&v, Some(2)));
+    assert_eq!(vec!["a", "c", "e"], paths(&v, Some(1)));
+    assert_eq!(vec![""], paths(&v, Some(0)));
+    assert_eq!(vec![""], paths(&Value::Null, None));
+  }
+
+  #[test]
+  fn flatten_value() {
+    let v: Value = serde_json::from_str(r#"{"servers": [{"host": "10.0.0.1"}, {"host": "10.0.0.2", "tags": []}], "n": 1}"#).unwrap();
+    let f: Vec<(String, Value)> = flatten(&v).into_iter().map(|(p, v)| { (p, v.clone()) }).collect();
+    assert_eq!(vec![
+      ("n".to_string(), Value::Number(1.into())),
+      ("servers.0.host".to_string(), Value::String("10.0.0.1".to_string())),
+      ("servers.1.host".to_string(), Value::String("10.0.0.2".to_string())),
+      ("servers.1.tags".to_string(), Value::Array(Vec::new())),
+    ], f);
+    assert_eq!(v, unflatten(&f).unwrap());
+
+    let v = Value::String("leaf".to_string());
+    let f: Vec<(String, Value)> = flatten(&v).into_iter().map(|(p, v)| { (p, v.clone()) }).collect();
+    assert_eq!(vec![("".to_string(), v.clone())], f);
+    assert_eq!(v, unflatten(&f).unwrap());
+
+    assert!(unflatten(&[("a".to_string(), Value::Bool(true)), ("a.b".to_string(), Value::Bool(true))]).is_err());
+    assert!(unflatten(&[("0".to_string(), Value::Bool(true)), ("b".to_string(), Value::Bool(true))]).is_err());
+  }
+
+  #[test]
+  fn flat_round_trip() {
+    let v: Value = serde_json::from_str(r#"{"port": "8080", "on": "true", "none": "null", "n": 8080, "t": true, "z": null, "text": "two\nlines", "a=b": {"c=d": "e=f"}, "eq": "=1", "list": [" x ", []]}"#).unwrap();
+    let flat = print_flat(&v);
+    assert!(flat.contains("port=\"8080\"\n"));
+    assert_eq!(v, parse_flat(&flat).unwrap());
+
+    let v = Value::String("leaf=1".to_string());
+    assert_eq!(v, parse_flat(&print_flat(&v)).unwrap());
+
+    // values printed unquoted by older versions are read as strings
+    assert_eq!(serde_json::json!({"a": {"b": "hello world", "n": 1}}), parse_flat("a.b=hello world\na.n=1\n").unwrap());
+    assert!(parse_flat("no separator").is_err());
+  }
+
+  #[test]
+  fn value_ci() {
+    let v: Value = serde_json::from_str(r#"{"Hosts": [{"Name": "a", "name": "b"}], "Straße": 1}"#).unwrap();
+    assert_eq!(Some(&Value::String("b".to_string())), Path::new("hosts.0.name").value_ci(&v));
+    assert_eq!(Some(&Value::String("a".to_string())), Path::new("HOSTS.0.NAME").value_ci(&v));
+    assert_eq!(Some(&Value::Number(1.into())), Path::new("STRASSE").value_ci(&v));
+    assert_eq!(None, Path::new("hosts.1").value_ci(&v));
+    assert_eq!(None, Path::new("hosts").value(&v));
+  }
+
+  #[test]
+  fn explain_path() {
+    let v: Value = serde_json::from_str(r#"{"hosts": [{"name": "a"}], "port": 80}"#).unwrap();
+    assert_eq!(None, Path::new("hosts.0.name").explain("cfg", &v));
+    assert_eq!(Some("No member 'host' in the object at cfg".to_string()), Path::new("host").explain("cfg", &v));
+    assert_eq!(Some("Index 1 is out of bounds for the array of length 1 at cfg.hosts".to_string()), Path::new("hosts.1.name").explain("cfg", &v));
+    assert_eq!(Some("'first' is not an index into the array at cfg.hosts".to_string()), Path::new("hosts.first").explain("cfg", &v));
+    assert_eq!(Some("Cannot look up 'n' in the number at cfg.port".to_string()), Path::new("port.n").explain("cfg", &v));
+  }
+
+  #[test]
+  fn unresolved_path() {
+    let v: Value = serde_json::from_str(r#"{"hosts": [{"name": "a"}], "port": 80}"#).unwrap();
+    assert_eq!(None, Path::new("hosts.0.name").unresolved("cfg", &v));
+    assert_eq!(Some(("cfg".to_string(), &v, "host".to_string())), Path::new("host").unresolved("cfg", &v));
+    assert_eq!(Some(("cfg.hosts.0".to_string(), &v["hosts"][0], "nam".to_string())), Path::new("hosts.0.nam.first").unresolved("cfg", &v));
+    assert_eq!(Some(("cfg.port".to_string(), &v["port"], "n".to_string())), Path::new("port.n").unresolved("cfg", &v));
+  }
+
+  #[test]
+  fn offline_paths() {
+    let doc = serde_json::json!({"a": {"b": [1, 2]}});
+    assert_eq!(Some(&serde_json::json!(2)), get(&doc, "a.b.1"));
+    assert_eq!(Some(&doc), get(&doc, ""));
+    assert_eq!(None, get(&doc, "a.c"));
+    assert_eq!(serde_json::json!({"a": {"b": [1, 3]}}), set(&doc, "a.b.1", &serde_json::json!(3), false).unwrap());
+    assert!(set(&doc, "a.c.d", &serde_json::json!(3), false).is_err());
+    assert_eq!(serde_json::json!({"a": {"b": [1, 2], "c": {"d": 3}}}), set(&doc, "a.c.d", &serde_json::json!(3), true).unwrap());
+    assert_eq!(serde_json::json!({"a": {"b": [2]}}), delete(&doc, "a.b.0").unwrap());
+    assert!(delete(&doc, "").is_err());
+  }
 }
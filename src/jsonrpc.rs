@@ -0,0 +1,184 @@
+use serde_json::{json, Map, Value};
+
+use crate::rpc;
+
+pub const VERSION: &str = "2.0";
+
+pub const METHOD_GET:     &str = "get";
+pub const METHOD_SET:     &str = "set";
+pub const METHOD_RANGE:   &str = "range";
+pub const METHOD_DELETE:  &str = "delete";
+pub const METHOD_VERSION: &str = "version";
+pub const METHOD_HEALTH:  &str = "health";
+
+pub const PARSE_ERROR:      i64 = -32700;
+pub const INVALID_REQUEST:  i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS:   i64 = -32602;
+pub const SERVER_ERROR:     i64 = -32000;
+pub const NOT_FOUND:        i64 = -32001;
+pub const RATE_LIMITED:     i64 = -32002;
+pub const PROTECTED:        i64 = -32003;
+pub const MISMATCH:         i64 = -32004;
+pub const EXISTS:           i64 = -32005;
+
+/// A JSON-RPC request translated into the operation it maps to. Requests
+/// without an id are notifications and expect no response.
+#[derive(Debug)]
+pub struct Call {
+  pub id: Option<Value>,
+  pub op: rpc::Operation,
+}
+
+/// Translate a JSON-RPC request into an operation, or produce the error
+/// response to send in its place.
+pub fn parse(line: &str) -> Result<Call, Value> {
+  let req: Value = match serde_json::from_str(line) {
+    Ok(req) => req,
+    Err(_)  => return Err(error(Value::Null, PARSE_ERROR, "Parse error")),
+  };
+  let req = match req {
+    Value::Object(req) => req,
+    _                  => return Err(error(Value::Null, INVALID_REQUEST, "Invalid request")),
+  };
+  let id = req.get("id").cloned();
+  let reply_id = id.clone().unwrap_or(Value::Null);
+  let method = match (req.get("jsonrpc"), req.get("method")) {
+    (Some(Value::String(v)), Some(Value::String(method))) if v == VERSION => method.as_str(),
+    _ => return Err(error(reply_id, INVALID_REQUEST, "Invalid request")),
+  };
+  let params = match req.get("params") {
+    Some(Value::Object(params)) => params.clone(),
+    None                        => Map::new(),
+    Some(_)                     => return Err(error(reply_id, INVALID_PARAMS, "Params must be an object")),
+  };
+  let op = match operation(method, &params) {
+    Ok(op)           => op,
+    Err((code, msg)) => return Err(error(reply_id, code, &msg)),
+  };
+  Ok(Call{
+    id,
+    op,
+  })
+}
+
+/// The operation a method maps to, or the code and message of the error
+/// which prevents it.
+fn operation(method: &str, params: &Map<String, Value>) -> Result<rpc::Operation, (i64, String)> {
+  Ok(match method {
+    METHOD_GET     => rpc::Operation::new_get(string_param(params, "key")?).with_flag(rpc::FLAG_CI, params.get("ci") == Some(&Value::Bool(true))),
+    METHOD_RANGE   => rpc::Operation::new_range(string_param(params, "key")?),
+    METHOD_SET     => {
+      let key = string_param(params, "key")?;
+      let value = params.get("value").cloned().unwrap_or(Value::Null);
+      let ttl = match params.get("ttl") {
+        Some(Value::Number(ttl)) if ttl.is_u64() => Some(ttl.to_string()),
+        Some(_) => return Err((INVALID_PARAMS, "Invalid param: ttl".to_string())),
+        None    => None,
+      };
+      match params.get("if_equals") {
+        Some(expected) => rpc::Operation::new_cas(key, &expected.to_string(), &value.to_string()).with_option(rpc::OPTION_TTL, ttl.as_deref()),
+        None           => rpc::Operation::new_set(key, &value.to_string()).with_flag(rpc::FLAG_NX, params.get("nx") == Some(&Value::Bool(true))).with_option(rpc::OPTION_TTL, ttl.as_deref()),
+      }
+    },
+    METHOD_DELETE  => {
+      let keys: Vec<&str> = match (params.get("key"), params.get("keys")) {
+        (Some(Value::String(key)), None) => vec![key.as_str()],
+        (None, Some(Value::Array(keys))) if !keys.is_empty() && keys.iter().all(Value::is_string) => keys.iter().filter_map(Value::as_str).collect(),
+        _ => return Err((INVALID_PARAMS, "Invalid params: expected key or keys".to_string())),
+      };
+      rpc::Operation::new_delete(&keys)
+    },
+    METHOD_VERSION => rpc::Operation::new_version(),
+    METHOD_HEALTH  => rpc::Operation::new_health(),
+    _ => return Err((METHOD_NOT_FOUND, format!("Method not found: {}", method))),
+  })
+}
+
+fn string_param<'a>(params: &'a Map<String, Value>, name: &str) -> Result<&'a str, (i64, String)> {
+  match params.get(name) {
+    Some(Value::String(v)) => Ok(v),
+    _                      => Err((INVALID_PARAMS, format!("Invalid param: {}", name))),
+  }
+}
+
+/// Translate the response to an operation into a JSON-RPC response.
+pub fn respond(id: Value, rsp: &rpc::Operation) -> Value {
+  match rsp.name() {
+    rpc::CMD_FOUND => match rsp.data() {
+      Some(data) => result(id, serde_json::from_str(data).unwrap_or_else(|_| { Value::String(data.to_owned()) })),
+      None       => result(id, Value::Null),
+    },
+    rpc::CMD_OK => match rsp.args().len() {
+      0 => result(id, Value::Bool(true)),
+      _ => result(id, Value::Array(rsp.args().iter().map(|e| { Value::String(e.to_owned()) }).collect())),
+    },
+    rpc::CMD_NONE  => error(id, NOT_FOUND, &format!("Not found: {}", rsp.args().join(" "))),
+    rpc::CMD_ERROR => error(id, SERVER_ERROR, rsp.data().as_deref().unwrap_or("Error")),
+    rpc::CMD_BUSY  => error(id, SERVER_ERROR, "Service is busy; retry later"),
+    rpc::CMD_PROTECTED => error(id, PROTECTED, &format!("Protected: {}", rsp.args().join(" "))),
+    rpc::CMD_EXISTS => error(id, EXISTS, &format!("Already exists: {}", rsp.args().join(" "))),
+    rpc::CMD_MISMATCH => {
+      let current = rsp.data().as_deref().and_then(|e| { serde_json::from_str::<Value>(e).ok() }).unwrap_or(Value::Null);
+      json!({"jsonrpc": VERSION, "id": id, "error": {"code": MISMATCH, "message": format!("Mismatch: {}", rsp.args().join(" ")), "data": {"current": current}}})
+    },
+    rpc::CMD_LIMITED => {
+      let retry = rsp.args().first().and_then(|e| { e.parse::<u64>().ok() }).unwrap_or(0);
+      json!({"jsonrpc": VERSION, "id": id, "error": {"code": RATE_LIMITED, "message": "Rate limit exceeded", "data": {"retry_after_ms": retry}}})
+    },
+    cmd            => error(id, SERVER_ERROR, &format!("Unexpected response: {}", cmd)),
+  }
+}
+
+fn result(id: Value, result: Value) -> Value {
+  json!({"jsonrpc": VERSION, "id": id, "result": result})
+}
+
+pub fn error(id: Value, code: i64, message: &str) -> Value {
+  json!({"jsonrpc": VERSION, "id": id, "error": {"code": code, "message": message}})
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_calls() {
+    let call = parse(r#"{"jsonrpc":"2.0","id":1,"method":"set","params":{"key":"a.b","value":{"x":1},"ttl":500}}"#).unwrap();
+    assert_eq!(Some(json!(1)), call.id);
+    assert_eq!(rpc::CMD_SET, call.op.name());
+    assert_eq!(&["a.b".to_string(), "ttl=500".to_string()], call.op.args());
+    assert_eq!(&Some(r#"{"x":1}"#.to_string()), call.op.data());
+
+    let call = parse(r#"{"jsonrpc":"2.0","id":1,"method":"set","params":{"key":"a","value":2,"if_equals":1}}"#).unwrap();
+    assert_eq!(rpc::CMD_CAS, call.op.name());
+    assert_eq!(&["a".to_string(), "1".to_string()], call.op.args());
+    assert_eq!(&Some("2".to_string()), call.op.data());
+
+    let call = parse(r#"{"jsonrpc":"2.0","id":1,"method":"set","params":{"key":"a","value":2,"nx":true}}"#).unwrap();
+    assert!(call.op.has_flag(1, rpc::FLAG_NX));
+
+    let call = parse(r#"{"jsonrpc":"2.0","method":"delete","params":{"keys":["a","b"]}}"#).unwrap();
+    assert_eq!(None, call.id);
+    assert_eq!(&["a".to_string(), "b".to_string()], call.op.args());
+
+    assert_eq!(json!(PARSE_ERROR), parse("{").unwrap_err()["error"]["code"]);
+    assert_eq!(json!(INVALID_REQUEST), parse(r#"{"id":2,"method":"get"}"#).unwrap_err()["error"]["code"]);
+    assert_eq!(json!(METHOD_NOT_FOUND), parse(r#"{"jsonrpc":"2.0","id":3,"method":"nope"}"#).unwrap_err()["error"]["code"]);
+    let err = parse(r#"{"jsonrpc":"2.0","id":4,"method":"get","params":{}}"#).unwrap_err();
+    assert_eq!((json!(4), json!(INVALID_PARAMS)), (err["id"].clone(), err["error"]["code"].clone()));
+  }
+
+  #[test]
+  fn responses() {
+    assert_eq!(json!({"jsonrpc": "2.0", "id": 1, "result": {"x": 1}}), respond(json!(1), &rpc::Operation::new_found("a", r#"{"x":1}"#)));
+    assert_eq!(json!({"jsonrpc": "2.0", "id": 1, "result": true}), respond(json!(1), &rpc::Operation::new_ok()));
+    assert_eq!(json!(["a"]), respond(json!(1), &rpc::Operation::new_deleted(&["a"]))["result"]);
+    assert_eq!(json!(NOT_FOUND), respond(json!(1), &rpc::Operation::new_none("a"))["error"]["code"]);
+    assert_eq!(json!({"code": RATE_LIMITED, "message": "Rate limit exceeded", "data": {"retry_after_ms": 250}}), respond(json!(1), &rpc::Operation::new_limited(std::time::Duration::from_millis(250)))["error"]);
+    assert_eq!(json!(PROTECTED), respond(json!(1), &rpc::Operation::new_protected("a.b"))["error"]["code"]);
+    assert_eq!(json!(EXISTS), respond(json!(1), &rpc::Operation::new_exists("a"))["error"]["code"]);
+    assert_eq!(json!({"current": [1]}), respond(json!(1), &rpc::Operation::new_mismatch("a", "[1]"))["error"]["data"]);
+    assert_eq!(json!("Bad"), respond(json!(1), &rpc::Operation::new_error("Bad"))["error"]["message"]);
+  }
+}
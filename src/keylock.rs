@@ -0,0 +1,99 @@
+use std::collections::BTreeSet;
+use std::sync::{Mutex, MutexGuard, Condvar};
+
+/// A table of locks on keys. Writers lock the keys they modify so that
+/// writes to the same key are serialized while writes to different keys
+/// may proceed concurrently. Every key an operation needs is acquired at
+/// once, so operations on several keys cannot deadlock one another.
+pub struct Locks {
+  held: Mutex<BTreeSet<String>>,
+  cond: Condvar,
+}
+
+impl Locks {
+  pub fn new() -> Self {
+    Self{
+      held: Mutex::new(BTreeSet::new()),
+      cond: Condvar::new(),
+    }
+  }
+
+  /// Lock the provided keys, waiting until none of them is held by another
+  /// operation. The keys are released when the guard is dropped.
+  pub fn lock(&self, keys: &[&str]) -> Guard<'_> {
+    let mut held = self.held();
+    while keys.iter().any(|e| { held.contains(*e) }) {
+      held = match self.cond.wait(held) {
+        Ok(held)  => held,
+        Err(held) => held.into_inner(),
+      };
+    }
+    let keys: BTreeSet<String> = keys.iter().map(|e| { e.to_string() }).collect();
+    held.extend(keys.iter().cloned());
+    Guard{
+      locks: self,
+      keys,
+    }
+  }
+
+  fn held(&self) -> MutexGuard<'_, BTreeSet<String>> {
+    match self.held.lock() {
+      Ok(held)  => held,
+      Err(held) => held.into_inner(), // the set itself is always consistent
+    }
+  }
+}
+
+pub struct Guard<'a> {
+  locks: &'a Locks,
+  keys: BTreeSet<String>,
+}
+
+impl Drop for Guard<'_> {
+  fn drop(&mut self) {
+    let mut held = self.locks.held();
+    for key in &self.keys {
+      held.remove(key);
+    }
+    self.locks.cond.notify_all();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::thread;
+  use std::time;
+
+  #[test]
+  fn serialize_same_key() {
+    let locks = Arc::new(Locks::new());
+    let active = Arc::new(AtomicUsize::new(0));
+    let mut threads = Vec::new();
+    for _ in 0..8 {
+      let (locks, active) = (locks.clone(), active.clone());
+      threads.push(thread::spawn(move || {
+        let _guard = locks.lock(&["a", "b"]);
+        assert_eq!(0, active.fetch_add(1, Ordering::SeqCst));
+        thread::sleep(time::Duration::from_millis(2));
+        active.fetch_sub(1, Ordering::SeqCst);
+      }));
+    }
+    for t in threads {
+      t.join().unwrap();
+    }
+  }
+
+  #[test]
+  fn concurrent_other_keys() {
+    let locks = Locks::new();
+    let a = locks.lock(&["a"]);
+    let b = locks.lock(&["b", "c"]); // would block forever if keys conflicted
+    drop(a);
+    let _a = locks.lock(&["a"]);
+    drop(b);
+    let _bc = locks.lock(&["c", "b"]);
+  }
+}
@@ -0,0 +1,14 @@
+//! The structs client library, for talking to a running structs service
+//! from Rust without going through the command line tool.
+
+pub mod error;
+pub mod rpc;
+pub mod duration;
+pub mod jsonpath;
+pub mod predicate;
+pub mod api;
+pub mod ffi;
+
+mod log;
+
+pub use api::Client;
@@ -5,29 +5,79 @@ use std::str;
 use std::path;
 use std::time;
 use std::process;
-use std::io::Read;
+use std::io::{Read, Write, IsTerminal};
+use std::os::unix::fs::{PermissionsExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::ExitStatusExt;
 
 use std::thread;
-use std::os::unix::net::{UnixStream, UnixListener};
-use std::sync::mpsc;
+use std::os::unix::net::UnixStream;
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use std::collections::BTreeMap;
 use rand::distributions::{Alphanumeric, DistString};
 
 use colored::Colorize;
-use clap::{Parser, Subcommand, Args};
+use clap::{Parser, Subcommand, Args, CommandFactory};
 
-use serde_json;
 
-mod error;
-mod rpc;
+use structs_sh::{error, rpc, duration, jsonpath, predicate};
+
 mod service;
 mod client;
-mod duration;
-mod jsonpath;
+mod storage;
+mod keylock;
+mod pool;
+mod stats;
+mod ttl;
+mod notify;
+mod supervise;
+mod doctor;
+mod resp;
+mod dump;
+mod jsonrpc;
+mod cas;
+mod pattern;
+mod ratelimit;
+mod watch;
+mod eventlog;
+mod metrics;
+mod diff;
+mod patch;
+mod sequence;
+mod clients;
+mod suggest;
+mod journal;
+mod crypto;
+mod timestamp;
+mod auth;
 mod log;
 
-const _VERSION: &str = env!("CARGO_PKG_VERSION");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Clients use the socket named by this variable, when it is set, unless
+/// another is provided.
+const SOCKET_ENV:   &str = "STRUCTS_SOCKET";
+const REQUIRED_ENV: &str = "STRUCTS_REQUIRED";
+/// When this variable is set, fetching a key or path which does not exist
+/// suggests similar ones, as if --suggest were given.
+const SUGGEST_ENV: &str = "STRUCTS_SUGGEST";
+
+/// The exit status when a key or path does not exist, distinct from other
+/// failures so scripts can tell a missing key from a broken service.
+const EXIT_NOT_FOUND: i32 = 2;
+
+/// The exit status when a conditional set finds a value other than the one
+/// expected, or any value where none was expected.
+const EXIT_CONFLICT: i32 = 3;
+
+/// The exit status when a command does not finish within --op-timeout, as
+/// with timeout(1).
+const EXIT_TIMEOUT: i32 = 124;
+
+/// How long a watch waits before reconnecting to a service it lost.
+const RECONNECT_DELAY: time::Duration = time::Duration::from_millis(500);
 
 #[derive(Parser, Debug, Clone)]
 #[clap(author, version, about, long_about = None)]
@@ -36,36 +86,203 @@ pub struct Options {
   pub debug: bool,
   #[clap(long, help="Enable verbose output")]
   pub verbose: bool,
+  #[clap(long="op-timeout", global=true, help="Give up on a client command which has not finished within this long, e.g., 5s, and exit with status 124")]
+  pub op_timeout: Option<duration::Duration>,
+  #[clap(long="sequence", global=true, help="Have the service apply this command's operations after those of any other command given the same token, in the order they were sent")]
+  pub sequence: Option<String>,
+  #[clap(long="client-name", global=true, help="Name this client's connections, as they are listed by the clients command")]
+  pub client_name: Option<String>,
   #[clap(subcommand)]
   command: Command,
 }
 
 #[derive(Subcommand, Debug, Clone)]
+#[allow(clippy::large_enum_variant)] // parsed once per invocation, size doesn't matter
 enum Command {
-  #[clap(name="run", about="Start the structs daemon")]
+  #[clap(name="run", alias="serve", about="Start the structs daemon")]
   Run(RunOptions),
+  #[clap(name="up", about="Start the structs daemon in the background, optionally keeping it alive")]
+  Up(UpOptions),
   #[clap(name="get", about="Query a value from the service")]
   Fetch(FetchOptions),
   #[clap(name="range", about="Range over an array or object value from the service")]
   Range(RangeOptions),
+  #[clap(name="flatten", about="Print the path and value of every leaf in a value")]
+  Flatten(FlattenOptions),
+  #[clap(name="paths", about="List the path of every leaf under a key, or in the whole store")]
+  Paths(PathsOptions),
+  #[clap(name="path", about="Get, set, or delete a path in a JSON document read from stdin, without the service")]
+  Path(PathOptions),
+  #[clap(name="duration", about="Convert durations written as the service reads them, like '1h30m', without the service")]
+  Duration(DurationOptions),
+  #[clap(name="keys", alias="ls", about="List the keys in the service, or those matching a prefix or pattern")]
+  Keys(KeysOptions),
+  #[clap(name="count", about="Count the keys in the service, or those matching a prefix or pattern")]
+  Count(CountOptions),
+  #[clap(name="find-paths", about="Find the paths of values matching a predicate")]
+  Find(FindOptions),
   #[clap(name="set", about="Store a value in the service")]
   Store(StoreOptions),
+  #[clap(name="mset", about="Store every entry of a JSON object read from stdin, all or nothing, in one operation")]
+  MultiStore(MultiStoreOptions),
+  #[clap(name="set-env", about="Store environment variables as an object under a key")]
+  StoreEnv(StoreEnvOptions),
+  #[clap(name="capture", about="Run a command and store its output, exit status, and duration as an object under a key")]
+  Capture(CaptureOptions),
+  #[clap(name="merge", about="Apply a JSON merge patch (RFC 7386) read from stdin to a value and print the result")]
+  Merge(MergeOptions),
+  #[clap(name="patch", about="Apply a JSON Patch (RFC 6902) read from stdin to a value, all or nothing, and print the result")]
+  Patch(PatchOptions),
+  #[clap(name="merge-keys", about="Deep-merge two values into a destination key")]
+  MergeKeys(MergeKeysOptions),
+  #[clap(name="mv", about="Move a value from one key or path to another")]
+  Move(MoveOptions),
+  #[clap(name="alias", about="Create, re-point, delete, or list aliases to keys")]
+  Alias(AliasOptions),
+  #[clap(name="defaults", about="Register, remove, or list templates which missing keys matching a glob take on")]
+  Defaults(DefaultsOptions),
+  #[clap(name="protect", about="Protect keys or paths from being written or deleted, or list those which are")]
+  Protect(ProtectOptions),
+  #[clap(name="unprotect", about="Allow protected keys or paths to be written and deleted again")]
+  Unprotect(UnprotectOptions),
   #[clap(name="rm", about="Delete a value from the service")]
   Delete(DeleteOptions),
+  #[clap(name="clear", about="Delete every key, leaving the service running, and print how many were deleted")]
+  Clear(ClearOptions),
+  #[clap(name="swap", about="Store a value read from stdin and print the value it replaced, in one operation")]
+  Swap(SwapOptions),
+  #[clap(name="take", about="Print a value and delete it in one operation, so no other client can also take it")]
+  Take(TakeOptions),
+  #[clap(name="push", about="Append a value to the array under a key or path, creating it if need be, and print its length")]
+  Push(PushOptions),
+  #[clap(name="pop", about="Remove the last, or first, element of the array under a key or path and print it, in one operation")]
+  Pop(PopOptions),
+  #[clap(name="incr", about="Add to the number under a key or path and print the result, in one operation")]
+  Incr(IncrOptions),
+  #[clap(name="decr", about="Subtract from the number under a key or path and print the result, in one operation")]
+  Decr(IncrOptions),
+  #[clap(name="watch", about="Print changes to keys, or to keys matching globs, as they happen")]
+  Watch(WatchOptions),
+  #[clap(name="wait-any", about="Wait until any of several keys changes or appears, then print which one and its value")]
+  WaitAny(WaitAnyOptions),
+  #[clap(name="log", about="Append to or tail a log of timestamped entries stored under a key")]
+  Log(LogOptions),
+  #[clap(name="metric", about="Update numeric counters and gauges, or dump them all")]
+  Metric(MetricOptions),
+  #[clap(name="touch", about="Mark a key as modified and restart its TTL without rewriting its value")]
+  Touch(TouchOptions),
+  #[clap(name="stat", about="Print access statistics for a key, or for the whole store")]
+  Stat(StatOptions),
+  #[clap(name="with", about="Run a command against a temporary service of its own, which is stopped afterward")]
+  With(WithOptions),
+  #[clap(name="fork", about="Start a new service on another socket with a copy of the state of this one")]
+  Fork(ForkOptions),
+  #[clap(name="dump-key", about="Serialize a key and its metadata into a blob which can be restored elsewhere")]
+  DumpKey(DumpKeyOptions),
+  #[clap(name="restore-key", about="Restore a key from a blob produced by dump-key, read from stdin")]
+  RestoreKey(RestoreKeyOptions),
+  #[clap(name="checksum", about="Print a checksum of every key and value in the service")]
+  Checksum(ChecksumOptions),
+  #[clap(name="verify", about="Verify that the service holds the same data as another service or a snapshot")]
+  Verify(VerifyOptions),
+  #[clap(name="import", about="Import keys holding JSON or strings from Redis")]
+  Import(ImportOptions),
+  #[clap(name="export", about="Export keys to Redis, as JSON or, for strings, as is")]
+  Export(ExportOptions),
+  #[clap(name="replay", about="Re-apply the operations recorded in a journal")]
+  Replay(ReplayOptions),
   #[clap(name="stop", about="Shutdown the service, if it is running")]
   Shutdown(ShutdownOptions),
   #[clap(name="cleanup", about="Cleanup after inconsistent state and delete the socket")]
   Cleanup(CleanupOptions),
+  #[clap(name="health", about="Check that the service is running and responsive")]
+  Health(HealthOptions),
+  #[clap(name="clients", about="List the clients connected to the service")]
+  Clients(ClientsOptions),
+  #[clap(name="kill-client", about="Disconnect a client from the service, by the id listed by clients")]
+  KillClient(KillClientOptions),
+  #[clap(name="doctor", about="Diagnose common problems with the service and suggest how to fix them")]
+  Doctor(DoctorOptions),
+  #[clap(name="version", about="Print the version of this client and of the running service")]
+  Version(VersionOptions),
+  #[clap(name="completions", about="Print a shell completion script")]
+  Completions(CompletionsOptions),
 }
 
 #[derive(Args, Debug, Clone)]
 pub struct RunOptions {
   #[clap(long="timeout", help="Shut down the service after the last entry is deleted")]
   pub timeout: Option<duration::Duration>,
-  #[clap(long="finalize", help="Shut down the service after the last entry is deleted")]
+  #[clap(long="finalize", help="Shut down the service after the last entry is deleted or expires")]
   pub finalize: bool,
+  #[clap(long="backend", default_value=storage::BACKEND_MEMORY, help="The storage backend to use: memory, interned, disk, or sqlite")]
+  pub backend: String,
+  #[clap(long="data", help="The path to the data managed by persistent storage backends")]
+  pub data: Option<String>,
+  #[clap(long="partition", multiple_occurrences=true, help="Keep keys beginning with a prefix elsewhere: PREFIX=memory to not persist them, or PREFIX=FILE to persist them to a JSON file; repeat for several")]
+  pub partition: Vec<storage::Partition>,
+  #[clap(long="seed", help="A JSON object whose members are read-only defaults for keys; reads fall back to them, writes never touch them")]
+  pub seed: Option<String>,
+  #[clap(long="load", help="Store every *.json file in a directory at startup, keyed by its name without the extension")]
+  pub load: Option<String>,
+  #[clap(long="save-on-exit", help="Write every key and its value to a file as a JSON object when the service shuts down")]
+  pub save_on_exit: Option<String>,
+  #[clap(long="on-start", help="A shell command to run once the service is listening")]
+  pub on_start: Option<String>,
+  #[clap(long="on-idle-exit", help="A shell command to run before the service shuts down after --timeout; the service waits for it")]
+  pub on_idle_exit: Option<String>,
+  #[clap(long="on-finalize", help="A shell command to run after the service finalizes and removes its socket")]
+  pub on_finalize: Option<String>,
+  #[clap(long="journal", help="Record every operation accepted by the service to a journal file")]
+  pub journal: Option<String>,
+  #[clap(long="encrypt-key-file", help="Encrypt persisted state with the hex-encoded 256-bit key in this file; otherwise read from $STRUCTS_ENCRYPT_KEY")]
+  pub encrypt_key_file: Option<String>,
+  #[clap(long="workers", help="The number of threads handling requests; defaults to the number of CPUs")]
+  pub workers: Option<usize>,
+  #[clap(long="max-clients", help="The maximum number of simultaneous client connections; others are told to retry")]
+  pub max_clients: Option<usize>,
+  #[clap(long="rate-limit", help="Limit how often each user, across their connections, may make requests, e.g., 1000/s; requests beyond it are refused with a retry delay")]
+  pub rate_limit: Option<ratelimit::Rate>,
+  #[clap(long="max-line", default_value="16777216", help="The maximum length, in bytes, of a command or data line sent by a client")]
+  pub max_line: usize,
+  #[clap(long="expire-strategy", default_value=ttl::STRATEGY_ACTIVE, help="How expired keys are removed: 'lazy', when they are accessed, or 'active', by also sweeping periodically")]
+  pub expire_strategy: ttl::Strategy,
+  #[clap(long="sweep-interval", default_value="10s", help="How often the active expiration strategy sweeps for expired keys, and idle keys are collected")]
+  pub sweep_interval: duration::Duration,
+  #[clap(long="stats-log", help="Append the statistics for the whole store to a file periodically: as CSV if it ends in .csv, otherwise as a JSON object per line")]
+  pub stats_log: Option<String>,
+  #[clap(long="stats-interval", default_value="30s", help="How often statistics are appended to --stats-log")]
+  pub stats_interval: duration::Duration,
+  #[clap(long="gc-idle-keys", help="Expire keys which have been neither read nor written for this long, e.g., 2h")]
+  pub gc_idle_keys: Option<duration::Duration>,
+  #[clap(long="watch-history", default_value="1000", help="The number of recent changes kept so that watchers which were disconnected can resume without missing any; 0 keeps none")]
+  pub watch_history: usize,
+  #[clap(long="allow-uid", help="Allow a user, by name or id, to connect to a shared socket; may be repeated")]
+  pub allow_uid: Vec<String>,
+  #[clap(long="allow-gid", help="Allow members of a group, by name or id, to connect to a shared socket; may be repeated")]
+  pub allow_gid: Vec<String>,
+  #[clap(long="protocol", default_value=rpc::PROTOCOL_LINE, help="The protocol clients speak: 'line', the native protocol, or 'jsonrpc', for JSON-RPC 2.0")]
+  pub protocol: rpc::Protocol,
+  #[clap(long="stdio", conflicts_with="ephemeral", help="Serve a single client over stdin and stdout instead of listening on a socket")]
+  pub stdio: bool,
+  #[clap(long="ephemeral", help="Listen on a unique socket, print its path, and exit when the parent process (or --for) does")]
+  pub ephemeral: bool,
+  #[clap(long="for", requires="ephemeral", help="The process whose lifetime an ephemeral service is tied to, instead of the parent")]
+  pub owner: Option<u32>,
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+struct UpOptions {
   #[clap(long="socket", name="socket", help="The path to the server socket")]
   path: Option<String>,
+  #[clap(long="supervise", help="Restart the service, with backoff, if it exits abnormally")]
+  supervise: bool,
+  #[clap(long="foreground", hide=true, help="Run in the foreground rather than returning once the service is ready")]
+  foreground: bool,
+  #[clap(last=true, help="Options passed to the service; see 'run'")]
+  args: Vec<String>,
 }
 
 #[derive(Args, Debug, Clone)]
@@ -74,8 +291,32 @@ struct FetchOptions {
   path: Option<String>,
   #[clap(help="The key to fetch the record from")]
   key: String,
+  #[clap(conflicts_with_all=&["binary", "exists-format", "output", "ci", "required", "suggest", "as-of", "extract", "join"], help="More keys to fetch in the same request; their values are printed as an object, or with --lines one per line")]
+  more: Vec<String>,
+  #[clap(long="lines", requires="more", help="Print the value of each key on a line of its own, in order, with an empty line for each missing key")]
+  lines: bool,
   #[clap(long="raw", short='r', name="raw", help="Print the raw value, instaed of JSON")]
   raw: bool,
+  #[clap(long="pretty", conflicts_with_all=&["raw", "binary"], help="Print the value as indented JSON")]
+  pretty: bool,
+  #[clap(long="binary", conflicts_with="raw", help="Decode a value which is a base64 string and write its bytes")]
+  binary: bool,
+  #[clap(long="exists-format", conflicts_with_all=&["raw", "binary"], help="Print {\"exists\":true,\"value\":...} or {\"exists\":false}, rather than failing when the key is missing, so null and missing values are distinct")]
+  exists_format: bool,
+  #[clap(long="output", short='o', help="Write the value to a file, which is replaced atomically once it is complete, instead of printing it")]
+  output: Option<String>,
+  #[clap(long="ci", help="Match the key and object members case-insensitively when there is no exact match")]
+  ci: bool,
+  #[clap(long="required", help="Fail with a message describing precisely which part of the key could not be resolved; the default when $STRUCTS_REQUIRED is set")]
+  required: bool,
+  #[clap(long="suggest", help="When the key or path does not exist, suggest similar ones which do; the default when $STRUCTS_SUGGEST is set")]
+  suggest: bool,
+  #[clap(long="as-of", conflicts_with="ci", help="Fetch the value the key had at a time, from the history of changes: milliseconds since the epoch, a UTC time like 2026-10-15T14:03:00Z, or a duration ago like 10m")]
+  as_of: Option<timestamp::Timestamp>,
+  #[clap(long="extract", conflicts_with="exists-format", help="Print only the part of the value at a path, where a '*' component stands for every element or member, e.g., 'hosts.*.name'")]
+  extract: Option<String>,
+  #[clap(long="join", conflicts_with_all=&["raw", "pretty", "binary", "exists-format"], help="Print the elements of an array raw, separated by a delimiter, e.g., ','")]
+  join: Option<String>,
 }
 
 #[derive(Args, Debug, Clone)]
@@ -84,255 +325,2763 @@ struct RangeOptions {
   path: Option<String>,
   #[clap(help="The key to fetch the record from")]
   key: String,
+  #[clap(long="print0", short='0', help="Terminate each result with a NUL instead of a newline, as for 'xargs -0'")]
+  print0: bool,
+  #[clap(long="exec", help="Run a shell command for each element instead of printing it, with '{}' replaced by the element")]
+  exec: Option<String>,
+  #[clap(long="project", requires="exec", help="Substitute the raw value at this path under each element, instead of the element itself")]
+  project: Option<String>,
+  #[clap(long="parallel", default_value="1", requires="exec", help="The number of commands to run at once")]
+  parallel: usize,
 }
 
 #[derive(Args, Debug, Clone)]
-struct StoreOptions {
+struct FlattenOptions {
   #[clap(long="socket", name="socket", help="The path to the server socket")]
   path: Option<String>,
-  #[clap(help="The key to store the record under")]
-  key: Option<String>,
+  #[clap(help="The key to flatten")]
+  key: String,
 }
 
 #[derive(Args, Debug, Clone)]
-struct DeleteOptions {
+struct PathOptions {
+  #[clap(subcommand)]
+  command: PathCommand,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum PathCommand {
+  #[clap(name="get", about="Print the value at a path in the document")]
+  Get(PathGetOptions),
+  #[clap(name="set", about="Replace the value at a path in the document and print the result")]
+  Set(PathSetOptions),
+  #[clap(name="del", alias="delete", about="Remove the value at a path in the document and print the result")]
+  Delete(PathDeleteOptions),
+  #[clap(name="check", about="Check that a path is well formed, without reading a document, and report where it is not")]
+  Check(PathCheckOptions),
+}
+
+#[derive(Args, Debug, Clone)]
+struct DurationOptions {
+  #[clap(subcommand)]
+  command: DurationCommand,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum DurationCommand {
+  #[clap(name="parse", about="Parse a duration, like '1h30m', and print it as a number or in ISO 8601")]
+  Parse(DurationParseOptions),
+}
+
+#[derive(Args, Debug, Clone)]
+struct DurationParseOptions {
+  #[clap(help="The duration, in days, hours, minutes, and seconds, like '1d', '1h30m', or '90s'")]
+  duration: String,
+  #[clap(long="output", short='o', default_value=duration::FORMAT_SECS, help="How to print the duration: 'secs', 'millis', or 'iso' for ISO 8601, like 'PT1H30M'")]
+  output: duration::Format,
+}
+
+#[derive(Args, Debug, Clone)]
+struct PathGetOptions {
+  #[clap(help="The path to the value, like 'a.b.0'; empty for the whole document")]
+  path: String,
+  #[clap(long="raw", short='r', help="Print the raw value, instead of JSON")]
+  raw: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+struct PathSetOptions {
+  #[clap(help="The path to the value, like 'a.b.0'")]
+  path: String,
+  #[clap(help="The JSON value to set")]
+  value: String,
+  #[clap(long="parents", short='p', help="Create any missing objects along the path")]
+  parents: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+struct PathDeleteOptions {
+  #[clap(help="The path to the value, like 'a.b.0'")]
+  path: String,
+}
+
+#[derive(Args, Debug, Clone)]
+struct PathCheckOptions {
+  #[clap(help="The path to check, like 'a.b.0'")]
+  path: String,
+}
+
+#[derive(Args, Debug, Clone)]
+struct PathsOptions {
   #[clap(long="socket", name="socket", help="The path to the server socket")]
   path: Option<String>,
-  #[clap(help="The key to the record to delete")]
-  key: String,
+  #[clap(long="depth", default_value="0", help="The maximum depth of paths to list; zero for unlimited")]
+  depth: usize,
+  #[clap(help="The key to list paths under; omit to list the whole store")]
+  key: Option<String>,
+  #[clap(long="print0", short='0', help="Terminate each result with a NUL instead of a newline, as for 'xargs -0'")]
+  print0: bool,
 }
 
 #[derive(Args, Debug, Clone)]
-struct ShutdownOptions {
+struct KeysOptions {
   #[clap(long="socket", name="socket", help="The path to the server socket")]
   path: Option<String>,
+  #[clap(help="The prefix of the keys to list, or with --glob or --regex a pattern which must match entire keys; omit to list every key")]
+  pattern: Option<String>,
+  #[clap(long="regex", short='E', help="Match keys against a regular expression instead of a prefix")]
+  regex: bool,
+  #[clap(long="glob", conflicts_with="regex", help="Match keys against a glob, like 'job:*', instead of a prefix")]
+  glob: bool,
+  #[clap(long="ci", help="Match keys case-insensitively")]
+  ci: bool,
+  #[clap(long="where", name="filter", multiple_occurrences=true, help="Only list keys which pass a filter, like 'value.status == \"running\"' or 'exists value.owner'; repeat to require several")]
+  filters: Vec<String>,
+  #[clap(long="values", help="Print an object of the matching keys and their values instead of the keys alone")]
+  values: bool,
+  #[clap(long="print0", short='0', conflicts_with="values", help="Terminate each result with a NUL instead of a newline, as for 'xargs -0'")]
+  print0: bool,
 }
 
 #[derive(Args, Debug, Clone)]
-struct CleanupOptions {
+struct CountOptions {
   #[clap(long="socket", name="socket", help="The path to the server socket")]
   path: Option<String>,
+  #[clap(help="The prefix of the keys to count, or with --glob or --regex a pattern which must match entire keys; omit to count every key")]
+  pattern: Option<String>,
+  #[clap(long="regex", short='E', help="Match keys against a regular expression instead of a prefix")]
+  regex: bool,
+  #[clap(long="glob", conflicts_with="regex", help="Match keys against a glob, like 'job:*', instead of a prefix")]
+  glob: bool,
 }
 
-fn main() {
-  match cmd(){
-    Ok(_)    => return,
-    Err(err) => {
-      log::logln!("{}", &format!("* * * {}", err).yellow().bold());
-      process::exit(1);
-    },
-  };
+#[derive(Args, Debug, Clone)]
+struct FindOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The key to search under")]
+  key: String,
+  #[clap(long="where", name="predicate", help="The predicate values must match, e.g.: 'status==\"error\"'")]
+  predicate: String,
+  #[clap(long="print0", short='0', help="Terminate each result with a NUL instead of a newline, as for 'xargs -0'")]
+  print0: bool,
 }
 
-fn run_svc<P: AsRef<path::Path>>(opts: &Options, path: P) -> Result<(), error::Error> {
-  let me = env::current_exe()?;
-  if opts.debug {
-    log::logln!(">>> No service running; starting: {}", me.display());
-  }
-  let mut cmd =  process::Command::new(me);
-  if opts.debug {
-    cmd.arg("--debug");
-  }
-  if opts.verbose {
-    cmd.arg("--verbose");
-  }
-  cmd.arg("run").arg("--finalize").arg("--timeout").arg("1m")
-    .stdin(process::Stdio::null())
-    .stdout(if opts.debug { process::Stdio::inherit() } else { process::Stdio::null() })
-    .stderr(if opts.debug { process::Stdio::inherit() } else { process::Stdio::null() })
-    .spawn()?;
-  let mut dur = time::Duration::from_millis(1);
-  for _ in 0..5 {
-    thread::sleep(dur);
-    if path.as_ref().exists() {
-      return Ok(());
-    }
-    dur *= 10 // backoff
-  }
-  Err(error::Error::ServiceError)
+#[derive(Args, Debug, Clone)]
+struct MultiStoreOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(long="lines", help="Read one {\"key\": ..., \"value\": ...} record per line, instead of an object")]
+  lines: bool,
 }
 
-fn cmd() -> Result<(), error::Error> {
-  let opts = Options::parse();
+#[derive(Args, Debug, Clone)]
+struct StoreEnvOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The key to store the variables under")]
+  key: String,
+  #[clap(help="The names of the variables to store", required_unless_present="all")]
+  names: Vec<String>,
+  #[clap(long="all", conflicts_with="names", help="Store every variable")]
+  all: bool,
+  #[clap(long="filter", requires="all", help="Store only the variables whose names match a glob, e.g.: 'CI_*'")]
+  filter: Option<String>,
+}
 
-  match &opts.command {
-    Command::Run(sub)      => cmd_run(&opts, sub),
-    Command::Fetch(sub)    => cmd_get(&opts, sub),
-    Command::Range(sub)    => cmd_range(&opts, sub),
-    Command::Store(sub)    => cmd_set(&opts, sub),
-    Command::Delete(sub)   => cmd_delete(&opts, sub),
-    Command::Shutdown(sub) => cmd_stop(&opts, sub),
-    Command::Cleanup(sub)  => cmd_cleanup(&opts, sub),
-  }?;
+#[derive(Args, Debug, Clone)]
+struct CaptureOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The key to store the result under")]
+  key: String,
+  #[clap(last=true, required=true, help="The command to run; this exits with its status once the result is stored")]
+  command: Vec<String>,
+}
 
-  Ok(())
+#[derive(Args, Debug, Clone)]
+struct StoreOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The key to store the record under")]
+  key: Option<String>,
+  #[clap(long="unflatten", help="Read 'path=value' lines, as produced by flatten, instead of JSON")]
+  unflatten: bool,
+  #[clap(long="dry-run", help="Print the changes the value would make without storing it")]
+  dry_run: bool,
+  #[clap(long="ttl", help="Expire the key after this long, e.g.: '30s'")]
+  ttl: Option<duration::Duration>,
+  #[clap(long="cas", conflicts_with_all=&["key", "dry-run"], help="Store the value under the SHA-256 of its canonical JSON, which is printed; equal values are stored once")]
+  cas: bool,
+  #[clap(long="nx", conflicts_with_all=&["cas", "dry-run", "if-equals"], help="Only store the value if the key or path has none; otherwise exit with status 3")]
+  nx: bool,
+  #[clap(long="if-equals", conflicts_with_all=&["cas", "dry-run"], help="Only store the value if the current one equals this JSON; otherwise print the current value and exit with status 3")]
+  if_equals: Option<String>,
 }
 
-fn cmd_run(opts: &Options, sub: &RunOptions) -> Result<(), error::Error> {
-  let path = socket_path(&sub.path);
-  let sock = rpc::Socket::new(&path);
-  let path = path.as_path();
-  if opts.debug {
-    log::logln!("==> Listening on: {}", path.display());
-  }
+#[derive(Args, Debug, Clone)]
+struct MergeOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The key or path of the value to patch; it is created if need be")]
+  key: String,
+  #[clap(long="quiet", short='q', help="Don't print the patched value")]
+  quiet: bool,
+}
 
-  let data: BTreeMap<String, serde_json::Value> = BTreeMap::new();
-  let (tx, rx) = mpsc::channel();
-  let svcopts = opts.clone();
-  let runopts = sub.clone();
-  thread::spawn(|| service::run(svcopts, runopts, data, sock, rx));
-
-  let listener = UnixListener::bind(path)?;
-  for stream in listener.incoming() {
-    match stream {
-      Ok(stream) => {
-        let tx = tx.clone();
-        let cliopts = opts.clone();
-        thread::spawn(|| client::run(cliopts, stream, tx));
-      }
-      Err(_) => {
-        break;
-      }
-    }
-  }
-  Ok(())
+#[derive(Args, Debug, Clone)]
+struct PatchOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The key or path of the value to patch")]
+  key: String,
+  #[clap(long="quiet", short='q', help="Don't print the patched value")]
+  quiet: bool,
 }
 
-fn cmd_stop(opts: &Options, sub: &ShutdownOptions) -> Result<(), error::Error> {
-  let path = socket_path(&sub.path);
-  if !path.exists() {
-    return Ok(()); // no service running, nothing to stop
-  }
+#[derive(Args, Debug, Clone)]
+struct MergeKeysOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The key of the base value")]
+  base: String,
+  #[clap(help="The key of the value to merge over the base")]
+  over: String,
+  #[clap(long="into", name="dest", help="The key to store the merged value under")]
+  dest: String,
+  #[clap(long="dry-run", help="Print the changes the merge would make without storing it")]
+  dry_run: bool,
+}
 
-  let stream = UnixStream::connect(path)?;
-  let mut rpc = rpc::RPC::new(stream, rpc::Options{debug: opts.debug})?;
+#[derive(Args, Debug, Clone)]
+struct MoveOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(long="parents", short='p', help="Create missing containers at the destination")]
+  parents: bool,
+  #[clap(help="The key or path of the value to move")]
+  src: String,
+  #[clap(help="The key or path to move the value to")]
+  dst: String,
+}
 
-  rpc.write_cmd(&rpc::Operation::new_shutdown())?;
-  rpc.expect_cmd(&[rpc::CMD_OK])?;
+#[derive(Args, Debug, Clone)]
+struct AliasOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(long="delete", short='d', help="Delete the alias instead of creating it")]
+  delete: bool,
+  #[clap(help="The name of the alias; omit to list all aliases")]
+  name: Option<String>,
+  #[clap(help="The key the alias refers to; omit to print the alias' target")]
+  target: Option<String>,
+}
 
-  Ok(())
+#[derive(Args, Debug, Clone)]
+struct DefaultsOptions {
+  #[clap(subcommand)]
+  command: DefaultsCommand,
 }
 
-fn cmd_cleanup(opts: &Options, sub: &CleanupOptions) -> Result<(), error::Error> {
-  let path = socket_path(&sub.path);
-  if opts.debug {
-    log::logln!("Deleting socket: {}", path.to_string_lossy())
-  }
-  if path.exists() {
-    fs::remove_file(&path)?;
-  }
-  Ok(())
+#[derive(Subcommand, Debug, Clone)]
+enum DefaultsCommand {
+  #[clap(name="set", about="Register the template for keys matching a glob, replacing any it had")]
+  Set(DefaultsSetOptions),
+  #[clap(name="rm", about="Remove the template for a glob")]
+  Delete(DefaultsDeleteOptions),
+  #[clap(name="ls", about="List the registered templates")]
+  List(DefaultsListOptions),
 }
 
-fn cmd_get(opts: &Options, sub: &FetchOptions) -> Result<(), error::Error> {
-  let path = socket_path(&sub.path);
-  if !path.exists() {
-    run_svc(opts, &path)?;
-  }
+#[derive(Args, Debug, Clone)]
+struct DefaultsSetOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The glob keys must match, e.g.: 'job:*'")]
+  glob: String,
+  #[clap(help="The JSON template gets of missing matching keys produce, and which first writes are merged into")]
+  template: String,
+}
 
-  let stream = UnixStream::connect(path)?;
-  let mut rpc = rpc::RPC::new(stream, rpc::Options{debug: opts.debug})?;
+#[derive(Args, Debug, Clone)]
+struct DefaultsDeleteOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The glob the template was registered for")]
+  glob: String,
+}
 
-  rpc.write_cmd(&rpc::Operation::new_get(&sub.key))?;
+#[derive(Args, Debug, Clone)]
+struct DefaultsListOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+}
 
-  let rsp = rpc.expect_cmd(&[rpc::CMD_FOUND, rpc::CMD_NONE])?;
-  let data = match rsp.name() {
-    rpc::CMD_NONE  => Err(error::Error::NotFound),
-    rpc::CMD_FOUND => match rsp.data() {
-      Some(data) => Ok(data),
-      None       => Err(error::Error::Malformed),
-    },
-    _ => Err(error::Error::Malformed),
-  }?;
+#[derive(Args, Debug, Clone)]
+struct ProtectOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The keys or paths to protect; omit to list those which are protected")]
+  names: Vec<String>,
+}
 
-  if sub.raw {
-    println!("{}", &jsonpath::print_raw(&serde_json::from_str::<serde_json::Value>(&data)?));
-  }else{
-    println!("{}", data);
-  }
-  Ok(())
+#[derive(Args, Debug, Clone)]
+struct UnprotectOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The keys or paths to unprotect", required=true)]
+  names: Vec<String>,
 }
 
-fn cmd_range(opts: &Options, sub: &RangeOptions) -> Result<(), error::Error> {
-  let path = socket_path(&sub.path);
-  if !path.exists() {
-    run_svc(opts, &path)?;
-  }
+#[derive(Args, Debug, Clone)]
+struct DeleteOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The keys of the records to delete", required_unless_present_any=&["regex", "prefix", "glob"])]
+  keys: Vec<String>,
+  #[clap(long="regex", short='E', conflicts_with_all=&["keys", "prefix", "glob"], help="Delete every key matching a regular expression, which must match entire keys")]
+  regex: Option<String>,
+  #[clap(long="prefix", conflicts_with_all=&["keys", "glob"], help="Delete every key which begins with a prefix, taken literally, and print how many were deleted")]
+  prefix: Option<String>,
+  #[clap(long="glob", conflicts_with="keys", help="Delete every key matching a glob, e.g.: 'job:*', and print how many were deleted")]
+  glob: Option<String>,
+}
 
-  let stream = UnixStream::connect(path)?;
-  let mut rpc = rpc::RPC::new(stream, rpc::Options{debug: opts.debug})?;
+#[derive(Args, Debug, Clone)]
+struct ClearOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(long="force", short='f', help="Don't ask for confirmation, which is otherwise required")]
+  force: bool,
+}
 
-  rpc.write_cmd(&rpc::Operation::new_range(&sub.key))?;
+#[derive(Args, Debug, Clone)]
+struct SwapOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The key or path to store the value under")]
+  key: String,
+  #[clap(long="raw", short='r', help="Print the raw previous value, instead of JSON")]
+  raw: bool,
+}
 
-  let rsp = rpc.expect_cmd(&[rpc::CMD_FOUND, rpc::CMD_NONE])?;
-  let data = match rsp.name() {
-    rpc::CMD_NONE  => Err(error::Error::NotFound),
-    rpc::CMD_FOUND => match rsp.data() {
-      Some(data) => Ok(data),
-      None       => Err(error::Error::Malformed),
-    },
-    _ => Err(error::Error::Malformed),
-  }?;
+#[derive(Args, Debug, Clone)]
+struct TakeOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The key or path of the value to take")]
+  key: String,
+  #[clap(long="raw", short='r', help="Print the raw value, instead of JSON")]
+  raw: bool,
+}
 
-  let value: serde_json::Value = serde_json::from_str(&data)?;
-  match value {
-    serde_json::Value::Array(v) => v.iter().for_each(|e| { println!("{}", jsonpath::print_raw(e)) }),
-    _                           => return Err(error::Error::Malformed),
-  }
+#[derive(Args, Debug, Clone)]
+struct PushOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The key or path of the array")]
+  key: String,
+  #[clap(help="The JSON value to append; read from stdin if omitted")]
+  value: Option<String>,
+}
 
-  Ok(())
+#[derive(Args, Debug, Clone)]
+struct PopOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The key or path of the array")]
+  key: String,
+  #[clap(long="front", help="Remove the first element, instead of the last")]
+  front: bool,
+  #[clap(long="raw", short='r', help="Print the raw value, instead of JSON")]
+  raw: bool,
 }
 
-fn cmd_set(opts: &Options, sub: &StoreOptions) -> Result<(), error::Error> {
-  let path = socket_path(&sub.path);
-  if !path.exists() {
-    run_svc(opts, &path)?;
-  }
+#[derive(Args, Debug, Clone)]
+struct IncrOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The key or path of the number; a missing value counts as 0")]
+  key: String,
+  #[clap(long="by", default_value="1", allow_hyphen_values=true, help="The amount to add or subtract; integers stay integers")]
+  by: serde_json::Number,
+}
 
-  let stream = UnixStream::connect(path)?;
-  let mut rpc = rpc::RPC::new(stream, rpc::Options{debug: opts.debug})?;
+#[derive(Args, Debug, Clone)]
+struct StatOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The key to print statistics for; omit for a report on the whole store")]
+  key: Option<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+struct WithOptions {
+  #[clap(last=true, required=true, help="The command to run; its environment has STRUCTS_SOCKET set to the service's socket")]
+  command: Vec<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+struct WatchOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The keys to watch; globs like 'job:*' watch every matching key", required=true)]
+  keys: Vec<String>,
+  #[clap(long="resume", help="First print the changes made after the one with this sequence number, which the service still remembers")]
+  resume: Option<u64>,
+  #[clap(long="reconnect", help="Reconnect if the connection is lost, resuming after the last change printed")]
+  reconnect: bool,
+  #[clap(long="initial", conflicts_with="resume", help="First print the current value of every watched key which exists")]
+  initial: bool,
+  #[clap(long="once", help="Exit after printing the first change, or with --initial the first current value")]
+  once: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+struct WaitAnyOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The keys to wait on; globs like 'job:*' wait on every matching key", required=true)]
+  keys: Vec<String>,
+  #[clap(long="timeout", help="Give up, exiting with non-zero status, if nothing changes for this long")]
+  timeout: Option<duration::Duration>,
+}
+
+#[derive(Args, Debug, Clone)]
+struct LogOptions {
+  #[clap(subcommand)]
+  command: LogCommand,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum LogCommand {
+  #[clap(name="append", about="Append an entry to a log, dropping the oldest entries beyond its cap")]
+  Append(LogAppendOptions),
+  #[clap(name="tail", about="Print the last entries in a log, optionally following new ones")]
+  Tail(LogTailOptions),
+}
+
+#[derive(Args, Debug, Clone)]
+struct LogAppendOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The key the log is stored under")]
+  key: String,
+  #[clap(help="The JSON value of the entry; read from stdin when omitted")]
+  value: Option<String>,
+  #[clap(long="cap", default_value_t=eventlog::DEFAULT_CAP, help="The most entries the log keeps")]
+  cap: usize,
+}
+
+#[derive(Args, Debug, Clone)]
+struct LogTailOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The key the log is stored under")]
+  key: String,
+  #[clap(short='n', long="lines", default_value_t=10, help="The number of entries to print")]
+  lines: usize,
+  #[clap(short='f', long="follow", help="Print new entries as they are appended")]
+  follow: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+struct MetricOptions {
+  #[clap(subcommand)]
+  command: MetricCommand,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum MetricCommand {
+  #[clap(name="incr", about="Add to a metric, creating a counter if it does not exist")]
+  Incr(MetricIncrOptions),
+  #[clap(name="set", about="Set a metric, creating a gauge if it does not exist")]
+  Set(MetricSetOptions),
+  #[clap(name="dump", about="Print every metric and its value")]
+  Dump(MetricDumpOptions),
+}
+
+#[derive(Args, Debug, Clone)]
+struct MetricIncrOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The name of the metric")]
+  name: String,
+  #[clap(long="by", default_value_t=1.0, allow_hyphen_values=true, help="The amount to add; only gauges may be decreased")]
+  by: f64,
+}
+
+#[derive(Args, Debug, Clone)]
+struct MetricSetOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The name of the metric")]
+  name: String,
+  #[clap(help="The value of the metric", allow_hyphen_values=true)]
+  value: f64,
+}
+
+#[derive(Args, Debug, Clone)]
+struct MetricDumpOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(long="prometheus", help="Print metrics in the Prometheus text exposition format instead of JSON")]
+  prometheus: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+struct TouchOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(long="ttl", help="Give the key this TTL instead of restarting the one it has, e.g.: '30s'")]
+  ttl: Option<duration::Duration>,
+  #[clap(help="The key to touch")]
+  key: String,
+}
+
+#[derive(Args, Debug, Clone)]
+struct ForkOptions {
+  #[clap(long="socket", name="socket", help="The path to the socket of the new service")]
+  path: String,
+  #[clap(long="from", help="The path to the socket of the service to copy")]
+  from: Option<String>,
+  #[clap(last=true, help="Options passed to the new service; see 'run'")]
+  args: Vec<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+struct DumpKeyOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The key to dump")]
+  key: String,
+}
+
+#[derive(Args, Debug, Clone)]
+struct RestoreKeyOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(long="replace", help="Replace the key if it already exists")]
+  replace: bool,
+  #[clap(help="The key to restore the dump to")]
+  key: String,
+}
+
+#[derive(Args, Debug, Clone)]
+struct ChecksumOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+struct VerifyOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(long="against", required_unless_present="snapshot", conflicts_with="snapshot", help="The socket of another service which should hold the same data")]
+  against: Option<String>,
+  #[clap(long="snapshot", help="A file containing the object of keys and values the service should hold, as printed by 'keys --values'")]
+  snapshot: Option<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+struct ImportOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(long="from", help="The Redis server to import from, e.g.: redis://localhost/0")]
+  from: resp::Url,
+  #[clap(long="pattern", default_value="*", help="A glob selecting the keys to import")]
+  pattern: String,
+}
+
+#[derive(Args, Debug, Clone)]
+struct ExportOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(long="to", help="The Redis server to export to, e.g.: redis://localhost/0")]
+  to: resp::Url,
+  #[clap(long="pattern", default_value="*", help="A glob selecting the keys to export")]
+  pattern: String,
+}
+
+#[derive(Args, Debug, Clone)]
+struct ReplayOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The journal file to replay")]
+  journal: String,
+  #[clap(long="encrypt-key-file", help="Decrypt the journal with the hex-encoded 256-bit key in this file; otherwise read from $STRUCTS_ENCRYPT_KEY")]
+  encrypt_key_file: Option<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+struct ShutdownOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+struct CleanupOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+struct HealthOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(long="timeout", default_value="5s", help="How long to wait for the service to respond")]
+  timeout: duration::Duration,
+}
+
+#[derive(Args, Debug, Clone)]
+struct ClientsOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(long="json", help="Print each client as a JSON object")]
+  json: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+struct KillClientOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(help="The id of the client to disconnect")]
+  id: usize,
+}
+
+#[derive(Args, Debug, Clone)]
+struct DoctorOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+  #[clap(long="data", help="The path to the data the service persists, to check that it is usable")]
+  data: Option<String>,
+  #[clap(long="journal", help="The path to the journal the service records, to check that it is usable")]
+  journal: Option<String>,
+  #[clap(long="timeout", default_value="5s", help="How long to wait for the service to respond")]
+  timeout: duration::Duration,
+}
+
+#[derive(Args, Debug, Clone)]
+struct VersionOptions {
+  #[clap(long="socket", name="socket", help="The path to the server socket")]
+  path: Option<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+struct CompletionsOptions {
+  #[clap(value_enum, help="The shell to generate completions for")]
+  shell: clap_complete::Shell,
+}
+
+fn main() {
+  match cmd(){
+    Ok(_)    => (),
+    Err(err) => {
+      log::logln!("{}", &format!("* * * {}", err).yellow().bold());
+      process::exit(exit_status(&err));
+    },
+  }
+}
+
+/// The status to exit with on an error, which distinguishes what scripts
+/// are likely to handle: a missing key, or a conditional write refused.
+fn exit_status(err: &error::Error) -> i32 {
+  match err {
+    error::Error::NotFound    => EXIT_NOT_FOUND,
+    error::Error::DidYouMean(err, _) if matches!(**err, error::Error::NotFound) => EXIT_NOT_FOUND,
+    error::Error::Mismatch(_) => EXIT_CONFLICT,
+    error::Error::Exists(_)   => EXIT_CONFLICT,
+    _                         => 1,
+  }
+}
+
+/// Produce a command which runs this program with the same global options.
+fn svc_command(opts: &Options) -> Result<process::Command, error::Error> {
+  let mut cmd = process::Command::new(env::current_exe()?);
+  if opts.debug {
+    cmd.arg("--debug");
+  }
+  if opts.verbose {
+    cmd.arg("--verbose");
+  }
+  Ok(cmd)
+}
+
+/// Spawn a command in the background, detached from our output unless
+/// debugging.
+fn spawn_detached(opts: &Options, cmd: &mut process::Command) -> Result<(), error::Error> {
+  cmd
+    .stdin(process::Stdio::null())
+    .stdout(if opts.debug { process::Stdio::inherit() } else { process::Stdio::null() })
+    .stderr(if opts.debug { process::Stdio::inherit() } else { process::Stdio::null() })
+    .spawn()?;
+  Ok(())
+}
+
+/// Start a service on the provided socket, unless another client beats us
+/// to it. Clients racing to start a service take turns holding a lock next
+/// to the socket, so exactly one of them starts it and the others find it
+/// running once they get the lock.
+fn run_svc<P: AsRef<path::Path>>(opts: &Options, path: P) -> Result<(), error::Error> {
+  let path = path.as_ref();
+  let _lock = lock_file(&lock_path(path))?;
+  if connect_running(opts, path)?.is_some() {
+    return Ok(()); // somebody else started it while we waited
+  }
+  if opts.debug {
+    log::logln!(">>> No service running; starting: {}", env::current_exe()?.display());
+  }
+  spawn_detached(opts, svc_command(opts)?.arg("run").arg("--finalize").arg("--timeout").arg("1m").arg("--socket").arg(path))?;
+  await_svc(path)
+}
+
+/// The path of the lock held while starting a service on a socket.
+fn lock_path(path: &path::Path) -> path::PathBuf {
+  let mut name = path.as_os_str().to_owned();
+  name.push(".lock");
+  path::PathBuf::from(name)
+}
+
+/// Take an exclusive lock on a file, creating it if necessary and waiting
+/// for anybody else holding it. The lock is released when the file is
+/// closed, including when its owner exits.
+fn lock_file(path: &path::Path) -> Result<fs::File, error::Error> {
+  let file = match fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path) {
+    Ok(file) => file,
+    Err(err) if err.kind() == io::ErrorKind::PermissionDenied => fs::File::open(path)?, // another user's lock; reading is enough
+    Err(err) => return Err(err.into()),
+  };
+  if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+    return Err(io::Error::last_os_error().into());
+  }
+  Ok(file)
+}
+
+/// Wait for a service we started to begin listening.
+fn await_svc<P: AsRef<path::Path>>(path: P) -> Result<(), error::Error> {
+  let mut dur = time::Duration::from_millis(1);
+  for _ in 0..5 {
+    thread::sleep(dur);
+    if path.as_ref().exists() {
+      return Ok(());
+    }
+    dur *= 10 // backoff
+  }
+  Err(error::Error::ServiceError)
+}
+
+/// The options for talking to the service, as the command line sets them.
+fn rpc_options(opts: &Options) -> rpc::Options {
+  rpc::Options{debug: opts.debug, sequence: opts.sequence.clone(), name: opts.client_name.clone()}
+}
+
+/// Connect to the service listening on the provided socket, starting it
+/// first if it is not running. A socket left behind by a service which did
+/// not exit cleanly is removed and the service is started in its place.
+fn connect(opts: &Options, path: &path::Path) -> Result<UnixStream, error::Error> {
+  match connect_running(opts, path)? {
+    Some(stream) => Ok(stream),
+    None         => {
+      run_svc(opts, path)?;
+      Ok(UnixStream::connect(path)?)
+    },
+  }
+}
+
+/// Connect to the service listening on the provided socket if it is
+/// running. A stale socket is removed.
+fn connect_running(opts: &Options, path: &path::Path) -> Result<Option<UnixStream>, error::Error> {
+  if !path.exists() {
+    return Ok(None);
+  }
+  match UnixStream::connect(path) {
+    Ok(stream) => Ok(Some(stream)),
+    Err(err)   => match err.kind() {
+      io::ErrorKind::ConnectionRefused => {
+        if opts.debug {
+          log::logln!(">>> Removing stale socket: {}", path.display());
+        }
+        match fs::remove_file(path) {
+          Ok(_)                                             => Ok(None),
+          Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None), // someone else got to it first
+          Err(err)                                          => Err(err.into()),
+        }
+      },
+      _ => Err(err.into()),
+    },
+  }
+}
+
+/// Exit once a client command has been running for longer than allowed,
+/// however far it got: connecting, starting a service, or waiting for a
+/// response.
+fn exit_on_timeout(timeout: time::Duration) {
+  thread::spawn(move || {
+    thread::sleep(timeout);
+    log::logln!("{}", &format!("* * * Timed out after {:?}", timeout).yellow().bold());
+    process::exit(EXIT_TIMEOUT);
+  });
+}
+
+fn cmd() -> Result<(), error::Error> {
+  let opts = Options::parse();
+
+  match (&opts.command, &opts.op_timeout) {
+    (Command::Run(_) | Command::Up(_), _) => {}, // services run until they are stopped
+    (_, Some(timeout))                    => exit_on_timeout(timeout.duration()),
+    (_, None)                             => {},
+  }
+  match &opts.command {
+    Command::Run(sub)         => cmd_run(&opts, sub),
+    Command::Up(sub)          => cmd_up(&opts, sub),
+    Command::Fetch(sub)       => cmd_get(&opts, sub),
+    Command::Range(sub)       => cmd_range(&opts, sub),
+    Command::Flatten(sub)     => cmd_flatten(&opts, sub),
+    Command::Paths(sub)       => cmd_paths(&opts, sub),
+    Command::Path(sub)        => cmd_path(sub),
+    Command::Duration(sub)    => cmd_duration(sub),
+    Command::Keys(sub)        => cmd_keys(&opts, sub),
+    Command::Count(sub)       => cmd_count(&opts, sub),
+    Command::Find(sub)        => cmd_find(&opts, sub),
+    Command::Store(sub)       => cmd_set(&opts, sub),
+    Command::MultiStore(sub)  => cmd_mset(&opts, sub),
+    Command::StoreEnv(sub)    => cmd_set_env(&opts, sub),
+    Command::Capture(sub)     => cmd_capture(&opts, sub),
+    Command::Merge(sub)       => cmd_merge(&opts, sub),
+    Command::Patch(sub)       => cmd_patch(&opts, sub),
+    Command::MergeKeys(sub)   => cmd_merge_keys(&opts, sub),
+    Command::Move(sub)        => cmd_move(&opts, sub),
+    Command::Alias(sub)       => cmd_alias(&opts, sub),
+    Command::Defaults(sub)    => cmd_defaults(&opts, sub),
+    Command::Protect(sub)     => cmd_protect(&opts, sub),
+    Command::Unprotect(sub)   => cmd_unprotect(&opts, sub),
+    Command::Clear(sub)       => cmd_clear(&opts, sub),
+    Command::Delete(sub)      => cmd_delete(&opts, sub),
+    Command::Take(sub)        => cmd_take(&opts, sub),
+    Command::Push(sub)        => cmd_push(&opts, sub),
+    Command::Pop(sub)         => cmd_pop(&opts, sub),
+    Command::Incr(sub)        => cmd_incr(&opts, sub, false),
+    Command::Decr(sub)        => cmd_incr(&opts, sub, true),
+    Command::Swap(sub)        => cmd_swap(&opts, sub),
+    Command::Watch(sub)       => cmd_watch(&opts, sub),
+    Command::WaitAny(sub)     => cmd_wait_any(&opts, sub),
+    Command::Log(sub)         => cmd_log(&opts, sub),
+    Command::Metric(sub)      => cmd_metric(&opts, sub),
+    Command::Touch(sub)       => cmd_touch(&opts, sub),
+    Command::Stat(sub)        => cmd_stat(&opts, sub),
+    Command::With(sub)        => cmd_with(&opts, sub),
+    Command::Fork(sub)        => cmd_fork(&opts, sub),
+    Command::DumpKey(sub)     => cmd_dump_key(&opts, sub),
+    Command::RestoreKey(sub)  => cmd_restore_key(&opts, sub),
+    Command::Checksum(sub)    => cmd_checksum(&opts, sub),
+    Command::Verify(sub)      => cmd_verify(&opts, sub),
+    Command::Import(sub)      => cmd_import(&opts, sub),
+    Command::Export(sub)      => cmd_export(&opts, sub),
+    Command::Replay(sub)      => cmd_replay(&opts, sub),
+    Command::Shutdown(sub)    => cmd_stop(&opts, sub),
+    Command::Cleanup(sub)     => cmd_cleanup(&opts, sub),
+    Command::Health(sub)      => cmd_health(&opts, sub),
+    Command::Clients(sub)     => cmd_clients(&opts, sub),
+    Command::KillClient(sub)  => cmd_kill_client(&opts, sub),
+    Command::Doctor(sub)      => cmd_doctor(&opts, sub),
+    Command::Version(sub)     => cmd_version(&opts, sub),
+    Command::Completions(sub) => cmd_completions(&opts, sub),
+  }?;
+
+  Ok(())
+}
+
+fn cmd_run(opts: &Options, sub: &RunOptions) -> Result<(), error::Error> {
+  let policy = auth::Policy::new(&sub.allow_uid, &sub.allow_gid)?;
+  let cipher = crypto::Cipher::load(sub.encrypt_key_file.as_ref().map(path::Path::new))?;
+  let mut data = storage::new(&sub.backend, sub.data.as_ref().map(path::Path::new), cipher.clone())?;
+  if !sub.partition.is_empty() {
+    let mut parts = Vec::new();
+    for part in &sub.partition {
+      parts.push((part.prefix.clone(), part.open(cipher.clone())?));
+    }
+    data = Box::new(storage::Partitioned::new(data, parts));
+  }
+  if let Some(dir) = &sub.load {
+    for (key, value) in service::load_dir(dir)? {
+      data.set(&key, value)?;
+    }
+  }
+  if sub.stdio {
+    return run_stdio(opts, sub, policy, data, cipher);
+  }
+  let path = match &sub.path {
+    None if sub.ephemeral => ephemeral_path(),
+    path                  => socket_path(path),
+  };
+  if connect_running(opts, &path)?.is_some() {
+    return Err(io::Error::new(io::ErrorKind::AddrInUse, "A service is already running on this socket").into());
+  }
+  let sock = rpc::Socket::new(&path);
+  let path = path.as_path();
+  if opts.debug {
+    log::logln!("==> Listening on: {}", path.display());
+  }
+
+  let (tx, rx) = mpsc::channel();
+  let inflight = rpc::Inflight::new();
+  let svcopts = opts.clone();
+  let runopts = sub.clone();
+  let svcflight = inflight.clone();
+  thread::spawn(|| service::run(svcopts, runopts, data, cipher, sock, rx, svcflight));
+
+  // connections are served by tasks on a small runtime rather than a
+  // thread each, so many idle clients don't exhaust threads
+  let runtime = tokio::runtime::Runtime::new()?;
+  runtime.block_on(async {
+    let listener = tokio::net::UnixListener::bind(path)?;
+    if !policy.is_open() {
+      // access is enforced per-connection, so anyone may reach the socket
+      fs::set_permissions(path, fs::Permissions::from_mode(0o666))?;
+    }
+    if let Err(err) = notify::notify(notify::READY) {
+      log::logln!("{}", &format!("* * * Could not notify readiness: {}", err).yellow().bold());
+    }
+    if sub.ephemeral {
+      println!("{}", path.display()); // whoever started us is waiting for this
+    }
+
+    let config = client::Config{ policy, max_line: sub.max_line, protocol: sub.protocol, limiter: sub.rate_limit.map(ratelimit::Limiter::new), registry: clients::Registry::new() };
+    let mut clients: usize = 0;
+    let active = Arc::new(AtomicUsize::new(0));
+    while let Ok((stream, _)) = listener.accept().await {
+      if let Some(max) = sub.max_clients {
+        if active.load(Ordering::SeqCst) >= max {
+          tokio::spawn(client::reject(opts.clone(), stream, sub.protocol));
+          continue;
+        }
+      }
+      let tx = tx.clone();
+      let cliopts = opts.clone();
+      let inflight = inflight.clone();
+      let config = config.clone();
+      let active = active.clone();
+      clients += 1;
+      let id = clients;
+      active.fetch_add(1, Ordering::SeqCst);
+      tokio::spawn(async move {
+        client::run(cliopts, id, stream, config, tx, inflight).await;
+        active.fetch_sub(1, Ordering::SeqCst);
+      });
+    }
+    Ok(())
+  })
+}
+
+/// Run the service for a single client speaking over our stdin and stdout,
+/// which is whoever started us; there is no socket.
+fn run_stdio(opts: &Options, sub: &RunOptions, policy: auth::Policy, data: Box<dyn storage::Store>, cipher: Option<crypto::Cipher>) -> Result<(), error::Error> {
+  let (tx, rx) = mpsc::channel();
+  let inflight = rpc::Inflight::new();
+  let svcopts = opts.clone();
+  let runopts = sub.clone();
+  let svcflight = inflight.clone();
+  thread::spawn(|| service::run(svcopts, runopts, data, cipher, rpc::Socket::none(), rx, svcflight));
+
+  let runtime = tokio::runtime::Runtime::new()?;
+  let config = client::Config{ policy, max_line: sub.max_line, protocol: sub.protocol, limiter: sub.rate_limit.map(ratelimit::Limiter::new), registry: clients::Registry::new() };
+  runtime.block_on(client::run_stdio(opts.clone(), config, tx, inflight));
+  Ok(())
+}
+
+fn cmd_up(opts: &Options, sub: &UpOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  if connect_running(opts, &path)?.is_some() {
+    return Err(io::Error::new(io::ErrorKind::AddrInUse, "A service is already running on this socket").into());
+  }
+  let run = || -> Result<process::Command, error::Error> {
+    let mut cmd = svc_command(opts)?;
+    cmd.arg("run").arg("--socket").arg(&path).args(&sub.args);
+    Ok(cmd)
+  };
+
+  if !sub.foreground {
+    if sub.supervise {
+      let mut cmd = svc_command(opts)?;
+      cmd.arg("up").arg("--foreground").arg("--supervise").arg("--socket").arg(&path).arg("--").args(&sub.args);
+      spawn_detached(opts, &mut cmd)?;
+    } else {
+      spawn_detached(opts, &mut run()?)?;
+    }
+    return await_svc(&path);
+  }
+
+  if sub.supervise {
+    supervise::supervise(opts, run)
+  } else if run()?.status()?.success() {
+    Ok(())
+  } else {
+    Err(error::Error::ServiceError)
+  }
+}
+
+fn cmd_checksum(opts: &Options, sub: &ChecksumOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+  println!("{}", checksum(&mut rpc)?);
+  Ok(())
+}
+
+fn cmd_verify(opts: &Options, sub: &VerifyOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  let mut other = match &sub.against {
+    Some(against) => {
+      let stream = connect(opts, &path::PathBuf::from(against))?;
+      Some(rpc::RPC::new(stream, rpc_options(opts))?)
+    },
+    None => None,
+  };
+  let expect: Option<BTreeMap<String, serde_json::Value>> = match &sub.snapshot {
+    Some(snapshot) => Some(serde_json::from_str(&fs::read_to_string(snapshot)?)?),
+    None           => None,
+  };
+
+  // compare checksums first, and only transfer the data to explain a mismatch
+  let sum = checksum(&mut rpc)?;
+  let expect_sum = match (&mut other, &expect) {
+    (Some(other), _)  => checksum(other)?,
+    (_, Some(expect)) => cas::checksum(expect),
+    (None, None)      => return Err(error::Error::Malformed),
+  };
+  if sum == expect_sum {
+    println!("{}", sum);
+    return Ok(());
+  }
+
+  let expect = match (&mut other, expect) {
+    (Some(other), _)  => fetch_op(other, &rpc::Operation::new_keys("", false, true))?,
+    (_, Some(expect)) => serde_json::to_string(&expect)?,
+    (None, None)      => return Err(error::Error::Malformed),
+  };
+  let expect: serde_json::Value = serde_json::from_str(&expect)?;
+  let actual: serde_json::Value = serde_json::from_str(&fetch_op(&mut rpc, &rpc::Operation::new_keys("", false, true))?)?;
+  let changes = diff::diff("", Some(&expect), Some(&actual));
+  print_change_list(&changes);
+  Err(error::Error::Diverged(changes.len()))
+}
+
+fn checksum(rpc: &mut rpc::RPC) -> Result<String, error::Error> {
+  rpc.write_cmd(&rpc::Operation::new_checksum())?;
+  let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_ERROR])?;
+  match rsp.name() {
+    rpc::CMD_OK => match rsp.args().first() {
+      Some(sum) => Ok(sum.to_owned()),
+      None      => Err(error::Error::Malformed),
+    },
+    _ => Err(error::Error::RemoteError(rsp.data().clone())),
+  }
+}
+
+fn cmd_import(opts: &Options, sub: &ImportOptions) -> Result<(), error::Error> {
+  let mut redis = resp::Conn::connect(&sub.from)?;
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  for key in redis.scan(&sub.pattern)? {
+    let skip = |why: &str| {
+      log::logln!("{}", &format!("* * * Skipping {}: {}", String::from_utf8_lossy(&key), why).yellow().bold());
+    };
+    // keys must not read as paths
+    let name = match String::from_utf8(key.clone()) {
+      Ok(name) if !name.is_empty() && jsonpath::Path::new(&name).next() == (Some(name.as_str()), None) => name,
+      _ => {
+        skip("the key cannot be represented");
+        continue;
+      },
+    };
+    let data = match redis.get(&key) {
+      Ok(Some(data)) => data,
+      Ok(None)       => continue, // deleted since it was listed
+      Err(error::Error::RemoteError(msg)) => {
+        skip(msg.as_deref().unwrap_or("not a string")); // hashes, lists, and so on
+        continue;
+      },
+      Err(err) => return Err(err),
+    };
+    let data = match String::from_utf8(data) {
+      Ok(data) => data,
+      Err(_)   => {
+        skip("the value is not UTF-8");
+        continue;
+      },
+    };
+    // values which are not JSON are imported as strings
+    let value = serde_json::from_str::<serde_json::Value>(&data).unwrap_or(serde_json::Value::String(data));
+    rpc.write_cmd(&rpc::Operation::new_set(&name, &value.to_string()))?;
+    let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_ERROR])?;
+    match rsp.name() {
+      rpc::CMD_OK => println!("{}", name),
+      _           => skip(&error::Error::RemoteError(rsp.data().clone()).to_string()),
+    }
+  }
+  Ok(())
+}
+
+fn cmd_export(opts: &Options, sub: &ExportOptions) -> Result<(), error::Error> {
+  let pattern = match pattern::Pattern::glob(&sub.pattern) {
+    Ok(pattern) => pattern,
+    Err(err)    => return Err(error::Error::RemoteError(Some(err))),
+  };
+  let mut redis = resp::Conn::connect(&sub.to)?;
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  let values = match serde_json::from_str(&fetch_op(&mut rpc, &rpc::Operation::new_keys("", false, true))?)? {
+    serde_json::Value::Object(values) => values,
+    _                                 => return Err(error::Error::Malformed),
+  };
+  for (key, value) in values.iter().filter(|(k, _)| { pattern.is_match(k) }) {
+    // strings are exported as is, for the benefit of tools which expect them
+    match value {
+      serde_json::Value::String(s) => redis.set(key.as_bytes(), s.as_bytes())?,
+      value                        => redis.set(key.as_bytes(), value.to_string().as_bytes())?,
+    }
+    println!("{}", key);
+  }
+  Ok(())
+}
+
+fn cmd_replay(opts: &Options, sub: &ReplayOptions) -> Result<(), error::Error> {
+  let cipher = crypto::Cipher::load(sub.encrypt_key_file.as_ref().map(path::Path::new))?;
+  let ops = journal::read(&sub.journal, cipher.as_ref())?;
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  let mut n = 0;
+  for op in &ops {
+    if op.name() == rpc::CMD_SHUTDOWN {
+      continue; // don't stop the service we're replaying against
+    }
+    rpc.write_cmd(op)?;
+    let rsp = match rpc.read_cmd()? {
+      Some(rsp) => rsp,
+      None      => return Err(error::Error::Unexpected),
+    };
+    if rsp.name() == rpc::CMD_ERROR {
+      log::logln!("{}", &format!("* * * {}: {}", op.name(), error::Error::RemoteError(rsp.data().clone())).yellow().bold());
+    }
+    n += 1;
+  }
+
+  if opts.debug || opts.verbose {
+    log::logln!(">>> Replayed {} operations", n);
+  }
+  Ok(())
+}
+
+fn cmd_stop(opts: &Options, sub: &ShutdownOptions) -> Result<(), error::Error> {
+  stop_svc(opts, &socket_path(&sub.path))
+}
+
+fn stop_svc(opts: &Options, path: &path::Path) -> Result<(), error::Error> {
+  let stream = match connect_running(opts, path)? {
+    Some(stream) => stream,
+    None         => return Ok(()), // no service running, nothing to stop
+  };
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  rpc.write_cmd(&rpc::Operation::new_shutdown())?;
+  rpc.expect_cmd(&[rpc::CMD_OK])?;
+
+  Ok(())
+}
+
+fn cmd_cleanup(opts: &Options, sub: &CleanupOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  if opts.debug {
+    log::logln!("Deleting socket: {}", path.to_string_lossy())
+  }
+  if path.exists() {
+    fs::remove_file(&path)?;
+  }
+  Ok(())
+}
+
+fn cmd_health(opts: &Options, sub: &HealthOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = match connect_running(opts, &path)? {
+    Some(stream) => stream,
+    None         => return Err(io::Error::new(io::ErrorKind::NotFound, "No service is running on this socket").into()), // don't start one just to check it
+  };
+  stream.set_read_timeout(Some(sub.timeout.duration()))?;
+  stream.set_write_timeout(Some(sub.timeout.duration()))?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  rpc.write_cmd(&rpc::Operation::new_health())?;
+  rpc.expect_cmd(&[rpc::CMD_OK])?;
+  println!("ok");
+  Ok(())
+}
+
+fn cmd_clients(opts: &Options, sub: &ClientsOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = match connect_running(opts, &path)? {
+    Some(stream) => stream,
+    None         => return Err(io::Error::new(io::ErrorKind::NotFound, "No service is running on this socket").into()),
+  };
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  let list: Vec<clients::Info> = serde_json::from_str(&fetch_op(&mut rpc, &rpc::Operation::new_clients())?)?;
+  for client in &list {
+    if sub.json {
+      println!("{}", serde_json::to_string(client)?);
+    }else{
+      let pid = client.pid.map(|e| { e.to_string() }).unwrap_or("-".to_string());
+      println!("{}\t{}\tpid={}\tops={}\tidle={:.1}s", client.id, client.name.as_deref().unwrap_or("-"), pid, client.ops, client.idle_ms as f64 / 1000.0);
+    }
+  }
+  Ok(())
+}
+
+fn cmd_kill_client(opts: &Options, sub: &KillClientOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = match connect_running(opts, &path)? {
+    Some(stream) => stream,
+    None         => return Err(io::Error::new(io::ErrorKind::NotFound, "No service is running on this socket").into()),
+  };
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  rpc.write_cmd(&rpc::Operation::new_kill_client(sub.id))?;
+  let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_NONE, rpc::CMD_ERROR])?;
+  match rsp.name() {
+    rpc::CMD_OK    => Ok(()),
+    rpc::CMD_NONE  => Err(error::Error::NotFound),
+    rpc::CMD_ERROR => Err(error::Error::RemoteError(rsp.data().clone())),
+    _              => Err(error::Error::Unexpected),
+  }
+}
+
+fn cmd_version(opts: &Options, sub: &VersionOptions) -> Result<(), error::Error> {
+  println!("client: {} (protocol {})", VERSION, rpc::PROTOCOL);
+  let path = socket_path(&sub.path);
+  let stream = match connect_running(opts, &path)? {
+    Some(stream) => stream,
+    None         => return Ok(()), // no service running, don't start one just to ask
+  };
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  rpc.write_cmd(&rpc::Operation::new_version())?;
+  let (version, protocol) = match rpc.read_cmd() {
+    Ok(Some(rsp)) if rsp.name() == rpc::CMD_OK && rsp.args().len() == 2 => (rsp.args()[0].to_owned(), rsp.args()[1].parse::<usize>().ok()),
+    _ => ("unknown".to_string(), None), // services which predate this command don't know it
+  };
+  match protocol {
+    Some(protocol) => println!("service: {} (protocol {})", version, protocol),
+    None           => println!("service: {}", version),
+  };
+
+  if protocol != Some(rpc::PROTOCOL) {
+    log::logln!("{}", &"* * * The running service is not compatible with this client; restart it with: structs stop".yellow().bold());
+  }else if version != VERSION {
+    log::logln!("{}", &"* * * The running service is a different version than this client".yellow().bold());
+  }
+  Ok(())
+}
+
+fn cmd_doctor(_opts: &Options, sub: &DoctorOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let mut findings: Vec<doctor::Finding> = Vec::new();
+  let mut files: Vec<&path::Path> = vec![path.as_path()];
+
+  let (finding, stream) = doctor::check_socket(&path);
+  findings.push(finding);
+  if let Some(stream) = stream {
+    findings.push(doctor::check_version(stream, sub.timeout.duration(), VERSION));
+  }
+  if let Some(data) = &sub.data {
+    findings.push(doctor::check_file("data", path::Path::new(data)));
+    files.push(path::Path::new(data));
+  }
+  if let Some(journal) = &sub.journal {
+    findings.push(doctor::check_file("journal", path::Path::new(journal)));
+    files.push(path::Path::new(journal));
+  }
+  findings.push(doctor::check_clock(time::SystemTime::now(), &files));
+
+  for finding in &findings {
+    finding.print();
+  }
+  match findings.iter().filter(|e| { e.status == doctor::Status::Problem }).count() {
+    0 => Ok(()),
+    n => Err(error::Error::Unhealthy(n)),
+  }
+}
+
+fn cmd_completions(_opts: &Options, sub: &CompletionsOptions) -> Result<(), error::Error> {
+  let name = env!("CARGO_BIN_NAME");
+  clap_complete::generate(sub.shell, &mut Options::command().name(name), name, &mut io::stdout());
+  Ok(())
+}
+
+fn cmd_get(opts: &Options, sub: &FetchOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  if !sub.more.is_empty() {
+    return get_many(&mut rpc, sub);
+  }
+  let required = sub.required || env_flag(REQUIRED_ENV);
+  let op = rpc::Operation::new_get(&sub.key)
+    .with_flag(rpc::FLAG_CI, sub.ci)
+    .with_flag(rpc::FLAG_REQUIRED, required)
+    .with_option(rpc::OPTION_AS_OF, sub.as_of.map(|e| { e.millis().to_string() }).as_deref());
+  let suggest = (sub.suggest || env_flag(SUGGEST_ENV)) && sub.as_of.is_none();
+  let data = match fetch_op(&mut rpc, &op) {
+    Ok(data) if sub.exists_format                    => serde_json::json!({"exists": true, "value": serde_json::from_str::<serde_json::Value>(&data)?}).to_string(),
+    Err(error::Error::NotFound) if sub.exists_format => serde_json::json!({"exists": false}).to_string(),
+    Err(err @ (error::Error::NotFound | error::Error::RemoteError(_))) if suggest => { // the latter explains what is missing, with --required
+      let names: Vec<String> = serde_json::from_str(&fetch_op(&mut rpc, &rpc::Operation::new_suggest(&sub.key))?)?;
+      return match names.is_empty() {
+        true  => Err(err),
+        false => Err(error::Error::DidYouMean(Box::new(err), names)),
+      };
+    },
+    res => res?,
+  };
+  let data = match &sub.extract {
+    Some(extract) => {
+      let value: serde_json::Value = serde_json::from_str(&data)?;
+      let found = jsonpath::select(&value, extract);
+      match (jsonpath::is_wildcard(extract), found.first()) {
+        (true, _)           => serde_json::Value::Array(found.into_iter().cloned().collect()).to_string(),
+        (false, Some(part)) => part.to_string(),
+        (false, None)       => return Err(error::Error::NotFound),
+      }
+    },
+    None => data,
+  };
+
+  let out = if let Some(sep) = &sub.join {
+    match serde_json::from_str::<serde_json::Value>(&data)? {
+      serde_json::Value::Array(list) => format!("{}\n", list.iter().map(jsonpath::print_raw).collect::<Vec<String>>().join(sep)).into_bytes(),
+      value                          => format!("{}\n", jsonpath::print_raw(&value)).into_bytes(),
+    }
+  } else if sub.binary {
+    match serde_json::from_str::<serde_json::Value>(&data)? {
+      serde_json::Value::String(text) => match decode_base64(&text) {
+        Some(out) => out,
+        None      => return Err(error::Error::InvalidValue(format!("{} is not base64", sub.key))),
+      },
+      _ => return Err(error::Error::InvalidValue(format!("{} is not a string", sub.key))),
+    }
+  } else if sub.raw {
+    format!("{}\n", &jsonpath::print_raw(&serde_json::from_str::<serde_json::Value>(&data)?)).into_bytes()
+  } else if sub.pretty {
+    format!("{}\n", serde_json::to_string_pretty(&serde_json::from_str::<serde_json::Value>(&data)?)?).into_bytes()
+  } else {
+    format!("{}\n", data).into_bytes()
+  };
+  match &sub.output {
+    Some(dst) => write_atomic(path::Path::new(dst), &out)?,
+    None      => io::stdout().write_all(&out)?,
+  }
+  Ok(())
+}
+
+/// Fetch several keys in a single request and print their values: as an
+/// object of those which were found, or one per line. Any key not found is
+/// reported, once the rest are printed, by failing with NotFound.
+fn get_many(rpc: &mut rpc::RPC, sub: &FetchOptions) -> Result<(), error::Error> {
+  let names: Vec<&str> = std::iter::once(&sub.key).chain(sub.more.iter()).map(|e| { e.as_str() }).collect();
+  let found: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&fetch_op(rpc, &rpc::Operation::new_mget(&names))?)?;
+  let format = |value: &serde_json::Value| -> Result<String, error::Error> {
+    Ok(if sub.raw {
+      jsonpath::print_raw(value)
+    } else if sub.pretty {
+      serde_json::to_string_pretty(value)?
+    } else {
+      value.to_string()
+    })
+  };
+  let complete = names.iter().all(|e| { found.contains_key(*e) });
+  if sub.lines {
+    for name in &names {
+      match found.get(*name) {
+        Some(value) => println!("{}", format(value)?),
+        None        => println!(),
+      }
+    }
+  } else {
+    println!("{}", format(&serde_json::Value::Object(found))?);
+  }
+  match complete {
+    true  => Ok(()),
+    false => Err(error::Error::NotFound),
+  }
+}
+
+/// Write a file by writing a temporary file beside it and renaming it into
+/// place, so readers never see a partial file, even if we are interrupted.
+pub fn write_atomic(dst: &path::Path, data: &[u8]) -> Result<(), error::Error> {
+  let name = match dst.file_name() {
+    Some(name) => name.to_string_lossy(),
+    None       => return Err(error::Error::InvalidValue(format!("{} is not a file", dst.display()))),
+  };
+  let tmp = dst.with_file_name(format!(".{}.{}.tmp", name, process::id()));
+  let _ = fs::remove_file(&tmp); // left over from a crash, or planted; never written through
+  let res = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&tmp).and_then(|mut file| {
+    file.write_all(data)?;
+    file.sync_all()
+  }).and_then(|_| {
+    fs::rename(&tmp, dst)
+  });
+  if let Err(err) = res {
+    let _ = fs::remove_file(&tmp);
+    return Err(err.into());
+  }
+  Ok(())
+}
+
+/// Decode base64, in the standard or the URL-safe alphabet but not a mix
+/// of the two, with or without padding, ignoring whitespace. Text which no
+/// encoder would produce is rejected: data after the padding, a length no
+/// number of bytes encodes to, or stray bits in the last character.
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+  let text: Vec<u8> = text.bytes().filter(|e| { !e.is_ascii_whitespace() }).collect();
+  let data = match text.iter().position(|&e| { e == b'=' }) {
+    Some(n) => &text[..n],
+    None    => &text[..],
+  };
+  let pad = text.len() - data.len();
+  if text[data.len()..].iter().any(|&e| { e != b'=' }) || pad > 2 || (pad > 0 && !text.len().is_multiple_of(4)) || data.len() % 4 == 1 {
+    return None;
+  }
+  let mut out = Vec::with_capacity(data.len() * 3 / 4);
+  let (mut acc, mut bits) = (0u32, 0);
+  let mut url_safe = None; // which alphabet, once a character tells
+  for &c in data {
+    let n = match c {
+      b'A'..=b'Z' => c - b'A',
+      b'a'..=b'z' => c - b'a' + 26,
+      b'0'..=b'9' => c - b'0' + 52,
+      b'+' | b'/' if url_safe != Some(true) => {
+        url_safe = Some(false);
+        if c == b'+' { 62 } else { 63 }
+      },
+      b'-' | b'_' if url_safe != Some(false) => {
+        url_safe = Some(true);
+        if c == b'-' { 62 } else { 63 }
+      },
+      _ => return None,
+    };
+    acc = (acc << 6) | n as u32;
+    bits += 6;
+    if bits >= 8 {
+      bits -= 8;
+      out.push((acc >> bits) as u8);
+    }
+  }
+  if acc & ((1 << bits) - 1) != 0 {
+    return None;
+  }
+  Some(out)
+}
+
+fn cmd_flatten(opts: &Options, sub: &FlattenOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  let value: serde_json::Value = serde_json::from_str(&fetch(&mut rpc, &sub.key)?)?;
+  print!("{}", jsonpath::print_flat(&value));
+  Ok(())
+}
+
+fn cmd_range(opts: &Options, sub: &RangeOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  let mut exec = sub.exec.as_ref().map(|e| { Executor::new(e, sub.parallel) });
+  // projections are fetched on a connection of their own, since this one is
+  // busy streaming the range
+  let mut project = match &sub.project {
+    Some(_) => Some(rpc::RPC::new(connect(opts, &path)?, rpc_options(opts))?),
+    None    => None,
+  };
+
+  rpc.write_cmd(&rpc::Operation::new_range_stream(&sub.key))?;
+  loop {
+    let rsp = rpc.expect_cmd(&[rpc::CMD_MORE, rpc::CMD_OK, rpc::CMD_NONE])?;
+    let list: Vec<serde_json::Value> = match rsp.name() {
+      rpc::CMD_NONE => return Err(error::Error::NotFound),
+      rpc::CMD_OK   => break,
+      rpc::CMD_MORE => match rsp.data() {
+        Some(data) => serde_json::from_str(data)?,
+        None       => return Err(error::Error::Malformed),
+      },
+      _ => return Err(error::Error::Malformed),
+    };
+    let exec = match &mut exec {
+      Some(exec) => exec,
+      None       => {
+        print_list(&list, sub.print0);
+        continue;
+      },
+    };
+    for e in list {
+      let arg = match (&sub.project, &mut project) {
+        (Some(project), Some(rpc)) => {
+          let name = jsonpath::join(&jsonpath::join(&sub.key, &jsonpath::print_raw(&e)), project);
+          jsonpath::print_raw(&serde_json::from_str(&fetch(rpc, &name)?)?)
+        },
+        _ => jsonpath::print_raw(&e),
+      };
+      exec.run(arg)?;
+    }
+  }
+  match exec {
+    Some(exec) => exec.finish(),
+    None       => Ok(()),
+  }
+}
+
+/// Runs a command for each element of a range, as many at a time as
+/// allowed. The element, or the value projected from it, is passed to the
+/// shell as a positional parameter which '{}' refers to, so it is never
+/// re-parsed.
+struct Executor {
+  script: String,
+  parallel: usize,
+  running: Vec<process::Child>,
+  failed: usize,
+}
+
+impl Executor {
+  fn new(exec: &str, parallel: usize) -> Self {
+    Self{
+      script: exec.replace("{}", "\"$1\""),
+      parallel: parallel.max(1),
+      running: Vec::new(),
+      failed: 0,
+    }
+  }
+
+  /// Run the command for an element, once fewer than the allowed number are
+  /// running.
+  fn run(&mut self, arg: String) -> Result<(), error::Error> {
+    while self.running.len() >= self.parallel {
+      self.failed += reap_children(&mut self.running)?;
+      thread::sleep(time::Duration::from_millis(5));
+    }
+    self.running.push(process::Command::new("sh").arg("-c").arg(&self.script).arg(env!("CARGO_BIN_NAME")).arg(arg).spawn()?);
+    Ok(())
+  }
+
+  /// Wait for every command to exit, failing if any did.
+  fn finish(self) -> Result<(), error::Error> {
+    let mut failed = self.failed;
+    for mut child in self.running {
+      if !child.wait()?.success() {
+        failed += 1;
+      }
+    }
+    match failed {
+      0 => Ok(()),
+      n => Err(error::Error::CommandFailed(n)),
+    }
+  }
+}
+
+/// Remove the children which have exited, producing the number which failed.
+fn reap_children(running: &mut Vec<process::Child>) -> Result<usize, error::Error> {
+  let mut failed: usize = 0;
+  let mut remaining: Vec<process::Child> = Vec::new();
+  for mut child in running.drain(..) {
+    match child.try_wait()? {
+      Some(status) => if !status.success() { failed += 1; },
+      None         => remaining.push(child),
+    }
+  }
+  *running = remaining;
+  Ok(failed)
+}
+
+fn cmd_duration(sub: &DurationOptions) -> Result<(), error::Error> {
+  match &sub.command {
+    DurationCommand::Parse(sub) => match sub.duration.parse::<duration::Duration>() {
+      Ok(duration) => {
+        println!("{}", duration.format(sub.output));
+        Ok(())
+      },
+      Err(err)     => Err(error::Error::InvalidValue(format!("{}: {}", sub.duration, err))),
+    },
+  }
+}
+
+fn cmd_path(sub: &PathOptions) -> Result<(), error::Error> {
+  if let PathCommand::Check(sub) = &sub.command {
+    return match jsonpath::check(&sub.path) {
+      Ok(_)    => Ok(()),
+      Err(err) => {
+        eprintln!("{}\n{:>width$}", sub.path, "^", width=err.column);
+        Err(error::Error::InvalidValue(format!("{}: {}", sub.path, err)))
+      },
+    };
+  }
+  let mut data = String::new();
+  io::stdin().read_to_string(&mut data)?;
+  let doc: serde_json::Value = serde_json::from_str(&data)?;
+  match &sub.command {
+    PathCommand::Get(sub) => match jsonpath::get(&doc, &sub.path) {
+      Some(value) if sub.raw => println!("{}", jsonpath::print_raw(value)),
+      Some(value)            => println!("{}", value),
+      None                   => return Err(error::Error::NotFound),
+    },
+    PathCommand::Set(sub)    => println!("{}", jsonpath::set(&doc, &sub.path, &serde_json::from_str(&sub.value)?, sub.parents)?),
+    PathCommand::Delete(sub) => println!("{}", jsonpath::delete(&doc, &sub.path)?),
+    PathCommand::Check(_)    => {}, // checked above
+  }
+  Ok(())
+}
+
+fn cmd_paths(opts: &Options, sub: &PathsOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  rpc.write_cmd(&rpc::Operation::new_paths(sub.key.as_deref(), sub.depth))?;
+
+  let rsp = rpc.expect_cmd(&[rpc::CMD_FOUND, rpc::CMD_NONE])?;
+  let data = match rsp.name() {
+    rpc::CMD_NONE  => Err(error::Error::NotFound),
+    rpc::CMD_FOUND => match rsp.data() {
+      Some(data) => Ok(data),
+      None       => Err(error::Error::Malformed),
+    },
+    _ => Err(error::Error::Malformed),
+  }?;
+
+  let value: serde_json::Value = serde_json::from_str(data)?;
+  match value {
+    serde_json::Value::Array(v) => print_list(&v, sub.print0),
+    _                           => return Err(error::Error::Malformed),
+  }
+
+  Ok(())
+}
+
+fn cmd_keys(opts: &Options, sub: &KeysOptions) -> Result<(), error::Error> {
+  let filter = match sub.filters.len() {
+    0 => None,
+    _ => Some(sub.filters.join(" && ").parse::<predicate::Filter>()?.to_string()), // validate before we contact the service
+  };
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  let pattern = sub.pattern.as_deref().unwrap_or("");
+  if sub.values {
+    let op = rpc::Operation::new_keys(pattern, sub.regex, true)
+      .with_flag(rpc::FLAG_GLOB, sub.glob)
+      .with_flag(rpc::FLAG_CI, sub.ci)
+      .with_option(rpc::OPTION_WHERE, filter.as_deref());
+    let value: serde_json::Value = serde_json::from_str(&fetch_op(&mut rpc, &op)?)?;
+    return match value {
+      serde_json::Value::Object(_) => {
+        println!("{}", value);
+        Ok(())
+      },
+      _                            => Err(error::Error::Malformed),
+    };
+  }
+
+  // keys are streamed, and printed a chunk at a time as they arrive
+  rpc.write_cmd(&rpc::Operation::new_keys_stream(pattern, sub.regex).with_flag(rpc::FLAG_GLOB, sub.glob).with_flag(rpc::FLAG_CI, sub.ci).with_option(rpc::OPTION_WHERE, filter.as_deref()))?;
+  loop {
+    let rsp = rpc.expect_cmd(&[rpc::CMD_MORE, rpc::CMD_OK, rpc::CMD_ERROR])?;
+    let list: Vec<serde_json::Value> = match rsp.name() {
+      rpc::CMD_OK    => return Ok(()),
+      rpc::CMD_ERROR => return Err(error::Error::RemoteError(rsp.data().clone())),
+      rpc::CMD_MORE  => match rsp.data() {
+        Some(data) => serde_json::from_str(data)?,
+        None       => return Err(error::Error::Malformed),
+      },
+      _ => return Err(error::Error::Malformed),
+    };
+    print_list(&list, sub.print0);
+  }
+}
+
+fn cmd_count(opts: &Options, sub: &CountOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  let count = fetch_op(&mut rpc, &rpc::Operation::new_count(sub.pattern.as_deref().unwrap_or(""), sub.regex, sub.glob))?;
+  println!("{}", count);
+  Ok(())
+}
+
+fn cmd_find(opts: &Options, sub: &FindOptions) -> Result<(), error::Error> {
+  let pred: predicate::Predicate = sub.predicate.parse()?; // validate before we contact the service
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  rpc.write_cmd(&rpc::Operation::new_find(&sub.key, &pred.to_string()))?;
+
+  let rsp = rpc.expect_cmd(&[rpc::CMD_FOUND, rpc::CMD_NONE, rpc::CMD_ERROR])?;
+  let data = match rsp.name() {
+    rpc::CMD_NONE  => Err(error::Error::NotFound),
+    rpc::CMD_ERROR => Err(error::Error::RemoteError(rsp.data().clone())),
+    rpc::CMD_FOUND => match rsp.data() {
+      Some(data) => Ok(data),
+      None       => Err(error::Error::Malformed),
+    },
+    _ => Err(error::Error::Malformed),
+  }?;
+
+  let value: serde_json::Value = serde_json::from_str(data)?;
+  match value {
+    serde_json::Value::Array(v) => print_list(&v, sub.print0),
+    _                           => return Err(error::Error::Malformed),
+  }
+
+  Ok(())
+}
+
+fn cmd_set(opts: &Options, sub: &StoreOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
   let key = match &sub.key {
     Some(key) => key.to_string(),
     None      => Alphanumeric.sample_string(&mut rand::thread_rng(), 16),
   };
 
+  let mut data = String::new();
+  io::stdin().read_to_string(&mut data)?;
+  let value: serde_json::Value = if sub.unflatten {
+    jsonpath::parse_flat(&data)?
+  }else{
+    serde_json::from_str(&data)?
+  };
+
+  // re-encode the value to ensure there is no extraneous whitespace
+  let ttl = sub.ttl.as_ref().map(|e| { e.duration().as_millis().to_string() });
+  if sub.cas {
+    rpc.write_cmd(&rpc::Operation::new_put(&value.to_string()).with_option(rpc::OPTION_TTL, ttl.as_deref()))?;
+    let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_ERROR])?;
+    return match rsp.name() {
+      rpc::CMD_OK    => match rsp.args().first() {
+        Some(key) => {
+          println!("{}", key);
+          Ok(())
+        },
+        None      => Err(error::Error::Malformed),
+      },
+      rpc::CMD_ERROR => Err(error::Error::RemoteError(rsp.data().clone())),
+      _              => Err(error::Error::Unexpected),
+    };
+  }
+  if let Some(expected) = &sub.if_equals {
+    let expected: serde_json::Value = match serde_json::from_str(expected) {
+      Ok(expected) => expected,
+      Err(err)     => return Err(error::Error::InvalidValue(format!("--if-equals: {}", err))),
+    };
+    rpc.write_cmd(&rpc::Operation::new_cas(&key, &expected.to_string(), &value.to_string()).with_option(rpc::OPTION_TTL, ttl.as_deref()))?;
+    let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_MISMATCH, rpc::CMD_NONE, rpc::CMD_ERROR])?;
+    return match rsp.name() {
+      rpc::CMD_OK       => {
+        println!("{}", key);
+        Ok(())
+      },
+      rpc::CMD_MISMATCH => match rsp.data() {
+        Some(current) => { println!("{}", current); Err(error::Error::Mismatch(key)) },
+        None          => Err(error::Error::Malformed),
+      },
+      rpc::CMD_NONE     => Err(error::Error::NotFound),
+      rpc::CMD_ERROR    => Err(error::Error::RemoteError(rsp.data().clone())),
+      _                 => Err(error::Error::Unexpected),
+    };
+  }
+  rpc.write_cmd(&rpc::Operation::new_set(&key, &value.to_string()).with_flag(rpc::FLAG_DRYRUN, sub.dry_run).with_flag(rpc::FLAG_NX, sub.nx).with_option(rpc::OPTION_TTL, ttl.as_deref()))?;
+  let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_FOUND, rpc::CMD_EXISTS, rpc::CMD_ERROR])?;
+  match rsp.name() {
+    rpc::CMD_OK     => {
+      println!("{}", key);
+      Ok(())
+    },
+    rpc::CMD_FOUND  => print_changes(rsp.data()),
+    rpc::CMD_EXISTS => Err(error::Error::Exists(key)),
+    rpc::CMD_ERROR  => Err(error::Error::RemoteError(rsp.data().clone())),
+    _               => Err(error::Error::Unexpected),
+  }
+}
+
+fn cmd_mset(opts: &Options, sub: &MultiStoreOptions) -> Result<(), error::Error> {
+  let mut data = String::new();
+  io::stdin().read_to_string(&mut data)?;
+  let entries = if sub.lines {
+    let mut entries = serde_json::Map::new();
+    for (i, line) in data.lines().enumerate().filter(|(_, e)| { !e.trim().is_empty() }) {
+      match serde_json::from_str(line)? {
+        serde_json::Value::Object(mut record) => match (record.remove("key"), record.remove("value")) {
+          (Some(serde_json::Value::String(key)), Some(value)) => { entries.insert(key, value); },
+          _ => return Err(error::Error::InvalidValue(format!("Line {}: expected a record with a key and a value", i + 1))),
+        },
+        _ => return Err(error::Error::InvalidValue(format!("Line {}: expected a record with a key and a value", i + 1))),
+      }
+    }
+    entries
+  }else{
+    match serde_json::from_str(&data)? {
+      serde_json::Value::Object(entries) => entries,
+      _                                  => return Err(error::Error::InvalidValue("Expected an object of keys and their values".to_string())),
+    }
+  };
+
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  let keys: Vec<String> = entries.keys().cloned().collect();
+  rpc.write_cmd(&rpc::Operation::new_mset(&serde_json::Value::Object(entries).to_string()))?;
+  let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_ERROR])?;
+  match rsp.name() {
+    rpc::CMD_OK    => {
+      keys.iter().for_each(|e| { println!("{}", e) });
+      Ok(())
+    },
+    rpc::CMD_ERROR => Err(error::Error::RemoteError(rsp.data().clone())),
+    _              => Err(error::Error::Unexpected),
+  }
+}
+
+fn cmd_set_env(opts: &Options, sub: &StoreEnvOptions) -> Result<(), error::Error> {
+  let filter = match sub.filter.as_deref().map(pattern::Pattern::glob) {
+    Some(Ok(filter)) => Some(filter),
+    Some(Err(err))   => return Err(error::Error::InvalidValue(err)),
+    None             => None,
+  };
+  let mut vars = serde_json::Map::new();
+  if sub.all {
+    for (name, value) in env::vars_os() {
+      let name = name.to_string_lossy().to_string();
+      if filter.as_ref().is_none_or(|e| { e.is_match(&name) }) {
+        vars.insert(name, value.to_string_lossy().into());
+      }
+    }
+  }
+  for name in &sub.names {
+    match env::var_os(name) {
+      Some(value) => { vars.insert(name.to_string(), value.to_string_lossy().into()); },
+      None        => log::logln!("{}", &format!("* * * Not set: {}", name).yellow().bold()),
+    }
+  }
+
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  rpc.write_cmd(&rpc::Operation::new_set(&sub.key, &serde_json::Value::Object(vars).to_string()))?;
+  let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_ERROR])?;
+  match rsp.name() {
+    rpc::CMD_OK    => {
+      println!("{}", sub.key);
+      Ok(())
+    },
+    rpc::CMD_ERROR => Err(error::Error::RemoteError(rsp.data().clone())),
+    _              => Err(error::Error::Unexpected),
+  }
+}
+
+fn cmd_capture(opts: &Options, sub: &CaptureOptions) -> Result<(), error::Error> {
+  let started = time::SystemTime::now().duration_since(time::UNIX_EPOCH)?;
+  let start = time::Instant::now();
+  let output = process::Command::new(&sub.command[0])
+    .args(&sub.command[1..])
+    .stdin(process::Stdio::inherit())
+    .output()
+    .map_err(|e| { io::Error::new(e.kind(), format!("{}: {}", sub.command[0], e)) })?;
+  let elapsed = start.elapsed();
+  let mut result = serde_json::json!({
+    "command": sub.command,
+    "status": output.status.code(),
+    "stdout": String::from_utf8_lossy(&output.stdout),
+    "stderr": String::from_utf8_lossy(&output.stderr),
+    "started": started.as_millis() as u64,
+    "duration_ms": elapsed.as_millis() as u64,
+  });
+  if let Some(signal) = output.status.signal() {
+    result["signal"] = signal.into(); // there is no status when it was killed
+  }
+
+  // connect only once the command is done, so a long one doesn't hold an idle connection
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  rpc.write_cmd(&rpc::Operation::new_set(&sub.key, &result.to_string()))?;
+  let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_ERROR])?;
+  match rsp.name() {
+    rpc::CMD_OK    => process::exit(output.status.code().unwrap_or(1)),
+    rpc::CMD_ERROR => Err(error::Error::RemoteError(rsp.data().clone())),
+    _              => Err(error::Error::Unexpected),
+  }
+}
+
+fn cmd_merge_keys(opts: &Options, sub: &MergeKeysOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  rpc.write_cmd(&rpc::Operation::new_merge_keys(&sub.base, &sub.over, &sub.dest).with_flag(rpc::FLAG_DRYRUN, sub.dry_run))?;
+  let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_FOUND, rpc::CMD_NONE, rpc::CMD_ERROR])?;
+  match rsp.name() {
+    rpc::CMD_OK    => {
+      println!("{}", sub.dest);
+      Ok(())
+    },
+    rpc::CMD_FOUND => print_changes(rsp.data()),
+    rpc::CMD_NONE  => Err(error::Error::NotFound),
+    rpc::CMD_ERROR => Err(error::Error::RemoteError(rsp.data().clone())),
+    _              => Err(error::Error::Unexpected),
+  }
+}
+
+fn cmd_move(opts: &Options, sub: &MoveOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  rpc.write_cmd(&rpc::Operation::new_move(&sub.src, &sub.dst, sub.parents))?;
+  let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_ERROR])?;
+  match rsp.name() {
+    rpc::CMD_OK    => {
+      println!("{}", sub.dst);
+      Ok(())
+    },
+    rpc::CMD_ERROR => Err(error::Error::RemoteError(rsp.data().clone())),
+    _              => Err(error::Error::Unexpected),
+  }
+}
+
+fn cmd_alias(opts: &Options, sub: &AliasOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  match (&sub.name, &sub.target, sub.delete) {
+    (Some(name), None, true) => {
+      rpc.write_cmd(&rpc::Operation::new_unalias(name))?;
+      let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_NONE])?;
+      match rsp.name() {
+        rpc::CMD_OK   => {
+          println!("{}", name);
+          Ok(())
+        },
+        rpc::CMD_NONE => Err(error::Error::NotFound),
+        _             => Err(error::Error::Unexpected),
+      }
+    },
+    (Some(name), Some(target), false) => {
+      rpc.write_cmd(&rpc::Operation::new_alias(name, target))?;
+      let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_ERROR])?;
+      match rsp.name() {
+        rpc::CMD_OK    => {
+          println!("{}", name);
+          Ok(())
+        },
+        rpc::CMD_ERROR => Err(error::Error::RemoteError(rsp.data().clone())),
+        _              => Err(error::Error::Unexpected),
+      }
+    },
+    (name, None, false) => {
+      rpc.write_cmd(&rpc::Operation::new_aliases())?;
+      let rsp = rpc.expect_cmd(&[rpc::CMD_FOUND])?;
+      let aliases: BTreeMap<String, String> = match rsp.data() {
+        Some(data) => serde_json::from_str(data)?,
+        None       => return Err(error::Error::Malformed),
+      };
+      match name {
+        Some(name) => match aliases.get(name) {
+          Some(target) => {
+            println!("{}", target);
+            Ok(())
+          },
+          None         => Err(error::Error::NotFound),
+        },
+        None => {
+          aliases.iter().for_each(|(k, v)| { println!("{} -> {}", k, v) });
+          Ok(())
+        },
+      }
+    },
+    _ => Err(error::Error::Malformed),
+  }
+}
+
+fn cmd_defaults(opts: &Options, sub: &DefaultsOptions) -> Result<(), error::Error> {
+  let path = match &sub.command {
+    DefaultsCommand::Set(sub)    => socket_path(&sub.path),
+    DefaultsCommand::Delete(sub) => socket_path(&sub.path),
+    DefaultsCommand::List(sub)   => socket_path(&sub.path),
+  };
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  match &sub.command {
+    DefaultsCommand::Set(sub) => {
+      let template: serde_json::Value = match serde_json::from_str(&sub.template) {
+        Ok(template) => template,
+        Err(err)     => return Err(error::Error::InvalidValue(format!("{}: {}", sub.glob, err))),
+      };
+      rpc.write_cmd(&rpc::Operation::new_default(&sub.glob, &template.to_string()))?;
+      let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_ERROR])?;
+      match rsp.name() {
+        rpc::CMD_OK    => {
+          println!("{}", sub.glob);
+          Ok(())
+        },
+        rpc::CMD_ERROR => Err(error::Error::RemoteError(rsp.data().clone())),
+        _              => Err(error::Error::Unexpected),
+      }
+    },
+    DefaultsCommand::Delete(sub) => {
+      rpc.write_cmd(&rpc::Operation::new_undefault(&sub.glob))?;
+      let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_NONE])?;
+      match rsp.name() {
+        rpc::CMD_OK   => {
+          println!("{}", sub.glob);
+          Ok(())
+        },
+        rpc::CMD_NONE => Err(error::Error::NotFound),
+        _             => Err(error::Error::Unexpected),
+      }
+    },
+    DefaultsCommand::List(_) => {
+      let defaults: BTreeMap<String, serde_json::Value> = serde_json::from_str(&fetch_op(&mut rpc, &rpc::Operation::new_defaults())?)?;
+      defaults.iter().for_each(|(k, v)| { println!("{} -> {}", k, v) });
+      Ok(())
+    },
+  }
+}
+
+fn cmd_protect(opts: &Options, sub: &ProtectOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  if sub.names.is_empty() {
+    let value: serde_json::Value = serde_json::from_str(&fetch_op(&mut rpc, &rpc::Operation::new_protections())?)?;
+    return match value {
+      serde_json::Value::Array(v) => {
+        print_list(&v, false);
+        Ok(())
+      },
+      _                           => Err(error::Error::Malformed),
+    };
+  }
+  let names: Vec<&str> = sub.names.iter().map(|e| { e.as_str() }).collect();
+  rpc.write_cmd(&rpc::Operation::new_protect(&names))?;
+  rpc.expect_cmd(&[rpc::CMD_OK])?;
+  Ok(())
+}
+
+fn cmd_unprotect(opts: &Options, sub: &UnprotectOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  let names: Vec<&str> = sub.names.iter().map(|e| { e.as_str() }).collect();
+  rpc.write_cmd(&rpc::Operation::new_unprotect(&names))?;
+  let rsp = rpc.expect_cmd(&[rpc::CMD_OK])?;
+  for name in &sub.names {
+    if !rsp.args().contains(name) {
+      log::logln!("{}", &format!("* * * Not protected: {}", name).yellow().bold());
+    }
+  }
+  Ok(())
+}
+
+fn cmd_watch(opts: &Options, sub: &WatchOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let keys: Vec<&str> = sub.keys.iter().map(|e| { e.as_str() }).collect();
+  let mut resume = sub.resume;
+  loop {
+    let stream = connect(opts, &path)?;
+    let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+    match resume {
+      None if sub.initial => rpc.write_cmd(&rpc::Operation::new_watch_initial(&keys))?, // only when we first connect
+      resume              => rpc.write_cmd(&rpc::Operation::new_watch_from(&keys, resume))?,
+    }
+    let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_ERROR])?;
+    if rsp.name() == rpc::CMD_ERROR {
+      return Err(error::Error::RemoteError(rsp.data().clone()));
+    }
+    if resume.is_none() { // resume from the latest change when we started
+      resume = rsp.args().first().and_then(|e| { e.parse::<u64>().ok() });
+    }
+
+    loop {
+      match rpc.read_cmd() {
+        Ok(Some(event)) if event.name() == rpc::CMD_ERROR => return Err(error::Error::RemoteError(event.data().clone())), // e.g., we were disconnected; don't come back
+        Ok(Some(event)) => {
+          let event = watch_event(&event)?;
+          if let Some(seq) = event.get("seq").and_then(|e| { e.as_u64() }) {
+            resume = Some(seq);
+          }
+          println!("{}", event);
+          if sub.once {
+            return Ok(());
+          }
+        },
+        Ok(None) | Err(_) if sub.reconnect => break,
+        Ok(None) => return Ok(()),
+        Err(err) => return Err(err),
+      }
+    }
+    if opts.verbose {
+      log::logln!("Connection lost; reconnecting after change: {}", resume.unwrap_or(0));
+    }
+    thread::sleep(RECONNECT_DELAY);
+  }
+}
+
+/// Describe a change to a watched key as JSON, tagged with the key it is
+/// for and its sequence number, if the service provided one.
+fn watch_event(event: &rpc::Operation) -> Result<serde_json::Value, error::Error> {
+  let key = match event.args().first() {
+    Some(key) => key,
+    None      => return Err(error::Error::Malformed),
+  };
+  let mut desc = match (event.name(), event.data()) {
+    (rpc::CMD_FOUND, Some(data)) => serde_json::json!({"key": key, "value": serde_json::from_str::<serde_json::Value>(data)?}),
+    (rpc::CMD_NONE, _)           => serde_json::json!({"key": key, "deleted": true}),
+    _                            => return Err(error::Error::Unexpected),
+  };
+  if let Some(seq) = event.args().get(1).and_then(|e| { e.parse::<u64>().ok() }) {
+    desc["seq"] = seq.into();
+  }
+  Ok(desc)
+}
+
+fn cmd_wait_any(opts: &Options, sub: &WaitAnyOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream.try_clone()?, rpc_options(opts))?;
+
+  let keys: Vec<&str> = sub.keys.iter().map(|e| { e.as_str() }).collect();
+  rpc.write_cmd(&rpc::Operation::new_watch(&keys))?;
+  let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_ERROR])?;
+  if rsp.name() == rpc::CMD_ERROR {
+    return Err(error::Error::RemoteError(rsp.data().clone()));
+  }
+
+  // the timeout starts once we are watching, so no change is missed
+  stream.set_read_timeout(sub.timeout.as_ref().map(|e| { e.duration() }))?;
+  match rpc.read_cmd() {
+    Ok(Some(event)) => {
+      println!("{}", watch_event(&event)?);
+      Ok(())
+    },
+    Ok(None) => Err(error::Error::ServiceError),
+    Err(error::Error::IOError(err)) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => {
+      Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for a change").into())
+    },
+    Err(err) => Err(err),
+  }
+}
+
+fn cmd_log(opts: &Options, sub: &LogOptions) -> Result<(), error::Error> {
+  match &sub.command {
+    LogCommand::Append(sub) => cmd_log_append(opts, sub),
+    LogCommand::Tail(sub)   => cmd_log_tail(opts, sub),
+  }
+}
+
+fn cmd_log_append(opts: &Options, sub: &LogAppendOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  let data = match &sub.value {
+    Some(value) => value.to_string(),
+    None        => {
+      let mut data = String::new();
+      io::stdin().read_to_string(&mut data)?;
+      data
+    },
+  };
+  let value: serde_json::Value = serde_json::from_str(&data)?;
+  rpc.write_cmd(&rpc::Operation::new_append(&sub.key, &value.to_string(), sub.cap))?;
+  let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_ERROR])?;
+  match rsp.name() {
+    rpc::CMD_OK    => Ok(()),
+    rpc::CMD_ERROR => Err(error::Error::RemoteError(rsp.data().clone())),
+    _              => Err(error::Error::Unexpected),
+  }
+}
+
+fn cmd_log_tail(opts: &Options, sub: &LogTailOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+
+  // subscribe before reading the log, so no entry appended in between is missed
+  let mut events = if sub.follow {
+    let mut rpc = rpc::RPC::new(connect(opts, &path)?, rpc_options(opts))?;
+    rpc.write_cmd(&rpc::Operation::new_watch(&[&sub.key]))?;
+    let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_ERROR])?;
+    if rsp.name() == rpc::CMD_ERROR {
+      return Err(error::Error::RemoteError(rsp.data().clone()));
+    }
+    Some(rpc)
+  }else{
+    None
+  };
+
+  let mut rpc = rpc::RPC::new(connect(opts, &path)?, rpc_options(opts))?;
+  let mut last = match fetch(&mut rpc, &sub.key) {
+    Ok(log) => {
+      let log: serde_json::Value = serde_json::from_str(&log)?;
+      let entries = log_entries(&log)?;
+      print_log(eventlog::tail(entries, sub.lines), 0);
+      entries.last().map(eventlog::seq).unwrap_or(0)
+    },
+    Err(error::Error::NotFound) if sub.follow => 0,
+    Err(err) => return Err(err),
+  };
+  let events = match &mut events {
+    Some(events) => events,
+    None         => return Ok(()),
+  };
+  while let Some(event) = events.read_cmd()? {
+    match (event.name(), event.data()) {
+      (rpc::CMD_FOUND, Some(data)) => last = print_log(log_entries(&serde_json::from_str(data)?)?, last),
+      (rpc::CMD_NONE, _)           => last = 0, // the log was deleted; a new one starts over
+      _                            => return Err(error::Error::Unexpected),
+    }
+  }
+  Ok(())
+}
+
+fn log_entries(log: &serde_json::Value) -> Result<&[serde_json::Value], error::Error> {
+  match log {
+    serde_json::Value::Array(entries) => Ok(entries),
+    _                                 => Err(error::Error::Malformed),
+  }
+}
+
+/// Print the entries in a log appended after the one with the provided
+/// sequence number, one per line, and produce the last sequence number
+/// printed.
+fn print_log(entries: &[serde_json::Value], after: u64) -> u64 {
+  let mut last = after;
+  for entry in eventlog::after(entries, after) {
+    println!("{}", entry);
+    last = eventlog::seq(entry);
+  }
+  last
+}
+
+fn cmd_metric(opts: &Options, sub: &MetricOptions) -> Result<(), error::Error> {
+  let (path, op) = match &sub.command {
+    MetricCommand::Incr(sub) => (&sub.path, rpc::Operation::new_metric_incr(&sub.name, sub.by)),
+    MetricCommand::Set(sub)  => (&sub.path, rpc::Operation::new_metric_set(&sub.name, sub.value)),
+    MetricCommand::Dump(sub) => (&sub.path, rpc::Operation::new_metric_dump()),
+  };
+  let path = socket_path(path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  rpc.write_cmd(&op)?;
+  let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_FOUND, rpc::CMD_ERROR])?;
+  match (rsp.name(), &sub.command) {
+    (rpc::CMD_OK, _) => match rsp.args().first() {
+      Some(value) => {
+        println!("{}", value);
+        Ok(())
+      },
+      None        => Err(error::Error::Malformed),
+    },
+    (rpc::CMD_FOUND, MetricCommand::Dump(dump)) => match rsp.data() {
+      Some(data) if dump.prometheus => {
+        print!("{}", metrics::prometheus(&serde_json::from_str(data)?));
+        Ok(())
+      },
+      Some(data)                    => {
+        println!("{}", data);
+        Ok(())
+      },
+      None                          => Err(error::Error::Malformed),
+    },
+    (rpc::CMD_ERROR, _) => Err(error::Error::RemoteError(rsp.data().clone())),
+    _                   => Err(error::Error::Unexpected),
+  }
+}
+
+fn cmd_touch(opts: &Options, sub: &TouchOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  let ttl = sub.ttl.as_ref().map(|e| { e.duration().as_millis().to_string() });
+  rpc.write_cmd(&rpc::Operation::new_touch(&sub.key).with_option(rpc::OPTION_TTL, ttl.as_deref()))?;
+  let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_NONE, rpc::CMD_ERROR])?;
+  match rsp.name() {
+    rpc::CMD_OK    => Ok(()),
+    rpc::CMD_NONE  => Err(error::Error::NotFound),
+    rpc::CMD_ERROR => Err(error::Error::RemoteError(rsp.data().clone())),
+    _              => Err(error::Error::Unexpected),
+  }
+}
+
+fn cmd_stat(opts: &Options, sub: &StatOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  rpc.write_cmd(&rpc::Operation::new_stat(sub.key.as_deref()))?;
+  let rsp = rpc.expect_cmd(&[rpc::CMD_FOUND, rpc::CMD_NONE])?;
+  match rsp.name() {
+    rpc::CMD_FOUND => match rsp.data() {
+      Some(data) => {
+        println!("{}", data);
+        Ok(())
+      },
+      None       => Err(error::Error::Malformed),
+    },
+    rpc::CMD_NONE => Err(error::Error::NotFound),
+    _             => Err(error::Error::Unexpected),
+  }
+}
+
+fn cmd_with(opts: &Options, sub: &WithOptions) -> Result<(), error::Error> {
+  let path = ephemeral_path();
+  // the service is tied to us, so it goes away even if we don't get to stop it
+  spawn_detached(opts, svc_command(opts)?.arg("run").arg("--ephemeral").arg("--socket").arg(&path))?;
+  await_svc(&path)?;
+
+  let status = process::Command::new(&sub.command[0])
+    .args(&sub.command[1..])
+    .env(SOCKET_ENV, &path)
+    .status();
+  stop_svc(opts, &path)?;
+  let start = time::Instant::now();
+  while path.exists() && start.elapsed() < time::Duration::from_secs(2) {
+    thread::sleep(time::Duration::from_millis(10)); // let it finish cleaning up
+  }
+  process::exit(status?.code().unwrap_or(1));
+}
+
+fn cmd_fork(opts: &Options, sub: &ForkOptions) -> Result<(), error::Error> {
+  let src = socket_path(&sub.from);
+  let stream = match connect_running(opts, &src)? {
+    Some(stream) => stream,
+    None         => return Err(io::Error::new(io::ErrorKind::NotFound, "No service is running on this socket").into()),
+  };
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+  rpc.write_cmd(&rpc::Operation::new_dump())?;
+  let rsp = rpc.expect_cmd(&[rpc::CMD_FOUND, rpc::CMD_ERROR])?;
+  let snapshot = match rsp.name() {
+    rpc::CMD_FOUND => match rsp.data() {
+      Some(data) => dump::Snapshot::decode(data)?,
+      None       => return Err(error::Error::Malformed),
+    },
+    rpc::CMD_ERROR => return Err(error::Error::RemoteError(rsp.data().clone())),
+    _              => return Err(error::Error::Unexpected),
+  };
+
+  let path = path::PathBuf::from(&sub.path);
+  if connect_running(opts, &path)?.is_some() {
+    return Err(io::Error::new(io::ErrorKind::AddrInUse, "A service is already running on this socket").into());
+  }
+  spawn_detached(opts, svc_command(opts)?.arg("run").arg("--socket").arg(&path).args(&sub.args))?;
+  await_svc(&path)?;
+
+  let mut rpc = rpc::RPC::new(UnixStream::connect(&path)?, rpc_options(opts))?;
+  for (key, dump) in &snapshot.keys {
+    rpc.write_cmd(&rpc::Operation::new_restore_key(key, &dump.encode()?, true))?;
+    let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_ERROR])?;
+    if rsp.name() == rpc::CMD_ERROR {
+      return Err(error::Error::RemoteError(rsp.data().clone()));
+    }
+  }
+  for (name, target) in &snapshot.aliases {
+    rpc.write_cmd(&rpc::Operation::new_alias(name, target))?;
+    let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_ERROR])?;
+    if rsp.name() == rpc::CMD_ERROR {
+      return Err(error::Error::RemoteError(rsp.data().clone()));
+    }
+  }
+  println!("{}", path.display());
+  Ok(())
+}
+
+fn cmd_dump_key(opts: &Options, sub: &DumpKeyOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  rpc.write_cmd(&rpc::Operation::new_dump_key(&sub.key))?;
+  let rsp = rpc.expect_cmd(&[rpc::CMD_FOUND, rpc::CMD_NONE, rpc::CMD_ERROR])?;
+  match rsp.name() {
+    rpc::CMD_FOUND => match rsp.data() {
+      Some(data) => {
+        println!("{}", data);
+        Ok(())
+      },
+      None       => Err(error::Error::Malformed),
+    },
+    rpc::CMD_NONE  => Err(error::Error::NotFound),
+    rpc::CMD_ERROR => Err(error::Error::RemoteError(rsp.data().clone())),
+    _              => Err(error::Error::Unexpected),
+  }
+}
+
+fn cmd_restore_key(opts: &Options, sub: &RestoreKeyOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  let mut blob = String::new();
+  io::stdin().read_to_string(&mut blob)?;
+  rpc.write_cmd(&rpc::Operation::new_restore_key(&sub.key, blob.trim(), sub.replace))?;
+  let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_ERROR])?;
+  match rsp.name() {
+    rpc::CMD_OK    => {
+      println!("{}", sub.key);
+      Ok(())
+    },
+    rpc::CMD_ERROR => Err(error::Error::RemoteError(rsp.data().clone())),
+    _              => Err(error::Error::Unexpected),
+  }
+}
+
+fn cmd_swap(opts: &Options, sub: &SwapOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
   let mut data = String::new();
   io::stdin().read_to_string(&mut data)?;
   let value: serde_json::Value = serde_json::from_str(&data)?;
 
-  // re-encode the value to ensure there is no extraneous whitespace
-  rpc.write_cmd(&rpc::Operation::new_set(&key, &value.to_string()))?;
+  rpc.write_cmd(&rpc::Operation::new_swap(&sub.key, &value.to_string()))?;
+  let rsp = rpc.expect_cmd(&[rpc::CMD_FOUND, rpc::CMD_NONE, rpc::CMD_ERROR])?;
+  match (rsp.name(), rsp.data()) {
+    (rpc::CMD_FOUND, Some(data)) if sub.raw => println!("{}", jsonpath::print_raw(&serde_json::from_str::<serde_json::Value>(data)?)),
+    (rpc::CMD_FOUND, Some(data))            => println!("{}", data),
+    (rpc::CMD_NONE, _)                      => {}, // stored; there was nothing to replace
+    (rpc::CMD_ERROR, data)                  => return Err(error::Error::RemoteError(data.clone())),
+    _                                       => return Err(error::Error::Malformed),
+  }
+  Ok(())
+}
+
+fn cmd_take(opts: &Options, sub: &TakeOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  let data = fetch_op(&mut rpc, &rpc::Operation::new_take(&sub.key))?;
+  if sub.raw {
+    println!("{}", jsonpath::print_raw(&serde_json::from_str::<serde_json::Value>(&data)?));
+  } else {
+    println!("{}", data);
+  }
+  Ok(())
+}
+
+fn cmd_merge(opts: &Options, sub: &MergeOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  let mut data = String::new();
+  io::stdin().read_to_string(&mut data)?;
+  let patch: serde_json::Value = serde_json::from_str(&data)?;
+
+  let data = fetch_op(&mut rpc, &rpc::Operation::new_merge(&sub.key, &patch.to_string()))?;
+  if !sub.quiet {
+    println!("{}", data);
+  }
+  Ok(())
+}
+
+fn cmd_patch(opts: &Options, sub: &PatchOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  let mut data = String::new();
+  io::stdin().read_to_string(&mut data)?;
+  let patch: serde_json::Value = serde_json::from_str(&data)?;
+
+  let data = fetch_op(&mut rpc, &rpc::Operation::new_patch(&sub.key, &patch.to_string()))?;
+  if !sub.quiet {
+    println!("{}", data);
+  }
+  Ok(())
+}
+
+fn cmd_push(opts: &Options, sub: &PushOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  let data = match &sub.value {
+    Some(value) => value.clone(),
+    None        => {
+      let mut data = String::new();
+      io::stdin().read_to_string(&mut data)?;
+      data
+    },
+  };
+  let value: serde_json::Value = serde_json::from_str(&data)?;
+
+  rpc.write_cmd(&rpc::Operation::new_push(&sub.key, &value.to_string()))?;
   let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_ERROR])?;
   match rsp.name() {
-    rpc::CMD_OK    => Ok(println!("{}", key)),
+    rpc::CMD_OK => match rsp.args().first() {
+      Some(len) => {
+        println!("{}", len);
+        Ok(())
+      },
+      None      => Err(error::Error::Malformed),
+    },
     rpc::CMD_ERROR => Err(error::Error::RemoteError(rsp.data().clone())),
     _              => Err(error::Error::Unexpected),
   }
 }
 
+fn cmd_pop(opts: &Options, sub: &PopOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  let data = fetch_op(&mut rpc, &rpc::Operation::new_pop(&sub.key, sub.front))?;
+  if sub.raw {
+    println!("{}", jsonpath::print_raw(&serde_json::from_str::<serde_json::Value>(&data)?));
+  } else {
+    println!("{}", data);
+  }
+  Ok(())
+}
+
+fn cmd_incr(opts: &Options, sub: &IncrOptions, negate: bool) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = connect(opts, &path)?;
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  let delta = match negate {
+    true  => match sub.by.to_string().strip_prefix('-') {
+      Some(by) => by.parse::<serde_json::Number>()?,
+      None     => format!("-{}", sub.by).parse::<serde_json::Number>()?,
+    },
+    false => sub.by.clone(),
+  };
+  println!("{}", fetch_op(&mut rpc, &rpc::Operation::new_incr(&sub.key, &delta))?);
+  Ok(())
+}
+
 fn cmd_delete(opts: &Options, sub: &DeleteOptions) -> Result<(), error::Error> {
   let path = socket_path(&sub.path);
-  if !path.exists() {
-    return Ok(()); // no service running, nothing do delete
+  let stream = match connect_running(opts, &path)? {
+    Some(stream) => stream,
+    None         => return Ok(()), // no service running, nothing do delete
+  };
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  let bulk = match (&sub.prefix, &sub.glob) {
+    (Some(prefix), _) => Some(rpc::Operation::new_delete_keys(prefix, false, false)),
+    (_, Some(glob))   => Some(rpc::Operation::new_delete_keys(glob, false, true)),
+    _                 => None,
+  };
+  if let Some(op) = bulk {
+    rpc.write_cmd(&op)?;
+    let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_ERROR])?;
+    if rsp.name() == rpc::CMD_ERROR {
+      return Err(error::Error::RemoteError(rsp.data().clone()));
+    }
+    println!("{}", rsp.args().len());
+    return Ok(());
+  }
+  if let Some(regex) = &sub.regex {
+    rpc.write_cmd(&rpc::Operation::new_delete_keys(regex, true, false))?;
+    let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_ERROR])?;
+    if rsp.name() == rpc::CMD_ERROR {
+      return Err(error::Error::RemoteError(rsp.data().clone()));
+    }
+    for key in rsp.args() {
+      println!("{}", key);
+    }
+    return Ok(());
   }
 
-  let stream = UnixStream::connect(path)?;
-  let mut rpc = rpc::RPC::new(stream, rpc::Options{debug: opts.debug})?;
+  let keys: Vec<&str> = sub.keys.iter().map(|e| { e.as_str() }).collect();
+  rpc.write_cmd(&rpc::Operation::new_delete(&keys))?;
+  let rsp = rpc.expect_cmd(&[rpc::CMD_OK])?;
 
-  rpc.write_cmd(&rpc::Operation::new_delete(&sub.key))?;
-  rpc.expect_cmd(&[rpc::CMD_OK])?;
+  // the service responds with the subset of keys which were actually deleted
+  let mut missing = false;
+  for key in &sub.keys {
+    if rsp.args().contains(key) {
+      println!("{}", key);
+    }else{
+      log::logln!("{}", &format!("* * * Not found: {}", key).yellow().bold());
+      missing = true;
+    }
+  }
+  match missing {
+    true  => Err(error::Error::NotFound),
+    false => Ok(()),
+  }
+}
+
+fn cmd_clear(opts: &Options, sub: &ClearOptions) -> Result<(), error::Error> {
+  let path = socket_path(&sub.path);
+  let stream = match connect_running(opts, &path)? {
+    Some(stream) => stream,
+    None         => return Ok(()), // no service running, nothing to clear
+  };
+  if !sub.force {
+    if !io::stdin().is_terminal() {
+      return Err(error::Error::InvalidValue("Refusing to clear without confirmation; pass --force".to_string()));
+    }
+    eprint!("Delete every key in the service at {}? [y/N] ", path.display());
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    if !answer.trim().eq_ignore_ascii_case("y") && !answer.trim().eq_ignore_ascii_case("yes") {
+      return Ok(());
+    }
+  }
+  let mut rpc = rpc::RPC::new(stream, rpc_options(opts))?;
+
+  rpc.write_cmd(&rpc::Operation::new_clear())?;
+  let rsp = rpc.expect_cmd(&[rpc::CMD_OK, rpc::CMD_ERROR])?;
+  match (rsp.name(), rsp.args().first()) {
+    (rpc::CMD_OK, Some(n)) => {
+      println!("{}", n);
+      Ok(())
+    },
+    (rpc::CMD_ERROR, _)    => Err(error::Error::RemoteError(rsp.data().clone())),
+    _                      => Err(error::Error::Malformed),
+  }
+}
+
+fn fetch(rpc: &mut rpc::RPC, key: &str) -> Result<String, error::Error> {
+  fetch_op(rpc, &rpc::Operation::new_get(key))
+}
+
+fn fetch_op(rpc: &mut rpc::RPC, op: &rpc::Operation) -> Result<String, error::Error> {
+  rpc.write_cmd(op)?;
+  let rsp = rpc.expect_cmd(&[rpc::CMD_FOUND, rpc::CMD_NONE, rpc::CMD_ERROR])?;
+  match rsp.name() {
+    rpc::CMD_NONE  => Err(error::Error::NotFound),
+    rpc::CMD_ERROR => Err(error::Error::RemoteError(rsp.data().clone())),
+    rpc::CMD_FOUND => match rsp.data() {
+      Some(data) => Ok(data.to_owned()),
+      None       => Err(error::Error::Malformed),
+    },
+    _ => Err(error::Error::Malformed),
+  }
+}
+
+/// Print each element of a list on its own line or, if requested, followed
+/// by a NUL instead, so elements containing whitespace survive `xargs -0`.
+fn print_list(list: &[serde_json::Value], print0: bool) {
+  for e in list {
+    if print0 {
+      print!("{}\0", jsonpath::print_raw(e));
+    } else {
+      println!("{}", jsonpath::print_raw(e));
+    }
+  }
+}
 
-  println!("{}", &sub.key);
+fn print_changes(data: &Option<String>) -> Result<(), error::Error> {
+  let changes: Vec<diff::Change> = match data {
+    Some(data) => serde_json::from_str(data)?,
+    None       => return Err(error::Error::Malformed),
+  };
+  print_change_list(&changes);
   Ok(())
 }
 
+fn print_change_list(changes: &[diff::Change]) {
+  for change in changes {
+    let text = change.to_string();
+    println!("{}", match change {
+      diff::Change::Add{..}     => text.green(),
+      diff::Change::Remove{..}  => text.red(),
+      diff::Change::Replace{..} => text.yellow(),
+    });
+  }
+}
+
+/// A unique socket path for an ephemeral service.
+fn ephemeral_path() -> path::PathBuf {
+  let mut path = env::temp_dir();
+  path.push(format!("structs-{}.sock", Alphanumeric.sample_string(&mut rand::thread_rng(), 12)));
+  path
+}
+
+/// The socket path provided, or the one set in the environment, or else the
+/// default.
 fn socket_path(path: &Option<String>) -> path::PathBuf {
   match path {
     Some(path) => path::PathBuf::from(path),
-    None => {
-      let mut path = env::temp_dir();
-      path.push("structs.sock");
-      path
+    None       => match env::var_os(SOCKET_ENV) {
+      Some(path) => path::PathBuf::from(path),
+      None       => {
+        let mut path = env::temp_dir();
+        path.push("structs.sock");
+        path
+      },
     },
   }
 }
+
+/// Determine if a boolean setting is enabled in the environment; any value
+/// other than empty, "0", or "false" enables it.
+fn env_flag(name: &str) -> bool {
+  match env::var(name) {
+    Ok(v)  => !(v.is_empty() || v == "0" || v.eq_ignore_ascii_case("false")),
+    Err(_) => false,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn strict_base64() {
+    assert_eq!(Some(b"hello".to_vec()), decode_base64("aGVsbG8="));
+    assert_eq!(Some(b"hello".to_vec()), decode_base64("aGVs\nbG8"));
+    assert_eq!(Some(vec![0xfb, 0xff]), decode_base64("+/8="));
+    assert_eq!(Some(vec![0xfb, 0xff]), decode_base64("-_8"));
+    assert_eq!(Some(vec![]), decode_base64(""));
+    assert_eq!(None, decode_base64("+_8=")); // mixed alphabets
+    assert_eq!(None, decode_base64("aGVsbG8=aGVs")); // data after padding
+    assert_eq!(None, decode_base64("aGVsbG8==")); // padded too far
+    assert_eq!(None, decode_base64("aGVsb")); // no length of bytes encodes to 5 characters
+    assert_eq!(None, decode_base64("aGVsbG9=")); // stray bits
+    assert_eq!(None, decode_base64("aGVs*G8="));
+  }
+
+  #[test]
+  fn exit_statuses() {
+    assert_eq!(3, exit_status(&error::Error::Mismatch("a".to_string()))); // set --if-equals
+    assert_eq!(3, exit_status(&error::Error::Exists("a".to_string()))); // set --nx
+    assert_eq!(2, exit_status(&error::Error::NotFound));
+    assert_eq!(2, exit_status(&error::Error::DidYouMean(Box::new(error::Error::NotFound), vec!["b".to_string()])));
+    assert_eq!(1, exit_status(&error::Error::Malformed));
+  }
+}
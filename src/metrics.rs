@@ -0,0 +1,119 @@
+use std::fmt::Write;
+use std::sync::{Mutex, MutexGuard};
+use std::collections::BTreeMap;
+
+use serde::{Serialize, Deserialize};
+
+/// The type of a metric, which is fixed when it is first written: counters
+/// only ever increase, while gauges may be set to any value.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum Kind {
+  Counter,
+  Gauge,
+}
+
+impl Kind {
+  fn name(&self) -> &'static str {
+    match self {
+      Self::Counter => "counter",
+      Self::Gauge   => "gauge",
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Metric {
+  #[serde(rename="type")]
+  pub kind: Kind,
+  pub value: f64,
+}
+
+/// Numeric metrics, kept apart from the documents in the store so simple
+/// counters need not be read, modified, and rewritten as JSON.
+pub struct Metrics {
+  values: Mutex<BTreeMap<String, Metric>>,
+}
+
+impl Metrics {
+  pub fn new() -> Self {
+    Self{
+      values: Mutex::new(BTreeMap::new()),
+    }
+  }
+
+  fn values(&self) -> MutexGuard<'_, BTreeMap<String, Metric>> {
+    match self.values.lock() {
+      Ok(values) => values,
+      Err(err)   => err.into_inner(),
+    }
+  }
+
+  /// Add to a metric, creating a counter if it does not exist. Counters may
+  /// not be decreased. Produces the updated value.
+  pub fn incr(&self, name: &str, by: f64) -> Result<f64, String> {
+    let mut values = self.values();
+    let metric = values.entry(name.to_string()).or_insert(Metric{ kind: Kind::Counter, value: 0.0 });
+    if metric.kind == Kind::Counter && by < 0.0 {
+      return Err(format!("Counters cannot be decreased: {}", name));
+    }
+    metric.value += by;
+    Ok(metric.value)
+  }
+
+  /// Set a metric, creating a gauge if it does not exist. Counters cannot be
+  /// set.
+  pub fn set(&self, name: &str, value: f64) -> Result<f64, String> {
+    let mut values = self.values();
+    let metric = values.entry(name.to_string()).or_insert(Metric{ kind: Kind::Gauge, value: 0.0 });
+    if metric.kind == Kind::Counter {
+      return Err(format!("Counters cannot be set: {}", name));
+    }
+    metric.value = value;
+    Ok(metric.value)
+  }
+
+  pub fn dump(&self) -> BTreeMap<String, Metric> {
+    self.values().clone()
+  }
+}
+
+/// Render metrics in the Prometheus text exposition format. Characters
+/// which are not valid in Prometheus metric names, like the dots in
+/// `builds.failed`, are replaced with underscores.
+pub fn prometheus(metrics: &BTreeMap<String, Metric>) -> String {
+  let mut text = String::new();
+  for (name, metric) in metrics {
+    let name: String = name.chars().enumerate().map(|(i, c)| {
+      match c {
+        'a'..='z' | 'A'..='Z' | '_' | ':' => c,
+        '0'..='9' if i > 0 => c,
+        _ => '_',
+      }
+    }).collect();
+    let _ = writeln!(text, "# TYPE {} {}", name, metric.kind.name());
+    let _ = writeln!(text, "{} {}", name, metric.value);
+  }
+  text
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn counters_and_gauges() {
+    let metrics = Metrics::new();
+    assert_eq!(Ok(1.0), metrics.incr("builds.failed", 1.0));
+    assert_eq!(Ok(3.5), metrics.incr("builds.failed", 2.5));
+    assert!(metrics.incr("builds.failed", -1.0).is_err());
+    assert!(metrics.set("builds.failed", 0.0).is_err());
+    assert_eq!(Ok(72.5), metrics.set("temp", 72.5));
+    assert_eq!(Ok(70.5), metrics.incr("temp", -2.0)); // gauges go either way
+
+    let dump = metrics.dump();
+    assert_eq!(Some(&Metric{ kind: Kind::Counter, value: 3.5 }), dump.get("builds.failed"));
+    assert_eq!(serde_json::json!({"builds.failed": {"type": "counter", "value": 3.5}, "temp": {"type": "gauge", "value": 70.5}}), serde_json::to_value(&dump).unwrap());
+    assert_eq!("# TYPE builds_failed counter\nbuilds_failed 3.5\n# TYPE temp gauge\ntemp 70.5\n", prometheus(&dump));
+  }
+}
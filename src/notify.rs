@@ -0,0 +1,80 @@
+use std::env;
+use std::time;
+use std::process;
+use std::os::unix::net::{UnixDatagram, SocketAddr};
+
+use crate::error;
+
+const NOTIFY_SOCKET:  &str = "NOTIFY_SOCKET";
+const WATCHDOG_USEC:  &str = "WATCHDOG_USEC";
+const WATCHDOG_PID:   &str = "WATCHDOG_PID";
+
+pub const READY:    &str = "READY=1";
+pub const WATCHDOG: &str = "WATCHDOG=1";
+
+/// Send a state notification to systemd, if we are running under a unit
+/// which expects them; otherwise do nothing. Produces whether the
+/// notification was sent.
+pub fn notify(state: &str) -> Result<bool, error::Error> {
+  let path = match env::var(NOTIFY_SOCKET) {
+    Ok(path) => path,
+    Err(_)   => return Ok(false),
+  };
+  let sock = UnixDatagram::unbound()?;
+  match path.strip_prefix('@') {
+    Some(name) => sock.send_to_addr(state.as_bytes(), &abstract_addr(name)?)?,
+    None       => sock.send_to(state.as_bytes(), &path)?,
+  };
+  Ok(true)
+}
+
+#[cfg(target_os = "linux")]
+fn abstract_addr(name: &str) -> Result<SocketAddr, error::Error> {
+  use std::os::linux::net::SocketAddrExt;
+  Ok(SocketAddr::from_abstract_name(name.as_bytes())?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn abstract_addr(_name: &str) -> Result<SocketAddr, error::Error> {
+  Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into())
+}
+
+/// Pings the systemd watchdog at half the interval the unit requires.
+pub struct Watchdog {
+  interval: time::Duration,
+  last: time::Instant,
+}
+
+impl Watchdog {
+  /// Create a watchdog if the unit we are running under enables one for
+  /// this process.
+  pub fn from_env() -> Option<Self> {
+    if let Ok(pid) = env::var(WATCHDOG_PID) {
+      if pid.parse::<u32>().ok() != Some(process::id()) {
+        return None; // it's for somebody else
+      }
+    }
+    let usec = env::var(WATCHDOG_USEC).ok()?.parse::<u64>().ok()?;
+    if usec == 0 {
+      return None;
+    }
+    Some(Self{
+      interval: time::Duration::from_micros(usec / 2),
+      last: time::Instant::now(),
+    })
+  }
+
+  /// How long to wait, at most, before pinging again.
+  pub fn interval(&self) -> time::Duration {
+    self.interval
+  }
+
+  /// Ping the watchdog if it is due.
+  pub fn ping(&mut self) -> Result<(), error::Error> {
+    if self.last.elapsed() >= self.interval {
+      notify(WATCHDOG)?;
+      self.last = time::Instant::now();
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,221 @@
+use std::fmt;
+
+use serde::{Serialize, Deserialize};
+use serde_json::value::Value;
+
+/// A single operation of a JSON Patch (RFC 6902). Paths are JSON Pointers
+/// (RFC 6901), like `/a/b/0`, rather than the dotted paths used elsewhere.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Op {
+  Add{path: String, value: Value},
+  Remove{path: String},
+  Replace{path: String, value: Value},
+  Move{from: String, path: String},
+  Copy{from: String, path: String},
+  Test{path: String, value: Value},
+}
+
+impl Op {
+  fn name(&self) -> &'static str {
+    match self {
+      Self::Add{..}     => "add",
+      Self::Remove{..}  => "remove",
+      Self::Replace{..} => "replace",
+      Self::Move{..}    => "move",
+      Self::Copy{..}    => "copy",
+      Self::Test{..}    => "test",
+    }
+  }
+
+  fn path(&self) -> &str {
+    match self {
+      Self::Add{path, ..} | Self::Remove{path} | Self::Replace{path, ..} | Self::Move{path, ..} | Self::Copy{path, ..} | Self::Test{path, ..} => path,
+    }
+  }
+}
+
+/// Why a patch could not be applied: the index of the operation which
+/// failed, what it was, and why.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+  pub index: usize,
+  pub op: String,
+  pub path: String,
+  pub message: String,
+}
+
+impl fmt::Display for Failure {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.op.as_str() {
+      "" => write!(f, "Operation {}: {}", self.index, self.message),
+      op => write!(f, "Operation {} ({} {}): {}", self.index, op, self.path, self.message),
+    }
+  }
+}
+
+/// Parse a patch document, which is an array of operations.
+pub fn parse(doc: &Value) -> Result<Vec<Op>, Failure> {
+  let ops = match doc {
+    Value::Array(ops) => ops,
+    _                 => return Err(Failure{ index: 0, op: String::new(), path: String::new(), message: "A patch must be an array of operations".to_string() }),
+  };
+  ops.iter().enumerate().map(|(i, op)| {
+    serde_json::from_value::<Op>(op.clone()).map_err(|e| { Failure{ index: i, op: String::new(), path: String::new(), message: e.to_string() } })
+  }).collect()
+}
+
+/// Apply every operation of a patch to a value in turn, producing the
+/// patched value, or the first operation which failed, in which case none
+/// of them are applied.
+pub fn apply(doc: &Value, ops: &[Op]) -> Result<Value, Failure> {
+  let mut doc = doc.clone();
+  for (i, op) in ops.iter().enumerate() {
+    if let Err(message) = apply_op(&mut doc, op) {
+      return Err(Failure{ index: i, op: op.name().to_string(), path: op.path().to_string(), message });
+    }
+  }
+  Ok(doc)
+}
+
+fn apply_op(doc: &mut Value, op: &Op) -> Result<(), String> {
+  match op {
+    Op::Add{path, value} => add(doc, &tokens(path)?, value.clone()),
+    Op::Remove{path} => remove(doc, &tokens(path)?).map(drop),
+    Op::Replace{path, value} => match lookup_mut(doc, &tokens(path)?) {
+      Some(target) => { *target = value.clone(); Ok(()) },
+      None         => Err(format!("No value at {}", path)),
+    },
+    Op::Move{from, path} => {
+      if path != from && path.starts_with(&format!("{}/", from)) {
+        return Err(format!("Cannot move {} into itself", from));
+      }
+      let value = remove(doc, &tokens(from)?)?;
+      add(doc, &tokens(path)?, value)
+    },
+    Op::Copy{from, path} => match lookup(doc, &tokens(from)?) {
+      Some(value) => add(doc, &tokens(path)?, value.clone()),
+      None        => Err(format!("No value at {}", from)),
+    },
+    Op::Test{path, value} => match lookup(doc, &tokens(path)?) {
+      Some(actual) if actual == value => Ok(()),
+      Some(actual) => Err(format!("Expected {}, found {}", value, actual)),
+      None         => Err(format!("No value at {}", path)),
+    },
+  }
+}
+
+/// Split a JSON Pointer into its unescaped reference tokens.
+fn tokens(pointer: &str) -> Result<Vec<String>, String> {
+  if pointer.is_empty() {
+    return Ok(Vec::new()); // the whole document
+  }
+  match pointer.strip_prefix('/') {
+    Some(rest) => Ok(rest.split('/').map(|e| { e.replace("~1", "/").replace("~0", "~") }).collect()),
+    None       => Err(format!("Invalid JSON Pointer, which must begin with '/': {}", pointer)),
+  }
+}
+
+/// The index an array token refers to; indexes have no leading zeros.
+fn index(token: &str) -> Option<usize> {
+  match token {
+    "0"                     => Some(0),
+    t if t.starts_with('0') => None,
+    t if t.chars().all(|c| { c.is_ascii_digit() }) => t.parse::<usize>().ok(),
+    _ => None,
+  }
+}
+
+fn lookup<'a>(doc: &'a Value, tokens: &[String]) -> Option<&'a Value> {
+  tokens.iter().try_fold(doc, |value, token| {
+    match value {
+      Value::Object(v) => v.get(token),
+      Value::Array(v)  => index(token).and_then(|i| { v.get(i) }),
+      _                => None,
+    }
+  })
+}
+
+fn lookup_mut<'a>(doc: &'a mut Value, tokens: &[String]) -> Option<&'a mut Value> {
+  tokens.iter().try_fold(doc, |value, token| {
+    match value {
+      Value::Object(v) => v.get_mut(token),
+      Value::Array(v)  => index(token).and_then(move |i| { v.get_mut(i) }),
+      _                => None,
+    }
+  })
+}
+
+fn add(doc: &mut Value, tokens: &[String], value: Value) -> Result<(), String> {
+  let (last, parent) = match tokens.split_last() {
+    Some(split) => split,
+    None        => { *doc = value; return Ok(()) },
+  };
+  match lookup_mut(doc, parent) {
+    Some(Value::Object(v)) => { v.insert(last.to_string(), value); Ok(()) },
+    Some(Value::Array(v)) => match (last.as_str(), index(last)) {
+      ("-", _)                     => { v.push(value); Ok(()) },
+      (_, Some(i)) if i <= v.len() => { v.insert(i, value); Ok(()) },
+      _                            => Err(format!("No index {} in an array of {}", last, v.len())),
+    },
+    Some(_) => Err("Cannot add a member to a value which is neither an object nor an array".to_string()),
+    None    => Err(format!("No value at /{}", parent.join("/"))),
+  }
+}
+
+fn remove(doc: &mut Value, tokens: &[String]) -> Result<Value, String> {
+  let (last, parent) = match tokens.split_last() {
+    Some(split) => split,
+    None        => return Err("Cannot remove the whole value".to_string()),
+  };
+  let removed = match lookup_mut(doc, parent) {
+    Some(Value::Object(v)) => v.remove(last),
+    Some(Value::Array(v)) => match index(last) {
+      Some(i) if i < v.len() => Some(v.remove(i)),
+      _                      => None,
+    },
+    _ => None,
+  };
+  removed.ok_or_else(|| { format!("No value at /{}", tokens.join("/")) })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn patch(doc: &str, ops: &str) -> Result<Value, Failure> {
+    let doc: Value = serde_json::from_str(doc).unwrap();
+    apply(&doc, &parse(&serde_json::from_str(ops).unwrap())?)
+  }
+
+  #[test]
+  fn apply_patches() {
+    let doc = r#"{"a": {"b": [1, 2]}, "c": "d", "e~f": 1, "g/h": 2}"#;
+    assert_eq!(serde_json::json!({"a": {"b": [0, 1, 2, 3]}, "x": "d", "e~f": 1, "g/h": 2, "y": {"b": [0, 1, 2, 3]}}), patch(doc, r#"[
+      {"op": "add", "path": "/a/b/0", "value": 0},
+      {"op": "add", "path": "/a/b/-", "value": 3},
+      {"op": "move", "from": "/c", "path": "/x"},
+      {"op": "copy", "from": "/a", "path": "/y"},
+      {"op": "test", "path": "/x", "value": "d"}
+    ]"#).unwrap());
+    assert_eq!(serde_json::json!({"a": {"b": [1, 5]}, "c": "d"}), patch(doc, r#"[
+      {"op": "remove", "path": "/e~0f"},
+      {"op": "remove", "path": "/g~1h"},
+      {"op": "replace", "path": "/a/b/1", "value": 5}
+    ]"#).unwrap());
+    assert_eq!(serde_json::json!([1]), patch(doc, r#"[{"op": "replace", "path": "", "value": [1]}]"#).unwrap());
+  }
+
+  #[test]
+  fn report_failures() {
+    let doc = r#"{"a": {"b": [1, 2]}, "c": "d"}"#;
+    let err = patch(doc, r#"[{"op": "remove", "path": "/c"}, {"op": "test", "path": "/a/b/0", "value": 2}]"#).unwrap_err();
+    assert_eq!("Operation 1 (test /a/b/0): Expected 2, found 1", err.to_string());
+    assert_eq!("No index 5 in an array of 2", patch(doc, r#"[{"op": "add", "path": "/a/b/5", "value": 0}]"#).unwrap_err().message);
+    assert_eq!(1, patch(doc, r#"[{"op": "add", "path": "/x", "value": 0}, {"op": "remove", "path": "/a/b/01"}]"#).unwrap_err().index);
+    assert_eq!(0, patch(doc, r#"[{"op": "move", "from": "/a", "path": "/a/b/c"}]"#).unwrap_err().index);
+    assert_eq!(1, patch(doc, r#"[{"op": "test", "path": "/c", "value": "d"}, {"op": "frob", "path": "/c"}]"#).unwrap_err().index);
+    assert!(patch(doc, r#"{"op": "remove", "path": "/c"}"#).is_err());
+    assert!(patch(doc, r#"[{"op": "remove", "path": "c"}]"#).is_err());
+  }
+}
@@ -0,0 +1,154 @@
+use regex::Regex;
+
+/// A pattern which selects keys: a literal prefix, which matches every key
+/// beginning with it, or a regular expression, which must match the entire
+/// key.
+#[derive(Debug, Clone)]
+pub enum Pattern {
+  Prefix(String),
+  Regex(Regex),
+}
+
+impl Pattern {
+  /// Parse a pattern, as a regular expression if requested and otherwise as
+  /// a prefix. Regular expressions are anchored at both ends. Errors are
+  /// described on a single line, so they can be sent back to a client.
+  pub fn new(pattern: &str, regex: bool) -> Result<Self, String> {
+    if regex {
+      match Regex::new(&format!("^(?:{})$", pattern)) {
+        Ok(regex) => Ok(Self::Regex(regex)),
+        Err(err)  => Err(format!("Invalid pattern: {}", err.to_string().lines().last().unwrap_or("").trim_start_matches("error: "))),
+      }
+    } else {
+      Ok(Self::Prefix(pattern.to_string()))
+    }
+  }
+
+  /// Parse a glob, in which `*` matches any run of characters, `?` matches
+  /// any one, and `[...]` matches any in a set (or, as `[!...]`, any not in
+  /// it). Globs must match entire keys.
+  pub fn glob(glob: &str) -> Result<Self, String> {
+    let mut expr = String::new();
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+      match c {
+        '*' => expr.push_str(".*"),
+        '?' => expr.push('.'),
+        '[' => {
+          expr.push('[');
+          if chars.peek() == Some(&'!') {
+            chars.next();
+            expr.push('^');
+          }
+          loop {
+            match chars.next() {
+              Some(']')  => break,
+              Some('\\') => expr.push_str("\\\\"),
+              Some(c)    => expr.push(c),
+              None       => return Err(format!("Invalid pattern: unclosed set in {}", glob)),
+            }
+          }
+          expr.push(']');
+        },
+        c => expr.push_str(&regex::escape(&c.to_string())),
+      }
+    }
+    Self::new(&expr, true)
+  }
+
+  /// Parse a pattern selecting keys as clients give it: as a regular
+  /// expression or glob if it is flagged as one, and otherwise as a literal
+  /// prefix, whatever characters it contains. This is the one place that is
+  /// decided, so every command reads a pattern the same.
+  pub fn keys(pattern: &str, regex: bool, glob: bool) -> Result<Self, String> {
+    if glob {
+      Self::glob(pattern)
+    } else {
+      Self::new(pattern, regex)
+    }
+  }
+
+  /// Make a pattern match keys case-insensitively.
+  pub fn ci(self) -> Result<Self, String> {
+    let expr = match &self {
+      Self::Prefix(prefix) => format!("^{}.*$", regex::escape(prefix)),
+      Self::Regex(regex)   => regex.as_str().to_string(),
+    };
+    match Regex::new(&format!("(?i){}", expr)) {
+      Ok(regex) => Ok(Self::Regex(regex)),
+      Err(err)  => Err(format!("Invalid pattern: {}", err.to_string().lines().last().unwrap_or("").trim_start_matches("error: "))),
+    }
+  }
+
+  /// The prefix every matching key shares, which narrows a scan of the
+  /// store before the pattern itself is applied.
+  pub fn prefix(&self) -> &str {
+    match self {
+      Self::Prefix(prefix) => prefix,
+      Self::Regex(_)       => "",
+    }
+  }
+
+  pub fn is_match(&self, key: &str) -> bool {
+    match self {
+      Self::Prefix(prefix) => key.starts_with(prefix.as_str()),
+      Self::Regex(regex)   => regex.is_match(key),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn match_keys() {
+    let p = Pattern::new("user", false).unwrap();
+    assert!(p.is_match("user"));
+    assert!(p.is_match("users"));
+    assert!(!p.is_match("a-user"));
+    assert!(Pattern::new("", false).unwrap().is_match("anything"));
+
+    let p = Pattern::new("user-[0-9]+", true).unwrap();
+    assert!(p.is_match("user-12"));
+    assert!(!p.is_match("user-12x"));
+    assert!(!p.is_match("a-user-12"));
+    assert!(Pattern::new("a|b", true).unwrap().is_match("b"));
+    assert!(!Pattern::new("a|b", true).unwrap().is_match("ab"));
+    assert_eq!("Invalid pattern: unclosed group", Pattern::new("(", true).unwrap_err());
+
+    let p = Pattern::glob("job:*").unwrap();
+    assert!(p.is_match("job:"));
+    assert!(p.is_match("job:12"));
+    assert!(!p.is_match("jobs:12"));
+    assert!(Pattern::glob("job.?").unwrap().is_match("job.a"));
+    assert!(!Pattern::glob("job.?").unwrap().is_match("jobxa"));
+    assert!(Pattern::glob("job-[0-9]").unwrap().is_match("job-3"));
+    assert!(!Pattern::glob("job-[!0-9]").unwrap().is_match("job-3"));
+    assert!(Pattern::glob("job-[").is_err());
+  }
+
+  #[test]
+  fn key_patterns() {
+    let p = Pattern::keys("job:", false, false).unwrap();
+    assert!(p.is_match("job:1") && p.is_match("job:"));
+    let p = Pattern::keys("job:*", false, false).unwrap(); // only a prefix, taken literally
+    assert!(p.is_match("job:*1") && !p.is_match("job:1"));
+    let p = Pattern::keys("job:*", false, true).unwrap();
+    assert!(p.is_match("job:1") && !p.is_match("job"));
+    assert!(!Pattern::keys("job:?", false, true).unwrap().is_match("job:12"));
+    assert!(Pattern::keys("job:1", false, true).unwrap().is_match("job:1"));
+    assert!(!Pattern::keys("job:1", false, true).unwrap().is_match("job:12"));
+    assert!(Pattern::keys("job:[0-9]+", true, false).unwrap().is_match("job:12"));
+  }
+
+  #[test]
+  fn ci_patterns() {
+    let p = Pattern::new("User.", false).unwrap().ci().unwrap();
+    assert!(p.is_match("user.1") && p.is_match("USER.1") && !p.is_match("userx1"));
+    assert_eq!("", p.prefix()); // every key must be scanned
+    let p = Pattern::glob("Job:*").unwrap().ci().unwrap();
+    assert!(p.is_match("JOB:1") && !p.is_match("xjob:1"));
+    assert!(Pattern::new("é", true).unwrap().ci().unwrap().is_match("É"));
+  }
+}
@@ -0,0 +1,162 @@
+use std::panic;
+use std::thread;
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// The number of urgent jobs run in a row while others wait, after which
+/// one of those is run, so that they are delayed but never starved.
+const URGENT_BURST: usize = 8;
+
+/// The lane a job waits in. Urgent jobs, like small reads, are run before
+/// any normal job which is waiting, so that they are not stuck behind bulk
+/// operations.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Priority {
+  Urgent,
+  Normal,
+}
+
+struct Queue {
+  urgent: VecDeque<Job>,
+  normal: VecDeque<Job>,
+  burst: usize,
+  closed: bool,
+}
+
+impl Queue {
+  fn next(&mut self) -> Option<Job> {
+    if self.burst >= URGENT_BURST || self.urgent.is_empty() {
+      if let Some(job) = self.normal.pop_front() {
+        self.burst = 0;
+        return Some(job);
+      }
+    }
+    self.burst = match self.normal.is_empty() {
+      true  => 0, // nobody is kept waiting
+      false => self.burst + 1,
+    };
+    self.urgent.pop_front()
+  }
+}
+
+struct Shared {
+  queue: Mutex<Queue>,
+  ready: Condvar,
+}
+
+impl Shared {
+  fn lock(&self) -> MutexGuard<'_, Queue> {
+    match self.queue.lock() {
+      Ok(queue) => queue,
+      Err(err)  => err.into_inner(),
+    }
+  }
+}
+
+/// A fixed set of threads which run the jobs submitted to it in the order
+/// they are submitted, within each priority. Jobs beyond the number of
+/// threads wait their turn.
+pub struct Pool {
+  shared: Arc<Shared>,
+}
+
+impl Pool {
+  pub fn new(size: usize) -> Self {
+    let shared = Arc::new(Shared{
+      queue: Mutex::new(Queue{
+        urgent: VecDeque::new(),
+        normal: VecDeque::new(),
+        burst: 0,
+        closed: false,
+      }),
+      ready: Condvar::new(),
+    });
+    for _ in 0..size.max(1) {
+      let shared = shared.clone();
+      thread::spawn(move || {
+        loop {
+          let job = {
+            let mut queue = shared.lock();
+            loop {
+              if let Some(job) = queue.next() {
+                break job;
+              }
+              if queue.closed {
+                return; // pool dropped
+              }
+              queue = match shared.ready.wait(queue) {
+                Ok(queue) => queue,
+                Err(err)  => err.into_inner(),
+              };
+            }
+          };
+          let _ = panic::catch_unwind(panic::AssertUnwindSafe(job)); // a failed job doesn't cost us a worker
+        }
+      });
+    }
+    Self{
+      shared,
+    }
+  }
+
+  pub fn execute<F: FnOnce() + Send + 'static>(&self, priority: Priority, job: F) {
+    let mut queue = self.shared.lock();
+    match priority {
+      Priority::Urgent => queue.urgent.push_back(Box::new(job)),
+      Priority::Normal => queue.normal.push_back(Box::new(job)),
+    }
+    self.shared.ready.notify_one();
+  }
+}
+
+impl Drop for Pool {
+  fn drop(&mut self) {
+    self.shared.lock().closed = true;
+    self.shared.ready.notify_all();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::mpsc;
+
+  #[test]
+  fn run_jobs() {
+    let pool = Pool::new(3);
+    let (tx, rx) = mpsc::channel();
+    for i in 0..10 {
+      let tx = tx.clone();
+      pool.execute(Priority::Normal, move || { tx.send(i).unwrap(); });
+    }
+    let mut res: Vec<usize> = (0..10).map(|_| { rx.recv().unwrap() }).collect();
+    res.sort();
+    assert_eq!((0..10).collect::<Vec<usize>>(), res);
+  }
+
+  #[test]
+  fn run_urgent_jobs_first() {
+    let pool = Pool::new(1);
+    let (block_tx, block_rx) = mpsc::channel::<()>();
+    let (tx, rx) = mpsc::channel();
+    let started = tx.clone();
+    pool.execute(Priority::Normal, move || { // occupy the only worker
+      started.send("started".to_string()).unwrap();
+      block_rx.recv().unwrap();
+    });
+    assert_eq!("started", rx.recv().unwrap());
+    for i in 0..10 {
+      let tx = tx.clone();
+      pool.execute(Priority::Normal, move || { tx.send(format!("n{}", i)).unwrap(); });
+    }
+    for i in 0..10 {
+      let tx = tx.clone();
+      pool.execute(Priority::Urgent, move || { tx.send(format!("u{}", i)).unwrap(); });
+    }
+    block_tx.send(()).unwrap();
+    let res: Vec<String> = (0..20).map(|_| { rx.recv().unwrap() }).collect();
+    assert_eq!(vec!["u0", "u1", "u2", "u3", "u4", "u5", "u6", "u7", "n0", "u8", "u9", "n1"], res[..12]);
+  }
+}
@@ -0,0 +1,344 @@
+use std::fmt;
+use std::cmp;
+use std::str::FromStr;
+
+use nom;
+use serde_json;
+use serde_json::value::Value;
+
+use crate::jsonpath;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+  ParseSyntaxError,
+  ParseLiteralError(String),
+}
+
+impl<E> From<nom::Err<E>> for Error {
+  fn from(_: nom::Err<E>) -> Self {
+    Self::ParseSyntaxError
+  }
+}
+
+impl From<serde_json::Error> for Error {
+  fn from(err: serde_json::Error) -> Self {
+    Self::ParseLiteralError(err.to_string())
+  }
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::ParseSyntaxError => write!(f, "Syntax error in predicate"),
+      Self::ParseLiteralError(msg) => write!(f, "Invalid literal in predicate: {}", msg),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+  Equal,
+  NotEqual,
+  Less,
+  LessEqual,
+  Greater,
+  GreaterEqual,
+}
+
+impl fmt::Display for Operator {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Equal        => write!(f, "=="),
+      Self::NotEqual     => write!(f, "!="),
+      Self::Less         => write!(f, "<"),
+      Self::LessEqual    => write!(f, "<="),
+      Self::Greater      => write!(f, ">"),
+      Self::GreaterEqual => write!(f, ">="),
+    }
+  }
+}
+
+/// A predicate of the form `path OP literal`, where the path is relative to
+/// the value being tested and the literal is any JSON value. An empty path
+/// tests the value itself, e.g.: `== "error"`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Predicate {
+  path: String,
+  op: Operator,
+  value: Value,
+}
+
+impl Predicate {
+  pub fn matches(&self, value: &Value) -> bool {
+    let value = if self.path.is_empty() {
+      Some(value)
+    } else {
+      jsonpath::Path::new(&self.path).value(value)
+    };
+    match value {
+      Some(value) => compare(value, self.op, &self.value),
+      None        => false,
+    }
+  }
+}
+
+impl FromStr for Predicate {
+  type Err = Error;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    parse(s)
+  }
+}
+
+impl fmt::Display for Predicate {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} {} {}", self.path, self.op, self.value)
+  }
+}
+
+fn compare(left: &Value, op: Operator, right: &Value) -> bool {
+  match op {
+    Operator::Equal    => return left == right,
+    Operator::NotEqual => return left != right,
+    _                  => {},
+  }
+  let ord = match (left, right) {
+    (Value::Number(l), Value::Number(r)) => match (l.as_f64(), r.as_f64()) {
+      (Some(l), Some(r)) => l.partial_cmp(&r),
+      _                  => None,
+    },
+    (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+    _                                    => None, // other types are not ordered
+  };
+  match ord {
+    Some(ord) => match op {
+      Operator::Less         => ord == cmp::Ordering::Less,
+      Operator::LessEqual    => ord != cmp::Ordering::Greater,
+      Operator::Greater      => ord == cmp::Ordering::Greater,
+      Operator::GreaterEqual => ord != cmp::Ordering::Less,
+      _                      => false,
+    },
+    None => false,
+  }
+}
+
+fn is_path(c: char) -> bool {
+  !c.is_whitespace() && !is_operator(c)
+}
+
+fn is_operator(c: char) -> bool {
+  matches!(c, '=' | '!' | '<' | '>')
+}
+
+fn path_primary(input: &str) -> nom::IResult<&str, &str> {
+  nom::sequence::delimited(
+    nom::character::complete::multispace0,
+    nom::bytes::complete::take_while(is_path),
+    nom::character::complete::multispace0,
+  )(input)
+}
+
+fn operator_primary(input: &str) -> nom::IResult<&str, Operator> {
+  nom::branch::alt((
+    nom::combinator::value(Operator::Equal,        nom::bytes::complete::tag("==")),
+    nom::combinator::value(Operator::NotEqual,     nom::bytes::complete::tag("!=")),
+    nom::combinator::value(Operator::LessEqual,    nom::bytes::complete::tag("<=")),
+    nom::combinator::value(Operator::GreaterEqual, nom::bytes::complete::tag(">=")),
+    nom::combinator::value(Operator::Less,         nom::bytes::complete::tag("<")),
+    nom::combinator::value(Operator::Greater,      nom::bytes::complete::tag(">")),
+  ))(input)
+}
+
+pub fn parse(input: &str) -> Result<Predicate, Error> {
+  let (input, (path, op)) = nom::sequence::tuple((path_primary, operator_primary))(input)?;
+  let input = input.trim();
+  if input.is_empty() { // a literal is required
+    return Err(Error::ParseSyntaxError);
+  }
+  Ok(Predicate{
+    path: path.to_string(),
+    op,
+    value: serde_json::from_str(input)?,
+  })
+}
+
+/// What a clause of a filter tests: the name of a key, or its value or a
+/// path within it.
+#[derive(Debug, Clone, PartialEq)]
+enum Subject {
+  Key,
+  Value(String),
+}
+
+impl Subject {
+  fn parse(path: &str) -> Result<Self, Error> {
+    match path {
+      "key"   => Ok(Self::Key),
+      "value" => Ok(Self::Value(String::new())),
+      path    => match path.strip_prefix("value.") {
+        Some(path) if !path.is_empty() => Ok(Self::Value(path.to_string())),
+        _                            => Err(Error::ParseSyntaxError),
+      },
+    }
+  }
+}
+
+impl fmt::Display for Subject {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Key                            => write!(f, "key"),
+      Self::Value(path) if path.is_empty() => write!(f, "value"),
+      Self::Value(path)                    => write!(f, "value.{}", path),
+    }
+  }
+}
+
+/// A clause of a filter, which either tests that its subject exists or
+/// compares it with a predicate whose path is relative to the subject.
+#[derive(Debug, Clone, PartialEq)]
+struct Clause {
+  subject: Subject,
+  test: Option<Predicate>,
+}
+
+impl Clause {
+  fn matches(&self, key: &str, value: &Value) -> bool {
+    match (&self.subject, &self.test) {
+      (Subject::Key, None)               => true,
+      (Subject::Key, Some(pred))         => pred.matches(&Value::String(key.to_string())),
+      (Subject::Value(path), None)       => path.is_empty() || jsonpath::Path::new(path).value(value).is_some(),
+      (Subject::Value(path), Some(pred)) => Predicate{ path: path.clone(), op: pred.op, value: pred.value.clone() }.matches(value),
+    }
+  }
+}
+
+impl fmt::Display for Clause {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match &self.test {
+      Some(pred) => write!(f, "{} {} {}", self.subject, pred.op, pred.value),
+      None       => write!(f, "exists {}", self.subject),
+    }
+  }
+}
+
+/// A filter over keys and their values, made of clauses joined by `&&`
+/// which must all match. Each clause is either `SUBJECT OP literal` or
+/// `exists SUBJECT`, where the subject is `key`, `value`, or a path within
+/// the value, e.g.: `key >= "job:100" && value.status == "running"`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+  clauses: Vec<Clause>,
+}
+
+impl Filter {
+  pub fn matches(&self, key: &str, value: &Value) -> bool {
+    self.clauses.iter().all(|e| { e.matches(key, value) })
+  }
+}
+
+impl FromStr for Filter {
+  type Err = Error;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut clauses = Vec::new();
+    for clause in split_clauses(s) {
+      let clause = clause.trim();
+      let clause = match clause.strip_prefix("exists") {
+        Some(path) if path.starts_with(char::is_whitespace) => Clause{
+          subject: Subject::parse(path.trim())?,
+          test: None,
+        },
+        _ => {
+          let pred = parse(clause)?;
+          Clause{
+            subject: Subject::parse(&pred.path)?,
+            test: Some(Predicate{ path: String::new(), op: pred.op, value: pred.value }),
+          }
+        },
+      };
+      clauses.push(clause);
+    }
+    Ok(Self{
+      clauses,
+    })
+  }
+}
+
+impl fmt::Display for Filter {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let clauses: Vec<String> = self.clauses.iter().map(|e| { e.to_string() }).collect();
+    write!(f, "{}", clauses.join(" && "))
+  }
+}
+
+/// Split a filter into its clauses on the `&&`s which are not within string
+/// literals.
+fn split_clauses(input: &str) -> Vec<&str> {
+  let mut clauses = Vec::new();
+  let (mut start, mut quoted, mut escaped) = (0, false, false);
+  let bytes = input.as_bytes();
+  let mut i = 0;
+  while i < bytes.len() {
+    match bytes[i] {
+      _ if escaped      => escaped = false,
+      b'\\' if quoted   => escaped = true,
+      b'"'              => quoted = !quoted,
+      b'&' if !quoted && bytes.get(i + 1) == Some(&b'&') => {
+        clauses.push(&input[start..i]);
+        start = i + 2;
+        i += 1;
+      },
+      _ => {},
+    }
+    i += 1;
+  }
+  clauses.push(&input[start..]);
+  clauses
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_predicate() {
+    assert_eq!(Ok(Predicate{path: "status".to_string(), op: Operator::Equal, value: Value::String("error".to_string())}), parse(r#"status=="error""#));
+    assert_eq!(Ok(Predicate{path: "a.b".to_string(), op: Operator::GreaterEqual, value: Value::Number(10.into())}), parse("a.b >= 10"));
+    assert_eq!(Ok(Predicate{path: "".to_string(), op: Operator::NotEqual, value: Value::Null}), parse(" != null"));
+    assert_eq!(Ok(Predicate{path: "n".to_string(), op: Operator::Less, value: Value::Number(1.into())}), parse("n<1"));
+    assert_eq!(Err(Error::ParseSyntaxError), parse("status"));
+    assert_eq!(Err(Error::ParseSyntaxError), parse("status =="));
+    assert!(matches!(parse("status == error"), Err(Error::ParseLiteralError(_))));
+  }
+
+  #[test]
+  fn test_match_predicate() {
+    let v: Value = serde_json::from_str(r#"{"status": "error", "count": 5, "sub": {"ok": true}}"#).unwrap();
+    assert!(parse(r#"status == "error""#).unwrap().matches(&v));
+    assert!(!parse(r#"status != "error""#).unwrap().matches(&v));
+    assert!(parse("count > 4").unwrap().matches(&v));
+    assert!(parse("count <= 5").unwrap().matches(&v));
+    assert!(!parse("count < 5").unwrap().matches(&v));
+    assert!(parse("sub.ok == true").unwrap().matches(&v));
+    assert!(!parse("missing == null").unwrap().matches(&v));
+    assert!(!parse(r#"count > "a""#).unwrap().matches(&v));
+    assert!(parse("== 5").unwrap().matches(&Value::Number(5.into())));
+  }
+
+  #[test]
+  fn test_filter() {
+    let f: Filter = r#"value.status == "running" && key >= "job:2""#.parse().unwrap();
+    assert_eq!(r#"value.status == "running" && key >= "job:2""#, f.to_string());
+    let v: Value = serde_json::from_str(r#"{"status": "running", "owner": "a && b"}"#).unwrap();
+    assert!(f.matches("job:3", &v));
+    assert!(!f.matches("job:1", &v));
+    assert!(!f.matches("job:3", &Value::Null));
+    assert!("exists value.owner".parse::<Filter>().unwrap().matches("k", &v));
+    assert!(!"exists value.missing".parse::<Filter>().unwrap().matches("k", &v));
+    assert!(r#"value.owner == "a && b""#.parse::<Filter>().unwrap().matches("k", &v));
+    assert!("value != null".parse::<Filter>().unwrap().matches("k", &v));
+    assert_eq!(Err(Error::ParseSyntaxError), "status == 1".parse::<Filter>());
+    assert_eq!(Err(Error::ParseSyntaxError), "exists key.a".parse::<Filter>());
+    assert_eq!(Err(Error::ParseSyntaxError), "value.a == 1 &&".parse::<Filter>());
+  }
+}
@@ -0,0 +1,148 @@
+use std::time;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+
+use crate::duration;
+
+/// A rate, as a number of requests allowed per period, e.g., `1000/s` or
+/// `50/10s`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+  count: u64,
+  per: time::Duration,
+}
+
+impl FromStr for Rate {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (count, per) = match s.trim().split_once('/') {
+      Some(parts) => parts,
+      None        => return Err(format!("Invalid rate, expected <count>/<period>: {}", s)),
+    };
+    let count = match count.parse::<u64>() {
+      Ok(count) if count > 0 => count,
+      _ => return Err(format!("Invalid rate count: {}", count)),
+    };
+    let per = match per.starts_with(|c: char| { c.is_ascii_digit() }) {
+      true  => duration::parse(per),
+      false => duration::parse(&format!("1{}", per)), // a bare unit, as in 1000/s
+    };
+    match per {
+      Ok(per) if !per.is_zero() => Ok(Self{ count, per }),
+      _ => Err(format!("Invalid rate period: {}", s)),
+    }
+  }
+}
+
+/// Who a limit applies to: a user, across all of their connections, or a
+/// single connection when its user is not known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Subject {
+  Uid(u32),
+  Connection(usize),
+}
+
+/// A token bucket which holds up to one period's worth of requests and is
+/// refilled continuously at the rate.
+#[derive(Debug)]
+struct Bucket {
+  tokens: f64,
+  updated: time::Instant,
+}
+
+impl Bucket {
+  fn new(rate: &Rate, now: time::Instant) -> Self {
+    Self{
+      tokens: rate.count as f64,
+      updated: now,
+    }
+  }
+
+  /// Take a token if one is available; otherwise produce how long it will
+  /// be until one is.
+  fn take(&mut self, rate: &Rate, now: time::Instant) -> Result<(), time::Duration> {
+    let per_token = rate.per.as_secs_f64() / rate.count as f64;
+    let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+    self.tokens = (self.tokens + elapsed / per_token).min(rate.count as f64);
+    self.updated = now;
+    if self.tokens >= 1.0 {
+      self.tokens -= 1.0;
+      Ok(())
+    } else {
+      Err(time::Duration::from_secs_f64((1.0 - self.tokens) * per_token))
+    }
+  }
+}
+
+/// Limits the rate at which each subject may make requests. Limiters are
+/// shared between connections, so a user can't escape their limit by
+/// opening more of them.
+#[derive(Debug, Clone)]
+pub struct Limiter {
+  rate: Rate,
+  buckets: Arc<Mutex<HashMap<Subject, Bucket>>>,
+}
+
+impl Limiter {
+  pub fn new(rate: Rate) -> Self {
+    Self{
+      rate,
+      buckets: Arc::new(Mutex::new(HashMap::new())),
+    }
+  }
+
+  /// Admit a request from a subject, or produce how long it should wait
+  /// before trying again.
+  pub fn take(&self, subject: Subject) -> Result<(), time::Duration> {
+    let now = time::Instant::now();
+    let mut buckets = match self.buckets.lock() {
+      Ok(buckets) => buckets,
+      Err(err)    => err.into_inner(),
+    };
+    buckets.entry(subject).or_insert_with(|| { Bucket::new(&self.rate, now) }).take(&self.rate, now)
+  }
+
+  /// Forget a connection's bucket once it has closed. Users' buckets are
+  /// kept, since they outlive any one connection.
+  pub fn release(&self, subject: Subject) {
+    if let Subject::Connection(_) = subject {
+      let mut buckets = match self.buckets.lock() {
+        Ok(buckets) => buckets,
+        Err(err)    => err.into_inner(),
+      };
+      buckets.remove(&subject);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_rate() {
+    assert_eq!(Ok(Rate{ count: 1000, per: time::Duration::from_secs(1) }), "1000/s".parse());
+    assert_eq!(Ok(Rate{ count: 50, per: time::Duration::from_secs(10) }), "50/10s".parse());
+    assert_eq!(Ok(Rate{ count: 5, per: time::Duration::from_secs(60) }), "5/m".parse());
+    assert!("1000".parse::<Rate>().is_err());
+    assert!("0/s".parse::<Rate>().is_err());
+    assert!("10/x".parse::<Rate>().is_err());
+  }
+
+  #[test]
+  fn token_bucket() {
+    let rate: Rate = "2/s".parse().unwrap();
+    let start = time::Instant::now();
+    let mut bucket = Bucket::new(&rate, start);
+    assert_eq!(Ok(()), bucket.take(&rate, start));
+    assert_eq!(Ok(()), bucket.take(&rate, start));
+    assert_eq!(Err(time::Duration::from_millis(500)), bucket.take(&rate, start));
+    assert_eq!(Ok(()), bucket.take(&rate, start + time::Duration::from_millis(500)));
+    assert!(bucket.take(&rate, start + time::Duration::from_millis(500)).is_err());
+    assert_eq!(Ok(()), bucket.take(&rate, start + time::Duration::from_secs(10)));
+    assert_eq!(Ok(()), bucket.take(&rate, start + time::Duration::from_secs(10)));
+    assert!(bucket.take(&rate, start + time::Duration::from_secs(10)).is_err()); // refills no higher than the rate
+  }
+}
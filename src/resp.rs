@@ -0,0 +1,241 @@
+use std::io;
+use std::str::FromStr;
+use std::net::TcpStream;
+use std::io::{BufRead, Write};
+
+use crate::error;
+
+pub const DEFAULT_PORT: u16 = 6379;
+
+/// A Redis server to connect to, as in `redis://:password@host:port/db`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Url {
+  pub host: String,
+  pub port: u16,
+  pub db: u32,
+  pub username: Option<String>,
+  pub password: Option<String>,
+}
+
+impl FromStr for Url {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let rest = match s.strip_prefix("redis://") {
+      Some(rest) => rest,
+      None       => return Err(format!("Invalid Redis URL, expected redis://host[:port][/db]: {}", s)),
+    };
+    let (auth, rest) = match rest.rsplit_once('@') {
+      Some((auth, rest)) => (Some(auth), rest),
+      None               => (None, rest),
+    };
+    let (addr, db) = match rest.split_once('/') {
+      Some((addr, "")) => (addr, 0),
+      Some((addr, db)) => match db.parse::<u32>() {
+        Ok(db) => (addr, db),
+        Err(_) => return Err(format!("Invalid Redis database: {}", db)),
+      },
+      None => (rest, 0),
+    };
+    let (host, port) = match addr.rsplit_once(':') {
+      Some((host, port)) => match port.parse::<u16>() {
+        Ok(port) => (host, port),
+        Err(_)   => return Err(format!("Invalid Redis port: {}", port)),
+      },
+      None => (addr, DEFAULT_PORT),
+    };
+    let (username, password) = match auth {
+      Some(auth) => match auth.split_once(':') {
+        Some((user, pass)) => ((!user.is_empty()).then(|| { user.to_string() }), Some(pass.to_string())),
+        None               => (None, Some(auth.to_string())),
+      },
+      None => (None, None),
+    };
+    Ok(Self{
+      host: if host.is_empty() { "localhost".to_string() } else { host.to_string() },
+      port,
+      db,
+      username,
+      password,
+    })
+  }
+}
+
+/// A reply in the Redis serialization protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+  Status(String),
+  Error(String),
+  Integer(i64),
+  Bulk(Option<Vec<u8>>),
+  Array(Option<Vec<Reply>>),
+}
+
+/// Encode a command as an array of bulk strings.
+pub fn encode(args: &[&[u8]]) -> Vec<u8> {
+  let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+  for arg in args {
+    buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+    buf.extend_from_slice(arg);
+    buf.extend_from_slice(b"\r\n");
+  }
+  buf
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, error::Error> {
+  let mut line = String::new();
+  if reader.read_line(&mut line)? == 0 {
+    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Redis closed the connection").into());
+  }
+  match line.strip_suffix("\r\n") {
+    Some(line) => Ok(line.to_string()),
+    None       => Err(error::Error::Malformed),
+  }
+}
+
+fn read_len(s: &str) -> Result<i64, error::Error> {
+  s.parse::<i64>().map_err(|_| { error::Error::Malformed })
+}
+
+/// Read a reply from any buffered reader.
+pub fn read_reply<R: BufRead>(reader: &mut R) -> Result<Reply, error::Error> {
+  let line = read_line(reader)?;
+  let (kind, rest) = match line.char_indices().nth(1) {
+    Some((i, _)) => line.split_at(i),
+    None         => (line.as_str(), ""),
+  };
+  match kind {
+    "+" => Ok(Reply::Status(rest.to_string())),
+    "-" => Ok(Reply::Error(rest.to_string())),
+    ":" => Ok(Reply::Integer(read_len(rest)?)),
+    "$" => match read_len(rest)? {
+      n if n < 0 => Ok(Reply::Bulk(None)),
+      n => {
+        let mut buf = vec![0u8; n as usize + 2];
+        reader.read_exact(&mut buf)?;
+        buf.truncate(n as usize); // drop the trailing CRLF
+        Ok(Reply::Bulk(Some(buf)))
+      },
+    },
+    "*" => match read_len(rest)? {
+      n if n < 0 => Ok(Reply::Array(None)),
+      n => {
+        let mut items = Vec::with_capacity(n as usize);
+        for _ in 0..n {
+          items.push(read_reply(reader)?);
+        }
+        Ok(Reply::Array(Some(items)))
+      },
+    },
+    _ => Err(error::Error::Malformed),
+  }
+}
+
+/// A connection to a Redis server.
+pub struct Conn {
+  reader: io::BufReader<TcpStream>,
+  writer: TcpStream,
+}
+
+impl Conn {
+  /// Connect, authenticate if the URL has credentials, and select its
+  /// database.
+  pub fn connect(url: &Url) -> Result<Self, error::Error> {
+    let stream = TcpStream::connect((url.host.as_str(), url.port))?;
+    let mut conn = Self{
+      reader: io::BufReader::new(stream.try_clone()?),
+      writer: stream,
+    };
+    match (&url.username, &url.password) {
+      (Some(user), Some(pass)) => { conn.cmd(&[b"AUTH", user.as_bytes(), pass.as_bytes()])?; },
+      (None, Some(pass))       => { conn.cmd(&[b"AUTH", pass.as_bytes()])?; },
+      _                        => {},
+    }
+    if url.db != 0 {
+      conn.cmd(&[b"SELECT", url.db.to_string().as_bytes()])?;
+    }
+    Ok(conn)
+  }
+
+  /// Send a command and produce its reply. Error replies are produced as
+  /// errors.
+  pub fn cmd(&mut self, args: &[&[u8]]) -> Result<Reply, error::Error> {
+    self.writer.write_all(&encode(args))?;
+    match read_reply(&mut self.reader)? {
+      Reply::Error(msg) => Err(error::Error::RemoteError(Some(msg))),
+      reply             => Ok(reply),
+    }
+  }
+
+  /// List the keys matching a glob, scanning incrementally so a large
+  /// database is not blocked.
+  pub fn scan(&mut self, pattern: &str) -> Result<Vec<Vec<u8>>, error::Error> {
+    let mut keys = Vec::new();
+    let mut cursor = "0".to_string();
+    loop {
+      let reply = self.cmd(&[b"SCAN", cursor.as_bytes(), b"MATCH", pattern.as_bytes(), b"COUNT", b"1000"])?;
+      let (next, batch) = match reply {
+        Reply::Array(Some(mut items)) if items.len() == 2 => (items.remove(0), items.remove(0)),
+        _ => return Err(error::Error::Malformed),
+      };
+      match batch {
+        Reply::Array(Some(batch)) => for key in batch {
+          match key {
+            Reply::Bulk(Some(key)) => keys.push(key),
+            _                      => return Err(error::Error::Malformed),
+          }
+        },
+        _ => return Err(error::Error::Malformed),
+      }
+      cursor = match next {
+        Reply::Bulk(Some(next)) => String::from_utf8(next)?,
+        _                       => return Err(error::Error::Malformed),
+      };
+      if cursor == "0" {
+        break;
+      }
+    }
+    Ok(keys)
+  }
+
+  /// Fetch the string stored under a key, if there is one. Keys holding
+  /// other types, like hashes, produce an error.
+  pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, error::Error> {
+    match self.cmd(&[b"GET", key])? {
+      Reply::Bulk(value) => Ok(value),
+      _                  => Err(error::Error::Malformed),
+    }
+  }
+
+  pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<(), error::Error> {
+    self.cmd(&[b"SET", key, value])?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_url() {
+    assert_eq!(Ok(Url{ host: "localhost".to_string(), port: 6379, db: 0, username: None, password: None }), "redis://localhost".parse());
+    assert_eq!(Ok(Url{ host: "cache".to_string(), port: 6380, db: 2, username: None, password: Some("pw".to_string()) }), "redis://:pw@cache:6380/2".parse());
+    assert_eq!(Ok(Url{ host: "localhost".to_string(), port: 6379, db: 0, username: Some("u".to_string()), password: Some("pw".to_string()) }), "redis://u:pw@localhost/".parse());
+    assert!("http://localhost".parse::<Url>().is_err());
+    assert!("redis://localhost/x".parse::<Url>().is_err());
+  }
+
+  #[test]
+  fn encode_and_read_replies() {
+    assert_eq!(b"*2\r\n$3\r\nGET\r\n$5\r\ncfg:a\r\n".to_vec(), encode(&[b"GET", b"cfg:a"]));
+    let mut input = io::Cursor::new(b"+OK\r\n-ERR bad\r\n:12\r\n$5\r\na\r\nbc\r\n$-1\r\n*2\r\n$1\r\n0\r\n*1\r\n$1\r\nk\r\n".to_vec());
+    assert_eq!(Reply::Status("OK".to_string()), read_reply(&mut input).unwrap());
+    assert_eq!(Reply::Error("ERR bad".to_string()), read_reply(&mut input).unwrap());
+    assert_eq!(Reply::Integer(12), read_reply(&mut input).unwrap());
+    assert_eq!(Reply::Bulk(Some(b"a\r\nbc".to_vec())), read_reply(&mut input).unwrap());
+    assert_eq!(Reply::Bulk(None), read_reply(&mut input).unwrap());
+    assert_eq!(Reply::Array(Some(vec![Reply::Bulk(Some(b"0".to_vec())), Reply::Array(Some(vec![Reply::Bulk(Some(b"k".to_vec()))]))])), read_reply(&mut input).unwrap());
+    assert!(read_reply(&mut input).is_err());
+  }
+}
@@ -1,25 +1,132 @@
 use std::io;
-use std::io::prelude::*;
 use std::fs;
 use std::path;
+use std::str;
+use std::str::FromStr;
+use std::borrow::Cow;
 
+use std::time;
 use std::os::unix::net::UnixStream;
-use std::sync::mpsc;
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
+
+use tokio::sync::mpsc;
 
 use crate::error;
 use crate::log;
 
-pub const CMD_SET:      &str = "set";
-pub const CMD_GET:      &str = "get";
-pub const CMD_RANGE:    &str = "range";
-pub const CMD_FOUND:    &str = "found";
-pub const CMD_NONE:     &str = "none";
-pub const CMD_DELETE:   &str = "delete";
-pub const CMD_SHUTDOWN: &str = "stop";
-pub const CMD_OK:       &str = "ok";
-pub const CMD_ERROR:    &str = "err";
-
-#[derive(Debug)]
+pub const CMD_SET:        &str = "set";
+pub const CMD_MSET:       &str = "mset";
+pub const CMD_GET:        &str = "get";
+pub const CMD_MGET:       &str = "mget";
+pub const CMD_PUT:        &str = "put";
+pub const CMD_APPEND:     &str = "append";
+pub const CMD_RANGE:      &str = "range";
+pub const CMD_FIND:       &str = "find";
+pub const CMD_PATHS:      &str = "paths";
+pub const CMD_SUGGEST:    &str = "suggest";
+pub const CMD_MERGEKEYS:  &str = "merge-keys";
+pub const CMD_MOVE:       &str = "mv";
+pub const CMD_ALIAS:      &str = "alias";
+pub const CMD_UNALIAS:    &str = "unalias";
+pub const CMD_PROTECT:    &str = "protect";
+pub const CMD_UNPROTECT:  &str = "unprotect";
+pub const CMD_DEFAULT:    &str = "default";
+pub const CMD_UNDEFAULT:  &str = "undefault";
+pub const CMD_DEFAULTS:   &str = "defaults";
+pub const CMD_FOUND:      &str = "found";
+pub const CMD_NONE:       &str = "none";
+pub const CMD_DELETE:     &str = "delete";
+pub const CMD_DELETEKEYS: &str = "delete-keys";
+pub const CMD_CLEAR:      &str = "clear";
+pub const CMD_TAKE:       &str = "take";
+pub const CMD_SWAP:       &str = "swap";
+pub const CMD_INCR:       &str = "incr";
+pub const CMD_PUSH:       &str = "push";
+pub const CMD_POP:        &str = "pop";
+pub const CMD_MERGE:      &str = "merge";
+pub const CMD_PATCH:      &str = "patch";
+pub const CMD_CAS:        &str = "cas";
+pub const CMD_SHUTDOWN:   &str = "stop";
+pub const CMD_VERSION:    &str = "version";
+pub const CMD_HEALTH:     &str = "health";
+pub const CMD_HELLO:      &str = "hello";
+pub const CMD_CLIENTS:    &str = "clients";
+pub const CMD_KILLCLIENT: &str = "kill-client";
+pub const CMD_SEQUENCE:   &str = "sequence";
+pub const CMD_STAT:       &str = "stat";
+pub const CMD_KEYS:       &str = "keys";
+pub const CMD_COUNT:      &str = "count";
+pub const CMD_CHECKSUM:   &str = "checksum";
+pub const CMD_WATCH:      &str = "watch";
+pub const CMD_METRIC:     &str = "metric";
+pub const CMD_TOUCH:      &str = "touch";
+pub const CMD_DUMP:       &str = "dump";
+pub const CMD_DUMPKEY:    &str = "dump-key";
+pub const CMD_RESTOREKEY: &str = "restore-key";
+pub const CMD_BUSY:       &str = "busy";
+pub const CMD_LIMITED:    &str = "limited";
+pub const CMD_PROTECTED:  &str = "protected";
+pub const CMD_MISMATCH:   &str = "mismatch";
+pub const CMD_EXISTS:     &str = "exists";
+pub const CMD_MORE:       &str = "more";
+pub const CMD_OK:         &str = "ok";
+pub const CMD_ERROR:      &str = "err";
+
+pub const METRIC_INCR: &str = "incr";
+pub const METRIC_SET:  &str = "set";
+pub const METRIC_DUMP: &str = "dump";
+
+/// The protocol level spoken by this version; incremented whenever the
+/// wire format or the semantics of an existing command change.
+pub const PROTOCOL: usize = 3;
+
+pub const PROTOCOL_LINE:    &str = "line";
+pub const PROTOCOL_JSONRPC: &str = "jsonrpc";
+
+/// The dialect spoken by clients of a service: the native line protocol,
+/// or JSON-RPC 2.0, which is translated to and from it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+  Line,
+  JsonRpc,
+}
+
+impl FromStr for Protocol {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      PROTOCOL_LINE    => Ok(Self::Line),
+      PROTOCOL_JSONRPC => Ok(Self::JsonRpc),
+      _                => Err(format!("Unknown protocol: {}", s)),
+    }
+  }
+}
+
+pub const FLAG_PARENTS: &str = "parents";
+pub const FLAG_DRYRUN:  &str = "dry-run";
+pub const FLAG_REPLACE: &str = "replace";
+pub const FLAG_CI:      &str = "ci";
+pub const FLAG_REGEX:   &str = "regex";
+pub const FLAG_VALUES:  &str = "values";
+pub const FLAG_REQUIRED: &str = "required";
+pub const FLAG_GLOB:    &str = "glob";
+pub const FLAG_STREAM:  &str = "stream";
+pub const FLAG_FRONT:   &str = "front";
+pub const FLAG_NX:      &str = "nx";
+
+pub const OPTION_TTL: &str = "ttl";
+pub const OPTION_CAP: &str = "cap";
+pub const OPTION_WHERE: &str = "where";
+pub const OPTION_RESUME: &str = "resume";
+pub const OPTION_INITIAL: &str = "initial";
+pub const OPTION_AS_OF:   &str = "as-of";
+
+/// Separates the options of an operation from arguments which follow it,
+/// so an argument is never mistaken for an option whatever it looks like.
+pub const ARG_END: &str = "--";
+
+#[derive(Debug, Clone)]
 pub struct Operation {
   name: String,
   args: Vec<String>,
@@ -31,29 +138,88 @@ impl Operation {
     Operation{
       name: name.to_owned(),
       args: args.iter().map(|e| { e.to_string() }).collect(),
-      data: match data {
-        Some(data) => Some(data.to_string()),
-        None => None,
-      },
+      data: data.map(|data| data.to_string()),
     }
   }
 
-  pub fn name<'a>(&'a self) -> &'a str {
+  pub fn name(&self) -> &str {
     &self.name
   }
 
-  pub fn data<'a>(&'a self) -> &'a Option<String> {
+  pub fn data(&self) -> &Option<String> {
     &self.data
   }
 
-  pub fn args<'a>(&'a self) -> &'a [String] {
+  pub fn args(&self) -> &[String] {
     &self.args
   }
 
+  /// Append a flag to the arguments of this operation if it is set.
+  pub fn with_flag(mut self, flag: &str, set: bool) -> Self {
+    if set {
+      self.args.push(flag.to_string());
+    }
+    self
+  }
+
+  /// Determine if a flag is present among the arguments of this operation
+  /// which follow the first n positional arguments.
+  pub fn has_flag(&self, n: usize, flag: &str) -> bool {
+    self.args.len() > n && self.args[n..].iter().any(|e| { e == flag })
+  }
+
+  /// Append an option, as `name=value`, to the arguments of this operation
+  /// if it is set.
+  pub fn with_option(mut self, name: &str, value: Option<&str>) -> Self {
+    if let Some(value) = value {
+      self.args.push(format!("{}={}", name, value));
+    }
+    self
+  }
+
+  /// Append arguments after the options of this operation, separated from
+  /// them by ARG_END.
+  pub fn with_rest(mut self, args: &[&str]) -> Self {
+    self.args.push(ARG_END.to_string());
+    self.args.extend(args.iter().map(|e| { e.to_string() }));
+    self
+  }
+
+  /// Split the arguments of this operation into its options and those
+  /// which follow ARG_END, or None if there is no ARG_END.
+  pub fn split_rest(&self) -> Option<(&[String], &[String])> {
+    let n = self.args.iter().position(|e| { e == ARG_END })?;
+    Some((&self.args[..n], &self.args[n + 1..]))
+  }
+
+  /// Find the value of an option among the arguments of this operation
+  /// which follow the first n positional arguments.
+  pub fn option<'a>(&'a self, n: usize, name: &str) -> Option<&'a str> {
+    if self.args.len() <= n {
+      return None;
+    }
+    self.args[n..].iter().find_map(|e| {
+      match e.split_once("=") {
+        Some((k, v)) if k == name => Some(v),
+        _                         => None,
+      }
+    })
+  }
+
   pub fn new_ok() -> Self {
     Self::new(CMD_OK, &[], None)
   }
 
+  pub fn new_busy() -> Self {
+    Self::new(CMD_BUSY, &[], None)
+  }
+
+  /// Turn away a request because the client has exceeded its rate limit,
+  /// noting how long, in milliseconds, it should wait before retrying.
+  pub fn new_limited(retry: time::Duration) -> Self {
+    Self::new(CMD_LIMITED, &[&retry.as_millis().to_string()], None)
+  }
+
   pub fn new_error(msg: &str) -> Self {
     Self::new(CMD_ERROR, &[], Some(msg))
   }
@@ -70,44 +236,424 @@ impl Operation {
     Self::new(CMD_GET, &[name], None)
   }
 
+  /// Fetch the values of several keys or paths at once. The service
+  /// responds `found` with an object whose members are the names which
+  /// were found and their values.
+  pub fn new_mget(names: &[&str]) -> Self {
+    Self::new(CMD_MGET, names, None)
+  }
+
   pub fn new_range(name: &str) -> Self {
     Self::new(CMD_RANGE, &[name], None)
   }
 
+  /// Range over a value as with new_range, but have the service stream the
+  /// elements in chunks, as `more` responses, followed by `ok`, rather than
+  /// as a single response.
+  pub fn new_range_stream(name: &str) -> Self {
+    Self::new_range(name).with_flag(FLAG_STREAM, true)
+  }
+
+  /// A chunk of a streamed response: a JSON array of elements.
+  pub fn new_more(name: &str, data: &str) -> Self {
+    Self::new(CMD_MORE, &[name], Some(data))
+  }
+
+  pub fn new_find(name: &str, predicate: &str) -> Self {
+    Self::new(CMD_FIND, &[name], Some(predicate))
+  }
+
+  /// Ask for the names most like one which was not found, which the
+  /// service produces as a JSON array.
+  pub fn new_suggest(name: &str) -> Self {
+    Self::new(CMD_SUGGEST, &[name], None)
+  }
+
+  pub fn new_paths(name: Option<&str>, depth: usize) -> Self {
+    let depth = depth.to_string();
+    match name {
+      Some(name) => Self::new(CMD_PATHS, &[&depth, name], None),
+      None       => Self::new(CMD_PATHS, &[&depth], None),
+    }
+  }
+
   pub fn new_set(name: &str, data: &str) -> Self {
     Self::new(CMD_SET, &[name], Some(data))
   }
 
-  pub fn new_delete(name: &str) -> Self {
-    Self::new(CMD_DELETE, &[name], None)
+  /// Store every value of an object under the key or path it is a member
+  /// for, or none of them if any cannot be stored.
+  pub fn new_mset(data: &str) -> Self {
+    Self::new(CMD_MSET, &[], Some(data))
+  }
+
+  /// Store a value under a key or path only if its current value equals
+  /// the expected one. The service responds `ok` if it was stored,
+  /// `mismatch` with the current value if it was not, or `none` if there is
+  /// no current value.
+  pub fn new_cas(name: &str, expected: &str, data: &str) -> Self {
+    Self::new(CMD_CAS, &[name, expected], Some(data))
+  }
+
+  /// The response to a compare-and-swap whose expected value did not
+  /// match, carrying the current value.
+  pub fn new_mismatch(name: &str, current: &str) -> Self {
+    Self::new(CMD_MISMATCH, &[name], Some(current))
+  }
+
+  /// The response to a set with the nx flag which found a value already
+  /// stored.
+  pub fn new_exists(name: &str) -> Self {
+    Self::new(CMD_EXISTS, &[name], None)
+  }
+
+  pub fn new_put(data: &str) -> Self {
+    Self::new(CMD_PUT, &[], Some(data))
+  }
+
+  pub fn new_stored(name: &str) -> Self {
+    Self::new(CMD_OK, &[name], None)
+  }
+
+  /// Append an entry to the log stored under a key, keeping no more than
+  /// cap entries.
+  pub fn new_append(name: &str, data: &str, cap: usize) -> Self {
+    Self::new(CMD_APPEND, &[name], Some(data)).with_option(OPTION_CAP, Some(&cap.to_string()))
+  }
+
+  /// The response to an append, noting the sequence number of the entry.
+  pub fn new_appended(seq: u64) -> Self {
+    Self::new(CMD_OK, &[&seq.to_string()], None)
+  }
+
+  /// Append a value to the array stored under a key or path, creating the
+  /// array if there is none.
+  pub fn new_push(name: &str, data: &str) -> Self {
+    Self::new(CMD_PUSH, &[name], Some(data))
+  }
+
+  /// The response to a push, noting the length of the array.
+  pub fn new_pushed(len: usize) -> Self {
+    Self::new(CMD_OK, &[&len.to_string()], None)
+  }
+
+  /// Remove the last element of the array stored under a key or path, or
+  /// the first if front is set, and fetch it in one operation. The service
+  /// responds `none` if the array is empty or there is none.
+  pub fn new_pop(name: &str, front: bool) -> Self {
+    Self::new(CMD_POP, &[name], None).with_flag(FLAG_FRONT, front)
+  }
+
+  /// Apply a JSON merge patch (RFC 7386) to the value under a key or path.
+  /// The service responds `found` with the patched value.
+  pub fn new_merge(name: &str, patch: &str) -> Self {
+    Self::new(CMD_MERGE, &[name], Some(patch))
+  }
+
+  /// Apply a JSON Patch (RFC 6902) to the value under a key or path, all
+  /// or nothing. The service responds `found` with the patched value, or
+  /// with an error naming the operation which failed.
+  pub fn new_patch(name: &str, patch: &str) -> Self {
+    Self::new(CMD_PATCH, &[name], Some(patch))
+  }
+
+  pub fn new_merge_keys(base: &str, over: &str, dest: &str) -> Self {
+    Self::new(CMD_MERGEKEYS, &[base, over, dest], None)
+  }
+
+  pub fn new_move(src: &str, dst: &str, parents: bool) -> Self {
+    Self::new(CMD_MOVE, &[src, dst], None).with_flag(FLAG_PARENTS, parents)
+  }
+
+  pub fn new_alias(name: &str, target: &str) -> Self {
+    Self::new(CMD_ALIAS, &[name, target], None)
+  }
+
+  pub fn new_aliases() -> Self {
+    Self::new(CMD_ALIAS, &[], None)
+  }
+
+  pub fn new_unalias(name: &str) -> Self {
+    Self::new(CMD_UNALIAS, &[name], None)
+  }
+
+  /// Register the template which gets of missing keys matching a glob
+  /// produce, and which the first write to such a key is merged into.
+  pub fn new_default(glob: &str, template: &str) -> Self {
+    Self::new(CMD_DEFAULT, &[glob], Some(template))
+  }
+
+  pub fn new_undefault(glob: &str) -> Self {
+    Self::new(CMD_UNDEFAULT, &[glob], None)
+  }
+
+  /// List the registered templates; the service responds `found` with an
+  /// object whose members are globs and their templates.
+  pub fn new_defaults() -> Self {
+    Self::new(CMD_DEFAULTS, &[], None)
+  }
+
+  pub fn new_protect(names: &[&str]) -> Self {
+    Self::new(CMD_PROTECT, names, None)
+  }
+
+  pub fn new_protections() -> Self {
+    Self::new(CMD_PROTECT, &[], None)
+  }
+
+  pub fn new_unprotect(names: &[&str]) -> Self {
+    Self::new(CMD_UNPROTECT, names, None)
+  }
+
+  /// Refuse a write because it would modify a protected key or path.
+  pub fn new_protected(name: &str) -> Self {
+    Self::new(CMD_PROTECTED, &[name], None)
+  }
+
+  pub fn new_delete(names: &[&str]) -> Self {
+    Self::new(CMD_DELETE, names, None)
+  }
+
+  /// Fetch the value of a key or path and delete it in one operation, so
+  /// only one client may ever receive it.
+  pub fn new_take(name: &str) -> Self {
+    Self::new(CMD_TAKE, &[name], None)
+  }
+
+  /// Store a value under a key or path and fetch the value it replaced in
+  /// one operation. The service responds `found` with the previous value,
+  /// or `none` if there was none; either way, the value is stored.
+  pub fn new_swap(name: &str, data: &str) -> Self {
+    Self::new(CMD_SWAP, &[name], Some(data))
+  }
+
+  /// Add a delta, which may be negative, to the number under a key or path
+  /// in one operation, treating a missing value as zero. The service
+  /// responds `found` with the new value.
+  pub fn new_incr(name: &str, delta: &serde_json::Number) -> Self {
+    Self::new(CMD_INCR, &[name, &delta.to_string()], None)
+  }
+
+  /// Delete every key matching a pattern, which is a prefix or, if regex is
+  /// set, an anchored regular expression, or if glob is set, a glob.
+  pub fn new_delete_keys(pattern: &str, regex: bool, glob: bool) -> Self {
+    Self::new(CMD_DELETEKEYS, &[], Some(pattern)).with_flag(FLAG_REGEX, regex).with_flag(FLAG_GLOB, glob)
+  }
+
+  /// List the keys matching a pattern, as with new_delete_keys, or their
+  /// values if values is set.
+  pub fn new_keys(pattern: &str, regex: bool, values: bool) -> Self {
+    Self::new(CMD_KEYS, &[], Some(pattern)).with_flag(FLAG_REGEX, regex).with_flag(FLAG_VALUES, values)
+  }
+
+  /// List the keys matching a pattern as with new_keys, but have the
+  /// service stream them in chunks, as `more` responses, followed by `ok`.
+  pub fn new_keys_stream(pattern: &str, regex: bool) -> Self {
+    Self::new_keys(pattern, regex, false).with_flag(FLAG_STREAM, true)
+  }
+
+  pub fn new_count(pattern: &str, regex: bool, glob: bool) -> Self {
+    Self::new(CMD_COUNT, &[], Some(pattern)).with_flag(FLAG_REGEX, regex).with_flag(FLAG_GLOB, glob)
+  }
+
+  /// Delete every key, leaving the service running. The service responds
+  /// `ok` with the number of keys deleted.
+  pub fn new_clear() -> Self {
+    Self::new(CMD_CLEAR, &[], None)
+  }
+
+  /// The response to a clear, noting the number of keys deleted.
+  pub fn new_cleared(n: usize) -> Self {
+    Self::new(CMD_OK, &[&n.to_string()], None)
+  }
+
+  pub fn new_deleted(names: &[&str]) -> Self {
+    Self::new(CMD_OK, names, None)
   }
 
   pub fn new_shutdown() -> Self {
     Self::new(CMD_SHUTDOWN, &[], None)
   }
+
+  pub fn new_stat(name: Option<&str>) -> Self {
+    match name {
+      Some(name) => Self::new(CMD_STAT, &[name], None),
+      None       => Self::new(CMD_STAT, &[], None),
+    }
+  }
+
+  /// Add to a metric, which is created as a counter if need be.
+  pub fn new_metric_incr(name: &str, by: f64) -> Self {
+    Self::new(CMD_METRIC, &[METRIC_INCR, name, &by.to_string()], None)
+  }
+
+  /// Set a metric, which is created as a gauge if need be.
+  pub fn new_metric_set(name: &str, value: f64) -> Self {
+    Self::new(CMD_METRIC, &[METRIC_SET, name, &value.to_string()], None)
+  }
+
+  /// The response to an update of a metric, noting its new value.
+  pub fn new_metric_value(value: f64) -> Self {
+    Self::new(CMD_OK, &[&value.to_string()], None)
+  }
+
+  pub fn new_metric_dump() -> Self {
+    Self::new(CMD_METRIC, &[METRIC_DUMP], None)
+  }
+
+  pub fn new_dump() -> Self {
+    Self::new(CMD_DUMP, &[], None)
+  }
+
+  pub fn new_dump_key(name: &str) -> Self {
+    Self::new(CMD_DUMPKEY, &[name], None)
+  }
+
+  pub fn new_restore_key(name: &str, blob: &str, replace: bool) -> Self {
+    Self::new(CMD_RESTOREKEY, &[name], Some(blob)).with_flag(FLAG_REPLACE, replace)
+  }
+
+  pub fn new_touch(name: &str) -> Self {
+    Self::new(CMD_TOUCH, &[name], None)
+  }
+
+  /// Watch for changes to the keys matching any of a set of globs. The
+  /// service acknowledges the subscription and then sends an event, in the
+  /// form of a `found` or `none` response, whenever a matching key changes.
+  pub fn new_watch(globs: &[&str]) -> Self {
+    Self::new(CMD_WATCH, &[], None).with_rest(globs)
+  }
+
+  /// Watch for changes as with new_watch, first catching up on those made
+  /// after the change with a sequence number.
+  pub fn new_watch_from(globs: &[&str], resume: Option<u64>) -> Self {
+    Self::new(CMD_WATCH, &[], None).with_option(OPTION_RESUME, resume.map(|e| { e.to_string() }).as_deref()).with_rest(globs)
+  }
+
+  /// Watch for changes as with new_watch, first receiving the current value
+  /// of every watched key which exists.
+  pub fn new_watch_initial(globs: &[&str]) -> Self {
+    Self::new(CMD_WATCH, &[], None).with_option(OPTION_INITIAL, Some("true")).with_rest(globs)
+  }
+
+  /// Acknowledge a watch, with the sequence number of the latest change.
+  pub fn new_watching(seq: u64) -> Self {
+    Self::new(CMD_OK, &[&seq.to_string()], None)
+  }
+
+  /// A change to a watched key, with its sequence number; a change without
+  /// a value means the key was deleted.
+  pub fn new_change(name: &str, seq: u64, data: Option<&str>) -> Self {
+    match data {
+      Some(data) => Self::new(CMD_FOUND, &[name, &seq.to_string()], Some(data)),
+      None       => Self::new(CMD_NONE, &[name, &seq.to_string()], None),
+    }
+  }
+
+  pub fn new_checksum() -> Self {
+    Self::new(CMD_CHECKSUM, &[], None)
+  }
+
+  /// The response to a checksum request.
+  pub fn new_checksummed(checksum: &str) -> Self {
+    Self::new(CMD_OK, &[checksum], None)
+  }
+
+  pub fn new_health() -> Self {
+    Self::new(CMD_HEALTH, &[], None)
+  }
+
+  pub fn new_version() -> Self {
+    Self::new(CMD_VERSION, &[], None)
+  }
+
+  /// Name the client making a connection, as it is listed by `clients`.
+  pub fn new_hello(name: &str) -> Self {
+    Self::new(CMD_HELLO, &[name], None)
+  }
+
+  /// Tag the operations which follow on a connection with a sequence token,
+  /// or stop tagging them if there is none.
+  pub fn new_sequence(token: Option<&str>) -> Self {
+    match token {
+      Some(token) => Self::new(CMD_SEQUENCE, &[token], None),
+      None        => Self::new(CMD_SEQUENCE, &[], None),
+    }
+  }
+
+  pub fn new_clients() -> Self {
+    Self::new(CMD_CLIENTS, &[], None)
+  }
+
+  /// Disconnect the client with an id, as listed by `clients`. The service
+  /// responds `ok`, or `none` if no such client is connected.
+  pub fn new_kill_client(id: usize) -> Self {
+    Self::new(CMD_KILLCLIENT, &[&id.to_string()], None)
+  }
+
+  pub fn new_version_info(version: &str, protocol: usize) -> Self {
+    Self::new(CMD_OK, &[version, &protocol.to_string()], None)
+  }
 }
 
 pub struct Request {
+  client: usize,
+  uid: Option<u32>,
+  sequence: Option<String>,
   op: Operation,
-  tx: mpsc::Sender<Operation>,
+  tx: mpsc::UnboundedSender<Operation>,
 }
 
 impl Request {
-  pub fn new(op: Operation, tx: mpsc::Sender<Operation>) -> Self {
+  pub fn new(client: usize, op: Operation, tx: mpsc::UnboundedSender<Operation>) -> Self {
     Request{
-      op: op,
-      tx: tx,
+      client,
+      uid: None,
+      sequence: None,
+      op,
+      tx,
     }
   }
 
-  pub fn name<'a>(&'a self) -> &'a str {
+  /// Note the user the client which made this request runs as, if known.
+  pub fn with_uid(mut self, uid: Option<u32>) -> Self {
+    self.uid = uid;
+    self
+  }
+
+  /// Note the sequence token the client's connection is tagged with, if any.
+  pub fn with_sequence(mut self, sequence: Option<String>) -> Self {
+    self.sequence = sequence;
+    self
+  }
+
+  pub fn client(&self) -> usize {
+    self.client
+  }
+
+  pub fn uid(&self) -> Option<u32> {
+    self.uid
+  }
+
+  /// The sequence token the client's connection was tagged with, if any.
+  pub fn sequence(&self) -> Option<&str> {
+    self.sequence.as_deref()
+  }
+
+  pub fn name(&self) -> &str {
     self.op.name()
   }
 
-  pub fn operation<'a>(&'a mut self) -> &'a Operation {
+  pub fn operation(&mut self) -> &Operation {
     &self.op
   }
 
+  /// Where responses to this request go, for those which produce more than
+  /// one.
+  pub fn sender(&self) -> mpsc::UnboundedSender<Operation> {
+    self.tx.clone()
+  }
+
   pub fn send(&self, op: Operation) -> Result<(), error::Error> {
     match self.tx.send(op) {
       Ok(_)  => Ok(()),
@@ -116,8 +662,56 @@ impl Request {
   }
 }
 
+/// Tracks requests which have been submitted to the service but whose
+/// responses have not yet been written back to the client. The service
+/// waits on this before exiting so the final response is not lost.
+#[derive(Clone)]
+pub struct Inflight(Arc<(Mutex<usize>, Condvar)>);
+
+impl Inflight {
+  pub fn new() -> Self {
+    Self(Arc::new((Mutex::new(0), Condvar::new())))
+  }
+
+  fn count(&self) -> MutexGuard<'_, usize> {
+    match self.0.0.lock() {
+      Ok(count)  => count,
+      Err(count) => count.into_inner(),
+    }
+  }
+
+  pub fn begin(&self) {
+    *self.count() += 1;
+  }
+
+  pub fn end(&self) {
+    *self.count() -= 1;
+    self.0.1.notify_all();
+  }
+
+  /// Wait until every request in flight has been answered, or the timeout
+  /// elapses, whichever is first.
+  pub fn wait(&self, timeout: time::Duration) {
+    let count = self.count();
+    let _ = self.0.1.wait_timeout_while(count, timeout, |count| { *count > 0 });
+  }
+}
+
+impl Default for Inflight {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
 pub struct Options {
   pub debug: bool,
+  /// Tag the connection with this sequence token when it is made, so the
+  /// service applies the operations written to it in order, after any
+  /// operations other clients wrote with the same token.
+  pub sequence: Option<String>,
+  /// Name the connection with this when it is made, so operators can tell
+  /// who is connected.
+  pub name: Option<String>,
 }
 
 pub struct RPC {
@@ -130,119 +724,241 @@ impl RPC {
   pub fn new(stream: UnixStream, opts: Options) -> Result<Self, error::Error> {
     let writer = stream.try_clone()?;
     let reader = io::BufReader::new(stream);
-    Ok(Self{
-      reader: reader,
-      writer: writer,
-      opts: opts,
-    })
+    let mut rpc = Self{
+      reader,
+      writer,
+      opts,
+    };
+    if let Some(name) = rpc.opts.name.clone() {
+      write_operation(&mut rpc.writer, &Operation::new_hello(&name), rpc.opts.debug)?;
+      rpc.expect_cmd(&[CMD_OK, CMD_ERROR])?; // older services don't know the name; that's fine
+    }
+    if let Some(token) = rpc.opts.sequence.clone() {
+      write_operation(&mut rpc.writer, &Operation::new_sequence(Some(&token)), rpc.opts.debug)?;
+      rpc.expect_cmd(&[CMD_OK])?; // unlike a name, the order matters
+    }
+    Ok(rpc)
   }
 
   pub fn read_cmd(&mut self) -> Result<Option<Operation>, error::Error> {
-    let mut line = String::new();
-    let res = match self.reader.read_line(&mut line)? {
-      0 => return Ok(None),
-      _ => line.trim(),
-    };
+    read_operation(&mut self.reader, self.opts.debug)
+  }
 
-    if self.opts.debug {
-      log::logln!("<<< rpc: {}", res);
-    }
-
-    let mut text = res;
-    let mut args: Vec<&str> = Vec::new();
-    loop {
-      match text.split_once(" ") {
-        Some((l, r)) => {
-          args.push(l);
-          text = r;
-        },
-        None => {
-          args.push(text);
-          break;
-        },
-      }
+  pub fn expect_cmd(&mut self, expect: &[&str]) -> Result<Operation, error::Error> {
+    expect_response(self.read_cmd()?, expect)
+  }
+
+  pub fn write_cmd(&mut self, cmd: &Operation) -> Result<(), error::Error> {
+    match write_operation(&mut self.writer, cmd, self.opts.debug) {
+      Err(error::Error::IOError(err)) if err.kind() == io::ErrorKind::BrokenPipe => {
+        // the service may have turned us away before we finished writing; find out why
+        match self.read_cmd() {
+          Ok(Some(rsp)) if rsp.name == CMD_BUSY  => Err(error::Error::Busy),
+          Ok(Some(rsp)) if rsp.name == CMD_ERROR => Err(error::Error::RemoteError(rsp.data)),
+          _                                     => Err(err.into()),
+        }
+      },
+      res => res,
     }
+  }
+}
 
-    if args.len() < 1 {
+/// Check that a response is one of those expected, mapping the responses
+/// which turn a request away to the errors they describe.
+pub fn expect_response(rsp: Option<Operation>, expect: &[&str]) -> Result<Operation, error::Error> {
+  match rsp {
+    Some(cmd) if cmd.name == CMD_BUSY => Err(error::Error::Busy),
+    Some(cmd) if cmd.name == CMD_PROTECTED => Err(error::Error::Protected(cmd.args.join(" "))),
+    Some(cmd) if cmd.name == CMD_LIMITED => Err(error::Error::RateLimited(time::Duration::from_millis(cmd.args.first().and_then(|e| { e.parse().ok() }).unwrap_or(0)))),
+    Some(cmd) => if expect.iter().any(|&e| { cmd.name == e}) {
+      Ok(cmd)
+    }else{
+      Err(error::Error::Unexpected)
+    },
+    None => Err(error::Error::Unexpected),
+  }
+}
+
+/// Read an operation in the wire format from any buffered reader. A return
+/// value of None indicates the end of the input.
+pub fn read_operation<R: io::BufRead>(reader: &mut R, debug: bool) -> Result<Option<Operation>, error::Error> {
+  let mut line = String::new();
+  if reader.read_line(&mut line)? == 0 {
+    return Ok(None);
+  }
+  let data = if expects_data(&line) {
+    let mut data = String::new();
+    if reader.read_line(&mut data)? == 0 {
       return Err(error::Error::Malformed);
     }
+    Some(data)
+  } else {
+    None
+  };
+  Ok(Some(parse_operation(&line, data.as_deref(), debug)?))
+}
 
-    let mut line = String::new();
-    let data = if match args[0] {
-      CMD_SET | CMD_FOUND| CMD_ERROR => true,
-      _                              => false,
-    }{
-      match self.reader.read_line(&mut line)? {
-        0 => return Err(error::Error::Malformed),
-        _ => Some(line.trim().to_string()),
-      }
-    } else {
-      None
-    };
+/// Determine if the command on the provided line is followed by a line of
+/// data.
+pub fn expects_data(line: &str) -> bool {
+  let name = match line.trim().split_once(" ") {
+    Some((name, _)) => name,
+    None            => line.trim(),
+  };
+  matches!(name, CMD_SET | CMD_MSET | CMD_SWAP | CMD_CAS | CMD_PUT | CMD_APPEND | CMD_PUSH | CMD_MERGE | CMD_PATCH | CMD_DEFAULT | CMD_FIND | CMD_KEYS | CMD_COUNT | CMD_DELETEKEYS | CMD_RESTOREKEY | CMD_FOUND | CMD_MISMATCH | CMD_MORE | CMD_ERROR)
+}
 
-    Ok(Some(Operation::new(args[0], &args[1..], data.as_deref())))
+/// Parse an operation from its command line and, if the command expects
+/// it, its data line.
+pub fn parse_operation(line: &str, data: Option<&str>, debug: bool) -> Result<Operation, error::Error> {
+  let res = line.trim();
+  if debug {
+    log::logln!("<<< rpc: {}", res);
   }
 
-  pub fn expect_cmd(&mut self, expect: &[&str]) -> Result<Operation, error::Error> {
-    match self.read_cmd()? {
-      Some(cmd) => if expect.iter().any(|&e| { cmd.name == e}) {
-        Ok(cmd)
-      }else{
-        Err(error::Error::Unexpected)
+  let mut text = res;
+  let mut args: Vec<String> = Vec::new();
+  loop {
+    match text.split_once(" ") {
+      Some((l, r)) => {
+        args.push(decode_arg(l)?);
+        text = r;
+      },
+      None => {
+        args.push(decode_arg(text)?);
+        break;
       },
-      None => Err(error::Error::Unexpected),
     }
   }
 
-  pub fn write_cmd(&mut self, cmd: &Operation) -> Result<(), error::Error> {
-    let mut line: Vec<&str> = Vec::new();
-    line.push(&cmd.name);
-    for arg in &cmd.args {
-      line.push(arg);
-    }
-    self.write_line(&line)?;
-    if let Some(data) = &cmd.data {
-      self.write_line(&[&data])?;
+  if args.is_empty() {
+    return Err(error::Error::Malformed);
+  }
+
+  Ok(Operation{
+    name: args.remove(0),
+    args,
+    data: data.map(|e| { e.trim_end_matches(['\r', '\n']).to_string() }), // data may begin or end with whitespace, like a prefix
+  })
+}
+
+/// Determine if a character must be escaped in an argument: whitespace,
+/// which delimits arguments and lines, control characters, and the escape
+/// character itself.
+fn is_escaped(c: char) -> bool {
+  c == '%' || c.is_whitespace() || c.is_control()
+}
+
+/// Encode an argument so it can be sent as a single token on a line: any
+/// character which would be mistaken for a delimiter is replaced by its
+/// UTF-8 bytes, each as `%XX`.
+pub fn encode_arg(arg: &str) -> Cow<'_, str> {
+  if !arg.contains(is_escaped) {
+    return Cow::Borrowed(arg);
+  }
+  let mut enc = String::with_capacity(arg.len() + 8);
+  let mut buf = [0u8; 4];
+  for c in arg.chars() {
+    if is_escaped(c) {
+      for b in c.encode_utf8(&mut buf).bytes() {
+        enc.push_str(&format!("%{:02X}", b));
+      }
+    } else {
+      enc.push(c);
     }
-    Ok(())
   }
+  Cow::Owned(enc)
+}
 
-  pub fn write_line(&mut self, line: &[&str]) -> Result<(), error::Error> {
-    let mut i = 0;
-    for cmd in line {
-      if i > 0 { self.writer.write_all(b" ")?; }
-      self.writer.write_all(cmd.trim().as_bytes())?;
+/// Decode an argument encoded by `encode_arg`.
+pub fn decode_arg(arg: &str) -> Result<String, error::Error> {
+  if !arg.contains('%') {
+    return Ok(arg.to_string());
+  }
+  let src = arg.as_bytes();
+  let mut dec: Vec<u8> = Vec::with_capacity(src.len());
+  let mut i = 0;
+  while i < src.len() {
+    if src[i] == b'%' {
+      let hex = match src.get(i + 1..i + 3).and_then(|e| { str::from_utf8(e).ok() }) {
+        Some(hex) => hex,
+        None      => return Err(error::Error::Malformed),
+      };
+      match u8::from_str_radix(hex, 16) {
+        Ok(b)  => dec.push(b),
+        Err(_) => return Err(error::Error::Malformed),
+      }
+      i += 3;
+    } else {
+      dec.push(src[i]);
       i += 1;
     }
-    self.writer.write_all(b"\n")?;
-    self.writer.flush()?;
-    if self.opts.debug {
-      let mut dbg = String::new();
-      let mut i = 0;
-      for cmd in line {
-        dbg += &((if i == 0 { "" } else { " " }).to_owned() + cmd.trim());
-        i += 1;
-      }
-      log::logln!(">>> rpc: {}", dbg);
+  }
+  Ok(String::from_utf8(dec)?)
+}
+
+/// Write an operation in the wire format to any writer.
+pub fn write_operation<W: io::Write>(writer: &mut W, cmd: &Operation, debug: bool) -> Result<(), error::Error> {
+  let mut line: Vec<Cow<str>> = Vec::new();
+  line.push(encode_arg(&cmd.name));
+  for arg in &cmd.args {
+    line.push(encode_arg(arg));
+  }
+  let line: Vec<&str> = line.iter().map(|e| { e.as_ref() }).collect();
+  write_line(writer, &line, debug)?;
+  if let Some(data) = &cmd.data {
+    write_line(writer, &[data], debug)?;
+  }
+  Ok(())
+}
+
+fn write_line<W: io::Write>(writer: &mut W, line: &[&str], debug: bool) -> Result<(), error::Error> {
+  for (i, cmd) in line.iter().enumerate() {
+    if i > 0 { writer.write_all(b" ")?; }
+    writer.write_all(cmd.as_bytes())?;
+  }
+  writer.write_all(b"\n")?;
+  writer.flush()?;
+  if debug {
+    let mut dbg = String::new();
+    for (i, cmd) in line.iter().enumerate() {
+      dbg += &((if i == 0 { "" } else { " " }).to_owned() + cmd);
     }
-    Ok(())
+    log::logln!(">>> rpc: {}", dbg);
   }
+  Ok(())
 }
 
 #[derive(Clone)]
 pub struct Socket {
-  path: path::PathBuf,
+  path: Option<path::PathBuf>,
 }
 
 impl Socket {
   pub fn new<P: AsRef<path::Path>>(path: P) -> Self {
     Self{
-      path: path.as_ref().into(),
+      path: Some(path.as_ref().into()),
+    }
+  }
+
+  /// A service which is not listening on a socket, and so has nothing to
+  /// clean up.
+  pub fn none() -> Self {
+    Self{
+      path: None,
     }
   }
 
+  /// The path of the socket, if there is one.
+  pub fn path(&self) -> Option<&path::Path> {
+    self.path.as_deref()
+  }
+
   pub fn cleanup(&mut self) -> io::Result<()> {
-    fs::remove_file(&self.path)
+    match &self.path {
+      Some(path) => fs::remove_file(path),
+      None       => Ok(()),
+    }
   }
 }
 
@@ -251,3 +967,79 @@ impl Drop for Socket {
     self.cleanup().expect("Could not remove socket");
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn escape_args() {
+    for name in ["plain", "with space", "two\nlines", "crlf\r\n", "tab\there", "100%", "%20", " lead and trail ", "caf\u{e9} \u{2003}wide", "nul\u{0}", ""] {
+      assert_eq!(name, decode_arg(&encode_arg(name)).unwrap(), "{:?}", name);
+      assert!(!encode_arg(name).contains(|c: char| { c.is_whitespace() }), "{:?}", name);
+    }
+    assert_eq!("a%20b%0Ac%25", encode_arg("a b\nc%"));
+    assert!(decode_arg("%2").is_err());
+    assert!(decode_arg("%zz").is_err());
+    assert!(decode_arg("%FF").is_err()); // not UTF-8
+  }
+
+  #[test]
+  fn round_trip_keys() {
+    let op = Operation::new_found("a key\nwith lines", r#"{"x":1}"#);
+    let mut buf: Vec<u8> = Vec::new();
+    write_operation(&mut buf, &op, false).unwrap();
+    assert_eq!(2, buf.iter().filter(|&&b| { b == b'\n' }).count());
+    let parsed = read_operation(&mut io::Cursor::new(buf), false).unwrap().unwrap();
+    assert_eq!((op.name(), op.args(), op.data()), (parsed.name(), parsed.args(), parsed.data()));
+
+    let op = Operation::new_delete(&["a b", "c"]).with_flag(FLAG_REPLACE, true);
+    let mut buf: Vec<u8> = Vec::new();
+    write_operation(&mut buf, &op, false).unwrap();
+    assert_eq!("delete a%20b c replace\n", String::from_utf8(buf).unwrap());
+  }
+
+  #[test]
+  fn rest_args() {
+    let op = Operation::new_watch_from(&["resume=5", "--", "job:*"], Some(3));
+    let mut buf: Vec<u8> = Vec::new();
+    write_operation(&mut buf, &op, false).unwrap();
+    let op = read_operation(&mut io::BufReader::new(&buf[..]), false).unwrap().unwrap();
+    let (options, rest) = op.split_rest().unwrap();
+    assert_eq!(vec!["resume=3"], options);
+    assert_eq!(vec!["resume=5", "--", "job:*"], rest);
+    assert_eq!(None, Operation::new_delete(&["a", "b"]).split_rest());
+
+    let (tx, _rx) = mpsc::unbounded_channel();
+    let req = Request::new(1, Operation::new_delete(&["sequence=deploy"]), tx); // sequences are per connection, never arguments
+    assert_eq!(None, req.sequence());
+    assert_eq!(vec!["sequence=deploy"], req.op.args());
+  }
+
+  #[test]
+  fn data_whitespace() {
+    for pattern in [" ", "job ", " job", "\tjob\t", ""] {
+      let mut buf: Vec<u8> = Vec::new();
+      write_operation(&mut buf, &Operation::new_delete_keys(pattern, false, false), false).unwrap();
+      let op = read_operation(&mut io::BufReader::new(&buf[..]), false).unwrap().unwrap();
+      assert_eq!(Some(pattern.to_string()), *op.data(), "{:?}", pattern);
+    }
+    let op = parse_operation("count\r\n", Some("job \r\n"), false).unwrap(); // as typed at a terminal
+    assert_eq!(Some("job ".to_string()), *op.data());
+  }
+
+  #[test]
+  fn drain_inflight() {
+    let inflight = Inflight::new();
+    inflight.wait(time::Duration::from_secs(5)); // nothing in flight, returns at once
+    inflight.begin();
+    let start = time::Instant::now();
+    inflight.wait(time::Duration::from_millis(20));
+    assert!(start.elapsed() >= time::Duration::from_millis(20));
+    let other = inflight.clone();
+    let done = std::thread::spawn(move || { other.end() });
+    inflight.wait(time::Duration::from_secs(5));
+    done.join().unwrap();
+    assert!(start.elapsed() < time::Duration::from_secs(5));
+  }
+}
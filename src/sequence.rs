@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, Condvar};
+
+/// A client's place in the order of the operations sharing its sequence
+/// token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ticket {
+  token: String,
+  number: u64,
+}
+
+#[derive(Default)]
+struct Order {
+  issued: u64,
+  done: u64,
+}
+
+/// Orders the operations which carry the same sequence token. Tickets are
+/// issued as operations are received, and each operation waits for those
+/// issued before it to finish before it proceeds, even if they arrived on
+/// other connections. Operations with different tokens, or none, are not
+/// held up.
+pub struct Sequencer {
+  orders: Mutex<HashMap<String, Order>>,
+  cond: Condvar,
+}
+
+impl Sequencer {
+  pub fn new() -> Self {
+    Self{
+      orders: Mutex::new(HashMap::new()),
+      cond: Condvar::new(),
+    }
+  }
+
+  /// Issue the next ticket for a token. Every ticket issued must be waited
+  /// for, or the operations after it wait forever.
+  pub fn issue(&self, token: &str) -> Ticket {
+    let mut orders = self.orders();
+    let order = orders.entry(token.to_string()).or_default();
+    order.issued += 1;
+    Ticket{
+      token: token.to_string(),
+      number: order.issued - 1,
+    }
+  }
+
+  /// Wait until every operation issued a ticket before this one has
+  /// finished. The next may proceed when the turn is dropped.
+  pub fn wait(&self, ticket: Ticket) -> Turn<'_> {
+    let mut orders = self.orders();
+    while orders.get(&ticket.token).is_some_and(|e| { e.done < ticket.number }) {
+      orders = match self.cond.wait(orders) {
+        Ok(orders)  => orders,
+        Err(orders) => orders.into_inner(),
+      };
+    }
+    Turn{
+      sequencer: self,
+      token: ticket.token,
+    }
+  }
+
+  fn orders(&self) -> MutexGuard<'_, HashMap<String, Order>> {
+    match self.orders.lock() {
+      Ok(orders)  => orders,
+      Err(orders) => orders.into_inner(), // the counts themselves are always consistent
+    }
+  }
+}
+
+pub struct Turn<'a> {
+  sequencer: &'a Sequencer,
+  token: String,
+}
+
+impl Drop for Turn<'_> {
+  fn drop(&mut self) {
+    let mut orders = self.sequencer.orders();
+    if let Some(order) = orders.get_mut(&self.token) {
+      order.done += 1;
+      if order.done == order.issued {
+        orders.remove(&self.token); // nothing is waiting on this token
+      }
+    }
+    self.sequencer.cond.notify_all();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::{mpsc, Arc};
+  use std::thread;
+  use std::time;
+
+  #[test]
+  fn wait_in_turn() {
+    let seq = Arc::new(Sequencer::new());
+    let (tx, rx) = mpsc::channel();
+    let tickets: Vec<Ticket> = (0..5).map(|_| { seq.issue("a") }).collect();
+    let other = seq.issue("b");
+    let mut threads = Vec::new();
+    for (i, ticket) in tickets.into_iter().enumerate().rev() { // started in the wrong order
+      let (seq, tx) = (seq.clone(), tx.clone());
+      threads.push(thread::spawn(move || {
+        let _turn = seq.wait(ticket);
+        tx.send(i).unwrap();
+      }));
+      thread::sleep(time::Duration::from_millis(2));
+    }
+    drop(seq.wait(other)); // would block if tokens were ordered together
+    for t in threads {
+      t.join().unwrap();
+    }
+    assert_eq!(vec![0, 1, 2, 3, 4], rx.try_iter().collect::<Vec<usize>>());
+    assert!(seq.orders().is_empty());
+  }
+}
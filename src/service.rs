@@ -1,31 +1,109 @@
+use std::io;
+use std::env;
+use std::fs;
+use std::path;
 use std::time;
 use std::thread;
 use std::process;
-use std::collections::BTreeMap;
-use std::sync::mpsc;
+use std::io::Write;
+use std::os::unix::fs::DirBuilderExt;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{mpsc, Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 use colored::Colorize;
-use serde_json;
-use ctrlc;
+use rand::distributions::{Alphanumeric, DistString};
 
 use crate::Options;
 use crate::RunOptions;
 use crate::error;
 use crate::rpc;
 use crate::jsonpath;
+use crate::diff;
+use crate::patch;
+use crate::storage;
+use crate::storage::Store;
+use crate::keylock;
+use crate::sequence;
+use crate::pool;
+use crate::notify;
+use crate::dump;
+use crate::cas;
+use crate::stats;
+use crate::ttl;
+use crate::journal;
+use crate::crypto;
+use crate::predicate;
+use crate::pattern;
+use crate::suggest;
+use crate::watch;
+use crate::eventlog;
+use crate::metrics;
 use crate::log;
 
 use crate::rpc::CMD_GET;
+use crate::rpc::CMD_MGET;
+use crate::rpc::CMD_MSET;
 use crate::rpc::CMD_RANGE;
+use crate::rpc::CMD_FIND;
+use crate::rpc::CMD_PATHS;
+use crate::rpc::CMD_SUGGEST;
 use crate::rpc::CMD_SET;
+use crate::rpc::CMD_PUT;
+use crate::rpc::CMD_APPEND;
+use crate::rpc::CMD_SWAP;
+use crate::rpc::CMD_CAS;
+use crate::rpc::CMD_INCR;
+use crate::rpc::CMD_PUSH;
+use crate::rpc::CMD_POP;
+use crate::rpc::CMD_MERGE;
+use crate::rpc::CMD_PATCH;
+use crate::rpc::CMD_MERGEKEYS;
+use crate::rpc::CMD_MOVE;
+use crate::rpc::CMD_ALIAS;
+use crate::rpc::CMD_UNALIAS;
+use crate::rpc::CMD_PROTECT;
+use crate::rpc::CMD_UNPROTECT;
+use crate::rpc::CMD_DEFAULT;
+use crate::rpc::CMD_UNDEFAULT;
+use crate::rpc::CMD_DEFAULTS;
 use crate::rpc::CMD_DELETE;
+use crate::rpc::CMD_DELETEKEYS;
+use crate::rpc::CMD_CLEAR;
+use crate::rpc::CMD_TAKE;
 use crate::rpc::CMD_SHUTDOWN;
+use crate::rpc::CMD_VERSION;
+use crate::rpc::CMD_HEALTH;
+use crate::rpc::CMD_STAT;
+use crate::rpc::CMD_KEYS;
+use crate::rpc::CMD_COUNT;
+use crate::rpc::CMD_CHECKSUM;
+use crate::rpc::CMD_WATCH;
+use crate::rpc::CMD_METRIC;
+use crate::rpc::CMD_TOUCH;
+use crate::rpc::CMD_DUMP;
+use crate::rpc::CMD_DUMPKEY;
+use crate::rpc::CMD_RESTOREKEY;
 
-fn cleanup_on_signal(opts: Options, mut sock: rpc::Socket) {
+/// The number of elements sent in each chunk of a streamed range.
+const RANGE_CHUNK: usize = 1000;
+
+/// The events lifecycle hooks are run for, which they are told in
+/// `$STRUCTS_EVENT`, along with the path of a file holding the state of the
+/// store in `$STRUCTS_STATE`.
+const HOOK_START:     &str = "start";
+const HOOK_IDLE_EXIT: &str = "idle-exit";
+const HOOK_FINALIZE:  &str = "finalize";
+const HOOK_EVENT_ENV: &str = "STRUCTS_EVENT";
+const HOOK_STATE_ENV: &str = "STRUCTS_STATE";
+/// How long the start hook waits for the service to begin listening.
+const HOOK_START_WAIT: time::Duration = time::Duration::from_secs(5);
+
+fn cleanup_on_signal(opts: Options, state: Arc<State>, save: Option<String>, mut sock: rpc::Socket) {
   ctrlc::set_handler(move || {
     if opts.debug || opts.verbose {
       log::logln!(">>> Shutting down due to signal...");
     }
+    save_on_exit(&opts, &state, save.as_deref());
     process::exit(match sock.cleanup() {
       Ok(_)  => 0,
       Err(_) => 1,
@@ -33,7 +111,7 @@ fn cleanup_on_signal(opts: Options, mut sock: rpc::Socket) {
   }).expect("Could not set signal handler");
 }
 
-fn cleanup_on_idle(opts: Options, mut sock: rpc::Socket, dur: time::Duration) -> Result<mpsc::Sender<()>, error::Error> {
+fn cleanup_on_idle(opts: Options, state: Arc<State>, save: Option<String>, hook: Option<String>, mut sock: rpc::Socket, dur: time::Duration) -> Result<mpsc::Sender<()>, error::Error> {
   if opts.debug {
     log::logln!(">>> Idle timeout: {:?}", &dur);
   }
@@ -52,6 +130,10 @@ fn cleanup_on_idle(opts: Options, mut sock: rpc::Socket, dur: time::Duration) ->
     if opts.debug || opts.verbose {
       log::logln!(">>> Shutting down after {:?} of inactivity...", last_op.elapsed().unwrap());
     }
+    if let Some(hook) = &hook {
+      run_hook(&opts, &state, HOOK_IDLE_EXIT, hook, sock.path());
+    }
+    save_on_exit(&opts, &state, save.as_deref());
     process::exit(match sock.cleanup() {
       Ok(_)  => 0,
       Err(_) => 1,
@@ -60,32 +142,561 @@ fn cleanup_on_idle(opts: Options, mut sock: rpc::Socket, dur: time::Duration) ->
   Ok(tx)
 }
 
-pub fn run(opts: Options, runopts: RunOptions, mut data: BTreeMap<String, serde_json::Value>, mut sock: rpc::Socket, rx: mpsc::Receiver<rpc::Request>) -> Result<(), error::Error> {
-  cleanup_on_signal(opts.clone(), sock.clone());
+/// The state maintained by the service: the store holding the data itself,
+/// the aliases which refer to it, the locks held by writers, access
+/// statistics, when keys expire, and who is watching them. State is shared
+/// by the threads handling requests. Reads which find nothing in the store
+/// fall back to the seed, if there is one, which is never written.
+pub struct State {
+  data: storage::Shared,
+  seed: Option<storage::Memory>,
+  aliases: RwLock<BTreeMap<String, String>>,
+  protected: RwLock<BTreeSet<String>>,
+  defaults: RwLock<BTreeMap<String, (pattern::Pattern, serde_json::Value)>>,
+  locks: keylock::Locks,
+  sequencer: sequence::Sequencer,
+  stats: stats::Stats,
+  expiry: ttl::Expiry,
+  watchers: watch::Watchers,
+  metrics: metrics::Metrics,
+}
+
+impl State {
+  pub fn new(data: Box<dyn storage::Store>) -> Self {
+    Self{
+      data: storage::Shared::new(data),
+      seed: None,
+      aliases: RwLock::new(BTreeMap::new()),
+      protected: RwLock::new(BTreeSet::new()),
+      defaults: RwLock::new(BTreeMap::new()),
+      locks: keylock::Locks::new(),
+      sequencer: sequence::Sequencer::new(),
+      stats: stats::Stats::new(),
+      expiry: ttl::Expiry::new(),
+      watchers: watch::Watchers::new(watch::DEFAULT_HISTORY),
+      metrics: metrics::Metrics::new(),
+    }
+  }
+
+  /// Keep up to a number of changes for watchers which resume after a
+  /// disconnect; zero keeps none.
+  pub fn with_watch_history(mut self, history: usize) -> Self {
+    self.watchers = watch::Watchers::new(history);
+    self
+  }
+
+  /// Provide read-only defaults for the keys of the store.
+  pub fn with_seed(mut self, keys: serde_json::Map<String, serde_json::Value>) -> Result<Self, error::Error> {
+    let mut seed = storage::Memory::new();
+    for (key, value) in keys {
+      seed.set(&key, value)?;
+    }
+    self.seed = Some(seed);
+    Ok(self)
+  }
+
+  fn aliases(&self) -> RwLockReadGuard<'_, BTreeMap<String, String>> {
+    match self.aliases.read() {
+      Ok(aliases)  => aliases,
+      Err(aliases) => aliases.into_inner(),
+    }
+  }
+
+  fn aliases_mut(&self) -> RwLockWriteGuard<'_, BTreeMap<String, String>> {
+    match self.aliases.write() {
+      Ok(aliases)  => aliases,
+      Err(aliases) => aliases.into_inner(),
+    }
+  }
+
+  fn protected(&self) -> RwLockReadGuard<'_, BTreeSet<String>> {
+    match self.protected.read() {
+      Ok(protected)  => protected,
+      Err(protected) => protected.into_inner(),
+    }
+  }
+
+  fn protected_mut(&self) -> RwLockWriteGuard<'_, BTreeSet<String>> {
+    match self.protected.write() {
+      Ok(protected)  => protected,
+      Err(protected) => protected.into_inner(),
+    }
+  }
+
+  fn defaults(&self) -> RwLockReadGuard<'_, BTreeMap<String, (pattern::Pattern, serde_json::Value)>> {
+    match self.defaults.read() {
+      Ok(defaults)  => defaults,
+      Err(defaults) => defaults.into_inner(),
+    }
+  }
+
+  fn defaults_mut(&self) -> RwLockWriteGuard<'_, BTreeMap<String, (pattern::Pattern, serde_json::Value)>> {
+    match self.defaults.write() {
+      Ok(defaults)  => defaults,
+      Err(defaults) => defaults.into_inner(),
+    }
+  }
+
+  /// Produce the template for a key which is stored nowhere, neither in
+  /// the store nor the seed, if one of the registered globs matches it.
+  /// The longest matching glob wins.
+  fn template(&self, key: &str) -> Result<Option<serde_json::Value>, error::Error> {
+    if self.data.get(key)?.is_some() || self.seed.as_ref().map(|e| { e.get(key) }).transpose()?.flatten().is_some() {
+      return Ok(None);
+    }
+    Ok(self.defaults().iter().filter(|(_, (pattern, _))| { pattern.is_match(key) }).max_by_key(|(glob, _)| { glob.len() }).map(|(_, (_, template))| { template.clone() }))
+  }
+
+  /// Produce the protected key or path which writing to a resolved name
+  /// would modify, if any: the name itself, one of its parents, or anything
+  /// under it.
+  fn protection(&self, name: &str) -> Option<String> {
+    self.protected().iter().find(|e| { overlaps(e, name) }).cloned()
+  }
+
+  /// Resolve the key which a name refers to. If the first component of the
+  /// name is an alias it is replaced with the alias' target; otherwise the
+  /// name is returned as-is.
+  fn resolve(&self, name: &str) -> String {
+    let path = jsonpath::Path::new(name);
+    match path.next() {
+      (Some(key), rest) => match self.aliases().get(key) {
+        Some(target) => match rest {
+          Some(rest) => jsonpath::join(target, rest),
+          None       => target.to_owned(),
+        },
+        None => name.to_string(),
+      },
+      _ => name.to_string(),
+    }
+  }
+
+  /// Fetch the value which a name refers to, noting the access. If the key
+  /// has expired it is removed and not found.
+  fn fetch(&self, name: &str) -> Result<serde_json::Value, error::Error> {
+    let name = self.resolve(name);
+    let key = key_of(&name);
+    if self.expiry.is_expired(&key) {
+      let _guard = self.locks.lock(&[&key]);
+      self.reap(&key)?;
+    }
+    let res = match (fetch(&self.data, &name), &self.seed) {
+      (Err(error::Error::NotFound), Some(seed)) => fetch(seed, &name),
+      (res, _)                                  => res,
+    };
+    let res = match res {
+      Err(error::Error::NotFound) => match (self.template(&key)?, jsonpath::Path::new(&name).first().1) {
+        (Some(template), Some(path)) => match path.find(&template) {
+          (Some(data), None) => Ok(data.clone()),
+          _                  => Err(error::Error::NotFound),
+        },
+        (Some(template), None)       => Ok(template),
+        (None, _)                    => Err(error::Error::NotFound),
+      },
+      res => res,
+    };
+    match &res {
+      Ok(_)                       => self.stats.read(&key_of(&name), true),
+      Err(error::Error::NotFound) => self.stats.read(&key_of(&name), false),
+      Err(_)                      => {},
+    }
+    res
+  }
+
+  /// Determine if a resolved name has a value in the store or the seed,
+  /// without noting an access. Templates don't count.
+  fn exists(&self, name: &str) -> Result<bool, error::Error> {
+    let res = match (fetch(&self.data, name), &self.seed) {
+      (Err(error::Error::NotFound), Some(seed)) => fetch(seed, name),
+      (res, _)                                  => res,
+    };
+    match res {
+      Ok(_)                       => Ok(true),
+      Err(error::Error::NotFound) => Ok(false),
+      Err(err)                    => Err(err),
+    }
+  }
+
+  /// Fetch the value which a name referred to at a time, in milliseconds
+  /// since the epoch, from the history of changes. If the history cannot
+  /// tell, the reason is produced as an error.
+  fn fetch_as_of(&self, name: &str, time: u128) -> Result<Result<serde_json::Value, String>, error::Error> {
+    let name = self.resolve(name);
+    let data = match self.watchers.as_of(&key_of(&name), time) {
+      watch::Past::Unchanged       => return self.fetch(&name).map(Ok),
+      watch::Past::Unknown(reason) => return Ok(Err(reason)),
+      watch::Past::Value(Some(v))  => v,
+      watch::Past::Value(None)     => return Err(error::Error::NotFound),
+    };
+    match jsonpath::Path::new(&name).first().1 {
+      Some(path) => match path.find(&data) {
+        (Some(data), None) => Ok(Ok(data.clone())),
+        _                  => Err(error::Error::NotFound),
+      },
+      None => Ok(Ok(data)),
+    }
+  }
+
+  /// Fetch the value a name refers to as with fetch, but match the key and
+  /// object members case-insensitively wherever there is no exact match.
+  /// As with fetch, a key which is not stored is looked for in the seed,
+  /// then among the templates.
+  fn fetch_ci(&self, name: &str) -> Result<serde_json::Value, error::Error> {
+    let name = self.resolve(name);
+    let (mut key, mut data) = (key_of(&name), None);
+    if let Some(found) = match_key_ci(&self.data, &key)? {
+      if self.expiry.is_expired(&found) {
+        let _guard = self.locks.lock(&[&found]);
+        self.reap(&found)?;
+      }
+      data = self.data.get(&found)?;
+      key = found;
+    }
+    if data.is_none() {
+      if let Some(seed) = &self.seed {
+        if let Some(found) = match_key_ci(seed, &key_of(&name))? {
+          data = seed.get(&found)?;
+          key = found;
+        }
+      }
+    }
+    if data.is_none() {
+      data = self.template(&key)?;
+    }
+    let res = match data {
+      Some(data) => match jsonpath::Path::new(&name).first().1 {
+        Some(path) => path.value_ci(&data).cloned().ok_or(error::Error::NotFound),
+        None       => Ok(data),
+      },
+      None => Err(error::Error::NotFound),
+    };
+    match &res {
+      Ok(_)                       => self.stats.read(&key, true),
+      Err(error::Error::NotFound) => self.stats.read(&key, false),
+      Err(_)                      => {},
+    }
+    res
+  }
+
+  /// Describe precisely why a name cannot be fetched.
+  fn explain(&self, name: &str) -> Result<String, error::Error> {
+    let name = self.resolve(name);
+    let path = jsonpath::Path::new(&name);
+    let (key, path) = path.first();
+    let key = match key {
+      Some(key) => key,
+      None      => return Ok("No key specified".to_string()),
+    };
+    let data = match self.data.get(key)? {
+      Some(data) if !self.expiry.is_expired(key) => data,
+      _                                          => return Ok(format!("No such key: {}", key)),
+    };
+    Ok(match path.and_then(|e| { e.explain(key, &data) }) {
+      Some(msg) => msg,
+      None      => format!("Not found: {}", name), // it has appeared since
+    })
+  }
+
+  /// Suggest the names most like one which cannot be fetched: other keys,
+  /// if the key is not stored, or else other members of the object where
+  /// the path stops resolving.
+  fn suggest(&self, name: &str) -> Result<Vec<String>, error::Error> {
+    let name = self.resolve(name);
+    let path = jsonpath::Path::new(&name);
+    let (key, path) = path.first();
+    let key = match key {
+      Some(key) => key,
+      None      => return Ok(Vec::new()),
+    };
+    let data = match self.data.get(key)? {
+      Some(data) if !self.expiry.is_expired(key) => data,
+      _ => {
+        let keys = self.data.scan("")?;
+        return Ok(suggest::similar(key, keys.iter().filter(|e| { !self.expiry.is_expired(e) }).map(|e| { e.as_str() })));
+      },
+    };
+    Ok(match path.and_then(|e| { e.unresolved(key, &data) }) {
+      Some((prefix, serde_json::Value::Object(members), name)) => suggest::similar(&name, members.keys().map(|e| { e.as_str() })).iter().map(|e| { jsonpath::join(&prefix, e) }).collect(),
+      _ => Vec::new(),
+    })
+  }
+
+  /// Produce every stored key, in order, which matches a pattern and has
+  /// not expired.
+  fn match_keys(&self, pattern: &pattern::Pattern) -> Result<Vec<String>, error::Error> {
+    let mut keys = self.data.scan(pattern.prefix())?;
+    keys.retain(|e| { pattern.is_match(e) && !self.expiry.is_expired(e) });
+    Ok(keys)
+  }
+
+  /// The number of stored keys which have not expired.
+  fn live_len(&self) -> Result<usize, error::Error> {
+    Ok(self.data.scan("")?.iter().filter(|e| { !self.expiry.is_expired(e) }).count())
+  }
+
+  /// Remove a key if it has expired, producing whether it was removed. The
+  /// caller must hold the lock on the key.
+  fn reap(&self, key: &str) -> Result<bool, error::Error> {
+    if !self.expiry.is_expired(key) {
+      return Ok(false);
+    }
+    self.data.clone().delete(key)?;
+    self.expiry.set(key, None);
+    self.stats.expire(key);
+    self.changed(key);
+    Ok(true)
+  }
+
+  /// Copy a key from the seed, or its template, into the store, if the
+  /// store doesn't have it, so that part of it can be written. The caller
+  /// must hold the lock on the key.
+  fn materialize(&self, key: &str) -> Result<(), error::Error> {
+    let mut data = self.data.clone();
+    if data.get(key)?.is_none() {
+      if let Some(value) = self.initial(key)? {
+        data.set(key, value)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// The value a key which is not stored begins with when a path in it is
+  /// written: its template, if it has one, or its seed.
+  fn initial(&self, key: &str) -> Result<Option<serde_json::Value>, error::Error> {
+    if let Some(template) = self.template(key)? {
+      return Ok(Some(template));
+    }
+    match &self.seed {
+      Some(seed) => seed.get(key),
+      None       => Ok(None),
+    }
+  }
+
+  /// Merge a value written over the whole of a key into the key's
+  /// template, if it is stored nowhere and has one, as a JSON merge patch.
+  /// The caller must hold the lock on the key.
+  fn templated(&self, key: &str, value: serde_json::Value) -> Result<serde_json::Value, error::Error> {
+    Ok(match self.template(key)? {
+      Some(template) => jsonpath::merge_patch(&template, &value),
+      None           => value,
+    })
+  }
+
+  /// Remove a key if it has not been accessed since a cutoff, in
+  /// milliseconds since the epoch, producing whether it was removed. The
+  /// caller must hold the lock on the key.
+  fn collect(&self, key: &str, cutoff: u128) -> Result<bool, error::Error> {
+    let last = self.stats.get(key).and_then(|e| { e.last_access }).unwrap_or(0);
+    if last >= cutoff || self.protection(key).is_some() || self.data.clone().delete(key)?.is_none() {
+      return Ok(false);
+    }
+    self.expiry.set(key, None);
+    self.stats.expire(key);
+    self.changed(key);
+    Ok(true)
+  }
+
+  /// Notify anybody watching a key of its value, or that it was deleted.
+  /// The caller should hold the lock on the key, so that events are sent in
+  /// the order changes are made.
+  fn changed(&self, key: &str) {
+    if !self.watchers.is_watched(key) {
+      return;
+    }
+    match self.data.get(key) {
+      Ok(value) => self.watchers.notify(key, value.as_ref()),
+      Err(err)  => log::logln!("{}", format!("* * * Could not notify watchers of {}: {}", key, err).yellow().bold()),
+    }
+  }
+}
+
+/// Determine if one name is the same as another, or is a parent or child of
+/// it.
+fn overlaps(a: &str, b: &str) -> bool {
+  let (a, b) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+  b == a || (b.starts_with(a) && b[a.len()..].starts_with(jsonpath::SEP))
+}
+
+/// Refuse a request which would write to any of the provided resolved
+/// names if one is protected, producing whether it was refused.
+fn refuse_protected(state: &State, req: &rpc::Request, names: &[&str]) -> Result<bool, error::Error> {
+  for name in names {
+    if let Some(protected) = state.protection(name) {
+      req.send(rpc::Operation::new_protected(&protected))?;
+      return Ok(true);
+    }
+  }
+  Ok(false)
+}
+
+/// The top-level key which a resolved name refers to; this is what writers
+/// lock.
+fn key_of(name: &str) -> String {
+  match jsonpath::Path::new(name).next() {
+    (Some(key), _) => key.to_string(),
+    _              => name.to_string(),
+  }
+}
+
+/// Clean up and exit once the process which owns an ephemeral service has
+/// exited: the one provided or, otherwise, our parent.
+fn cleanup_with_owner(opts: Options, state: Arc<State>, save: Option<String>, mut sock: rpc::Socket, owner: Option<u32>) {
+  let parent = unsafe { libc::getppid() };
+  thread::spawn(move || {
+    loop {
+      thread::sleep(time::Duration::from_millis(100));
+      let alive = match owner {
+        Some(pid) => is_alive(pid),
+        None      => parent == unsafe { libc::getppid() }, // we are reparented when our parent exits
+      };
+      if !alive {
+        break;
+      }
+    }
+    if opts.debug || opts.verbose {
+      log::logln!(">>> Shutting down after owner exited...");
+    }
+    save_on_exit(&opts, &state, save.as_deref());
+    process::exit(match sock.cleanup() {
+      Ok(_)  => 0,
+      Err(_) => 1,
+    });
+  });
+}
+
+fn is_alive(pid: u32) -> bool {
+  if unsafe { libc::kill(pid as libc::pid_t, 0) } == 0 {
+    return true;
+  }
+  io::Error::last_os_error().raw_os_error() == Some(libc::EPERM) // exists, but isn't ours
+}
+
+pub fn run(opts: Options, runopts: RunOptions, data: Box<dyn storage::Store>, cipher: Option<crypto::Cipher>, mut sock: rpc::Socket, rx: mpsc::Receiver<rpc::Request>, inflight: rpc::Inflight) -> Result<(), error::Error> {
+  let mut journal = match &runopts.journal {
+    Some(path) => Some(journal::Journal::open(path, cipher)?),
+    None       => None,
+  };
+
+  let workers = match runopts.workers {
+    Some(n) => n,
+    None    => thread::available_parallelism().map(|e| { e.get() }).unwrap_or(4),
+  };
+  let pool = pool::Pool::new(workers);
+
+  let state = State::new(data).with_watch_history(runopts.watch_history);
+  let state = match &runopts.seed {
+    Some(path) => state.with_seed(load_seed(path)?)?,
+    None       => state,
+  };
+  let state = Arc::new(state);
+  cleanup_on_signal(opts.clone(), state.clone(), runopts.save_on_exit.clone(), sock.clone());
   let poll_tx = match runopts.timeout {
-    Some(dur) => Some(cleanup_on_idle(opts.clone(), sock.clone(), dur.duration())?),
+    Some(dur) => Some(cleanup_on_idle(opts.clone(), state.clone(), runopts.save_on_exit.clone(), runopts.on_idle_exit.clone(), sock.clone(), dur.duration())?),
     None => None,
   };
-
+  if runopts.ephemeral {
+    cleanup_with_owner(opts.clone(), state.clone(), runopts.save_on_exit.clone(), sock.clone(), runopts.owner);
+  }
+  if let Some(hook) = &runopts.on_start {
+    hook_on_start(opts.clone(), state.clone(), hook.clone(), sock.path().map(path::Path::to_path_buf));
+  }
+  if runopts.expire_strategy == ttl::Strategy::Active {
+    sweep_expired(opts.clone(), state.clone(), runopts.sweep_interval.duration());
+  }
+  if let Some(path) = &runopts.stats_log {
+    log_stats(opts.clone(), state.clone(), path.clone(), runopts.stats_interval.duration());
+  }
+  if let Some(idle) = &runopts.gc_idle_keys {
+    collect_idle(opts.clone(), state.clone(), idle.duration(), runopts.sweep_interval.duration());
+  }
+  let mut watchdog = notify::Watchdog::from_env();
+  if opts.debug {
+    if let Some(watchdog) = &watchdog {
+      log::logln!(">>> Pinging watchdog every: {:?}", &watchdog.interval());
+    }
+  }
+  // when finalizing, we also wake periodically to notice the last key
+  // expiring, which nobody asks us to do
+  let tick = match (&watchdog, runopts.finalize) {
+    (Some(watchdog), true)  => Some(watchdog.interval().min(runopts.sweep_interval.duration())),
+    (Some(watchdog), false) => Some(watchdog.interval()),
+    (None, true)            => Some(runopts.sweep_interval.duration()),
+    (None, false)           => None,
+  };
+  let mut expired = state.stats.expired();
   loop {
-    let req = rx.recv()?;
-    let res = match req.name().as_ref() {
-      CMD_GET      => run_get(&opts, &data, req),
-      CMD_RANGE    => run_range(&opts, &data, req),
-      CMD_SET      => run_set(&opts, &mut data, req),
-      CMD_DELETE   => {
-        run_delete(&opts, &mut data, req)?;
-        Ok(if runopts.finalize && data.len() == 0 { break; })
+    // the watchdog is pinged from here so that it notices if this loop
+    // stops making progress, not merely if the process is alive
+    if let Some(watchdog) = &mut watchdog {
+      if let Err(err) = watchdog.ping() {
+        log::logln!("{}", format!("* * * Could not ping watchdog: {}", err).yellow().bold());
+      }
+    }
+    let mut req = match tick {
+      Some(tick) => match rx.recv_timeout(tick) {
+        Ok(req) => req,
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+          let now = state.stats.expired();
+          let lapsed = now > expired || state.data.len()? > 0; // keys were removed on expiring, or may have expired in place
+          expired = now;
+          if runopts.finalize && lapsed && state.live_len()? == 0 {
+            inflight.wait(time::Duration::from_secs(1)); // let concurrent writes land before deciding
+            if state.live_len()? == 0 { break; }
+          }
+          continue;
+        },
+        Err(mpsc::RecvTimeoutError::Disconnected) => return Err(mpsc::RecvError.into()),
       },
-      CMD_SHUTDOWN => {
+      None => rx.recv()?,
+    };
+    if let Some(journal) = &mut journal {
+      if let Err(err) = journal.record(req.client(), req.operation()) {
+        log::logln!("{}", format!("* * * Could not record operation: {}", err).yellow().bold());
+      }
+    }
+    // tickets are issued here, in the order requests arrive from every
+    // connection; watches never finish, so they take no part
+    let mut ticket = match req.sequence() {
+      Some(token) if req.name() != CMD_WATCH => Some(state.sequencer.issue(token)),
+      _                                      => None,
+    };
+    let _turn = match req.name() {
+      CMD_ALIAS | CMD_UNALIAS | CMD_PROTECT | CMD_UNPROTECT | CMD_DEFAULT | CMD_UNDEFAULT | CMD_DEFAULTS | CMD_DELETE | CMD_DELETEKEYS | CMD_TAKE | CMD_HEALTH | CMD_SHUTDOWN => ticket.take().map(|e| { state.sequencer.wait(e) }),
+      _                                                                                                                                                                       => None, // waited for by the worker
+    };
+    let tx = req.sender();
+    let res = match req.name() {
+      CMD_ALIAS     => run_alias(&opts, &state, req),
+      CMD_UNALIAS   => run_unalias(&opts, &state, req),
+      CMD_PROTECT   => run_protect(&opts, &state, req),
+      CMD_UNPROTECT => run_unprotect(&opts, &state, req),
+      CMD_DEFAULT   => run_default(&opts, &state, req),
+      CMD_UNDEFAULT => run_undefault(&opts, &state, req),
+      CMD_DEFAULTS  => run_defaults(&opts, &state, req),
+      CMD_DELETE | CMD_DELETEKEYS | CMD_TAKE => {
+        let res = match req.name() {
+          CMD_DELETE => run_delete(&opts, &state, req),
+          CMD_TAKE   => run_take(&opts, &state, req),
+          _          => run_delete_keys(&opts, &state, req),
+        };
+        fail(&tx, &res);
+        if runopts.finalize && state.live_len()? == 0 {
+          inflight.wait(time::Duration::from_secs(1)); // let concurrent writes land before deciding
+          if state.live_len()? == 0 { break; }
+        }
+        Ok(())
+      },
+      CMD_HEALTH    => run_health(&opts, req), // answered here, to show this loop is responsive
+      CMD_SHUTDOWN  => {
         run_stop(&opts, req)?;
         break;
       },
-      cmd => Ok(log::logln!("{}", &format!("* * * Unknown command: {}", cmd).yellow().bold())),
+      _ => {
+        dispatch(&opts, &pool, &state, req, ticket);
+        Ok(())
+      },
     };
-    if let Err(err) = res {
-      log::logln!("{}", format!("* * * Error: {}", err).yellow().bold());
-    }
+    fail(&tx, &res);
     if let Some(poll_tx) = &poll_tx {
       if let Err(err) = poll_tx.send(()) {
         log::logln!("{}", format!("* * * Could not poll: {}", err).yellow().bold());
@@ -96,92 +707,500 @@ pub fn run(opts: Options, runopts: RunOptions, mut data: BTreeMap<String, serde_
   if opts.debug || opts.verbose {
     log::logln!(">>> Shutting down due to finalization...");
   }
-  process::exit(match sock.cleanup() {
+  inflight.wait(time::Duration::from_secs(1)); // let the final response make it out
+  save_on_exit(&opts, &state, runopts.save_on_exit.as_deref());
+  let status = match sock.cleanup() {
     Ok(_)  => 0,
     Err(_) => 1,
+  };
+  if let Some(hook) = &runopts.on_finalize {
+    run_hook(&opts, &state, HOOK_FINALIZE, hook, sock.path());
+  }
+  process::exit(status);
+}
+
+/// Write every key which has not expired to a file, as an object whose
+/// members are keys and their values.
+fn save_state(state: &State, path: &path::Path) -> Result<(), error::Error> {
+  let mut keys = state.data.snapshot()?;
+  keys.retain(|key, _| { !state.expiry.is_expired(key) });
+  crate::write_atomic(path, &serde_json::to_vec(&keys)?)
+}
+
+/// Save the state of the store as the service exits, if asked to. Failures
+/// are logged, since there is nothing more to be done about them.
+fn save_on_exit(opts: &Options, state: &State, path: Option<&str>) {
+  let path = match path {
+    Some(path) => path,
+    None       => return,
+  };
+  if opts.debug || opts.verbose {
+    log::logln!(">>> Saving state to: {}", path);
+  }
+  if let Err(err) = save_state(state, path::Path::new(path)) {
+    log::logln!("{}", format!("* * * Could not save state to {}: {}", path, err).yellow().bold());
+  }
+}
+
+/// Run a lifecycle hook with `sh -c` and wait for it to finish. The hook is
+/// told the event it is run for, the socket the service listens on, if any,
+/// and the path of a file holding the state of the store, as with
+/// `save_on_exit`, which is removed once the hook finishes. Failures are
+/// logged.
+fn run_hook(opts: &Options, state: &State, event: &str, script: &str, sock: Option<&path::Path>) {
+  if opts.debug || opts.verbose {
+    log::logln!(">>> Running {} hook: {}", event, script);
+  }
+  // the state is written in the clear, so it goes in a directory only we
+  // can enter; its name is unpredictable and it must not already exist
+  let dir = env::temp_dir().join(format!("structs-{}-{}", process::id(), Alphanumeric.sample_string(&mut rand::thread_rng(), 12)));
+  if let Err(err) = fs::DirBuilder::new().mode(0o700).create(&dir) {
+    log::logln!("{}", format!("* * * Could not run the {} hook: {}", event, err).yellow().bold());
+    return;
+  }
+  let dump = dir.join(format!("{}.json", event));
+  let res = save_state(state, &dump).and_then(|_| {
+    let mut cmd = process::Command::new("sh");
+    cmd.arg("-c").arg(script).env(HOOK_EVENT_ENV, event).env(HOOK_STATE_ENV, &dump);
+    if let Some(path) = sock {
+      cmd.env(crate::SOCKET_ENV, path);
+    }
+    Ok(cmd.status()?)
+  });
+  let _ = fs::remove_dir_all(&dir);
+  match res {
+    Ok(status) if status.success() => {},
+    Ok(status) => log::logln!("{}", format!("* * * The {} hook failed: {}", event, status).yellow().bold()),
+    Err(err)   => log::logln!("{}", format!("* * * Could not run the {} hook: {}", event, err).yellow().bold()),
+  }
+}
+
+/// Run the start hook once the service is listening, so that it may connect,
+/// without holding up the service.
+fn hook_on_start(opts: Options, state: Arc<State>, hook: String, sock: Option<path::PathBuf>) {
+  thread::spawn(move || {
+    if let Some(path) = &sock {
+      let start = time::Instant::now();
+      while !path.exists() && start.elapsed() < HOOK_START_WAIT {
+        thread::sleep(time::Duration::from_millis(10));
+      }
+    }
+    run_hook(&opts, &state, HOOK_START, &hook, sock.as_deref());
+  });
+}
+
+/// Load a seed document: an object whose members are keys and their values.
+fn load_seed(path: &str) -> Result<serde_json::Map<String, serde_json::Value>, error::Error> {
+  match serde_json::from_str(&fs::read_to_string(path)?)? {
+    serde_json::Value::Object(keys) => Ok(keys),
+    _                               => Err(error::Error::Malformed),
+  }
+}
+
+/// Load every JSON file in a directory, keyed by its name without the
+/// extension, in order. Other files are ignored.
+pub fn load_dir(dir: &str) -> Result<Vec<(String, serde_json::Value)>, error::Error> {
+  let mut paths: Vec<path::PathBuf> = fs::read_dir(dir)?.map(|e| { e.map(|e| { e.path() }) }).collect::<Result<_, _>>()?;
+  paths.retain(|e| { e.is_file() && e.extension().is_some_and(|e| { e == "json" }) });
+  paths.sort();
+  let mut keys = Vec::new();
+  for path in paths {
+    let key = match path.file_stem().and_then(|e| { e.to_str() }) {
+      Some(key) => key.to_string(),
+      None      => continue,
+    };
+    match serde_json::from_str(&fs::read_to_string(&path)?) {
+      Ok(value) => keys.push((key, value)),
+      Err(err)  => return Err(error::Error::InvalidValue(format!("{}: {}", path.display(), err))),
+    }
+  }
+  Ok(keys)
+}
+
+/// Periodically remove every key which has expired, rather than waiting for
+/// expired keys to be accessed.
+fn sweep_expired(opts: Options, state: Arc<State>, dur: time::Duration) {
+  if opts.debug {
+    log::logln!(">>> Sweeping expired keys every: {:?}", &dur);
+  }
+  thread::spawn(move || {
+    loop {
+      thread::sleep(dur);
+      for key in state.expiry.expired() {
+        let _guard = state.locks.lock(&[&key]);
+        if let Err(err) = state.reap(&key) {
+          log::logln!("{}", format!("* * * Could not expire {}: {}", key, err).yellow().bold());
+        }
+      }
+    }
+  });
+}
+
+/// Periodically append a sample of the statistics for the whole store to a
+/// file: as CSV, with a header, if its name ends in `.csv`, and otherwise as
+/// a JSON object per line. Failures are logged.
+fn log_stats(opts: Options, state: Arc<State>, path: String, dur: time::Duration) {
+  if opts.debug {
+    log::logln!(">>> Logging statistics to {} every: {:?}", &path, &dur);
+  }
+  let csv = path.ends_with(".csv");
+  let mut file = match fs::OpenOptions::new().create(true).append(true).open(&path) {
+    Ok(file) => file,
+    Err(err) => {
+      log::logln!("{}", format!("* * * Could not open statistics log {}: {}", path, err).yellow().bold());
+      return;
+    },
+  };
+  thread::spawn(move || {
+    if csv && file.metadata().map(|e| { e.len() == 0 }).unwrap_or(false) {
+      if let Err(err) = writeln!(file, "{}", stats::Sample::CSV_HEADER) {
+        log::logln!("{}", format!("* * * Could not write statistics log {}: {}", path, err).yellow().bold());
+      }
+    }
+    loop {
+      thread::sleep(dur);
+      let sample = state.stats.sample(state.live_len().unwrap_or_default());
+      let line = match csv {
+        true  => Ok(sample.to_csv()),
+        false => serde_json::to_string(&sample),
+      };
+      let res = match line {
+        Ok(line) => writeln!(file, "{}", line).map_err(error::Error::from),
+        Err(err) => Err(err.into()),
+      };
+      if let Err(err) = res {
+        log::logln!("{}", format!("* * * Could not write statistics log {}: {}", path, err).yellow().bold());
+      }
+    }
+  });
+}
+
+/// Periodically delete every key which has been neither read nor written
+/// within the idle window, as though it had expired. Keys which have not
+/// been accessed since the service started are considered to have been
+/// accessed when it did.
+fn collect_idle(opts: Options, state: Arc<State>, idle: time::Duration, dur: time::Duration) {
+  if opts.debug {
+    log::logln!(">>> Collecting keys idle for {:?} every: {:?}", &idle, &dur);
+  }
+  let started = time::SystemTime::now();
+  thread::spawn(move || {
+    loop {
+      thread::sleep(dur);
+      let cutoff = match time::SystemTime::now().checked_sub(idle) {
+        Some(cutoff) if cutoff >= started => cutoff,
+        _                                 => continue, // nothing has been idle long enough yet
+      };
+      let cutoff = match cutoff.duration_since(time::UNIX_EPOCH) {
+        Ok(cutoff) => cutoff.as_millis(),
+        Err(_)     => continue,
+      };
+      let keys = match state.data.scan("") {
+        Ok(keys) => keys,
+        Err(err) => {
+          log::logln!("{}", format!("* * * Could not list keys to collect: {}", err).yellow().bold());
+          continue;
+        },
+      };
+      for key in keys {
+        let _guard = state.locks.lock(&[&key]);
+        match state.collect(&key, cutoff) {
+          Ok(true)  => if opts.debug {
+            log::logln!(">>> Collected idle key: {}", key);
+          },
+          Ok(false) => {},
+          Err(err)  => log::logln!("{}", format!("* * * Could not collect {}: {}", key, err).yellow().bold()),
+        }
+      }
+    }
+  });
+}
+
+/// The lane a request waits in for a worker. Small reads go first, so they
+/// are answered promptly even while bulk writes and scans are queued.
+fn priority(name: &str) -> pool::Priority {
+  match name {
+    CMD_GET | CMD_RANGE | CMD_PATHS | CMD_STAT | CMD_VERSION => pool::Priority::Urgent,
+    _                                                        => pool::Priority::Normal,
+  }
+}
+
+/// Handle a request which may proceed concurrently with others on a worker
+/// thread. Reads need no coordination; writers lock the keys they modify.
+/// A request with a sequence ticket first waits its turn; it is never run
+/// urgently, since it could then overtake the request it waits for and
+/// hold every worker.
+fn dispatch(opts: &Options, pool: &pool::Pool, state: &Arc<State>, req: rpc::Request, ticket: Option<sequence::Ticket>) {
+  let (opts, state) = (opts.clone(), state.clone());
+  let priority = match ticket {
+    Some(_) => pool::Priority::Normal,
+    None    => priority(req.name()),
+  };
+  pool.execute(priority, move || {
+    let _turn = ticket.map(|e| { state.sequencer.wait(e) });
+    let tx = req.sender();
+    let res = match req.name() {
+      CMD_GET        => run_get(&opts, &state, req),
+      CMD_MGET       => run_mget(&opts, &state, req),
+      CMD_RANGE      => run_range(&opts, &state, req),
+      CMD_FIND       => run_find(&opts, &state, req),
+      CMD_PATHS      => run_paths(&opts, &state, req),
+      CMD_SUGGEST    => run_suggest(&opts, &state, req),
+      CMD_SET        => run_set(&opts, &state, req),
+      CMD_MSET       => run_mset(&opts, &state, req),
+      CMD_SWAP       => run_swap(&opts, &state, req),
+      CMD_CAS        => run_cas(&opts, &state, req),
+      CMD_INCR       => run_incr(&opts, &state, req),
+      CMD_PUSH       => run_push(&opts, &state, req),
+      CMD_POP        => run_pop(&opts, &state, req),
+      CMD_MERGE      => run_merge(&opts, &state, req),
+      CMD_PATCH      => run_patch(&opts, &state, req),
+      CMD_PUT        => run_put(&opts, &state, req),
+      CMD_APPEND     => run_append(&opts, &state, req),
+      CMD_MERGEKEYS  => run_merge_keys(&opts, &state, req),
+      CMD_MOVE       => run_move(&opts, &state, req),
+      CMD_STAT       => run_stat(&opts, &state, req),
+      CMD_METRIC     => run_metric(&opts, &state, req),
+      CMD_KEYS       => run_keys(&opts, &state, req),
+      CMD_COUNT      => run_count(&opts, &state, req),
+      CMD_CHECKSUM   => run_checksum(&opts, &state, req),
+      CMD_WATCH      => run_watch(&opts, &state, req),
+      CMD_TOUCH      => run_touch(&opts, &state, req),
+      CMD_DUMP       => run_dump(&opts, &state, req),
+      CMD_CLEAR      => run_clear(&opts, &state, req),
+      CMD_DUMPKEY    => run_dump_key(&opts, &state, req),
+      CMD_RESTOREKEY => run_restore_key(&opts, &state, req),
+      CMD_VERSION    => run_version(&opts, req),
+      cmd => {
+        log::logln!("{}", &format!("* * * Unknown command: {}", cmd).yellow().bold());
+        req.send(rpc::Operation::new_error(&format!("Unknown command: {}", cmd)))
+      },
+    };
+    fail(&tx, &res);
   });
 }
 
-fn fetch<'a>(store: &'a BTreeMap<String, serde_json::Value>, key: &str) -> Result<&'a serde_json::Value, error::Error> {
+/// Log a request which failed and answer it with the error, since its
+/// handler gave up without doing so. Each request has a channel of its own,
+/// so if the handler had answered after all, the client has stopped
+/// listening and this goes nowhere.
+fn fail(tx: &tokio::sync::mpsc::UnboundedSender<rpc::Operation>, res: &Result<(), error::Error>) {
+  if let Err(err) = res {
+    log::logln!("{}", format!("* * * Error: {}", err).yellow().bold());
+    let _ = tx.send(rpc::Operation::new_error(&err.to_string()));
+  }
+}
+
+/// Produce the key in a store which matches a name case-insensitively,
+/// preferring an exact match.
+fn match_key_ci(store: &dyn storage::Store, name: &str) -> Result<Option<String>, error::Error> {
+  if store.get(name)?.is_some() {
+    return Ok(Some(name.to_string()));
+  }
+  Ok(store.scan("")?.into_iter().find(|e| { jsonpath::eq_ci(e, name) }))
+}
+
+fn fetch(store: &dyn storage::Store, key: &str) -> Result<serde_json::Value, error::Error> {
   let path = jsonpath::Path::new(key);
   let (key, path) = path.next();
   let key = match key {
     Some(key) => key,
     None => return Err(error::Error::Malformed),
   };
-  let (data, rest) = if let Some(data) = store.get(key) {
-    match path {
-      Some(path) => jsonpath::Path::new(path).find(data),
-      None       => (Some(data), None),
-    }
-  } else {
-    (None, None)
+  let data = match store.get(key)? {
+    Some(data) => data,
+    None       => return Err(error::Error::NotFound),
   };
-  match rest {
-    Some(_) => Err(error::Error::NotFound),
-    None    => match data {
-      Some(data) => Ok(data),
-      None       => Err(error::Error::NotFound),
-    }
+  match path {
+    Some(path) => match jsonpath::Path::new(path).find(&data) {
+      (Some(data), None) => Ok(data.clone()),
+      _                  => Err(error::Error::NotFound),
+    },
+    None => Ok(data),
   }
 }
 
-fn write<'a>(store: &'a mut BTreeMap<String, serde_json::Value>, key: &str, path: Option<jsonpath::Path>, val: serde_json::Value) -> Result<serde_json::Value, error::Error> {
+fn write(store: &mut dyn storage::Store, key: &str, path: Option<jsonpath::Path>, val: serde_json::Value) -> Result<serde_json::Value, error::Error> {
   let path = match path {
     Some(path) => path,
     None       => {
-      store.insert(key.to_string(), val.clone());
+      store.set(key, val.clone())?;
       return Ok(val);
     },
   };
-  log::logln!("AFFIRMATIVE: HERE: 1. {} / {:?}", path, path.last());
-  let data = match store.get(key) {
-    Some(data) => data.clone(),
+  let data = match store.get(key)? {
+    Some(data) => data,
     None       => return Err(error::Error::NotFound),
   };
   let data = path.set_value(&data, &val)?;
-  println!(">>> UPDATE: {:?} -> {}", &path, data);
   // persist a copy in the store, return the updated value
-  store.insert(key.to_owned(), data.clone());
+  store.set(key, data.clone())?;
   Ok(data)
 }
 
-fn run_get(opts: &Options, store: &BTreeMap<String, serde_json::Value>, mut req: rpc::Request) -> Result<(), error::Error> {
-  let cmd = req.operation();
-  if opts.debug {
-    log::logln!(">>> {:?}", cmd);
-  }
-  if cmd.args().len() != 1 {
-    return Err(error::Error::Malformed);
-  }
-  let name = cmd.args()[0].to_string();
-  match fetch(store, &name) {
-    Ok(data) => req.send(rpc::Operation::new_found(&name, &data.to_string()))?,
-    Err(err) => match err {
-      error::Error::NotFound => req.send(rpc::Operation::new_none(&name))?,
-      _                      => return Err(err),
-    },
+/// Apply a write to a scratch copy of the key it affects and report the
+/// changes it would make, without modifying the store itself.
+fn preview(store: &dyn storage::Store, name: &str, val: serde_json::Value) -> Result<Vec<diff::Change>, error::Error> {
+  let path = jsonpath::Path::new(name);
+  let (key, path) = match path.next() {
+    (Some(key), path) => (key, path),
+    _                 => return Err(error::Error::Malformed),
+  };
+  let current = store.get(key)?;
+  let mut scratch = storage::Memory::new();
+  if let Some(data) = &current {
+    storage::Store::set(&mut scratch, key, data.clone())?;
   }
-  Ok(())
+  let update = write(&mut scratch, key, path.map(jsonpath::Path::new), val)?;
+  Ok(diff::diff(key, current.as_ref(), Some(&update)))
 }
 
-fn run_range(opts: &Options, store: &BTreeMap<String, serde_json::Value>, mut req: rpc::Request) -> Result<(), error::Error> {
+fn send_preview(req: &rpc::Request, name: &str, res: Result<Vec<diff::Change>, error::Error>) -> Result<(), error::Error> {
+  match res {
+    Ok(changes) => req.send(rpc::Operation::new_found(name, &serde_json::to_string(&changes)?)),
+    Err(err)    => req.send(rpc::Operation::new_error(&err.to_string())),
+  }
+}
+
+fn graft(store: &mut dyn storage::Store, key: &str, path: Option<jsonpath::Path>, val: serde_json::Value, parents: bool) -> Result<(), error::Error> {
+  let path = match path {
+    Some(path) => path,
+    None       => return store.set(key, val),
+  };
+  let data = match store.get(key)? {
+    Some(data) => data,
+    None if parents => serde_json::Value::Object(serde_json::Map::new()),
+    None => return Err(error::Error::NotFound),
+  };
+  let data = path.graft_value(&data, &val, parents)?;
+  store.set(key, data)
+}
+
+fn relocate(store: &mut dyn storage::Store, src: &str, dst: &str, parents: bool) -> Result<(), error::Error> {
+  let src = jsonpath::Path::new(src);
+  let (skey, spath) = match src.next() {
+    (Some(key), path) => (key, path),
+    _                 => return Err(error::Error::Malformed),
+  };
+  let orig = match store.get(skey)? {
+    Some(data) => data,
+    None       => return Err(error::Error::NotFound),
+  };
+  let val = match spath {
+    Some(spath) => {
+      let (update, removed) = jsonpath::Path::new(spath).delete_value(&orig)?;
+      store.set(skey, update)?;
+      removed
+    },
+    None => {
+      store.delete(skey)?;
+      orig.clone()
+    },
+  };
+  let dst = jsonpath::Path::new(dst);
+  let res = match dst.next() {
+    (Some(key), path) => graft(store, key, path.map(jsonpath::Path::new), val, parents),
+    _                 => Err(error::Error::Malformed),
+  };
+  if let Err(err) = res {
+    store.set(skey, orig)?; // restore the source; moves are all-or-nothing
+    return Err(err);
+  }
+  Ok(())
+}
+
+fn run_get(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
   let cmd = req.operation();
   if opts.debug {
     log::logln!(">>> {:?}", cmd);
   }
-  if cmd.args().len() != 1 {
+  if cmd.args().is_empty() {
+    return Err(error::Error::Malformed);
+  }
+  let name = cmd.args()[0].to_string();
+  let as_of = match cmd.option(1, rpc::OPTION_AS_OF).map(|e| { e.parse::<u128>() }) {
+    Some(Ok(time)) => Some(time),
+    Some(Err(_))   => return Err(error::Error::Malformed),
+    None           => None,
+  };
+  let res = if let Some(time) = as_of {
+    match state.fetch_as_of(&name, time) {
+      Ok(Err(reason)) => return req.send(rpc::Operation::new_error(&reason)),
+      Ok(Ok(data))    => Ok(data),
+      Err(err)        => Err(err),
+    }
+  } else if cmd.has_flag(1, rpc::FLAG_CI) {
+    state.fetch_ci(&name)
+  } else {
+    state.fetch(&name)
+  };
+  match res {
+    Ok(data) => req.send(rpc::Operation::new_found(&name, &data.to_string()))?,
+    Err(err) => match err {
+      error::Error::NotFound if cmd.has_flag(1, rpc::FLAG_REQUIRED) && as_of.is_none() => req.send(rpc::Operation::new_error(&state.explain(&name)?))?,
+      error::Error::NotFound => req.send(rpc::Operation::new_none(&name))?,
+      _                      => return Err(err),
+    },
+  }
+  Ok(())
+}
+
+/// Suggest the names most like one which was not found.
+fn run_suggest(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().is_empty() {
+    return Err(error::Error::Malformed);
+  }
+  let name = cmd.args()[0].to_string();
+  let names = state.suggest(&name)?;
+  req.send(rpc::Operation::new_found(&name, &serde_json::to_string(&names)?))
+}
+
+/// Fetch the values of several names in one request. Names which are not
+/// found are left out of the response.
+fn run_mget(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().is_empty() {
+    return Err(error::Error::Malformed);
+  }
+  let mut found = serde_json::Map::new();
+  for name in cmd.args() {
+    match state.fetch(name) {
+      Ok(data)                    => { found.insert(name.to_string(), data); },
+      Err(error::Error::NotFound) => {},
+      Err(err)                    => return Err(err),
+    }
+  }
+  req.send(rpc::Operation::new_found("", &serde_json::Value::Object(found).to_string()))
+}
+
+fn run_range(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().is_empty() {
     return Err(error::Error::Malformed);
   }
   let name = cmd.args()[0].to_string();
-  let data = match fetch(store, &name) {
+  let stream = cmd.has_flag(1, rpc::FLAG_STREAM);
+  let data = match state.fetch(&name) {
     Ok(data) => data,
     Err(err) => match err {
       error::Error::NotFound => return req.send(rpc::Operation::new_none(&name)),
       _                      => return Err(err),
     },
   };
-  let range = match data {
+  if stream {
+    return stream_range(&req, &name, &data);
+  }
+  let range = match &data {
     serde_json::Value::Array(v)  => Some((0..v.len()).map(|e| { serde_json::Value::Number(e.into()) }).collect::<Vec<serde_json::Value>>()),
     serde_json::Value::Object(v) => Some(v.keys().map(|e| { serde_json::Value::String(e.to_string()) }).collect::<Vec<serde_json::Value>>()),
     _                            => None,
@@ -193,7 +1212,33 @@ fn run_range(opts: &Options, store: &BTreeMap<String, serde_json::Value>, mut re
   Ok(())
 }
 
-fn run_set(opts: &Options, store: &mut BTreeMap<String, serde_json::Value>, mut req: rpc::Request) -> Result<(), error::Error> {
+/// Stream the indexes or keys of a value in chunks, so neither end need
+/// hold the whole range as a single response.
+fn stream_range(req: &rpc::Request, name: &str, data: &serde_json::Value) -> Result<(), error::Error> {
+  let range: Box<dyn Iterator<Item=serde_json::Value>> = match data {
+    serde_json::Value::Array(v)  => Box::new((0..v.len()).map(|e| { serde_json::Value::Number(e.into()) })),
+    serde_json::Value::Object(v) => Box::new(v.keys().map(|e| { serde_json::Value::String(e.to_string()) })),
+    _                            => return req.send(rpc::Operation::new_none(name)),
+  };
+  stream_chunks(req, name, range)
+}
+
+/// Send elements in chunks, as `more` responses, followed by `ok`.
+fn stream_chunks<I: Iterator<Item=serde_json::Value>>(req: &rpc::Request, name: &str, elements: I) -> Result<(), error::Error> {
+  let mut chunk = Vec::with_capacity(RANGE_CHUNK);
+  for e in elements {
+    chunk.push(e);
+    if chunk.len() >= RANGE_CHUNK {
+      req.send(rpc::Operation::new_more(name, &serde_json::Value::Array(chunk.split_off(0)).to_string()))?;
+    }
+  }
+  if !chunk.is_empty() {
+    req.send(rpc::Operation::new_more(name, &serde_json::Value::Array(chunk).to_string()))?;
+  }
+  req.send(rpc::Operation::new_ok())
+}
+
+fn run_find(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
   let cmd = req.operation();
   if opts.debug {
     log::logln!(">>> {:?}", cmd);
@@ -201,20 +1246,124 @@ fn run_set(opts: &Options, store: &mut BTreeMap<String, serde_json::Value>, mut
   if cmd.args().len() != 1 {
     return Err(error::Error::Malformed);
   }
+  let name = cmd.args()[0].to_string();
+  let pred: predicate::Predicate = match cmd.data() {
+    Some(data) => match data.parse() {
+      Ok(pred) => pred,
+      Err(err) => return req.send(rpc::Operation::new_error(&err.to_string())),
+    },
+    None => return Err(error::Error::Malformed),
+  };
+  let data = match state.fetch(&name) {
+    Ok(data) => data,
+    Err(err) => match err {
+      error::Error::NotFound => return req.send(rpc::Operation::new_none(&name)),
+      _                      => return Err(err),
+    },
+  };
+  let mut paths: Vec<serde_json::Value> = Vec::new();
+  jsonpath::walk(&data, &mut |path, value| {
+    if pred.matches(value) {
+      paths.push(serde_json::Value::String(jsonpath::join(&name, path)));
+    }
+  });
+  req.send(rpc::Operation::new_found(&name, &serde_json::Value::Array(paths).to_string()))
+}
+
+fn run_paths(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().is_empty() || cmd.args().len() > 2 {
+    return Err(error::Error::Malformed);
+  }
+  let depth = match cmd.args()[0].parse::<usize>() {
+    Ok(0)  => None, // zero depth means unlimited
+    Ok(v)  => Some(v),
+    Err(_) => return Err(error::Error::Malformed),
+  };
+  let mut paths: Vec<serde_json::Value> = Vec::new();
+  let name = if cmd.args().len() > 1 {
+    let name = cmd.args()[1].to_string();
+    let data = match state.fetch(&name) {
+      Ok(data) => data,
+      Err(err) => match err {
+        error::Error::NotFound => return req.send(rpc::Operation::new_none(&name)),
+        _                      => return Err(err),
+      },
+    };
+    for path in jsonpath::paths(&data, depth) {
+      paths.push(serde_json::Value::String(jsonpath::join(&name, &path)));
+    }
+    name
+  } else {
+    let depth = depth.map(|e| { e - 1 }); // keys themselves are the first level
+    for key in state.data.scan("")? {
+      if state.expiry.is_expired(&key) {
+        continue;
+      }
+      let data = match state.data.get(&key)? {
+        Some(data) => data,
+        None       => continue,
+      };
+      for path in jsonpath::paths(&data, depth) {
+        paths.push(serde_json::Value::String(jsonpath::join(&key, &path)));
+      }
+    }
+    String::new()
+  };
+  req.send(rpc::Operation::new_found(&name, &serde_json::Value::Array(paths).to_string()))
+}
+
+fn run_set(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().is_empty() {
+    return Err(error::Error::Malformed);
+  }
   let data = match cmd.data() {
-    Some(data) => serde_json::from_str(&data)?,
+    Some(data) => serde_json::from_str(data)?,
     None       => serde_json::Value::Null,
   };
-  let key = cmd.args()[0].clone();
+  let ttl = match cmd.option(1, rpc::OPTION_TTL) {
+    Some(ttl) => match ttl.parse::<u64>() {
+      Ok(ttl) => Some(time::Duration::from_millis(ttl)),
+      Err(_)  => return Err(error::Error::Malformed),
+    },
+    None => None,
+  };
+  let key = state.resolve(&cmd.args()[0]);
+  let (name, dry_run, nx) = (cmd.args()[0].clone(), cmd.has_flag(1, rpc::FLAG_DRYRUN), cmd.has_flag(1, rpc::FLAG_NX));
+  if refuse_protected(state, &req, &[&key])? {
+    return Ok(());
+  }
+  if dry_run {
+    return send_preview(&req, &name, preview(&state.data, &key, data));
+  }
+  let _guard = state.locks.lock(&[&key_of(&key)]);
+  state.reap(&key_of(&key))?;
+  if nx && state.exists(&key)? { // checked under the lock, so only the first writer stores its value
+    return req.send(rpc::Operation::new_exists(&name));
+  }
   let path = jsonpath::Path::new(&key);
   if opts.debug {
     log::logln!("... {:?}", path.next());
   }
   let res = match path.next() {
-    (Some(key), Some(path)) => write(store, key, Some(jsonpath::Path::new(path)), data),
-    (Some(key), None)       => write(store, key, None, data),
+    (Some(key), Some(path)) => state.materialize(key).and_then(|_| { write(&mut state.data.clone(), key, Some(jsonpath::Path::new(path)), data) }),
+    (Some(key), None)       => state.templated(key, data).and_then(|data| { write(&mut state.data.clone(), key, None, data) }),
     _                       => Err(error::Error::Malformed),
   };
+  if res.is_ok() {
+    state.stats.write(&key_of(&key), req.uid());
+    state.changed(&key_of(&key));
+    if ttl.is_some() || key_of(&key) == key { // replacing a whole key clears its TTL
+      state.expiry.set(&key_of(&key), ttl);
+    }
+  }
   match res {
     Ok(_)    =>  req.send(rpc::Operation::new_ok())?,
     Err(err) =>  req.send(rpc::Operation::new_error(&err.to_string()))?,
@@ -222,24 +1371,1466 @@ fn run_set(opts: &Options, store: &mut BTreeMap<String, serde_json::Value>, mut
   Ok(())
 }
 
-fn run_delete(opts: &Options, store: &mut BTreeMap<String, serde_json::Value>, mut req: rpc::Request) -> Result<(), error::Error> {
+/// Store every value of an object under the key or path it is a member
+/// for. The keys are locked together and the values are written to a
+/// scratch copy of them first, so that either every value is stored or, if
+/// any cannot be, none are. The keys are then written to the store one at a
+/// time; if the store fails part way, as a disk may, those already written
+/// are rolled back to what they were before the error is sent.
+fn run_mset(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
   let cmd = req.operation();
   if opts.debug {
     log::logln!(">>> {:?}", cmd);
   }
-  if cmd.args().len() != 1 {
+  let entries = match cmd.data().as_deref().map(serde_json::from_str) {
+    Some(Ok(serde_json::Value::Object(entries))) => entries,
+    Some(Err(err))                               => return Err(err.into()),
+    _                                            => return Err(error::Error::Malformed),
+  };
+  let entries: Vec<(String, serde_json::Value)> = entries.into_iter().map(|(k, v)| { (state.resolve(&k), v) }).collect();
+  let names: Vec<&str> = entries.iter().map(|(e, _)| { e.as_str() }).collect();
+  if refuse_protected(state, &req, &names)? {
+    return Ok(());
+  }
+  let keys: BTreeSet<String> = names.iter().map(|e| { key_of(e) }).collect();
+  let _guard = state.locks.lock(&keys.iter().map(|e| { e.as_str() }).collect::<Vec<&str>>());
+  let mut scratch = storage::Memory::new();
+  let mut before: BTreeMap<&str, Option<serde_json::Value>> = BTreeMap::new();
+  for key in &keys {
+    state.reap(key)?;
+    let data = state.data.get(key)?;
+    if let Some(data) = &data {
+      storage::Store::set(&mut scratch, key, data.clone())?;
+    }
+    before.insert(key, data);
+  }
+  let mut whole = BTreeSet::new();
+  for (name, value) in entries.iter() {
+    let res = match jsonpath::Path::new(name).next() {
+      (Some(key), Some(path)) => {
+        if storage::Store::get(&scratch, key)?.is_none() {
+          if let Some(initial) = state.initial(key)? {
+            storage::Store::set(&mut scratch, key, initial)?;
+          }
+        }
+        write(&mut scratch, key, Some(jsonpath::Path::new(path)), value.clone())
+      },
+      (Some(key), None) => {
+        whole.insert(key.to_string());
+        state.templated(key, value.clone()).and_then(|data| { write(&mut scratch, key, None, data) })
+      },
+      _ => Err(error::Error::Malformed),
+    };
+    if let Err(err) = res {
+      return req.send(rpc::Operation::new_error(&format!("{}: {}", name, err))); // nothing has been stored
+    }
+  }
+  let mut data = state.data.clone();
+  let mut written: Vec<&str> = Vec::new();
+  for key in &keys {
+    if let Some(value) = storage::Store::get(&scratch, key)? {
+      if let Err(err) = data.set(key, value) {
+        roll_back(&mut data, &before, &written);
+        return req.send(rpc::Operation::new_error(&format!("{}: {}", key, err)));
+      }
+      written.push(key);
+    }
+  }
+  for key in &keys {
+    state.stats.write(key, req.uid());
+    state.changed(key);
+    if whole.contains(key) { // replacing a whole key clears its TTL
+      state.expiry.set(key, None);
+    }
+  }
+  req.send(rpc::Operation::new_ok())
+}
+
+/// Restore keys which were written to what they were before, deleting those
+/// which didn't exist. Failures are logged; there is nothing more to do.
+fn roll_back(data: &mut storage::Shared, before: &BTreeMap<&str, Option<serde_json::Value>>, written: &[&str]) {
+  for key in written {
+    let res = match before.get(key).cloned().flatten() {
+      Some(value) => data.set(key, value),
+      None        => data.delete(key).map(|_| {}),
+    };
+    if let Err(err) = res {
+      log::logln!("{}", format!("* * * Could not roll back {}: {}", key, err).yellow().bold());
+    }
+  }
+}
+
+/// Store a value under a key or path, producing the value it replaced. The
+/// previous value is read and replaced under the same lock, so no write can
+/// come between them.
+fn run_swap(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().is_empty() {
     return Err(error::Error::Malformed);
   }
-  store.remove(&cmd.args()[0]);
-  req.send(rpc::Operation::new_ok())?;
-  Ok(())
+  let data: serde_json::Value = match cmd.data() {
+    Some(data) => serde_json::from_str(data)?,
+    None       => return Err(error::Error::Malformed),
+  };
+  let name = cmd.args()[0].clone();
+  let key = state.resolve(&name);
+  if refuse_protected(state, &req, &[&key])? {
+    return Ok(());
+  }
+  let _guard = state.locks.lock(&[&key_of(&key)]);
+  state.reap(&key_of(&key))?;
+  let res = match jsonpath::Path::new(&key).next() {
+    (Some(key), path) => state.materialize(key).and_then(|_| {
+      let previous = match fetch(&state.data, &jsonpath::join(key, path.unwrap_or(""))) {
+        Ok(previous)                => Some(previous),
+        Err(error::Error::NotFound) => None,
+        Err(err)                    => return Err(err),
+      };
+      write(&mut state.data.clone(), key, path.map(jsonpath::Path::new), data)?;
+      Ok(previous)
+    }),
+    _ => Err(error::Error::Malformed),
+  };
+  let previous = match res {
+    Ok(previous) => previous,
+    Err(err)     => return req.send(rpc::Operation::new_error(&err.to_string())),
+  };
+  state.stats.write(&key_of(&key), req.uid());
+  state.changed(&key_of(&key));
+  if key_of(&key) == key { // replacing a whole key clears its TTL
+    state.expiry.set(&key, None);
+  }
+  match previous {
+    Some(previous) => req.send(rpc::Operation::new_found(&name, &previous.to_string())),
+    None           => req.send(rpc::Operation::new_none(&name)),
+  }
 }
 
-fn run_stop(opts: &Options,  mut req: rpc::Request) -> Result<(), error::Error> {
+/// Store a value under a key or path only if its current value equals the
+/// expected one. The current value is compared and replaced under the same
+/// lock, so of any number of clients expecting the same value, only one
+/// stores theirs.
+fn run_cas(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
   let cmd = req.operation();
   if opts.debug {
     log::logln!(">>> {:?}", cmd);
   }
-  req.send(rpc::Operation::new_ok())?;
-  Ok(())
+  if cmd.args().len() < 2 {
+    return Err(error::Error::Malformed);
+  }
+  let expected: serde_json::Value = serde_json::from_str(&cmd.args()[1])?;
+  let data: serde_json::Value = match cmd.data() {
+    Some(data) => serde_json::from_str(data)?,
+    None       => return Err(error::Error::Malformed),
+  };
+  let ttl = match cmd.option(2, rpc::OPTION_TTL) {
+    Some(ttl) => match ttl.parse::<u64>() {
+      Ok(ttl) => Some(time::Duration::from_millis(ttl)),
+      Err(_)  => return Err(error::Error::Malformed),
+    },
+    None => None,
+  };
+  let name = cmd.args()[0].clone();
+  let key = state.resolve(&name);
+  if refuse_protected(state, &req, &[&key])? {
+    return Ok(());
+  }
+  let _guard = state.locks.lock(&[&key_of(&key)]);
+  state.reap(&key_of(&key))?;
+  let res = match jsonpath::Path::new(&key).next() {
+    (Some(key), path) => state.materialize(key).and_then(|_| {
+      let current = fetch(&state.data, &jsonpath::join(key, path.unwrap_or("")))?;
+      if current != expected {
+        return Ok(Some(current));
+      }
+      write(&mut state.data.clone(), key, path.map(jsonpath::Path::new), data)?;
+      Ok(None)
+    }),
+    _ => Err(error::Error::Malformed),
+  };
+  match res {
+    Ok(Some(current))           => return req.send(rpc::Operation::new_mismatch(&name, &current.to_string())),
+    Ok(None)                    => {},
+    Err(error::Error::NotFound) => return req.send(rpc::Operation::new_none(&name)),
+    Err(err)                    => return req.send(rpc::Operation::new_error(&err.to_string())),
+  }
+  state.stats.write(&key_of(&key), req.uid());
+  state.changed(&key_of(&key));
+  if ttl.is_some() || key_of(&key) == key { // replacing a whole key clears its TTL
+    state.expiry.set(&key_of(&key), ttl);
+  }
+  req.send(rpc::Operation::new_ok())
+}
+
+/// Add a delta to the number under a key or path, producing the new value.
+/// The value is read and written under the same lock, so concurrent
+/// increments are never lost.
+fn run_incr(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().len() < 2 {
+    return Err(error::Error::Malformed);
+  }
+  let delta = match cmd.args()[1].parse::<serde_json::Number>() {
+    Ok(delta) => delta,
+    Err(_)    => return Err(error::Error::Malformed),
+  };
+  let name = cmd.args()[0].clone();
+  let key = state.resolve(&name);
+  if refuse_protected(state, &req, &[&key])? {
+    return Ok(());
+  }
+  let _guard = state.locks.lock(&[&key_of(&key)]);
+  state.reap(&key_of(&key))?;
+  let res = match jsonpath::Path::new(&key).next() {
+    (Some(key), path) => state.materialize(key).and_then(|_| {
+      let current = match fetch(&state.data, &jsonpath::join(key, path.unwrap_or(""))) {
+        Ok(current)                 => Some(current),
+        Err(error::Error::NotFound) => None,
+        Err(err)                    => return Err(err),
+      };
+      let value = match jsonpath::add(current.as_ref(), &delta) {
+        Ok(value) => value,
+        Err(err)  => return Err(error::Error::InvalidValue(format!("{}: {}", name, err))),
+      };
+      write(&mut state.data.clone(), key, path.map(jsonpath::Path::new), value.clone())?;
+      Ok(value)
+    }),
+    _ => Err(error::Error::Malformed),
+  };
+  let value = match res {
+    Ok(value) => value,
+    Err(err)  => return req.send(rpc::Operation::new_error(&err.to_string())),
+  };
+  state.stats.write(&key_of(&key), req.uid());
+  state.changed(&key_of(&key));
+  req.send(rpc::Operation::new_found(&name, &value.to_string()))
+}
+
+/// Append a value to the array under a key or path, creating the array,
+/// and any objects leading to it, if there is none. Produces the length of
+/// the array.
+fn run_push(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().is_empty() {
+    return Err(error::Error::Malformed);
+  }
+  let data: serde_json::Value = match cmd.data() {
+    Some(data) => serde_json::from_str(data)?,
+    None       => return Err(error::Error::Malformed),
+  };
+  let name = cmd.args()[0].clone();
+  let key = state.resolve(&name);
+  if refuse_protected(state, &req, &[&key])? {
+    return Ok(());
+  }
+  let _guard = state.locks.lock(&[&key_of(&key)]);
+  state.reap(&key_of(&key))?;
+  let res = match jsonpath::Path::new(&key).next() {
+    (Some(key), path) => state.materialize(key).and_then(|_| {
+      let array = match fetch(&state.data, &jsonpath::join(key, path.unwrap_or(""))) {
+        Ok(serde_json::Value::Array(mut array)) => { array.push(data); array },
+        Ok(value)                               => return Err(error::Error::InvalidValue(format!("{}: Cannot push to {}, which is not an array", name, jsonpath::type_name(&value)))),
+        Err(error::Error::NotFound)             => vec![data],
+        Err(err)                                => return Err(err),
+      };
+      let len = array.len();
+      graft(&mut state.data.clone(), key, path.map(jsonpath::Path::new), serde_json::Value::Array(array), true)?;
+      Ok(len)
+    }),
+    _ => Err(error::Error::Malformed),
+  };
+  let len = match res {
+    Ok(len)  => len,
+    Err(err) => return req.send(rpc::Operation::new_error(&err.to_string())),
+  };
+  state.stats.write(&key_of(&key), req.uid());
+  state.changed(&key_of(&key));
+  req.send(rpc::Operation::new_pushed(len))
+}
+
+/// Remove the last element of the array under a key or path, or the first,
+/// and produce it. Of any number of clients popping the same array, each
+/// element is received by exactly one.
+fn run_pop(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().is_empty() {
+    return Err(error::Error::Malformed);
+  }
+  let name = cmd.args()[0].clone();
+  let front = cmd.has_flag(1, rpc::FLAG_FRONT);
+  let key = state.resolve(&name);
+  if refuse_protected(state, &req, &[&key])? {
+    return Ok(());
+  }
+  let _guard = state.locks.lock(&[&key_of(&key)]);
+  state.reap(&key_of(&key))?;
+  let res = match jsonpath::Path::new(&key).next() {
+    (Some(key), path) => state.materialize(key).and_then(|_| {
+      let mut array = match fetch(&state.data, &jsonpath::join(key, path.unwrap_or(""))) {
+        Ok(serde_json::Value::Array(array)) => array,
+        Ok(value)                           => return Err(error::Error::InvalidValue(format!("{}: Cannot pop from {}, which is not an array", name, jsonpath::type_name(&value)))),
+        Err(error::Error::NotFound)         => return Ok(None),
+        Err(err)                            => return Err(err),
+      };
+      let value = match front {
+        true if !array.is_empty() => Some(array.remove(0)),
+        true                      => None,
+        false                     => array.pop(),
+      };
+      if value.is_some() {
+        write(&mut state.data.clone(), key, path.map(jsonpath::Path::new), serde_json::Value::Array(array))?;
+      }
+      Ok(value)
+    }),
+    _ => Err(error::Error::Malformed),
+  };
+  let value = match res {
+    Ok(Some(value)) => value,
+    Ok(None)        => return req.send(rpc::Operation::new_none(&name)),
+    Err(err)        => return req.send(rpc::Operation::new_error(&err.to_string())),
+  };
+  state.stats.write(&key_of(&key), req.uid());
+  state.changed(&key_of(&key));
+  req.send(rpc::Operation::new_found(&name, &value.to_string()))
+}
+
+/// Apply a JSON merge patch to the value under a key or path, creating it,
+/// and any objects leading to it, if there is none. Produces the patched
+/// value.
+fn run_merge(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().is_empty() {
+    return Err(error::Error::Malformed);
+  }
+  let patch: serde_json::Value = match cmd.data() {
+    Some(data) => serde_json::from_str(data)?,
+    None       => return Err(error::Error::Malformed),
+  };
+  let name = cmd.args()[0].clone();
+  let key = state.resolve(&name);
+  if refuse_protected(state, &req, &[&key])? {
+    return Ok(());
+  }
+  let _guard = state.locks.lock(&[&key_of(&key)]);
+  state.reap(&key_of(&key))?;
+  let res = match jsonpath::Path::new(&key).next() {
+    (Some(key), path) => state.materialize(key).and_then(|_| {
+      let current = match fetch(&state.data, &jsonpath::join(key, path.unwrap_or(""))) {
+        Ok(current)                 => current,
+        Err(error::Error::NotFound) => serde_json::Value::Null,
+        Err(err)                    => return Err(err),
+      };
+      let value = jsonpath::merge_patch(&current, &patch);
+      graft(&mut state.data.clone(), key, path.map(jsonpath::Path::new), value.clone(), true)?;
+      Ok(value)
+    }),
+    _ => Err(error::Error::Malformed),
+  };
+  let value = match res {
+    Ok(value) => value,
+    Err(err)  => return req.send(rpc::Operation::new_error(&err.to_string())),
+  };
+  state.stats.write(&key_of(&key), req.uid());
+  state.changed(&key_of(&key));
+  req.send(rpc::Operation::new_found(&name, &value.to_string()))
+}
+
+/// Apply a JSON Patch to the value under a key or path, producing the
+/// patched value. Either every operation is applied or, if one fails, none
+/// are, and the failure names the operation.
+fn run_patch(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().is_empty() {
+    return Err(error::Error::Malformed);
+  }
+  let ops = match cmd.data() {
+    Some(data) => patch::parse(&serde_json::from_str(data)?),
+    None       => return Err(error::Error::Malformed),
+  };
+  let ops = match ops {
+    Ok(ops)  => ops,
+    Err(err) => return req.send(rpc::Operation::new_error(&format!("Invalid patch: {}", err))),
+  };
+  let name = cmd.args()[0].clone();
+  let key = state.resolve(&name);
+  if refuse_protected(state, &req, &[&key])? {
+    return Ok(());
+  }
+  let _guard = state.locks.lock(&[&key_of(&key)]);
+  state.reap(&key_of(&key))?;
+  let res = match jsonpath::Path::new(&key).next() {
+    (Some(key), path) => state.materialize(key).and_then(|_| {
+      let current = fetch(&state.data, &jsonpath::join(key, path.unwrap_or("")))?;
+      let value = match patch::apply(&current, &ops) {
+        Ok(value) => value,
+        Err(err)  => return Err(error::Error::InvalidValue(format!("{}: {}", name, err))),
+      };
+      write(&mut state.data.clone(), key, path.map(jsonpath::Path::new), value.clone())?;
+      Ok(value)
+    }),
+    _ => Err(error::Error::Malformed),
+  };
+  let value = match res {
+    Ok(value)                   => value,
+    Err(error::Error::NotFound) => return req.send(rpc::Operation::new_none(&name)),
+    Err(err)                    => return req.send(rpc::Operation::new_error(&err.to_string())),
+  };
+  state.stats.write(&key_of(&key), req.uid());
+  state.changed(&key_of(&key));
+  req.send(rpc::Operation::new_found(&name, &value.to_string()))
+}
+
+/// Store a value under its content address, producing the address. A value
+/// which is already stored is not written again.
+fn run_put(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  let data: serde_json::Value = match cmd.data() {
+    Some(data) => serde_json::from_str(data)?,
+    None       => return Err(error::Error::Malformed),
+  };
+  let ttl = match cmd.option(0, rpc::OPTION_TTL) {
+    Some(ttl) => match ttl.parse::<u64>() {
+      Ok(ttl) => Some(time::Duration::from_millis(ttl)),
+      Err(_)  => return Err(error::Error::Malformed),
+    },
+    None => None,
+  };
+  let key = cas::address(&data);
+  if refuse_protected(state, &req, &[&key])? {
+    return Ok(());
+  }
+  let _guard = state.locks.lock(&[&key]);
+  state.reap(&key)?;
+  let mut store = state.data.clone();
+  if store.get(&key)?.is_none() {
+    store.set(&key, data)?;
+    state.stats.write(&key, req.uid());
+    state.changed(&key);
+  }
+  state.expiry.set(&key, ttl);
+  req.send(rpc::Operation::new_stored(&key))
+}
+
+/// Append a timestamped entry to the log stored under a key, creating it if
+/// need be, and drop its oldest entries beyond the cap.
+fn run_append(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().is_empty() {
+    return Err(error::Error::Malformed);
+  }
+  let entry: serde_json::Value = match cmd.data() {
+    Some(data) => serde_json::from_str(data)?,
+    None       => return Err(error::Error::Malformed),
+  };
+  let cap = match cmd.option(1, rpc::OPTION_CAP) {
+    Some(cap) => match cap.parse::<usize>() {
+      Ok(cap) if cap > 0 => cap,
+      _ => return Err(error::Error::Malformed),
+    },
+    None => eventlog::DEFAULT_CAP,
+  };
+  let key = state.resolve(&cmd.args()[0]);
+  if key_of(&key) != key {
+    return req.send(rpc::Operation::new_error(&format!("Logs are stored in whole keys: {}", key)));
+  }
+  if refuse_protected(state, &req, &[&key])? {
+    return Ok(());
+  }
+  let _guard = state.locks.lock(&[&key]);
+  state.reap(&key)?;
+  let mut data = state.data.clone();
+  let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH)?;
+  let (log, seq) = match eventlog::append(data.get(&key)?, entry, now.as_millis(), cap) {
+    Ok(res) => res,
+    Err(_)  => return req.send(rpc::Operation::new_error(&format!("Not a log: {}", key))),
+  };
+  data.set(&key, log)?;
+  state.stats.write(&key, req.uid());
+  state.changed(&key);
+  req.send(rpc::Operation::new_appended(seq))
+}
+
+fn run_merge_keys(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().len() < 3 {
+    return Err(error::Error::Malformed);
+  }
+  let (base, over, dest) = (cmd.args()[0].clone(), cmd.args()[1].clone(), cmd.args()[2].clone());
+  let dry_run = cmd.has_flag(3, rpc::FLAG_DRYRUN);
+  let merged = {
+    let base = match state.fetch(&base) {
+      Ok(data) => data,
+      Err(error::Error::NotFound) => return req.send(rpc::Operation::new_none(&base)),
+      Err(err) => return Err(err),
+    };
+    let over = match state.fetch(&over) {
+      Ok(data) => data,
+      Err(error::Error::NotFound) => return req.send(rpc::Operation::new_none(&over)),
+      Err(err) => return Err(err),
+    };
+    jsonpath::merge(&base, &over)
+  };
+  if refuse_protected(state, &req, &[&state.resolve(&dest)])? {
+    return Ok(());
+  }
+  if dry_run {
+    return send_preview(&req, &dest, preview(&state.data, &state.resolve(&dest), merged));
+  }
+  let dest = state.resolve(&dest);
+  let _guard = state.locks.lock(&[&key_of(&dest)]);
+  state.reap(&key_of(&dest))?;
+  let path = jsonpath::Path::new(&dest);
+  let res = match path.next() {
+    (Some(key), Some(path)) => state.materialize(key).and_then(|_| { write(&mut state.data.clone(), key, Some(jsonpath::Path::new(path)), merged) }),
+    (Some(key), None)       => state.templated(key, merged).and_then(|data| { write(&mut state.data.clone(), key, None, data) }),
+    _                       => Err(error::Error::Malformed),
+  };
+  if res.is_ok() {
+    state.stats.write(&key_of(&dest), req.uid());
+    state.changed(&key_of(&dest));
+    if key_of(&dest) == dest { // replacing a whole key clears its TTL, as set does
+      state.expiry.set(&dest, None);
+    }
+  }
+  match res {
+    Ok(_)    =>  req.send(rpc::Operation::new_ok())?,
+    Err(err) =>  req.send(rpc::Operation::new_error(&err.to_string()))?,
+  }
+  Ok(())
+}
+
+fn run_move(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().len() < 2 || cmd.args().len() > 3 {
+    return Err(error::Error::Malformed);
+  }
+  let parents = cmd.has_flag(2, rpc::FLAG_PARENTS);
+  let (src, dst) = (state.resolve(&cmd.args()[0]), state.resolve(&cmd.args()[1]));
+  if refuse_protected(state, &req, &[&src, &dst])? {
+    return Ok(());
+  }
+  let (skey, dkey) = (key_of(&src), key_of(&dst));
+  let _guard = state.locks.lock(&[&skey, &dkey]);
+  state.reap(&skey)?;
+  state.reap(&dkey)?;
+  match relocate(&mut state.data.clone(), &src, &dst, parents) {
+    Ok(_)    => {
+      if skey == src { // a whole key was moved, its TTL goes with it
+        let ttl = state.expiry.remaining(&skey);
+        state.expiry.set(&skey, None);
+        if dkey == dst {
+          state.expiry.set(&dkey, ttl);
+        }
+      }
+      state.stats.write(&skey, req.uid());
+      state.stats.write(&dkey, req.uid());
+      state.changed(&skey);
+      if dkey != skey {
+        state.changed(&dkey);
+      }
+      req.send(rpc::Operation::new_ok())?
+    },
+    Err(err) =>  req.send(rpc::Operation::new_error(&err.to_string()))?,
+  }
+  Ok(())
+}
+
+fn run_alias(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  match cmd.args().len() {
+    0 => { // list aliases
+      let aliases: serde_json::Map<String, serde_json::Value> = state.aliases().iter().map(|(k, v)| { (k.to_owned(), serde_json::Value::String(v.to_owned())) }).collect();
+      req.send(rpc::Operation::new_found("", &serde_json::Value::Object(aliases).to_string()))
+    },
+    2 => { // create or re-point an alias
+      let name = cmd.args()[0].clone();
+      let target = state.resolve(&cmd.args()[1]); // aliases to aliases refer to the underlying key
+      if name.contains(jsonpath::SEP) || name == target {
+        return req.send(rpc::Operation::new_error(&format!("Invalid alias: {} -> {}", name, target)));
+      }
+      state.aliases_mut().insert(name, target);
+      req.send(rpc::Operation::new_ok())
+    },
+    _ => Err(error::Error::Malformed),
+  }
+}
+
+fn run_unalias(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().len() != 1 {
+    return Err(error::Error::Malformed);
+  }
+  let name = cmd.args()[0].clone();
+  match state.aliases_mut().remove(&name) {
+    Some(_) => req.send(rpc::Operation::new_ok()),
+    None    => req.send(rpc::Operation::new_none(&name)),
+  }
+}
+
+/// Protect keys or paths from being written or deleted, or list those which
+/// are protected.
+fn run_protect(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().is_empty() {
+    let protected: Vec<serde_json::Value> = state.protected().iter().map(|e| { serde_json::Value::String(e.to_owned()) }).collect();
+    return req.send(rpc::Operation::new_found("", &serde_json::Value::Array(protected).to_string()));
+  }
+  let names: Vec<String> = cmd.args().iter().map(|e| { state.resolve(e) }).collect();
+  state.protected_mut().extend(names);
+  req.send(rpc::Operation::new_ok())
+}
+
+fn run_unprotect(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().is_empty() {
+    return Err(error::Error::Malformed);
+  }
+  let names = cmd.args().to_vec();
+  let removed: Vec<&str> = names.iter().filter(|e| { state.protected_mut().remove(&state.resolve(e)) }).map(|e| { e.as_str() }).collect();
+  req.send(rpc::Operation::new_deleted(&removed))
+}
+
+/// Register the template for keys matching a glob, replacing any the glob
+/// had.
+fn run_default(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().len() != 1 {
+    return Err(error::Error::Malformed);
+  }
+  let glob = cmd.args()[0].clone();
+  let template: serde_json::Value = match cmd.data() {
+    Some(data) => serde_json::from_str(data)?,
+    None       => return Err(error::Error::Malformed),
+  };
+  let pattern = match pattern::Pattern::glob(&glob) {
+    Ok(pattern) => pattern,
+    Err(err)    => return req.send(rpc::Operation::new_error(&err)),
+  };
+  state.defaults_mut().insert(glob, (pattern, template));
+  req.send(rpc::Operation::new_ok())
+}
+
+fn run_undefault(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().len() != 1 {
+    return Err(error::Error::Malformed);
+  }
+  let glob = cmd.args()[0].clone();
+  match state.defaults_mut().remove(&glob) {
+    Some(_) => req.send(rpc::Operation::new_ok()),
+    None    => req.send(rpc::Operation::new_none(&glob)),
+  }
+}
+
+fn run_defaults(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  let defaults: serde_json::Map<String, serde_json::Value> = state.defaults().iter().map(|(k, (_, v))| { (k.to_owned(), v.clone()) }).collect();
+  req.send(rpc::Operation::new_found("", &serde_json::Value::Object(defaults).to_string()))
+}
+
+fn run_delete(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().is_empty() {
+    return Err(error::Error::Malformed);
+  }
+  let names = cmd.args().to_vec();
+  let resolved: Vec<String> = names.iter().map(|e| { state.resolve(e) }).collect();
+  let keys: Vec<&str> = resolved.iter().map(|e| { e.as_str() }).collect();
+  if refuse_protected(state, &req, &keys)? {
+    return Ok(());
+  }
+  let _guard = state.locks.lock(&keys);
+  let mut data = state.data.clone();
+  let mut deleted: Vec<&str> = Vec::new();
+  for (name, key) in names.iter().zip(keys) {
+    state.expiry.set(key, None);
+    if data.delete(key)?.is_some() {
+      state.stats.remove(key);
+      state.changed(key);
+      deleted.push(name); // as the client named it
+    }
+  }
+  req.send(rpc::Operation::new_deleted(&deleted))?;
+  Ok(())
+}
+
+/// Delete a key or path, producing the value it had. The value is read and
+/// removed under the same lock, so no two clients can take the same value.
+fn run_take(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().is_empty() {
+    return Err(error::Error::Malformed);
+  }
+  let name = cmd.args()[0].clone();
+  let key = state.resolve(&name);
+  if refuse_protected(state, &req, &[&key])? {
+    return Ok(());
+  }
+  let _guard = state.locks.lock(&[&key_of(&key)]);
+  state.reap(&key_of(&key))?;
+  let mut data = state.data.clone();
+  let taken = match jsonpath::Path::new(&key).next() {
+    (Some(key), None) => data.delete(key)?,
+    (Some(key), Some(path)) => match data.get(key)? {
+      Some(orig) => match jsonpath::Path::new(path).delete_value(&orig) {
+        Ok((update, removed)) => {
+          data.set(key, update)?;
+          Some(removed)
+        },
+        Err(error::Error::NotFound) => None,
+        Err(err) => return req.send(rpc::Operation::new_error(&err.to_string())),
+      },
+      None => None,
+    },
+    _ => return req.send(rpc::Operation::new_error(&error::Error::Malformed.to_string())),
+  };
+  let taken = match taken {
+    Some(taken) => taken,
+    None        => return req.send(rpc::Operation::new_none(&name)),
+  };
+  if key_of(&key) == key {
+    state.expiry.set(&key, None);
+    state.stats.remove(&key);
+  } else {
+    state.stats.write(&key_of(&key), req.uid());
+  }
+  state.changed(&key_of(&key));
+  req.send(rpc::Operation::new_found(&name, &taken.to_string()))
+}
+
+/// Delete every key matching a pattern, producing the keys which were
+/// deleted.
+fn run_delete_keys(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  let pattern = match key_pattern(state, cmd) {
+    Ok(pattern) => pattern,
+    Err(err)    => return req.send(rpc::Operation::new_error(&err)),
+  };
+  let keys = state.match_keys(&pattern)?;
+  let keys: Vec<&str> = keys.iter().map(|e| { e.as_str() }).collect();
+  if refuse_protected(state, &req, &keys)? {
+    return Ok(());
+  }
+  let _guard = state.locks.lock(&keys);
+  let mut data = state.data.clone();
+  let mut deleted: Vec<&str> = Vec::new();
+  for key in keys {
+    state.expiry.set(key, None);
+    if data.delete(key)?.is_some() {
+      state.stats.remove(key);
+      state.changed(key);
+      deleted.push(key);
+    }
+  }
+  req.send(rpc::Operation::new_deleted(&deleted))?;
+  Ok(())
+}
+
+/// Delete every key, producing how many there were, not counting those
+/// which had expired. Nothing is deleted if any key is protected. Unlike
+/// other deletes, this is not followed by the check for finalization, so
+/// the service keeps running.
+fn run_clear(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  let keys = state.data.scan("")?;
+  let keys: Vec<&str> = keys.iter().map(|e| { e.as_str() }).collect();
+  if refuse_protected(state, &req, &keys)? {
+    return Ok(());
+  }
+  let _guard = state.locks.lock(&keys);
+  let mut data = state.data.clone();
+  let mut cleared = 0;
+  for key in keys {
+    let live = !state.expiry.is_expired(key);
+    state.expiry.set(key, None);
+    if data.delete(key)?.is_some() {
+      state.stats.remove(key);
+      state.changed(key);
+      cleared += live as usize;
+    }
+  }
+  req.send(rpc::Operation::new_cleared(cleared))
+}
+
+/// List every key matching a pattern or, if requested, produce an object
+/// which maps each of them to its value. Only matches are sent back.
+fn run_keys(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  let pattern = match key_pattern(state, cmd) {
+    Ok(pattern) => pattern,
+    Err(err)    => return req.send(rpc::Operation::new_error(&err)),
+  };
+  let filter = match cmd.option(0, rpc::OPTION_WHERE).map(|e| { e.parse::<predicate::Filter>() }) {
+    Some(Ok(filter)) => Some(filter),
+    Some(Err(err))   => return req.send(rpc::Operation::new_error(&err.to_string())),
+    None             => None,
+  };
+  let (values, stream) = (cmd.has_flag(0, rpc::FLAG_VALUES), cmd.has_flag(0, rpc::FLAG_STREAM));
+  let keys = state.match_keys(&pattern)?;
+  let res = if values || filter.is_some() {
+    // filter in a single pass over the matching keys, so values are only
+    // read once and never sent back unless they were asked for
+    let mut matched = serde_json::Map::new();
+    for key in keys {
+      if let Some(value) = state.data.get(&key)? { // it may have been deleted since
+        if filter.as_ref().is_none_or(|e| { e.matches(&key, &value) }) {
+          if values {
+            state.stats.read(&key, true);
+          }
+          matched.insert(key, value);
+        }
+      }
+    }
+    match values {
+      true  => serde_json::Value::Object(matched),
+      false => serde_json::Value::Array(matched.into_iter().map(|(k, _)| { serde_json::Value::String(k) }).collect()),
+    }
+  } else {
+    serde_json::Value::Array(keys.into_iter().map(serde_json::Value::String).collect())
+  };
+  match (res, stream) {
+    (serde_json::Value::Array(keys), true) => stream_chunks(&req, "", keys.into_iter()),
+    (res, _)                               => req.send(rpc::Operation::new_found("", &res.to_string())),
+  }
+}
+
+/// Count the keys matching a pattern, without sending them.
+fn run_count(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  let pattern = match key_pattern(state, cmd) {
+    Ok(pattern) => pattern,
+    Err(err)    => return req.send(rpc::Operation::new_error(&err)),
+  };
+  let count = state.match_keys(&pattern)?.len();
+  req.send(rpc::Operation::new_found("", &count.to_string()))
+}
+
+/// The pattern a request matches keys against, which is its data, read as
+/// described by `Pattern::keys`, and case-insensitive if it is flagged so.
+/// A prefix or glob which begins with an alias is resolved, as names are,
+/// so listing, counting, and deleting keys all select the same ones.
+fn key_pattern(state: &State, cmd: &rpc::Operation) -> Result<pattern::Pattern, String> {
+  let (text, regex) = (cmd.data().as_deref().unwrap_or(""), cmd.has_flag(0, rpc::FLAG_REGEX));
+  let text = match regex {
+    true  => text.to_string(),
+    false => state.resolve(text),
+  };
+  let pattern = pattern::Pattern::keys(&text, regex, cmd.has_flag(0, rpc::FLAG_GLOB))?;
+  match cmd.has_flag(0, rpc::FLAG_CI) {
+    true  => pattern.ci(),
+    false => Ok(pattern),
+  }
+}
+
+/// Subscribe a client to changes to the keys matching any of a set of globs.
+/// Events are sent on the same channel as the acknowledgement, until the
+/// client goes away.
+fn run_watch(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  let (options, globs): (&[String], Vec<&String>) = match cmd.split_rest() {
+    Some((options, globs)) => (options, globs.iter().collect()),
+    None => { // a client which runs options and globs together
+      let options = [format!("{}=", rpc::OPTION_RESUME), format!("{}=", rpc::OPTION_INITIAL)];
+      (cmd.args(), cmd.args().iter().filter(|e| { !options.iter().any(|o| { e.starts_with(o) }) }).collect())
+    },
+  };
+  let option = |name: &str| { options.iter().find_map(|e| { e.strip_prefix(name)?.strip_prefix('=') }) };
+  let resume = match option(rpc::OPTION_RESUME).map(|e| { e.parse::<u64>() }) {
+    Some(Ok(resume)) => Some(resume),
+    Some(Err(_))     => return Err(error::Error::Malformed),
+    None             => None,
+  };
+  let initial = option(rpc::OPTION_INITIAL) == Some("true");
+  if initial && resume.is_some() {
+    return req.send(rpc::Operation::new_error("Cannot both resume and receive initial values"));
+  }
+  if globs.is_empty() {
+    return Err(error::Error::Malformed);
+  }
+  let mut patterns: Vec<pattern::Pattern> = Vec::new();
+  for glob in globs {
+    match pattern::Pattern::glob(&state.resolve(glob)) {
+      Ok(pattern) => patterns.push(pattern),
+      Err(err)    => return req.send(rpc::Operation::new_error(&err)),
+    }
+  }
+  let watched = patterns.clone();
+  let current = || {
+    let mut keys: BTreeSet<String> = BTreeSet::new();
+    for pattern in &watched {
+      keys.extend(state.match_keys(pattern).map_err(|e| { e.to_string() })?);
+    }
+    let mut values = Vec::new();
+    for key in keys {
+      if let Some(value) = state.data.get(&key).map_err(|e| { e.to_string() })? {
+        state.stats.read(&key, true);
+        values.push((key, value));
+      }
+    }
+    Ok(values)
+  };
+  let current: Option<watch::Current> = initial.then_some(&current);
+  // the subscription is acknowledged by the watchers, so that no change is
+  // sent between the acknowledgement and the changes being replayed
+  if let Err(err) = state.watchers.subscribe(patterns, req.sender(), resume, current) {
+    return req.send(rpc::Operation::new_error(&err));
+  }
+  Ok(())
+}
+
+/// Produce the checksum of every key which has not expired.
+fn run_checksum(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if !cmd.args().is_empty() {
+    return Err(error::Error::Malformed);
+  }
+  let mut data = state.data.snapshot()?;
+  data.retain(|k, _| { !state.expiry.is_expired(k) });
+  req.send(rpc::Operation::new_checksummed(&cas::checksum(&data)))
+}
+
+/// Update a metric and produce its new value, or dump every metric.
+fn run_metric(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  let args: Vec<&str> = cmd.args().iter().map(|e| { e.as_str() }).collect();
+  let res = match args[..] {
+    [rpc::METRIC_DUMP] => return req.send(rpc::Operation::new_found("", &serde_json::to_string(&state.metrics.dump())?)),
+    [rpc::METRIC_INCR, name, by] => match by.parse::<f64>() {
+      Ok(by) if by.is_finite() => state.metrics.incr(name, by),
+      _ => return Err(error::Error::Malformed),
+    },
+    [rpc::METRIC_SET, name, value] => match value.parse::<f64>() {
+      Ok(value) if value.is_finite() => state.metrics.set(name, value),
+      _ => return Err(error::Error::Malformed),
+    },
+    _ => return Err(error::Error::Malformed),
+  };
+  match res {
+    Ok(value) => req.send(rpc::Operation::new_metric_value(value)),
+    Err(err)  => req.send(rpc::Operation::new_error(&err)),
+  }
+}
+
+fn run_stat(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  match cmd.args().len() {
+    0 => {
+      let mut report = state.stats.snapshot();
+      report.storage = state.data.usage();
+      req.send(rpc::Operation::new_found("", &serde_json::to_string(&report)?))
+    },
+    1 => {
+      let name = cmd.args()[0].clone();
+      match state.stats.get(&key_of(&state.resolve(&name))) {
+        Some(stats) => req.send(rpc::Operation::new_found(&name, &serde_json::to_string(&stats)?)),
+        None        => req.send(rpc::Operation::new_none(&name)),
+      }
+    },
+    _ => Err(error::Error::Malformed),
+  }
+}
+
+/// Mark a key as modified and restart its TTL, if it has one, or give it a
+/// new one, without rewriting its value.
+fn run_touch(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().is_empty() {
+    return Err(error::Error::Malformed);
+  }
+  let ttl = match cmd.option(1, rpc::OPTION_TTL) {
+    Some(ttl) => match ttl.parse::<u64>() {
+      Ok(ttl) => Some(time::Duration::from_millis(ttl)),
+      Err(_)  => return Err(error::Error::Malformed),
+    },
+    None => None,
+  };
+  let name = cmd.args()[0].clone();
+  let key = key_of(&state.resolve(&name));
+  let _guard = state.locks.lock(&[&key]);
+  state.reap(&key)?;
+  if state.data.get(&key)?.is_none() {
+    return req.send(rpc::Operation::new_none(&name));
+  }
+  match ttl {
+    Some(ttl) => state.expiry.set(&key, Some(ttl)),
+    None      => { state.expiry.reset(&key); },
+  }
+  state.stats.touch(&key);
+  req.send(rpc::Operation::new_ok())
+}
+
+fn run_dump(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if !cmd.args().is_empty() {
+    return Err(error::Error::Malformed);
+  }
+  let mut keys: BTreeMap<String, dump::Dump> = BTreeMap::new();
+  for (key, data) in state.data.snapshot()? {
+    if !state.expiry.is_expired(&key) {
+      let ttl = state.expiry.remaining(&key);
+      let stats = state.stats.get(&key).unwrap_or_default();
+      keys.insert(key, dump::Dump::new(data, ttl).with_times(stats.created, stats.modified));
+    }
+  }
+  let aliases = state.aliases().clone();
+  req.send(rpc::Operation::new_found("", &dump::Snapshot::new(keys, aliases).encode()?))
+}
+
+fn run_dump_key(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().len() != 1 {
+    return Err(error::Error::Malformed);
+  }
+  let name = cmd.args()[0].to_string();
+  let key = state.resolve(&name);
+  let data = match state.fetch(&key) {
+    Ok(data) => data,
+    Err(error::Error::NotFound) => return req.send(rpc::Operation::new_none(&name)),
+    Err(err) => return Err(err),
+  };
+  let dump = if key_of(&key) == key {
+    let stats = state.stats.get(&key).unwrap_or_default();
+    dump::Dump::new(data, state.expiry.remaining(&key)).with_times(stats.created, stats.modified)
+  } else {
+    dump::Dump::new(data, None) // a sub-structure has no metadata of its own
+  };
+  req.send(rpc::Operation::new_found(&name, &dump.encode()?))
+}
+
+fn run_restore_key(opts: &Options, state: &State, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  if cmd.args().is_empty() {
+    return Err(error::Error::Malformed);
+  }
+  let dump = match cmd.data().as_deref().map(dump::Dump::decode) {
+    Some(Ok(dump)) => dump,
+    Some(Err(_))   => return req.send(rpc::Operation::new_error("Invalid dump")),
+    None           => return Err(error::Error::Malformed),
+  };
+  let replace = cmd.has_flag(1, rpc::FLAG_REPLACE);
+  let key = state.resolve(&cmd.args()[0]);
+  if key_of(&key) != key {
+    return req.send(rpc::Operation::new_error(&format!("Dumps are restored to whole keys: {}", key)));
+  }
+  if refuse_protected(state, &req, &[&key])? {
+    return Ok(());
+  }
+  let _guard = state.locks.lock(&[&key]);
+  state.reap(&key)?;
+  let mut data = state.data.clone();
+  if !replace && data.get(&key)?.is_some() {
+    return req.send(rpc::Operation::new_error(&format!("Key exists: {}", key)));
+  }
+  data.set(&key, dump.value.clone())?;
+  state.expiry.set(&key, dump.ttl());
+  state.stats.write(&key, req.uid());
+  state.stats.restore(&key, dump.created, dump.modified);
+  state.changed(&key);
+  req.send(rpc::Operation::new_ok())
+}
+
+fn run_version(opts: &Options, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  req.send(rpc::Operation::new_version_info(crate::VERSION, rpc::PROTOCOL))?;
+  Ok(())
+}
+
+fn run_health(opts: &Options, mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  req.send(rpc::Operation::new_ok())
+}
+
+fn run_stop(opts: &Options,  mut req: rpc::Request) -> Result<(), error::Error> {
+  let cmd = req.operation();
+  if opts.debug {
+    log::logln!(">>> {:?}", cmd);
+  }
+  req.send(rpc::Operation::new_ok())?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use clap::Parser;
+  use serde_json::json;
+
+  type Handler = fn(&Options, &State, rpc::Request) -> Result<(), error::Error>;
+
+  /// Have a handler answer an operation, producing its response; a failed
+  /// handler is answered for as the service does.
+  fn call(handler: Handler, state: &State, op: rpc::Operation) -> rpc::Operation {
+    let opts = Options::parse_from(["structs", "stop"]);
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let res = handler(&opts, state, rpc::Request::new(1, op, tx.clone()));
+    fail(&tx, &res);
+    rx.try_recv().unwrap()
+  }
+
+  fn value(state: &State, key: &str) -> Option<serde_json::Value> {
+    state.data.get(key).unwrap()
+  }
+
+  /// A store which cannot write one key, like a disk which fills up.
+  struct Failing {
+    store: storage::Memory,
+    key: String,
+  }
+
+  impl storage::Store for Failing {
+    fn get(&self, key: &str) -> Result<Option<serde_json::Value>, error::Error> {
+      self.store.get(key)
+    }
+
+    fn set(&mut self, key: &str, value: serde_json::Value) -> Result<(), error::Error> {
+      match key == self.key {
+        true  => Err(error::Error::BackendError(format!("No space to write {}", key))),
+        false => self.store.set(key, value),
+      }
+    }
+
+    fn delete(&mut self, key: &str) -> Result<Option<serde_json::Value>, error::Error> {
+      self.store.delete(key)
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<String>, error::Error> {
+      self.store.scan(prefix)
+    }
+
+    fn snapshot(&self) -> Result<BTreeMap<String, serde_json::Value>, error::Error> {
+      self.store.snapshot()
+    }
+
+    fn len(&self) -> Result<usize, error::Error> {
+      self.store.len()
+    }
+  }
+
+  #[test]
+  fn mset_rolls_back() {
+    let state = State::new(Box::new(Failing{ store: storage::Memory::new(), key: "b".to_string() }));
+    assert_eq!(rpc::CMD_OK, call(run_set, &state, rpc::Operation::new_set("a", "1")).name());
+    // keys are written in order, so a is written before b fails
+    let rsp = call(run_mset, &state, rpc::Operation::new_mset(r#"{"a": 2, "b": 3, "c": 4}"#));
+    assert_eq!(rpc::CMD_ERROR, rsp.name());
+    assert_eq!(Some(json!(1)), value(&state, "a"));
+    assert_eq!((None, None), (value(&state, "b"), value(&state, "c")));
+  }
+
+  fn memory() -> State {
+    State::new(Box::new(storage::Memory::new()))
+  }
+
+  /// The name, arguments, and data of a response, for comparison.
+  fn parts(op: rpc::Operation) -> (String, Vec<String>, Option<String>) {
+    (op.name().to_string(), op.args().to_vec(), op.data().clone())
+  }
+
+  fn found(name: &str, data: &str) -> (String, Vec<String>, Option<String>) {
+    parts(rpc::Operation::new_found(name, data))
+  }
+
+  #[test]
+  fn cas_outcomes() {
+    let state = memory();
+    call(run_set, &state, rpc::Operation::new_set("a", r#"{"n": 1}"#));
+    assert_eq!(rpc::CMD_OK, call(run_cas, &state, rpc::Operation::new_cas("a.n", "1", "2")).name());
+    assert_eq!(Some(json!({"n": 2})), value(&state, "a"));
+    // the current value is sent back, and nothing is stored
+    let rsp = call(run_cas, &state, rpc::Operation::new_cas("a", r#"{"n": 1}"#, "3"));
+    assert_eq!(parts(rpc::Operation::new_mismatch("a", r#"{"n":2}"#)), parts(rsp));
+    assert_eq!(Some(json!({"n": 2})), value(&state, "a"));
+    assert_eq!(parts(rpc::Operation::new_none("b")), parts(call(run_cas, &state, rpc::Operation::new_cas("b", "null", "1"))));
+    assert_eq!(None, value(&state, "b"));
+  }
+
+  #[test]
+  fn take_values() {
+    let state = memory();
+    call(run_set, &state, rpc::Operation::new_set("a", r#"{"n": 1, "m": 2}"#));
+    assert_eq!(found("a.n", "1"), parts(call(run_take, &state, rpc::Operation::new_take("a.n"))));
+    assert_eq!(Some(json!({"m": 2})), value(&state, "a"));
+    assert_eq!(found("a", r#"{"m":2}"#), parts(call(run_take, &state, rpc::Operation::new_take("a"))));
+    assert_eq!(None, value(&state, "a"));
+    assert_eq!(parts(rpc::Operation::new_none("a")), parts(call(run_take, &state, rpc::Operation::new_take("a"))));
+    assert_eq!(rpc::CMD_ERROR, call(run_take, &state, rpc::Operation::new(CMD_TAKE, &[], None)).name());
+  }
+
+  #[test]
+  fn failures_answered() {
+    let state = memory();
+    assert_eq!(rpc::CMD_ERROR, call(run_get, &state, rpc::Operation::new(CMD_GET, &[], None)).name());
+    assert_eq!(rpc::CMD_ERROR, call(run_set, &state, rpc::Operation::new_set("a", "{")).name());
+    assert_eq!(rpc::CMD_ERROR, call(run_merge_keys, &state, rpc::Operation::new(CMD_MERGEKEYS, &["a"], None)).name());
+    assert_eq!(rpc::CMD_ERROR, call(run_delete, &state, rpc::Operation::new(CMD_DELETE, &[], None)).name());
+  }
+
+  #[test]
+  fn through_aliases() {
+    let state = memory();
+    call(run_alias, &state, rpc::Operation::new_alias("cur", "release-2"));
+    assert_eq!(rpc::CMD_OK, call(run_set, &state, rpc::Operation::new_set("cur", r#"{"n": 1}"#)).name());
+    assert_eq!(Some(json!({"n": 1})), value(&state, "release-2"));
+    assert_eq!(found("cur.n", "1"), parts(call(run_get, &state, rpc::Operation::new_get("cur.n"))));
+    assert_eq!(found("cur.n", "1"), parts(call(run_take, &state, rpc::Operation::new_take("cur.n"))));
+    assert_eq!(Some(json!({})), value(&state, "release-2"));
+    // deletes are reported by the name they were given
+    assert_eq!(parts(rpc::Operation::new_deleted(&["cur"])), parts(call(run_delete, &state, rpc::Operation::new_delete(&["cur", "other"]))));
+    assert_eq!(None, value(&state, "release-2"));
+    call(run_set, &state, rpc::Operation::new_set("release-2", "1"));
+    call(run_set, &state, rpc::Operation::new_set("release-3", "1"));
+    assert_eq!(parts(rpc::Operation::new_deleted(&["release-2"])), parts(call(run_delete_keys, &state, rpc::Operation::new_delete_keys("cur", false, false))));
+    assert_eq!((None, Some(json!(1))), (value(&state, "release-2"), value(&state, "release-3")));
+  }
+
+  #[test]
+  fn seed_and_templates() {
+    let mut state = memory();
+    let mut seed = storage::Memory::new();
+    seed.set("Seeded", json!({"Name": "x"})).unwrap();
+    state.seed = Some(seed);
+    call(run_default, &state, rpc::Operation::new_default("cfg:*", r#"{"a": 1, "b": 2}"#));
+    let get_ci = |name: &str| { call(run_get, &state, rpc::Operation::new_get(name).with_flag(rpc::FLAG_CI, true)) };
+    assert_eq!(found("seeded.name", r#""x""#), parts(get_ci("seeded.name")));
+    assert_eq!(found("cfg:1.b", "2"), parts(get_ci("cfg:1.b")));
+    assert_eq!(rpc::CMD_NONE, get_ci("other").name());
+
+    // a whole key merged into is templated; a path in one starts from the template
+    call(run_set, &state, rpc::Operation::new_set("x", r#"{"b": 3}"#));
+    call(run_set, &state, rpc::Operation::new_set("y", r#"{"c": 4}"#));
+    assert_eq!(rpc::CMD_OK, call(run_merge_keys, &state, rpc::Operation::new_merge_keys("x", "y", "cfg:1")).name());
+    assert_eq!(Some(json!({"a": 1, "b": 3, "c": 4})), value(&state, "cfg:1"));
+    assert_eq!(rpc::CMD_OK, call(run_merge_keys, &state, rpc::Operation::new_merge_keys("x", "y", "cfg:2.a")).name());
+    assert_eq!(Some(json!({"a": {"b": 3, "c": 4}, "b": 2})), value(&state, "cfg:2"));
+  }
+
+  #[test]
+  fn merge_keys_ttl() {
+    let state = memory();
+    call(run_set, &state, rpc::Operation::new_set("x", r#"{"a": 1}"#));
+    call(run_set, &state, rpc::Operation::new_set("y", r#"{"b": 2}"#));
+    call(run_set, &state, rpc::Operation::new_set("z", "{}").with_option(rpc::OPTION_TTL, Some("60000")));
+    call(run_set, &state, rpc::Operation::new_set("w", r#"{"m": 0}"#).with_option(rpc::OPTION_TTL, Some("60000")));
+    assert_eq!(rpc::CMD_OK, call(run_merge_keys, &state, rpc::Operation::new_merge_keys("x", "y", "w.m")).name());
+    assert!(state.expiry.remaining("w").is_some());
+    // replacing the whole key clears its TTL, as set does
+    assert_eq!(rpc::CMD_OK, call(run_merge_keys, &state, rpc::Operation::new_merge_keys("x", "y", "z")).name());
+    assert_eq!(None, state.expiry.remaining("z"));
+    assert_eq!(Some(json!({"a": 1, "b": 2})), value(&state, "z"));
+  }
+
+  #[test]
+  fn protection_refused() {
+    let state = memory();
+    call(run_set, &state, rpc::Operation::new_set("a", r#"{"n": 1}"#));
+    call(run_set, &state, rpc::Operation::new_set("b", "2"));
+    call(run_protect, &state, rpc::Operation::new_protect(&["a.n"]));
+    // writes to the name, its parents, or anything under it are refused
+    let protected = parts(rpc::Operation::new_protected("a.n"));
+    assert_eq!(protected, parts(call(run_set, &state, rpc::Operation::new_set("a", "{}"))));
+    assert_eq!(protected, parts(call(run_take, &state, rpc::Operation::new_take("a.n"))));
+    assert_eq!(protected, parts(call(run_delete, &state, rpc::Operation::new_delete(&["b", "a"]))));
+    assert_eq!(protected, parts(call(run_delete_keys, &state, rpc::Operation::new_delete_keys("", false, false))));
+    assert_eq!(protected, parts(call(run_merge_keys, &state, rpc::Operation::new_merge_keys("a", "a", "a"))));
+    assert_eq!((Some(json!({"n": 1})), Some(json!(2))), (value(&state, "a"), value(&state, "b")));
+    // reads and other keys are not
+    assert_eq!(found("a.n", "1"), parts(call(run_get, &state, rpc::Operation::new_get("a.n"))));
+    assert_eq!(rpc::CMD_OK, call(run_set, &state, rpc::Operation::new_set("b", "3")).name());
+    call(run_unprotect, &state, rpc::Operation::new_unprotect(&["a.n"]));
+    assert_eq!(rpc::CMD_OK, call(run_set, &state, rpc::Operation::new_set("a", "{}")).name());
+  }
+
+  #[test]
+  fn templates() {
+    let state = memory();
+    call(run_default, &state, rpc::Operation::new_default("job:*", r#"{"state": "new", "tries": 0}"#));
+    call(run_default, &state, rpc::Operation::new_default("job:urgent:*", r#"{"state": "new", "tries": 0, "urgent": true}"#));
+    assert_eq!(found("job:1.state", r#""new""#), parts(call(run_get, &state, rpc::Operation::new_get("job:1.state"))));
+    assert_eq!(found("job:urgent:1.urgent", "true"), parts(call(run_get, &state, rpc::Operation::new_get("job:urgent:1.urgent")))); // the longest glob wins
+    assert_eq!(None, value(&state, "job:1")); // reads store nothing
+    // writing part of a key fills in the rest; writing the whole key merges into the template
+    call(run_set, &state, rpc::Operation::new_set("job:1.tries", "1"));
+    assert_eq!(Some(json!({"state": "new", "tries": 1})), value(&state, "job:1"));
+    call(run_set, &state, rpc::Operation::new_set("job:2", r#"{"state": "done"}"#));
+    assert_eq!(Some(json!({"state": "done", "tries": 0})), value(&state, "job:2"));
+    // once stored, a key is its own
+    call(run_set, &state, rpc::Operation::new_set("job:2", r#"{"state": "failed"}"#));
+    assert_eq!(Some(json!({"state": "failed"})), value(&state, "job:2"));
+    call(run_undefault, &state, rpc::Operation::new_undefault("job:*"));
+    assert_eq!(rpc::CMD_NONE, call(run_get, &state, rpc::Operation::new_get("job:3")).name());
+  }
+
+  #[test]
+  fn delete_keys() {
+    let state = memory();
+    for key in ["job:1", "job:2", "job:*", "jobs", "job 1", " job", "other"] {
+      call(run_set, &state, rpc::Operation::new_set(key, "1"));
+    }
+    let delete_keys = |pattern: &str, regex: bool, glob: bool| { parts(call(run_delete_keys, &state, rpc::Operation::new_delete_keys(pattern, regex, glob))) };
+    // a prefix is literal, even with glob characters or spaces in it
+    assert_eq!(parts(rpc::Operation::new_deleted(&["job:*"])), delete_keys("job:*", false, false));
+    assert_eq!(parts(rpc::Operation::new_deleted(&["job 1"])), delete_keys("job ", false, false));
+    assert_eq!(parts(rpc::Operation::new_deleted(&[" job"])), delete_keys(" ", false, false));
+    // globs and regular expressions must match entire keys
+    assert_eq!(parts(rpc::Operation::new_deleted(&["job:1"])), delete_keys("job:1*", false, true));
+    assert_eq!(parts(rpc::Operation::new_deleted(&["jobs"])), delete_keys("jobs?", true, false));
+    assert_eq!(parts(rpc::Operation::new_deleted(&[])), delete_keys("nothing", false, false));
+    assert_eq!(rpc::CMD_ERROR, delete_keys("(", true, false).0);
+    assert_eq!(vec!["job:2", "other"], state.data.scan("").unwrap());
+  }
+
+  #[test]
+  fn swap_values() {
+    let state = memory();
+    assert_eq!(parts(rpc::Operation::new_none("a")), parts(call(run_swap, &state, rpc::Operation::new_swap("a", "1"))));
+    assert_eq!(found("a", "1"), parts(call(run_swap, &state, rpc::Operation::new_swap("a", "2"))));
+    assert_eq!(Some(json!(2)), value(&state, "a"));
+  }
+
+  #[test]
+  fn set_nx() {
+    let state = memory();
+    assert_eq!(rpc::CMD_OK, call(run_set, &state, rpc::Operation::new_set("a", "1").with_flag(rpc::FLAG_NX, true)).name());
+    assert_eq!(parts(rpc::Operation::new_exists("a")), parts(call(run_set, &state, rpc::Operation::new_set("a", "2").with_flag(rpc::FLAG_NX, true))));
+    assert_eq!(Some(json!(1)), value(&state, "a"));
+  }
+
+  #[test]
+  fn incr_push_pop() {
+    let state = memory();
+    assert_eq!(found("n", "5"), parts(call(run_incr, &state, rpc::Operation::new_incr("n", &5.into()))));
+    assert_eq!(found("n", "3"), parts(call(run_incr, &state, rpc::Operation::new_incr("n", &(-2).into()))));
+    call(run_set, &state, rpc::Operation::new_set("s", r#""text""#));
+    assert_eq!(rpc::CMD_ERROR, call(run_incr, &state, rpc::Operation::new_incr("s", &1.into())).name());
+
+    assert_eq!(parts(rpc::Operation::new_pushed(1)), parts(call(run_push, &state, rpc::Operation::new_push("l", "1"))));
+    assert_eq!(parts(rpc::Operation::new_pushed(2)), parts(call(run_push, &state, rpc::Operation::new_push("l", "2"))));
+    assert_eq!(found("l", "2"), parts(call(run_pop, &state, rpc::Operation::new_pop("l", false))));
+    assert_eq!(found("l", "1"), parts(call(run_pop, &state, rpc::Operation::new_pop("l", true))));
+    assert_eq!(parts(rpc::Operation::new_none("l")), parts(call(run_pop, &state, rpc::Operation::new_pop("l", false))));
+  }
+
+  #[test]
+  fn mset_invalid_entry() {
+    let state = memory();
+    call(run_set, &state, rpc::Operation::new_set("a", "1"));
+    // b does not exist, so there is nothing to hold b.c; a is left alone
+    let rsp = call(run_mset, &state, rpc::Operation::new_mset(r#"{"a": 2, "b.c": 3}"#));
+    assert_eq!(rpc::CMD_ERROR, rsp.name());
+    assert_eq!((Some(json!(1)), None), (value(&state, "a"), value(&state, "b")));
+    assert_eq!(rpc::CMD_OK, call(run_mset, &state, rpc::Operation::new_mset(r#"{"a": 2, "b": {"c": 3}}"#)).name());
+    assert_eq!((Some(json!(2)), Some(json!({"c": 3}))), (value(&state, "a"), value(&state, "b")));
+  }
+
+  #[test]
+  fn clear_keys() {
+    let state = memory();
+    call(run_set, &state, rpc::Operation::new_set("a", "1"));
+    call(run_set, &state, rpc::Operation::new_set("b", "2"));
+    call(run_protect, &state, rpc::Operation::new_protect(&["b"]));
+    assert_eq!(rpc::CMD_PROTECTED, call(run_clear, &state, rpc::Operation::new_clear()).name());
+    assert_eq!(Some(json!(1)), value(&state, "a"));
+    call(run_unprotect, &state, rpc::Operation::new_unprotect(&["b"]));
+    assert_eq!(parts(rpc::Operation::new_cleared(2)), parts(call(run_clear, &state, rpc::Operation::new_clear())));
+    assert_eq!(0, state.data.len().unwrap());
+  }
 }
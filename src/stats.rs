@@ -0,0 +1,200 @@
+use std::time;
+use std::sync::{Mutex, MutexGuard};
+use std::collections::BTreeMap;
+
+use serde::{Serialize, Deserialize};
+
+use crate::storage;
+
+/// Access statistics and metadata for a single key: when it was created and
+/// last modified, and the uid of the client which last wrote to it, if that
+/// is known. Times are in milliseconds since the epoch.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct KeyStats {
+  pub reads: u64,
+  pub hits: u64,
+  pub misses: u64,
+  pub writes: u64,
+  pub last_access: Option<u128>,
+  pub created: Option<u128>,
+  pub modified: Option<u128>,
+  pub writer: Option<u32>,
+}
+
+/// A report of access statistics for the whole store and for each key.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+  pub reads: u64,
+  pub hits: u64,
+  pub misses: u64,
+  pub writes: u64,
+  pub expired: u64,
+  pub keys: BTreeMap<String, KeyStats>,
+  /// The memory saved by sharing strings, for backends which do.
+  #[serde(skip_serializing_if="Option::is_none", default)]
+  pub storage: Option<storage::Usage>,
+}
+
+/// The totals for the whole store at a point in time, in milliseconds since
+/// the epoch, as logged periodically over the life of a service.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Sample {
+  pub time: u128,
+  pub keys: usize,
+  pub reads: u64,
+  pub hits: u64,
+  pub misses: u64,
+  pub writes: u64,
+  pub expired: u64,
+}
+
+impl Sample {
+  pub const CSV_HEADER: &'static str = "time,keys,reads,hits,misses,writes,expired";
+
+  pub fn to_csv(&self) -> String {
+    format!("{},{},{},{},{},{},{}", self.time, self.keys, self.reads, self.hits, self.misses, self.writes, self.expired)
+  }
+}
+
+/// Collects access statistics as the service handles requests.
+pub struct Stats {
+  report: Mutex<Report>,
+}
+
+impl Stats {
+  pub fn new() -> Self {
+    Self{
+      report: Mutex::new(Report::default()),
+    }
+  }
+
+  fn report(&self) -> MutexGuard<'_, Report> {
+    match self.report.lock() {
+      Ok(report)  => report,
+      Err(report) => report.into_inner(), // counters are never left inconsistent
+    }
+  }
+
+  /// Note a read of a key, which either found a value or did not.
+  pub fn read(&self, key: &str, hit: bool) {
+    let mut report = self.report();
+    report.reads += 1;
+    if hit { report.hits += 1; } else { report.misses += 1; }
+    let stats = report.keys.entry(key.to_string()).or_default();
+    stats.reads += 1;
+    if hit { stats.hits += 1; } else { stats.misses += 1; }
+    stats.last_access = now();
+  }
+
+  /// Note a write to a key by a client running as the provided user.
+  pub fn write(&self, key: &str, writer: Option<u32>) {
+    let mut report = self.report();
+    report.writes += 1;
+    let stats = report.keys.entry(key.to_string()).or_default();
+    let now = now();
+    stats.writes += 1;
+    stats.last_access = now;
+    stats.created = stats.created.or(now);
+    stats.modified = now;
+    stats.writer = writer;
+  }
+
+  /// Note that a key was touched, which modifies it without writing to it.
+  pub fn touch(&self, key: &str) {
+    let mut report = self.report();
+    let stats = report.keys.entry(key.to_string()).or_default();
+    let now = now();
+    stats.last_access = now;
+    stats.modified = now;
+  }
+
+  /// Carry over when a key restored from elsewhere was created and last
+  /// modified.
+  pub fn restore(&self, key: &str, created: Option<u128>, modified: Option<u128>) {
+    let mut report = self.report();
+    let stats = report.keys.entry(key.to_string()).or_default();
+    stats.created = created.or(stats.created);
+    stats.modified = modified.or(stats.modified);
+  }
+
+  /// Forget the statistics for a key which has been deleted.
+  pub fn remove(&self, key: &str) {
+    self.report().keys.remove(key);
+  }
+
+  /// Note that a key has expired and forget its statistics.
+  pub fn expire(&self, key: &str) {
+    let mut report = self.report();
+    report.expired += 1;
+    report.keys.remove(key);
+  }
+
+  pub fn get(&self, key: &str) -> Option<KeyStats> {
+    self.report().keys.get(key).cloned()
+  }
+
+  /// The number of keys which have expired.
+  pub fn expired(&self) -> u64 {
+    self.report().expired
+  }
+
+  pub fn snapshot(&self) -> Report {
+    self.report().clone()
+  }
+
+  /// Sample the totals for the whole store, which holds some number of keys.
+  pub fn sample(&self, keys: usize) -> Sample {
+    let report = self.report();
+    Sample{
+      time: now().unwrap_or_default(),
+      keys,
+      reads: report.reads,
+      hits: report.hits,
+      misses: report.misses,
+      writes: report.writes,
+      expired: report.expired,
+    }
+  }
+}
+
+fn now() -> Option<u128> {
+  match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+    Ok(now) => Some(now.as_millis()),
+    Err(_)  => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn key_stats() {
+    let stats = Stats::new();
+    stats.read("a", false);
+    stats.write("a", Some(1000));
+    stats.read("a", true);
+    stats.read("b", true);
+    let a = stats.get("a").unwrap();
+    assert_eq!((2, 1, 1, 1), (a.reads, a.hits, a.misses, a.writes));
+    assert!(a.last_access.is_some());
+    assert_eq!((a.modified, Some(1000)), (a.created, a.writer));
+    assert_eq!(None, stats.get("b").unwrap().created);
+    let report = stats.snapshot();
+    assert_eq!((3, 2, 1, 1), (report.reads, report.hits, report.misses, report.writes));
+    stats.write("a", None);
+    assert_eq!((a.created, None), (stats.get("a").unwrap().created, stats.get("a").unwrap().writer));
+    stats.touch("a");
+    assert!(stats.get("a").unwrap().modified >= a.modified);
+    stats.restore("a", Some(1), Some(2));
+    assert_eq!((Some(1), Some(2)), (stats.get("a").unwrap().created, stats.get("a").unwrap().modified));
+    stats.remove("a");
+    assert_eq!(None, stats.get("a"));
+    assert_eq!(1, stats.snapshot().keys.len());
+    stats.expire("b");
+    assert_eq!((1, 0), (stats.snapshot().expired, stats.snapshot().keys.len()));
+    let sample = stats.sample(7);
+    assert_eq!((7, 3, 1), (sample.keys, sample.reads, sample.expired));
+    assert_eq!(Sample::CSV_HEADER.split(',').count(), sample.to_csv().split(',').count());
+  }
+}
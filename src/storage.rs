@@ -0,0 +1,881 @@
+use std::fs;
+use std::io;
+use std::path;
+use std::str::FromStr;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use serde::{Serialize, Deserialize};
+use serde_json::value::Value;
+use sha2::{Digest, Sha256};
+
+use crate::error;
+use crate::crypto;
+
+pub const BACKEND_MEMORY:   &str = "memory";
+pub const BACKEND_INTERNED: &str = "interned";
+pub const BACKEND_DISK:     &str = "disk";
+pub const BACKEND_SQLITE:   &str = "sqlite";
+
+/// A store holds the top-level values managed by the service, by key. The
+/// service interprets paths within values itself, so a store need only deal
+/// in whole values.
+pub trait Store: Send {
+  /// Fetch the value stored under a key, if any.
+  fn get(&self, key: &str) -> Result<Option<Value>, error::Error>;
+  /// Store a value under a key, replacing any existing value.
+  fn set(&mut self, key: &str, value: Value) -> Result<(), error::Error>;
+  /// Delete the value stored under a key, producing it if it existed.
+  fn delete(&mut self, key: &str) -> Result<Option<Value>, error::Error>;
+  /// List the keys which begin with a prefix, in order.
+  fn scan(&self, prefix: &str) -> Result<Vec<String>, error::Error>;
+  /// Produce a copy of every key and value in the store.
+  #[allow(dead_code)]
+  fn snapshot(&self) -> Result<BTreeMap<String, Value>, error::Error>;
+  /// The number of keys in the store.
+  fn len(&self) -> Result<usize, error::Error>;
+  /// How much memory the store saves by sharing what its values have in
+  /// common, if it does.
+  fn usage(&self) -> Option<Usage> {
+    None
+  }
+}
+
+/// The memory used by the strings in a store's values, including the names
+/// of object members, against what they would use if they were not shared.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+  pub strings: usize,
+  pub string_bytes: u64,
+  pub interned_bytes: u64,
+  pub saved_bytes: u64,
+}
+
+/// Create a store using the named backend. Backends which persist data
+/// require the path of the data they manage, and encrypt it when a cipher
+/// is provided.
+pub fn new(backend: &str, data: Option<&path::Path>, cipher: Option<crypto::Cipher>) -> Result<Box<dyn Store>, error::Error> {
+  match backend {
+    BACKEND_MEMORY   => Ok(Box::new(Memory::new())),
+    BACKEND_INTERNED => Ok(Box::new(Interned::new())),
+    BACKEND_DISK     => match data {
+      Some(data) => Ok(Box::new(Disk::open(data, cipher)?)),
+      None       => Err(error::Error::BackendError(format!("The {} backend requires a data path", backend))),
+    },
+    BACKEND_SQLITE   => match data {
+      Some(_) if cipher.is_some() => Err(error::Error::BackendError(format!("The {} backend does not support encryption", backend))),
+      Some(data) => Ok(Box::new(Sqlite::open(data)?)),
+      None       => Err(error::Error::BackendError(format!("The {} backend requires a data path", backend))),
+    },
+    _ => Err(error::Error::UnknownBackend(backend.to_string())),
+  }
+}
+
+/// A store which keeps everything in memory.
+pub struct Memory {
+  data: BTreeMap<String, Value>,
+}
+
+impl Memory {
+  pub fn new() -> Self {
+    Self{
+      data: BTreeMap::new(),
+    }
+  }
+}
+
+impl Store for Memory {
+  fn get(&self, key: &str) -> Result<Option<Value>, error::Error> {
+    Ok(self.data.get(key).cloned())
+  }
+
+  fn set(&mut self, key: &str, value: Value) -> Result<(), error::Error> {
+    self.data.insert(key.to_string(), value);
+    Ok(())
+  }
+
+  fn delete(&mut self, key: &str) -> Result<Option<Value>, error::Error> {
+    Ok(self.data.remove(key))
+  }
+
+  fn scan(&self, prefix: &str) -> Result<Vec<String>, error::Error> {
+    Ok(self.data.range(prefix.to_string()..).map(|(k, _)| { k }).take_while(|k| { k.starts_with(prefix) }).cloned().collect())
+  }
+
+  fn snapshot(&self) -> Result<BTreeMap<String, Value>, error::Error> {
+    Ok(self.data.clone())
+  }
+
+  fn len(&self) -> Result<usize, error::Error> {
+    Ok(self.data.len())
+  }
+}
+
+/// A value whose strings, including the names of object members, are
+/// shared with every other value in the same store.
+enum Node {
+  Null,
+  Bool(bool),
+  Number(serde_json::Number),
+  String(Arc<str>),
+  Array(Vec<Node>),
+  Object(Vec<(Arc<str>, Node)>),
+}
+
+impl Node {
+  fn value(&self) -> Value {
+    match self {
+      Self::Null      => Value::Null,
+      Self::Bool(v)   => Value::Bool(*v),
+      Self::Number(v) => Value::Number(v.clone()),
+      Self::String(v) => Value::String(v.to_string()),
+      Self::Array(v)  => Value::Array(v.iter().map(Node::value).collect()),
+      Self::Object(v) => Value::Object(v.iter().map(|(k, e)| { (k.to_string(), e.value()) }).collect()),
+    }
+  }
+
+  /// Collect the strings in this value.
+  fn strings(self, strings: &mut Vec<Arc<str>>) {
+    match self {
+      Self::String(v) => strings.push(v),
+      Self::Array(v)  => v.into_iter().for_each(|e| { e.strings(strings) }),
+      Self::Object(v) => v.into_iter().for_each(|(k, e)| {
+        strings.push(k);
+        e.strings(strings);
+      }),
+      _ => {},
+    }
+  }
+}
+
+/// A store which keeps everything in memory, like `Memory`, but stores each
+/// distinct string only once, however many values contain it. Stores of
+/// many similar documents, whose member names and enumerated values repeat,
+/// use far less memory this way, at the cost of converting values as they
+/// are read and written.
+pub struct Interned {
+  data: BTreeMap<String, Node>,
+  strings: HashSet<Arc<str>>,
+  string_bytes: u64,
+  interned_bytes: u64,
+}
+
+impl Interned {
+  pub fn new() -> Self {
+    Self{
+      data: BTreeMap::new(),
+      strings: HashSet::new(),
+      string_bytes: 0,
+      interned_bytes: 0,
+    }
+  }
+
+  fn intern(&mut self, s: &str) -> Arc<str> {
+    self.string_bytes += s.len() as u64;
+    if let Some(s) = self.strings.get(s) {
+      return s.clone();
+    }
+    let s: Arc<str> = Arc::from(s);
+    self.interned_bytes += s.len() as u64;
+    self.strings.insert(s.clone());
+    s
+  }
+
+  fn node(&mut self, value: Value) -> Node {
+    match value {
+      Value::Null      => Node::Null,
+      Value::Bool(v)   => Node::Bool(v),
+      Value::Number(v) => Node::Number(v),
+      Value::String(v) => Node::String(self.intern(&v)),
+      Value::Array(v)  => Node::Array(v.into_iter().map(|e| { self.node(e) }).collect()),
+      Value::Object(v) => Node::Object(v.into_iter().map(|(k, e)| { (self.intern(&k), self.node(e)) }).collect()),
+    }
+  }
+
+  /// Release a value which has been removed, forgetting any strings which
+  /// no other value shares.
+  fn release(&mut self, node: Node) {
+    let mut strings = Vec::new();
+    node.strings(&mut strings);
+    for s in strings {
+      self.string_bytes -= s.len() as u64;
+      if Arc::strong_count(&s) == 2 && self.strings.remove(&s) { // held only by the table and us
+        self.interned_bytes -= s.len() as u64;
+      }
+    }
+  }
+}
+
+impl Store for Interned {
+  fn get(&self, key: &str) -> Result<Option<Value>, error::Error> {
+    Ok(self.data.get(key).map(Node::value))
+  }
+
+  fn set(&mut self, key: &str, value: Value) -> Result<(), error::Error> {
+    let node = self.node(value);
+    if let Some(prev) = self.data.insert(key.to_string(), node) {
+      self.release(prev);
+    }
+    Ok(())
+  }
+
+  fn delete(&mut self, key: &str) -> Result<Option<Value>, error::Error> {
+    let node = match self.data.remove(key) {
+      Some(node) => node,
+      None       => return Ok(None),
+    };
+    let value = node.value();
+    self.release(node);
+    Ok(Some(value))
+  }
+
+  fn scan(&self, prefix: &str) -> Result<Vec<String>, error::Error> {
+    Ok(self.data.range(prefix.to_string()..).map(|(k, _)| { k }).take_while(|k| { k.starts_with(prefix) }).cloned().collect())
+  }
+
+  fn snapshot(&self) -> Result<BTreeMap<String, Value>, error::Error> {
+    Ok(self.data.iter().map(|(k, e)| { (k.clone(), e.value()) }).collect())
+  }
+
+  fn len(&self) -> Result<usize, error::Error> {
+    Ok(self.data.len())
+  }
+
+  fn usage(&self) -> Option<Usage> {
+    Some(Usage{
+      strings: self.strings.len(),
+      string_bytes: self.string_bytes,
+      interned_bytes: self.interned_bytes,
+      saved_bytes: self.string_bytes - self.interned_bytes,
+    })
+  }
+}
+
+/// A store which may be shared between threads. Each operation holds the
+/// underlying store only for its own duration, so operations which read,
+/// modify, and write a value must coordinate among themselves; see
+/// `keylock::Locks`.
+#[derive(Clone)]
+pub struct Shared {
+  store: Arc<Mutex<Box<dyn Store>>>,
+}
+
+impl Shared {
+  pub fn new(store: Box<dyn Store>) -> Self {
+    Self{
+      store: Arc::new(Mutex::new(store)),
+    }
+  }
+
+  fn store(&self) -> MutexGuard<'_, Box<dyn Store>> {
+    match self.store.lock() {
+      Ok(store)  => store,
+      Err(store) => store.into_inner(), // a panicked operation never leaves a store half-written
+    }
+  }
+}
+
+impl Store for Shared {
+  fn get(&self, key: &str) -> Result<Option<Value>, error::Error> {
+    self.store().get(key)
+  }
+
+  fn set(&mut self, key: &str, value: Value) -> Result<(), error::Error> {
+    self.store().set(key, value)
+  }
+
+  fn delete(&mut self, key: &str) -> Result<Option<Value>, error::Error> {
+    self.store().delete(key)
+  }
+
+  fn scan(&self, prefix: &str) -> Result<Vec<String>, error::Error> {
+    self.store().scan(prefix)
+  }
+
+  fn snapshot(&self) -> Result<BTreeMap<String, Value>, error::Error> {
+    self.store().snapshot()
+  }
+
+  fn len(&self) -> Result<usize, error::Error> {
+    self.store().len()
+  }
+
+  fn usage(&self) -> Option<Usage> {
+    self.store().usage()
+  }
+}
+
+const DISK_EXT: &str = "json";
+/// Keys whose encoded names are longer than this are kept in files named
+/// for their hash, since file systems limit names to 255 bytes and room is
+/// left for the temporary name a file is written under.
+const DISK_NAME_MAX: usize = 200;
+/// Begins the name of a file named for the hash of its key, which no
+/// encoded key begins with.
+const DISK_HASHED: &str = "%%";
+
+/// What a file named for the hash of its key holds, so the key can be
+/// recovered.
+#[derive(Serialize, Deserialize)]
+struct Hashed {
+  key: String,
+  value: Value,
+}
+
+/// A store which keeps each value in its own file within a directory, so
+/// that data can exceed available memory and survives the service exiting.
+/// Values are decoded when read and encoded when written; updates replace
+/// files atomically. When a cipher is provided files are encrypted.
+pub struct Disk {
+  dir: path::PathBuf,
+  cipher: Option<crypto::Cipher>,
+}
+
+impl Disk {
+  pub fn open<P: AsRef<path::Path>>(dir: P, cipher: Option<crypto::Cipher>) -> Result<Self, error::Error> {
+    let dir = dir.as_ref().to_path_buf();
+    fs::create_dir_all(&dir)?;
+    Ok(Self{
+      dir,
+      cipher,
+    })
+  }
+
+  fn path(&self, key: &str) -> path::PathBuf {
+    let name = encode_filename(key);
+    if name.len() <= DISK_NAME_MAX {
+      return self.dir.join(format!("{}.{}", name, DISK_EXT));
+    }
+    self.dir.join(format!("{}{}.{}", DISK_HASHED, crypto::encode_hex(&Sha256::digest(key.as_bytes())), DISK_EXT))
+  }
+
+  fn read(&self, path: &path::Path) -> Result<Option<Value>, error::Error> {
+    match fs::read(path) {
+      Ok(data) => match &self.cipher {
+        Some(cipher) => Ok(Some(serde_json::from_slice(&cipher.open(&data)?)?)),
+        None         => Ok(Some(serde_json::from_slice(&data)?)),
+      },
+      Err(err) => match err.kind() {
+        io::ErrorKind::NotFound => Ok(None),
+        _                       => Err(err.into()),
+      },
+    }
+  }
+
+  fn keys(&self) -> Result<Vec<String>, error::Error> {
+    let mut keys: Vec<String> = Vec::new();
+    for entry in fs::read_dir(&self.dir)? {
+      let name = entry?.file_name();
+      let name = match name.to_str() {
+        Some(name) => name,
+        None       => continue, // not one of ours
+      };
+      match name.strip_suffix(&format!(".{}", DISK_EXT)) {
+        Some(hashed) if hashed.starts_with(DISK_HASHED) => {
+          if let Some(value) = self.read(&self.dir.join(name))? {
+            keys.push(serde_json::from_value::<Hashed>(value)?.key);
+          }
+        },
+        Some(name) => if let Some(key) = decode_filename(name) {
+          keys.push(key);
+        },
+        None => {},
+      }
+    }
+    keys.sort();
+    Ok(keys)
+  }
+}
+
+impl Store for Disk {
+  fn get(&self, key: &str) -> Result<Option<Value>, error::Error> {
+    let path = self.path(key);
+    let value = self.read(&path)?;
+    if !is_hashed(&path) {
+      return Ok(value);
+    }
+    match value.map(serde_json::from_value::<Hashed>).transpose()? {
+      Some(hashed) if hashed.key == key => Ok(Some(hashed.value)),
+      _                                 => Ok(None),
+    }
+  }
+
+  fn set(&mut self, key: &str, value: Value) -> Result<(), error::Error> {
+    let path = self.path(key);
+    let value = match is_hashed(&path) {
+      true  => serde_json::to_value(Hashed{key: key.to_string(), value})?,
+      false => value,
+    };
+    let data = match &self.cipher {
+      Some(cipher) => cipher.seal(value.to_string().as_bytes())?,
+      None         => value.to_string().into_bytes(),
+    };
+    crate::write_atomic(&path, &data) // replace the value atomically
+  }
+
+  fn delete(&mut self, key: &str) -> Result<Option<Value>, error::Error> {
+    let value = self.get(key)?;
+    if value.is_some() {
+      fs::remove_file(self.path(key))?;
+    }
+    Ok(value)
+  }
+
+  fn scan(&self, prefix: &str) -> Result<Vec<String>, error::Error> {
+    Ok(self.keys()?.into_iter().filter(|k| { k.starts_with(prefix) }).collect())
+  }
+
+  fn snapshot(&self) -> Result<BTreeMap<String, Value>, error::Error> {
+    let mut data: BTreeMap<String, Value> = BTreeMap::new();
+    for key in self.keys()? {
+      if let Some(value) = self.get(&key)? {
+        data.insert(key, value);
+      }
+    }
+    Ok(data)
+  }
+
+  fn len(&self) -> Result<usize, error::Error> {
+    Ok(self.keys()?.len())
+  }
+}
+
+/// A store which keeps values in an SQLite database, as JSON text in a
+/// single table, `structs(key, value)`. The database can be opened with
+/// standard tools and queried with the JSON functions while the service is
+/// running, e.g.: `SELECT key FROM structs WHERE value->>'status' = 'error'`.
+pub struct Sqlite {
+  conn: rusqlite::Connection,
+}
+
+impl Sqlite {
+  pub fn open<P: AsRef<path::Path>>(path: P) -> Result<Self, error::Error> {
+    let conn = rusqlite::Connection::open(path)?;
+    // write-ahead logging lets other processes read while we write
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.execute("CREATE TABLE IF NOT EXISTS structs (key TEXT PRIMARY KEY NOT NULL, value TEXT NOT NULL CHECK (json_valid(value)))", ())?;
+    Ok(Self{
+      conn,
+    })
+  }
+}
+
+impl Store for Sqlite {
+  fn get(&self, key: &str) -> Result<Option<Value>, error::Error> {
+    let mut stmt = self.conn.prepare_cached("SELECT value FROM structs WHERE key = ?1")?;
+    let mut rows = stmt.query([key])?;
+    match rows.next()? {
+      Some(row) => Ok(Some(serde_json::from_str(&row.get::<_, String>(0)?)?)),
+      None      => Ok(None),
+    }
+  }
+
+  fn set(&mut self, key: &str, value: Value) -> Result<(), error::Error> {
+    let mut stmt = self.conn.prepare_cached("INSERT INTO structs (key, value) VALUES (?1, ?2) ON CONFLICT (key) DO UPDATE SET value = excluded.value")?;
+    stmt.execute((key, value.to_string()))?;
+    Ok(())
+  }
+
+  fn delete(&mut self, key: &str) -> Result<Option<Value>, error::Error> {
+    let value = self.get(key)?;
+    if value.is_some() {
+      self.conn.prepare_cached("DELETE FROM structs WHERE key = ?1")?.execute([key])?;
+    }
+    Ok(value)
+  }
+
+  fn scan(&self, prefix: &str) -> Result<Vec<String>, error::Error> {
+    // compare the prefix directly rather than with LIKE, which has its own metacharacters
+    let mut stmt = self.conn.prepare_cached("SELECT key FROM structs WHERE substr(key, 1, length(?1)) = ?1 ORDER BY key")?;
+    let keys = stmt.query_map([prefix], |row| { row.get::<_, String>(0) })?;
+    Ok(keys.collect::<Result<Vec<String>, rusqlite::Error>>()?)
+  }
+
+  fn snapshot(&self) -> Result<BTreeMap<String, Value>, error::Error> {
+    let mut stmt = self.conn.prepare_cached("SELECT key, value FROM structs")?;
+    let mut rows = stmt.query(())?;
+    let mut data: BTreeMap<String, Value> = BTreeMap::new();
+    while let Some(row) = rows.next()? {
+      data.insert(row.get(0)?, serde_json::from_str(&row.get::<_, String>(1)?)?);
+    }
+    Ok(data)
+  }
+
+  fn len(&self) -> Result<usize, error::Error> {
+    Ok(self.conn.query_row("SELECT count(*) FROM structs", (), |row| { row.get(0) })?)
+  }
+}
+
+/// A store which keeps everything in memory and persists it to a single
+/// file, as a JSON object whose members are keys and their values. The
+/// whole file is rewritten, atomically, on every change, which suits small
+/// amounts of data which matter, like configuration. When a cipher is
+/// provided the file is encrypted.
+pub struct File {
+  path: path::PathBuf,
+  data: Memory,
+  cipher: Option<crypto::Cipher>,
+}
+
+impl File {
+  pub fn open<P: AsRef<path::Path>>(path: P, cipher: Option<crypto::Cipher>) -> Result<Self, error::Error> {
+    let path = path.as_ref().to_path_buf();
+    let mut data = Memory::new();
+    match fs::read(&path) {
+      Ok(text) => {
+        let text = match &cipher {
+          Some(cipher) => cipher.open(&text)?,
+          None         => text,
+        };
+        data.data = serde_json::from_slice(&text)?;
+      },
+      Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+      Err(err) => return Err(err.into()),
+    }
+    Ok(Self{
+      path,
+      data,
+      cipher,
+    })
+  }
+
+  fn save(&self) -> Result<(), error::Error> {
+    let data = serde_json::to_vec(&self.data.data)?;
+    let data = match &self.cipher {
+      Some(cipher) => cipher.seal(&data)?,
+      None         => data,
+    };
+    crate::write_atomic(&self.path, &data) // replace the file atomically
+  }
+}
+
+impl Store for File {
+  fn get(&self, key: &str) -> Result<Option<Value>, error::Error> {
+    self.data.get(key)
+  }
+
+  fn set(&mut self, key: &str, value: Value) -> Result<(), error::Error> {
+    self.data.set(key, value)?;
+    self.save()
+  }
+
+  fn delete(&mut self, key: &str) -> Result<Option<Value>, error::Error> {
+    let value = self.data.delete(key)?;
+    if value.is_some() {
+      self.save()?;
+    }
+    Ok(value)
+  }
+
+  fn scan(&self, prefix: &str) -> Result<Vec<String>, error::Error> {
+    self.data.scan(prefix)
+  }
+
+  fn snapshot(&self) -> Result<BTreeMap<String, Value>, error::Error> {
+    self.data.snapshot()
+  }
+
+  fn len(&self) -> Result<usize, error::Error> {
+    self.data.len()
+  }
+}
+
+/// Where the keys beginning with a prefix are kept, as in `cache:=memory`,
+/// which keeps them in memory only, or `config:=config.json`, which
+/// persists them to a file. A trailing `*` on the prefix is ignored, so
+/// `cache:*=memory` means the same.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Partition {
+  pub prefix: String,
+  pub target: String,
+}
+
+impl FromStr for Partition {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.rsplit_once('=') {
+      Some((prefix, target)) if !target.is_empty() => Ok(Self{
+        prefix: prefix.strip_suffix('*').unwrap_or(prefix).to_string(),
+        target: target.to_string(),
+      }),
+      _ => Err(format!("Invalid partition, expected PREFIX=memory or PREFIX=FILE: {}", s)),
+    }
+  }
+}
+
+impl Partition {
+  /// Open the store the partition's keys are kept in.
+  pub fn open(&self, cipher: Option<crypto::Cipher>) -> Result<Box<dyn Store>, error::Error> {
+    match self.target.as_str() {
+      BACKEND_MEMORY => Ok(Box::new(Memory::new())),
+      path           => Ok(Box::new(File::open(path, cipher)?)),
+    }
+  }
+}
+
+/// A store which keeps the keys beginning with certain prefixes in stores
+/// of their own, and every other key in a default store, so that each kind
+/// of data can be persisted as durably as it deserves. The longest prefix
+/// a key begins with decides where it is kept.
+pub struct Partitioned {
+  default: Box<dyn Store>,
+  parts: Vec<(String, Box<dyn Store>)>,
+}
+
+impl Partitioned {
+  pub fn new(default: Box<dyn Store>, mut parts: Vec<(String, Box<dyn Store>)>) -> Self {
+    parts.sort_by(|a, b| { b.0.len().cmp(&a.0.len()) });
+    Self{
+      default,
+      parts,
+    }
+  }
+
+  fn store(&self, key: &str) -> &dyn Store {
+    match self.parts.iter().find(|(prefix, _)| { key.starts_with(prefix.as_str()) }) {
+      Some((_, store)) => store.as_ref(),
+      None             => self.default.as_ref(),
+    }
+  }
+
+  fn store_mut(&mut self, key: &str) -> &mut dyn Store {
+    match self.parts.iter_mut().find(|(prefix, _)| { key.starts_with(prefix.as_str()) }) {
+      Some((_, store)) => store.as_mut(),
+      None             => self.default.as_mut(),
+    }
+  }
+
+  fn stores(&self) -> impl Iterator<Item=&dyn Store> {
+    std::iter::once(self.default.as_ref()).chain(self.parts.iter().map(|(_, e)| { e.as_ref() }))
+  }
+}
+
+impl Store for Partitioned {
+  fn get(&self, key: &str) -> Result<Option<Value>, error::Error> {
+    self.store(key).get(key)
+  }
+
+  fn set(&mut self, key: &str, value: Value) -> Result<(), error::Error> {
+    self.store_mut(key).set(key, value)
+  }
+
+  fn delete(&mut self, key: &str) -> Result<Option<Value>, error::Error> {
+    self.store_mut(key).delete(key)
+  }
+
+  fn scan(&self, prefix: &str) -> Result<Vec<String>, error::Error> {
+    let mut keys: Vec<String> = Vec::new();
+    for store in self.stores() {
+      keys.extend(store.scan(prefix)?);
+    }
+    keys.sort();
+    Ok(keys)
+  }
+
+  fn snapshot(&self) -> Result<BTreeMap<String, Value>, error::Error> {
+    let mut data: BTreeMap<String, Value> = BTreeMap::new();
+    for store in self.stores() {
+      data.extend(store.snapshot()?);
+    }
+    Ok(data)
+  }
+
+  fn len(&self) -> Result<usize, error::Error> {
+    let mut n = 0;
+    for store in self.stores() {
+      n += store.len()?;
+    }
+    Ok(n)
+  }
+
+  fn usage(&self) -> Option<Usage> {
+    self.default.usage()
+  }
+}
+
+/// Encode a key as a file name. Anything other than ASCII alphanumerics,
+/// '-', and '_' is percent-encoded so that every key produces a valid,
+/// distinct, non-hidden file name. The empty key, which would otherwise
+/// produce an empty name, is a lone '%', which nothing else encodes to.
+fn encode_filename(key: &str) -> String {
+  if key.is_empty() {
+    return "%".to_string();
+  }
+  let mut name = String::new();
+  for b in key.bytes() {
+    match b {
+      b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => name.push(b as char),
+      _ => name.push_str(&format!("%{:02X}", b)),
+    }
+  }
+  name
+}
+
+/// Determine if a file in a disk store is named for the hash of its key.
+fn is_hashed(path: &path::Path) -> bool {
+  path.file_name().is_some_and(|e| { e.to_string_lossy().starts_with(DISK_HASHED) })
+}
+
+fn decode_filename(name: &str) -> Option<String> {
+  if name == "%" {
+    return Some(String::new());
+  }
+  if name.is_empty() {
+    return None; // not a name encode_filename produces
+  }
+  let mut key: Vec<u8> = Vec::new();
+  let mut bytes = name.bytes();
+  while let Some(b) = bytes.next() {
+    if b == b'%' {
+      let hex = [bytes.next()?, bytes.next()?];
+      key.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+    } else {
+      key.push(b);
+    }
+  }
+  String::from_utf8(key).ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn memory_store() {
+    let mut s = new(BACKEND_MEMORY, None, None).unwrap();
+    s.set("job:1", Value::Number(1.into())).unwrap();
+    s.set("job:2", Value::Number(2.into())).unwrap();
+    s.set("other", Value::Null).unwrap();
+    assert_eq!(Some(Value::Number(1.into())), s.get("job:1").unwrap());
+    assert_eq!(None, s.get("job:3").unwrap());
+    assert_eq!(vec!["job:1", "job:2"], s.scan("job:").unwrap());
+    assert_eq!(vec!["job:1", "job:2", "other"], s.scan("").unwrap());
+    assert_eq!(3, s.len().unwrap());
+    assert_eq!(Some(Value::Null), s.delete("other").unwrap());
+    assert_eq!(None, s.delete("other").unwrap());
+    assert_eq!(2, s.snapshot().unwrap().len());
+    assert!(new("nonsense", None, None).is_err());
+  }
+
+  #[test]
+  fn interned_store() {
+    let mut s = new(BACKEND_INTERNED, None, None).unwrap();
+    let job = serde_json::json!({"status": "running", "tags": ["running", 1]});
+    s.set("job:1", job.clone()).unwrap();
+    s.set("job:2", job.clone()).unwrap();
+    assert_eq!(Some(job.clone()), s.get("job:1").unwrap());
+    assert_eq!(vec!["job:1", "job:2"], s.scan("job:").unwrap());
+    assert_eq!(Some(Usage{ strings: 3, string_bytes: 48, interned_bytes: 17, saved_bytes: 31 }), s.usage());
+    s.set("job:2", serde_json::json!({"status": "done"})).unwrap();
+    assert_eq!(Some(Usage{ strings: 4, string_bytes: 34, interned_bytes: 21, saved_bytes: 13 }), s.usage());
+    assert_eq!(Some(job), s.delete("job:1").unwrap());
+    assert_eq!(Some(Usage{ strings: 2, string_bytes: 10, interned_bytes: 10, saved_bytes: 0 }), s.usage());
+    assert_eq!(1, s.snapshot().unwrap().len());
+  }
+
+  #[test]
+  fn disk_store() {
+    let dir = std::env::temp_dir().join(format!("structs-test-{}", std::process::id()));
+    let mut s = new(BACKEND_DISK, Some(&dir), None).unwrap();
+    s.set("job:1", Value::Number(1.into())).unwrap();
+    s.set("job:2", Value::Number(2.into())).unwrap();
+    s.set("../other", Value::Null).unwrap();
+    s.set("", Value::Bool(true)).unwrap();
+    assert!(dir.join("%.json").is_file());
+    assert_eq!(Some(Value::Bool(true)), s.delete("").unwrap());
+    assert_eq!(Some(Value::Number(1.into())), s.get("job:1").unwrap());
+    assert_eq!(None, s.get("job:3").unwrap());
+    assert_eq!(vec!["job:1", "job:2"], s.scan("job:").unwrap());
+    assert_eq!(vec!["../other", "job:1", "job:2"], s.scan("").unwrap());
+    assert_eq!(3, s.len().unwrap());
+    assert_eq!(Some(Value::Null), s.delete("../other").unwrap());
+    assert_eq!(None, s.delete("../other").unwrap());
+    let long = "k".repeat(300); // too long for a file name
+    s.set(&long, Value::Bool(true)).unwrap();
+    assert_eq!(Some(Value::Bool(true)), s.get(&long).unwrap());
+    assert_eq!(None, s.get(&"k".repeat(301)).unwrap());
+    let s = new(BACKEND_DISK, Some(&dir), None).unwrap(); // reopen
+    assert_eq!(vec!["job:1".to_string(), "job:2".to_string(), long.clone()], s.scan("").unwrap());
+    let mut s = new(BACKEND_DISK, Some(&dir), None).unwrap();
+    assert_eq!(Some(Value::Bool(true)), s.delete(&long).unwrap());
+    assert_eq!(2, s.snapshot().unwrap().len());
+    assert!(new(BACKEND_DISK, None, None).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn encrypted_disk_store() {
+    let dir = std::env::temp_dir().join(format!("structs-test-enc-{}", std::process::id()));
+    let cipher = crypto::Cipher::new(&[1u8; 32]).unwrap();
+    let mut s = new(BACKEND_DISK, Some(&dir), Some(cipher.clone())).unwrap();
+    s.set("token", Value::String("hunter2".to_string())).unwrap();
+    assert_eq!(Some(Value::String("hunter2".to_string())), s.get("token").unwrap());
+    let raw = fs::read(dir.join(format!("token.{}", DISK_EXT))).unwrap();
+    assert!(!String::from_utf8_lossy(&raw).contains("hunter2"));
+    let s = new(BACKEND_DISK, Some(&dir), None).unwrap(); // no key
+    assert!(s.get("token").is_err());
+    assert!(new(BACKEND_SQLITE, Some(&dir.join("x.db")), Some(cipher)).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn sqlite_store() {
+    let path = std::env::temp_dir().join(format!("structs-test-{}.db", std::process::id()));
+    let mut s = new(BACKEND_SQLITE, Some(&path), None).unwrap();
+    s.set("job:1", Value::Number(1.into())).unwrap();
+    s.set("job:2", Value::Number(2.into())).unwrap();
+    s.set("job%", Value::Null).unwrap();
+    s.set("job:1", Value::Number(10.into())).unwrap();
+    assert_eq!(Some(Value::Number(10.into())), s.get("job:1").unwrap());
+    assert_eq!(None, s.get("job:3").unwrap());
+    assert_eq!(vec!["job:1", "job:2"], s.scan("job:").unwrap());
+    assert_eq!(vec!["job%"], s.scan("job%").unwrap());
+    assert_eq!(3, s.len().unwrap());
+    assert_eq!(Some(Value::Null), s.delete("job%").unwrap());
+    assert_eq!(None, s.delete("job%").unwrap());
+    let s = new(BACKEND_SQLITE, Some(&path), None).unwrap(); // reopen
+    assert_eq!(2, s.snapshot().unwrap().len());
+    for ext in ["", "-wal", "-shm"] {
+      let _ = fs::remove_file(format!("{}{}", path.display(), ext));
+    }
+  }
+
+  #[test]
+  fn partitioned_store() {
+    let path = std::env::temp_dir().join(format!("structs-test-{}.json", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let parts = || -> Vec<(String, Box<dyn Store>)> {
+      let config: Partition = format!("config:={}", path.display()).parse().unwrap();
+      let cache: Partition = "cache:*=memory".parse().unwrap();
+      assert_eq!("cache:", cache.prefix);
+      vec![(config.prefix.clone(), config.open(None).unwrap()), (cache.prefix.clone(), cache.open(None).unwrap())]
+    };
+    let mut s = Partitioned::new(Box::new(Memory::new()), parts());
+    s.set("config:db", Value::Number(1.into())).unwrap();
+    s.set("cache:a", Value::Number(2.into())).unwrap();
+    s.set("job", Value::Number(3.into())).unwrap();
+    assert_eq!(Some(Value::Number(1.into())), s.get("config:db").unwrap());
+    assert_eq!(vec!["cache:a", "config:db", "job"], s.scan("").unwrap());
+    assert_eq!(3, s.len().unwrap());
+    assert_eq!(r#"{"config:db":1}"#, fs::read_to_string(&path).unwrap());
+
+    let s = Partitioned::new(Box::new(Memory::new()), parts()); // restart
+    assert_eq!(vec!["config:db"], s.scan("").unwrap());
+    assert!("nonsense".parse::<Partition>().is_err());
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn filenames() {
+    assert_eq!("job%3A1", encode_filename("job:1"));
+    assert_eq!("%2E%2E%2Fx", encode_filename("../x"));
+    assert_eq!("%C3%A9t%C3%A9", encode_filename("été"));
+    assert_eq!("%", encode_filename(""));
+    for key in ["job:1", "../x", "été", "a b", ""] {
+      assert_eq!(Some(key.to_string()), decode_filename(&encode_filename(key)));
+    }
+    assert_eq!(None, decode_filename("%2"));
+    assert_eq!(None, decode_filename(""));
+  }
+}
@@ -0,0 +1,62 @@
+/// The most names offered as suggestions.
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Choose the names most like one which was not found, to offer as
+/// suggestions: those which differ from it by a few edits, or of which it
+/// is a prefix or which are a prefix of it, ignoring case. The closest come
+/// first.
+pub fn similar<'a, I: IntoIterator<Item=&'a str>>(name: &str, candidates: I) -> Vec<String> {
+  let name = name.to_lowercase();
+  let mut found: Vec<(usize, &str)> = candidates.into_iter().filter_map(|e| {
+    let lower = e.to_lowercase();
+    let dist = distance(&name, &lower);
+    let most = (name.chars().count().max(lower.chars().count()) / 3).max(1); // about one edit in three
+    let prefix = !name.is_empty() && (lower.starts_with(&name) || name.starts_with(&lower));
+    match dist <= most || prefix {
+      true  => Some((dist, e)),
+      false => None,
+    }
+  }).collect();
+  found.sort();
+  found.into_iter().take(MAX_SUGGESTIONS).map(|(_, e)| { e.to_string() }).collect()
+}
+
+/// The Levenshtein distance between two strings: the number of characters
+/// which must be inserted, deleted, or substituted to turn one into the
+/// other.
+fn distance(a: &str, b: &str) -> usize {
+  let b: Vec<char> = b.chars().collect();
+  let mut row: Vec<usize> = (0..=b.len()).collect();
+  for (i, ca) in a.chars().enumerate() {
+    let mut diag = row[0];
+    row[0] = i + 1;
+    for (j, cb) in b.iter().enumerate() {
+      let next = (row[j + 1] + 1).min(row[j] + 1).min(diag + (ca != *cb) as usize);
+      diag = row[j + 1];
+      row[j + 1] = next;
+    }
+  }
+  row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn edit_distance() {
+    assert_eq!(0, distance("servers", "servers"));
+    assert_eq!(1, distance("sever", "server"));
+    assert_eq!(3, distance("kitten", "sitting"));
+    assert_eq!(4, distance("", "port"));
+  }
+
+  #[test]
+  fn suggest_names() {
+    let names = ["servers", "server-list", "ports", "timeout", "Config"];
+    assert_eq!(vec!["servers"], similar("sevrers", names));
+    assert_eq!(vec!["servers", "server-list"], similar("server", names));
+    assert_eq!(vec!["Config"], similar("config", names));
+    assert!(similar("xyz", names).is_empty());
+  }
+}
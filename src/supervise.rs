@@ -0,0 +1,53 @@
+use std::time;
+use std::thread;
+use std::process;
+
+use colored::Colorize;
+
+use crate::Options;
+use crate::error;
+use crate::log;
+
+const MIN_BACKOFF: time::Duration = time::Duration::from_millis(100);
+const MAX_BACKOFF: time::Duration = time::Duration::from_secs(30);
+const STABLE:      time::Duration = time::Duration::from_secs(60);
+
+/// Run the service produced by `command` until it exits cleanly, restarting
+/// it whenever it does not. Restarts back off exponentially so a service
+/// which can't start doesn't spin; a service which stays up for a while is
+/// considered recovered and the backoff is reset.
+pub fn supervise<F: Fn() -> Result<process::Command, error::Error>>(opts: &Options, command: F) -> Result<(), error::Error> {
+  let mut backoff = MIN_BACKOFF;
+  loop {
+    let start = time::Instant::now();
+    let status = command()?.status()?;
+    if status.success() {
+      return Ok(()); // stopped or finalized
+    }
+    if start.elapsed() >= STABLE {
+      backoff = MIN_BACKOFF;
+    }
+    log::logln!("{}", &format!("* * * Service exited ({}); restarting in {:?}", status, backoff).yellow().bold());
+    thread::sleep(backoff);
+    backoff = next_backoff(backoff);
+    if opts.debug {
+      log::logln!(">>> Restarting service...");
+    }
+  }
+}
+
+fn next_backoff(backoff: time::Duration) -> time::Duration {
+  (backoff * 2).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn backoff() {
+    assert_eq!(time::Duration::from_millis(200), next_backoff(MIN_BACKOFF));
+    assert_eq!(MAX_BACKOFF, next_backoff(time::Duration::from_secs(20)));
+    assert_eq!(MAX_BACKOFF, next_backoff(MAX_BACKOFF));
+  }
+}
@@ -0,0 +1,95 @@
+use std::time;
+use std::str::FromStr;
+
+use structs_sh::duration;
+
+/// A point in time, in milliseconds since the epoch. It is written as a
+/// number of milliseconds, as an RFC 3339 time in UTC, like
+/// `2026-10-15T14:03:00Z`, or as a duration, like `10m`, meaning that long
+/// ago.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timestamp(u128);
+
+impl Timestamp {
+  pub fn millis(&self) -> u128 {
+    self.0
+  }
+}
+
+impl FromStr for Timestamp {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if let Ok(millis) = s.parse::<u128>() {
+      return Ok(Self(millis));
+    }
+    if let Some(millis) = parse_rfc3339(s) {
+      return Ok(Self(millis));
+    }
+    match s.parse::<duration::Duration>() {
+      Ok(ago) => match time::SystemTime::now().checked_sub(ago.duration()).and_then(|e| { e.duration_since(time::UNIX_EPOCH).ok() }) {
+        Some(since) => Ok(Self(since.as_millis())),
+        None        => Err(format!("Too long ago: {}", s)),
+      },
+      Err(_) => Err(format!("Invalid time, expected milliseconds since the epoch, a UTC time like 2026-10-15T14:03:00Z, or a duration ago like 10m: {}", s)),
+    }
+  }
+}
+
+/// Parse `YYYY-MM-DDTHH:MM:SS[.fff]Z` into milliseconds since the epoch.
+fn parse_rfc3339(s: &str) -> Option<u128> {
+  let s = s.strip_suffix('Z').or_else(|| { s.strip_suffix('z') })?;
+  let (date, time) = s.split_once(|c| { c == 'T' || c == 't' || c == ' ' })?;
+  let date: Vec<&str> = date.split('-').collect();
+  let (time, frac) = match time.split_once('.') {
+    Some((time, frac)) => (time, Some(frac)),
+    None               => (time, None),
+  };
+  let time: Vec<&str> = time.split(':').collect();
+  if date.len() != 3 || time.len() != 3 {
+    return None;
+  }
+  let (year, month, day) = (date[0].parse::<i64>().ok()?, date[1].parse::<u32>().ok()?, date[2].parse::<u32>().ok()?);
+  let (hour, min, sec) = (time[0].parse::<u64>().ok()?, time[1].parse::<u64>().ok()?, time[2].parse::<u64>().ok()?);
+  if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || min > 59 || sec > 60 {
+    return None;
+  }
+  let millis = match frac {
+    Some(frac) if !frac.is_empty() && frac.chars().all(|c| { c.is_ascii_digit() }) => format!("{:0<3}", &frac[..frac.len().min(3)]).parse::<u64>().ok()?,
+    Some(_)                                                                        => return None,
+    None                                                                           => 0,
+  };
+  let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+  Some(((((days * 24 + hour) * 60 + min) * 60 + sec) * 1000 + millis) as u128)
+}
+
+/// The number of days from the epoch to a date in the proleptic Gregorian
+/// calendar.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+  let year = if month <= 2 { year - 1 } else { year };
+  let era = year.div_euclid(400);
+  let yoe = year - era * 400;
+  let mp = (month as i64 + 9) % 12; // March is 0
+  let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+  let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+  era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_timestamps() {
+    assert_eq!(Ok(Timestamp(1792042008419)), "1792042008419".parse());
+    assert_eq!(Ok(Timestamp(0)), "1970-01-01T00:00:00Z".parse());
+    assert_eq!(Ok(Timestamp(951782400000)), "2000-02-29T00:00:00Z".parse());
+    assert_eq!(Ok(Timestamp(1792073580250)), "2026-10-15T14:13:00.25Z".parse());
+    let ago = "10m".parse::<Timestamp>().unwrap().millis();
+    let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_millis();
+    assert!(now - ago >= 600000 && now - ago < 660000);
+    assert!("2026-13-01T00:00:00Z".parse::<Timestamp>().is_err());
+    assert!("2026-10-15T14:03:00+02:00".parse::<Timestamp>().is_err());
+    assert!("yesterday".parse::<Timestamp>().is_err());
+  }
+}
@@ -0,0 +1,121 @@
+use std::fmt;
+use std::time;
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard};
+use std::collections::BTreeMap;
+
+pub const STRATEGY_LAZY:   &str = "lazy";
+pub const STRATEGY_ACTIVE: &str = "active";
+
+/// How expired keys are removed. Expired keys are always removed when they
+/// are accessed; the active strategy also sweeps the store periodically so
+/// that keys which are never accessed again don't linger.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+  Lazy,
+  Active,
+}
+
+impl FromStr for Strategy {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      STRATEGY_LAZY   => Ok(Self::Lazy),
+      STRATEGY_ACTIVE => Ok(Self::Active),
+      _               => Err(format!("Unknown expiration strategy: {}", s)),
+    }
+  }
+}
+
+impl fmt::Display for Strategy {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Lazy   => write!(f, "{}", STRATEGY_LAZY),
+      Self::Active => write!(f, "{}", STRATEGY_ACTIVE),
+    }
+  }
+}
+
+/// Tracks the keys which expire, when they do, and the TTL they were given.
+pub struct Expiry {
+  deadlines: Mutex<BTreeMap<String, (time::Instant, time::Duration)>>,
+}
+
+impl Expiry {
+  pub fn new() -> Self {
+    Self{
+      deadlines: Mutex::new(BTreeMap::new()),
+    }
+  }
+
+  fn deadlines(&self) -> MutexGuard<'_, BTreeMap<String, (time::Instant, time::Duration)>> {
+    match self.deadlines.lock() {
+      Ok(deadlines)  => deadlines,
+      Err(deadlines) => deadlines.into_inner(),
+    }
+  }
+
+  /// Expire a key after the provided duration, or never, if no duration is
+  /// provided.
+  pub fn set(&self, key: &str, ttl: Option<time::Duration>) {
+    match ttl {
+      Some(ttl) => { self.deadlines().insert(key.to_string(), (time::Instant::now() + ttl, ttl)); },
+      None      => { self.deadlines().remove(key); },
+    }
+  }
+
+  /// The time remaining before a key expires, if it expires.
+  pub fn remaining(&self, key: &str) -> Option<time::Duration> {
+    self.deadlines().get(key).map(|(e, _)| { e.saturating_duration_since(time::Instant::now()) })
+  }
+
+  /// Restart the TTL of a key which expires, as though it had just been set,
+  /// producing whether it expires.
+  pub fn reset(&self, key: &str) -> bool {
+    match self.deadlines().get_mut(key) {
+      Some((deadline, ttl)) => {
+        *deadline = time::Instant::now() + *ttl;
+        true
+      },
+      None => false,
+    }
+  }
+
+  pub fn is_expired(&self, key: &str) -> bool {
+    match self.deadlines().get(key) {
+      Some((deadline, _)) => *deadline <= time::Instant::now(),
+      None                => false,
+    }
+  }
+
+  /// List every key which has expired.
+  pub fn expired(&self) -> Vec<String> {
+    let now = time::Instant::now();
+    self.deadlines().iter().filter(|(_, (v, _))| { *v <= now }).map(|(k, _)| { k.to_owned() }).collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn expire_keys() {
+    let e = Expiry::new();
+    e.set("a", Some(time::Duration::from_secs(0)));
+    e.set("b", Some(time::Duration::from_secs(60)));
+    e.set("c", Some(time::Duration::from_secs(0)));
+    e.set("c", None);
+    assert!(e.is_expired("a"));
+    assert!(!e.is_expired("b"));
+    assert!(!e.is_expired("c"));
+    assert_eq!(vec!["a".to_string()], e.expired());
+    assert!(e.remaining("b").unwrap() > time::Duration::from_secs(59));
+    assert_eq!(None, e.remaining("c"));
+    assert!(e.reset("b"));
+    assert!(!e.reset("c"));
+    assert_eq!(Ok(Strategy::Active), "active".parse());
+    assert!("eager".parse::<Strategy>().is_err());
+  }
+}
@@ -0,0 +1,305 @@
+use std::time;
+use std::sync::Mutex;
+use std::collections::VecDeque;
+
+use tokio::sync::mpsc;
+
+use crate::rpc;
+use crate::pattern;
+
+/// A client's interest in changes to the keys matching any of a set of
+/// patterns, and where to send them.
+struct Subscription {
+  patterns: Vec<pattern::Pattern>,
+  tx: mpsc::UnboundedSender<rpc::Operation>,
+}
+
+impl Subscription {
+  fn is_match(&self, key: &str) -> bool {
+    self.patterns.iter().any(|e| { e.is_match(key) })
+  }
+}
+
+/// The number of events kept, by default, for watchers which resume after
+/// a disconnect.
+pub const DEFAULT_HISTORY: usize = 1000;
+
+/// A change which has been sent to watchers, kept so a watcher which
+/// reconnects can catch up on what it missed, and so past values can be
+/// read. Times are in milliseconds since the epoch.
+struct Event {
+  seq: u64,
+  time: u128,
+  key: String,
+  value: Option<serde_json::Value>,
+}
+
+impl Event {
+  fn operation(&self) -> rpc::Operation {
+    rpc::Operation::new_change(&self.key, self.seq, self.value.as_ref().map(|e| { e.to_string() }).as_deref())
+  }
+}
+
+/// Produces the current value of every watched key which exists, for a
+/// client which wants them before any change.
+pub type Current<'a> = &'a dyn Fn() -> Result<Vec<(String, serde_json::Value)>, String>;
+
+/// What the history says about the value a key had at some time.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Past {
+  /// The key has not changed since, so it had its current value.
+  Unchanged,
+  /// The key had this value, or none if it had been deleted.
+  Value(Option<serde_json::Value>),
+  /// The history does not reach back far enough to tell, for this reason.
+  Unknown(String),
+}
+
+struct Inner {
+  subs: Vec<Subscription>,
+  seq: u64,
+  history: VecDeque<Event>,
+  started: u128,
+  evicted: Option<u128>,
+}
+
+/// Tracks the clients watching for changes to keys. A single subscription
+/// may cover many keys, so a client need not open a connection for each.
+/// Every change is numbered in sequence, and the most recent are kept in a
+/// bounded history, from which a client which was disconnected may resume.
+pub struct Watchers {
+  inner: Mutex<Inner>,
+  history: usize,
+}
+
+impl Watchers {
+  pub fn new(history: usize) -> Self {
+    Self{
+      inner: Mutex::new(Inner{
+        subs: Vec::new(),
+        seq: 0,
+        history: VecDeque::new(),
+        started: now(),
+        evicted: None,
+      }),
+      history,
+    }
+  }
+
+  fn inner(&self) -> std::sync::MutexGuard<'_, Inner> {
+    match self.inner.lock() {
+      Ok(inner) => inner,
+      Err(err)  => err.into_inner(),
+    }
+  }
+
+  /// Send changes to keys matching any of the patterns to a client until it
+  /// goes away. The subscription is acknowledged with the sequence number of
+  /// the latest change. If the client is resuming from the change with a
+  /// sequence number, the changes it missed since are sent first; if they
+  /// are no longer in the history, the client is refused, since it would
+  /// otherwise miss them silently. Otherwise, if the current values of the
+  /// keys are provided, they are sent first, as changes with the sequence
+  /// number of the latest change. They are produced while no change can be
+  /// sent, so none is missed between them and the first change.
+  pub fn subscribe(&self, patterns: Vec<pattern::Pattern>, tx: mpsc::UnboundedSender<rpc::Operation>, resume: Option<u64>, current: Option<Current>) -> Result<(), String> {
+    let mut inner = self.inner();
+    let sub = Subscription{
+      patterns,
+      tx,
+    };
+    if let Some(resume) = resume {
+      if resume > inner.seq {
+        return Err(format!("Cannot resume from {}; the service has restarted since, and is at {}", resume, inner.seq));
+      }
+      let oldest = inner.history.front().map_or(inner.seq + 1, |e| { e.seq });
+      if resume + 1 < oldest {
+        return Err(format!("Cannot resume from {}; changes before {} are no longer in the history", resume, oldest));
+      }
+    }
+    let values = match (resume, current) {
+      (None, Some(current)) => current()?,
+      _                     => Vec::new(),
+    };
+    if sub.tx.send(rpc::Operation::new_watching(inner.seq)).is_err() {
+      return Ok(()); // the client went away
+    }
+    for (key, value) in values {
+      if sub.tx.send(rpc::Operation::new_change(&key, inner.seq, Some(&value.to_string()))).is_err() {
+        return Ok(());
+      }
+    }
+    if let Some(resume) = resume {
+      for event in inner.history.iter().filter(|e| { e.seq > resume && sub.is_match(&e.key) }) {
+        if sub.tx.send(event.operation()).is_err() {
+          return Ok(());
+        }
+      }
+    }
+    inner.subs.push(sub);
+    Ok(())
+  }
+
+  /// Determine if anybody is watching a key, or changes are kept for those
+  /// who may resume watching it, so the caller can avoid producing an event
+  /// nobody will receive.
+  pub fn is_watched(&self, key: &str) -> bool {
+    self.history > 0 || self.inner().subs.iter().any(|e| { e.is_match(key) })
+  }
+
+  /// Notify the clients watching a key that it has changed; an event
+  /// without a value means the key was deleted. Clients which have gone
+  /// away are unsubscribed.
+  pub fn notify(&self, key: &str, value: Option<&serde_json::Value>) {
+    self.record(key, value, now());
+  }
+
+  fn record(&self, key: &str, value: Option<&serde_json::Value>, time: u128) {
+    let mut inner = self.inner();
+    inner.seq += 1;
+    let event = Event{
+      seq: inner.seq,
+      time,
+      key: key.to_string(),
+      value: value.cloned(),
+    };
+    let op = event.operation();
+    inner.subs.retain(|e| {
+      !e.is_match(key) || e.tx.send(op.clone()).is_ok()
+    });
+    if self.history > 0 {
+      if inner.history.len() >= self.history {
+        inner.evicted = inner.history.pop_front().map(|e| { e.time });
+      }
+      inner.history.push_back(event);
+    }
+  }
+
+  /// Determine the value a key had at a time, in milliseconds since the
+  /// epoch, from the history of changes. That is the value of the last
+  /// change to the key at or before the time, if there is one, or its
+  /// current value, if it has not changed since. Either is only known if no
+  /// change since the time has been dropped from the history.
+  pub fn as_of(&self, key: &str, time: u128) -> Past {
+    let inner = self.inner();
+    if self.history == 0 {
+      return Past::Unknown("No history of changes is kept; see run --watch-history".to_string());
+    }
+    if time < inner.started {
+      return Past::Unknown(format!("The service started after {}", time));
+    }
+    if let Some(evicted) = inner.evicted {
+      if time < evicted {
+        return Past::Unknown(format!("Changes made before {} are no longer in the history", evicted));
+      }
+    }
+    let mut changes = inner.history.iter().filter(|e| { e.key == key });
+    let mut last = None;
+    for event in &mut changes {
+      if event.time > time {
+        return match last {
+          Some(last) => Past::Value(last),
+          None       => Past::Unknown(format!("No record of the value of {} before it changed at {}", key, event.time)),
+        };
+      }
+      last = Some(event.value.clone());
+    }
+    match last {
+      Some(last) => Past::Value(last),
+      None       => Past::Unchanged,
+    }
+  }
+}
+
+fn now() -> u128 {
+  match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+    Ok(now) => now.as_millis(),
+    Err(_)  => 0,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn notify_watchers() {
+    let watchers = Watchers::new(0);
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    watchers.subscribe(vec![pattern::Pattern::glob("job:*").unwrap(), pattern::Pattern::glob("config").unwrap()], tx, None, None).unwrap();
+    assert_eq!(rpc::CMD_OK, rx.try_recv().unwrap().name());
+    assert!(watchers.is_watched("job:1"));
+    assert!(!watchers.is_watched("other"));
+
+    watchers.notify("job:1", Some(&serde_json::json!({"n": 1})));
+    watchers.notify("other", Some(&serde_json::json!(1)));
+    watchers.notify("config", None);
+    let event = rx.try_recv().unwrap();
+    assert_eq!((rpc::CMD_FOUND, &["job:1".to_string(), "1".to_string()][..], &Some(r#"{"n":1}"#.to_string())), (event.name(), event.args(), event.data()));
+    let event = rx.try_recv().unwrap();
+    assert_eq!((rpc::CMD_NONE, &["config".to_string(), "3".to_string()][..]), (event.name(), event.args()));
+    assert!(rx.try_recv().is_err());
+
+    drop(rx);
+    watchers.notify("job:2", None);
+    assert!(!watchers.is_watched("job:2")); // the client went away
+  }
+
+  #[test]
+  fn resume_watchers() {
+    let watchers = Watchers::new(2);
+    for n in 1..=3 {
+      watchers.notify("job:1", Some(&serde_json::json!(n)));
+    }
+    watchers.notify("other", None);
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    watchers.subscribe(vec![pattern::Pattern::glob("job:*").unwrap()], tx, Some(2), None).unwrap();
+    assert_eq!(&["4".to_string()][..], rx.try_recv().unwrap().args());
+    let event = rx.try_recv().unwrap();
+    assert_eq!((&["job:1".to_string(), "3".to_string()][..], &Some("3".to_string())), (event.args(), event.data()));
+    assert!(rx.try_recv().is_err()); // other is not watched
+
+    let (tx, _rx) = mpsc::unbounded_channel();
+    assert!(watchers.subscribe(vec![pattern::Pattern::glob("job:*").unwrap()], tx.clone(), Some(1), None).is_err()); // 2 was dropped
+    assert!(watchers.subscribe(vec![pattern::Pattern::glob("job:*").unwrap()], tx, Some(5), None).is_err());
+  }
+
+  #[test]
+  fn initial_values() {
+    let watchers = Watchers::new(0);
+    watchers.notify("job:1", Some(&serde_json::json!(1)));
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let current = || { Ok(vec![("job:1".to_string(), serde_json::json!(1))]) };
+    watchers.subscribe(vec![pattern::Pattern::glob("job:*").unwrap()], tx, None, Some(&current)).unwrap();
+    assert_eq!(&["1".to_string()][..], rx.try_recv().unwrap().args());
+    let event = rx.try_recv().unwrap();
+    assert_eq!((rpc::CMD_FOUND, &["job:1".to_string(), "1".to_string()][..], &Some("1".to_string())), (event.name(), event.args(), event.data()));
+    watchers.notify("job:1", None);
+    assert_eq!(rpc::CMD_NONE, rx.try_recv().unwrap().name());
+
+    let (tx, _rx) = mpsc::unbounded_channel();
+    let failed = || { Err("failed".to_string()) };
+    assert!(watchers.subscribe(vec![pattern::Pattern::glob("job:*").unwrap()], tx, None, Some(&failed)).is_err());
+  }
+
+  #[test]
+  fn past_values() {
+    let watchers = Watchers::new(3);
+    let t = watchers.inner().started;
+    watchers.record("config", Some(&serde_json::json!(1)), t + 10);
+    watchers.record("other", None, t + 20);
+    watchers.record("config", None, t + 30);
+    assert!(matches!(watchers.as_of("config", t + 5), Past::Unknown(_))); // set before we know
+    assert_eq!(Past::Value(Some(serde_json::json!(1))), watchers.as_of("config", t + 10));
+    assert_eq!(Past::Value(Some(serde_json::json!(1))), watchers.as_of("config", t + 29));
+    assert_eq!(Past::Value(None), watchers.as_of("config", t + 30));
+    assert_eq!(Past::Unchanged, watchers.as_of("job", t + 5));
+    assert!(matches!(watchers.as_of("job", t - 1), Past::Unknown(_)));
+
+    watchers.record("config", Some(&serde_json::json!(2)), t + 40); // drops the change at t + 10
+    assert!(matches!(watchers.as_of("config", t + 9), Past::Unknown(_)));
+    assert_eq!(Past::Value(None), watchers.as_of("config", t + 35));
+    assert!(matches!(Watchers::new(0).as_of("config", t + 35), Past::Unknown(_)));
+  }
+}